@@ -135,6 +135,11 @@ check, modify and correct any inconsistencies in the bcachefs filesystem.
 %{_sbindir}/fsck.fuse.bcachefs
 %{_sbindir}/mkfs.fuse.bcachefs
 %{_unitdir}/bcachefs-wait-devices@.service
+%{_unitdir}/bcachefs-autosnap.service
+%{_unitdir}/bcachefs-autosnap.timer
+%{_unitdir}/bcachefs-usage-history.service
+%{_unitdir}/bcachefs-usage-history.timer
+%{_systemdgeneratordir}/bcachefs-generator
 
 %package -n %{dkmsname}
 Summary:        Bcachefs kernel module managed by DKMS
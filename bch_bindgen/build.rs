@@ -469,6 +469,9 @@ fn main() {
         .allowlist_function(".*_cmds")
         .allowlist_function("bio_.*")
         .allowlist_function("derive_passphrase")
+        .allowlist_function("derive_key_slot")
+        .allowlist_function("bch_key_slot_kdf_init")
+        .allowlist_function("key_server_.*")
         .allowlist_function("request_key")
         .allowlist_function("add_key")
         .allowlist_function("keyctl_search")
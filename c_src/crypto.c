@@ -12,7 +12,12 @@
 
 #include <keyutils.h>
 #include <linux/random.h>
+#include <sodium/core.h>
+#include <sodium/crypto_box.h>
+#include <sodium/crypto_generichash.h>
 #include <sodium/crypto_pwhash_scryptsalsa208sha256.h>
+#include <sodium/crypto_scalarmult_curve25519.h>
+#include <sodium/randombytes.h>
 #include <uuid/uuid.h>
 
 #include "crypto.h"
@@ -81,6 +86,99 @@ struct bch_key derive_passphrase(struct bch_sb_field_crypt *crypt,
 	return key;
 }
 
+/*
+ * Key slots get their own salt, unlike the passphrase in the crypt field: two
+ * people picking the same passphrase shouldn't end up with the same slot key.
+ */
+void bch_key_slot_kdf_init(struct bch_key_slot *slot)
+{
+	SET_BCH_KEY_SLOT_KDF_TYPE(slot, BCH_KDF_SCRYPT);
+	SET_BCH_KEY_SLOT_SCRYPT_N(slot, ilog2(16384));
+	SET_BCH_KEY_SLOT_SCRYPT_R(slot, ilog2(8));
+	SET_BCH_KEY_SLOT_SCRYPT_P(slot, ilog2(16));
+	get_random_bytes(slot->salt, sizeof(slot->salt));
+}
+
+struct bch_key derive_key_slot(struct bch_key_slot *slot,
+			       const void *secret, size_t len)
+{
+	struct bch_key key;
+	int ret;
+
+	switch (BCH_KEY_SLOT_KDF_TYPE(slot)) {
+	case BCH_KDF_SCRYPT:
+		ret = crypto_pwhash_scryptsalsa208sha256_ll(
+			secret, len,
+			slot->salt, sizeof(slot->salt),
+			1ULL << BCH_KEY_SLOT_SCRYPT_N(slot),
+			1ULL << BCH_KEY_SLOT_SCRYPT_R(slot),
+			1ULL << BCH_KEY_SLOT_SCRYPT_P(slot),
+			(void *) &key, sizeof(key));
+		if (ret)
+			die("scrypt error: %i", ret);
+		break;
+	default:
+		die("unknown kdf type %llu", BCH_KEY_SLOT_KDF_TYPE(slot));
+	}
+
+	return key;
+}
+
+/*
+ * Network-bound key slots - the crypto half of the key server protocol; see
+ * src/key_server.rs for the rest.
+ *
+ * Keys are X25519. A slot's secret is a hash of the shared secret between a
+ * client key and the server's key: at provisioning time we compute it with the
+ * client's private key and then throw that away, leaving only the server able
+ * to compute it again, from the client public key stored in the slot. It sends
+ * it back sealed to an ephemeral key, so that it's never on the wire in the
+ * clear, and only the server with the private key we pinned can have sealed
+ * it.
+ */
+int key_server_keypair(u8 *pk, u8 *sk)
+{
+	if (sodium_init() < 0)
+		return -1;
+
+	return crypto_box_keypair(pk, sk);
+}
+
+int key_server_public_key(u8 *pk, const u8 *sk)
+{
+	return crypto_scalarmult_curve25519_base(pk, sk);
+}
+
+int key_server_secret(u8 *secret, const u8 *sk, const u8 *pk)
+{
+	u8 shared[crypto_scalarmult_curve25519_BYTES];
+
+	int ret = crypto_scalarmult_curve25519(shared, sk, pk) ?:
+		crypto_generichash(secret, KEY_SERVER_SECRET_BYTES,
+				   shared, sizeof(shared), NULL, 0);
+
+	memzero_explicit(shared, sizeof(shared));
+	return ret;
+}
+
+int key_server_seal(u8 *sealed, u8 *nonce, const u8 *secret,
+		    const u8 *eph_pk, const u8 *server_sk)
+{
+	if (sodium_init() < 0)
+		return -1;
+
+	randombytes_buf(nonce, crypto_box_NONCEBYTES);
+	return crypto_box_easy(sealed, secret, KEY_SERVER_SECRET_BYTES,
+			       nonce, eph_pk, server_sk);
+}
+
+int key_server_open(u8 *secret, const u8 *sealed, const u8 *nonce,
+		    const u8 *server_pk, const u8 *eph_sk)
+{
+	return crypto_box_open_easy(secret, sealed, KEY_SERVER_SEALED_BYTES,
+				    nonce, server_pk, eph_sk);
+}
+
 bool bch2_sb_is_encrypted(struct bch_sb *sb)
 {
 	struct bch_sb_field_crypt *crypt;
@@ -7,10 +7,25 @@ struct bch_sb;
 struct bch_sb_field_crypt;
 struct bch_key;
 struct bch_encrypted_key;
+struct bch_key_slot;
 
 char *read_passphrase(const char *);
 
 struct bch_key derive_passphrase(struct bch_sb_field_crypt *, const char *);
+void bch_key_slot_kdf_init(struct bch_key_slot *);
+struct bch_key derive_key_slot(struct bch_key_slot *, const void *, size_t);
+
+#define KEY_SERVER_KEY_BYTES		32
+#define KEY_SERVER_SECRET_BYTES		32
+#define KEY_SERVER_NONCE_BYTES		24
+#define KEY_SERVER_SEALED_BYTES		(KEY_SERVER_SECRET_BYTES + 16)
+
+int key_server_keypair(u8 *, u8 *);
+int key_server_public_key(u8 *, const u8 *);
+int key_server_secret(u8 *, const u8 *, const u8 *);
+int key_server_seal(u8 *, u8 *, const u8 *, const u8 *, const u8 *);
+int key_server_open(u8 *, const u8 *, const u8 *, const u8 *, const u8 *);
+
 bool bch2_sb_is_encrypted(struct bch_sb *);
 bool bch2_passphrase_check(struct bch_sb *, const char *,
 			   struct bch_key *, struct bch_encrypted_key *);
@@ -142,8 +142,9 @@ found:
 			bad = true;
 		} else if (bch2_checksum_type_valid(c, BSET_CSUM_TYPE(&bn->keys))) {
 			struct nonce nonce = btree_nonce(&bn->keys, 0);
-			struct bch_csum csum = csum_vstruct(c, BSET_CSUM_TYPE(&bn->keys),
-							    nonce, bn);
+			struct bch_csum csum = csum_vstruct_key(bch2_bset_key(c, &bn->keys),
+								BSET_CSUM_TYPE(&bn->keys),
+								nonce, bn);
 			bad = bch2_crc_cmp(bn->csum, csum);
 		} else {
 			bad = false;
@@ -154,8 +155,9 @@ found:
 			      le64_to_cpu(bkey_s_c_to_btree_ptr_v2(extent).v->seq);
 	} else {
 		struct nonce nonce = extent_nonce(extent.k->bversion, p.crc);
-		struct bch_csum csum = bch2_checksum(c, p.crc.csum_type, nonce,
-						     data_buf, bytes);
+		struct bch_csum csum = bch2_checksum_key(bch2_extent_key(c, extent.k->bversion),
+							 p.crc.csum_type, nonce,
+							 data_buf, bytes);
 		bad = bch2_crc_cmp(csum, p.crc.csum);
 	}
 
@@ -677,11 +677,13 @@ struct journal_seq_blacklist_table {
 	x(gc_gens)							\
 	x(presplit_shard_boundaries)					\
 	x(snapshot_delete_pagecache)					\
+	x(subvol_quota_refresh)						\
 	x(sysfs)							\
 	x(btree_write_buffer)						\
 	x(btree_node_scrub)						\
 	x(async_recovery_passes)					\
-	x(ioctl_data)
+	x(ioctl_data)							\
+	x(key_rotate)
 
 enum bch_write_ref {
 #define x(n) BCH_WRITE_REF_##n,
@@ -822,8 +824,20 @@ struct bch_fs {
 				nocow_locks;
 	struct rhltable		update_table;
 
-	struct bch_key		chacha20_key;
+	/*
+	 * chacha20_keys[gen] is the master key; while a key rotation is in
+	 * progress, chacha20_keys[!gen] is the key being rotated out.
+	 *
+	 * key_rotate packs the current generation (bit 63) with the rotation's
+	 * rotate_version (0 when not rotating), so that readers always see the
+	 * two together - see bch2_extent_key():
+	 */
+	struct bch_key		chacha20_keys[2];
 	bool			chacha20_key_set;
+	atomic64_t		key_rotate;
+	struct delayed_work	key_rotate_work;
+	/* bch_sb_field_crypt.key_journal_seq: see journal_read_bucket() */
+	u64			key_journal_seq;
 
 	atomic64_t		key_version;
 
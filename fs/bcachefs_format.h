@@ -647,7 +647,13 @@ struct bch_sb_field {
 	  "Per-extent-type size limits")				\
 	x(errors_v2,		17,						\
 	  "Persistent error log, v2: adds the time of "			\
-	  "first occurrence to each entry")
+	  "first occurrence to each entry")				\
+	x(key_slots,		18,						\
+	  "Additional passphrases, keyfiles and recovery keys "		\
+	  "that unlock an encrypted filesystem")				\
+	x(key_servers,		19,						\
+	  "Key servers that network-bound key slots are unlocked "	\
+	  "with")
 
 enum btree_id_flags {
 	BTREE_IS_extents	= BIT(0),
@@ -894,9 +900,32 @@ struct bch_sb_field_crypt {
 	__le64			flags;
 	__le64			kdf_flags;
 	struct bch_encrypted_key key;
+
+	/*
+	 * Master key rotation - absent on filesystems that have never had
+	 * their key rotated:
+	 *
+	 * @key_seq:		bumped by every rotation and mixed into the nonce
+	 *			@key is wrapped with, so that wrapping a new master
+	 *			key with the same passphrase never reuses keystream.
+	 *			The low bit is the key generation that
+	 *			BSET_KEY_GEN/JSET_KEY_GEN refer to
+	 * @old_key:		while BCH_CRYPT_ROTATING, the key being rotated
+	 *			out, still wrapped as it was (at key_seq - 1)
+	 * @rotate_version:	while BCH_CRYPT_ROTATING, extents with a bversion
+	 *			below this are still encrypted with @old_key
+	 * @key_journal_seq:	journal entries below this seq may be encrypted with
+	 *			a key a finished rotation dropped: the rotation
+	 *			flushed the journal past them, so they're not needed
+	 */
+	__le64			key_seq;
+	struct bch_encrypted_key old_key;
+	__le64			rotate_version;
+	__le64			key_journal_seq;
 };
 
 LE64_BITMASK(BCH_CRYPT_KDF_TYPE,	struct bch_sb_field_crypt, flags, 0, 4);
+LE64_BITMASK(BCH_CRYPT_ROTATING,	struct bch_sb_field_crypt, flags, 4, 5);
 
 enum bch_kdf_types {
 	BCH_KDF_SCRYPT		= 0,
@@ -908,6 +937,73 @@ LE64_BITMASK(BCH_KDF_SCRYPT_N,	struct bch_sb_field_crypt, kdf_flags,  0, 16);
 LE64_BITMASK(BCH_KDF_SCRYPT_R,	struct bch_sb_field_crypt, kdf_flags, 16, 32);
 LE64_BITMASK(BCH_KDF_SCRYPT_P,	struct bch_sb_field_crypt, kdf_flags, 32, 48);
 
+/*
+ * Key slots: ways of unlocking an encrypted filesystem, LUKS-style.
+ *
+ * Without key slots, @crypt->key is wrapped with a key derived from the
+ * passphrase. Once there are key slots, it's wrapped with a random key instead
+ * - what userspace adds to the keyring, and the kernel unwraps the master key
+ * with - and each slot, the passphrase included, holds its own copy of that
+ * key, wrapped with a key derived from the slot's own secret and salt: a
+ * passphrase, the contents of a keyfile, or a generated recovery key. Slots can
+ * then be added, changed and removed independently of each other.
+ *
+ * Slots wrap the keyring key and not the master key itself because the master
+ * key changes on key rotation, which the kernel does online, without any of
+ * the slots' secrets. The kernel never looks inside them.
+ */
+#define BCH_KEY_SLOT_TYPES()		\
+	x(passphrase,		0)	\
+	x(keyfile,		1)	\
+	x(recovery,		2)	\
+	x(network,		3)
+
+enum bch_key_slot_type {
+#define x(t, n)	BCH_KEY_SLOT_##t = n,
+	BCH_KEY_SLOT_TYPES()
+#undef x
+	BCH_KEY_SLOT_NR
+};
+
+struct bch_key_slot {
+	__le64			flags;
+	__le64			kdf_flags;
+	__u8			salt[16];
+	__u8			label[32];
+	struct bch_encrypted_key key;
+};
+
+LE64_BITMASK(BCH_KEY_SLOT_TYPE,		struct bch_key_slot, flags, 0, 4);
+LE64_BITMASK(BCH_KEY_SLOT_KDF_TYPE,	struct bch_key_slot, flags, 4, 8);
+
+/* stored as base 2 log of scrypt params: */
+LE64_BITMASK(BCH_KEY_SLOT_SCRYPT_N,	struct bch_key_slot, kdf_flags,  0, 16);
+LE64_BITMASK(BCH_KEY_SLOT_SCRYPT_R,	struct bch_key_slot, kdf_flags, 16, 32);
+LE64_BITMASK(BCH_KEY_SLOT_SCRYPT_P,	struct bch_key_slot, kdf_flags, 32, 48);
+
+struct bch_sb_field_key_slots {
+	struct bch_sb_field	field;
+	struct bch_key_slot	slots[];
+};
+
+/*
+ * Network-bound key slots (BCH_KEY_SLOT_network): the slot's secret is an
+ * X25519 shared secret between @client_pk, whose private half was thrown away,
+ * and the key server's @server_pk - so only the key server at @addr can compute
+ * it again. Matched up with their key slot by the slot's salt.
+ */
+struct bch_key_server {
+	__u8			slot_salt[16];
+	__u8			server_pk[32];
+	__u8			client_pk[32];
+	__u8			addr[64];
+};
+
+struct bch_sb_field_key_servers {
+	struct bch_sb_field	field;
+	struct bch_key_server	servers[];
+};
+
 /*
  * On clean shutdown, store btree roots and current journal sequence number in
  * the superblock:
@@ -957,6 +1053,15 @@ struct bch_sb_field_ext {
 	 * the check pass. Runtime copy: bch_sb.btrees_clean.
 	 */
 	__le64			btrees_clean;
+	/*
+	 * Most recent journal rewind: when it was done, and the journal range
+	 * it discarded - updates after journal_rewind_to, up to
+	 * journal_rewind_from, were thrown away:
+	 */
+	__le64			journal_rewind_time;
+	__le64			journal_rewind_from;
+	__le64			journal_rewind_to;
+	__le64			flags1;
 };
 
 LE64_BITMASK(BCH_SB_EXT_DEV_READAHEAD,		struct bch_sb_field_ext, flags0, 0, 20);
@@ -966,6 +1071,8 @@ LE64_BITMASK(BCH_SB_EXT_DISCARD_BUFFER,		struct bch_sb_field_ext, flags0, 38, 42
 LE64_BITMASK(BCH_SB_EXT_BTREE_CACHE_SHRINKER_SEEKS,
 						struct bch_sb_field_ext, flags0, 42, 49);
 LE64_BITMASK(BCH_SB_EXT_MISSING_DEV_TIMEOUT,	struct bch_sb_field_ext, flags0, 49, 61);
+LE64_BITMASK(BCH_SB_EXT_RECONCILE_RATE_LIMIT,	struct bch_sb_field_ext, flags1,  0, 32);
+LE64_BITMASK(BCH_SB_EXT_RECONCILE_WINDOW,	struct bch_sb_field_ext, flags1, 32, 54);
 
 /* Superblock: */
 
@@ -1130,7 +1237,13 @@ LE64_BITMASK(BCH_SB_EXT_MISSING_DEV_TIMEOUT,	struct bch_sb_field_ext, flags0, 49
 	  "discard eligibility checks",				"2026-03")	\
 	x(per_dev_fragmentation_lru,	BCH_VERSION(1, 39),			\
 	  "Per-device bucket fragmentation LRUs, so copygc can reason "		\
-	  "about fragmentation per device",			"2026-07")
+	  "about fragmentation per device",			"2026-07")	\
+	x(subvolume_label_quota,	BCH_VERSION(1, 40),			\
+	  "Subvolume labels, and subvolume and snapshot tree "		\
+	  "quotas",						"2026-10")	\
+	x(reconcile_kick,		BCH_VERSION(1, 41),			\
+	  "Extents may be high priority reconcile work for any "		\
+	  "reason, so kicked work is queued persistently",	"2026-10")
 
 enum bcachefs_metadata_version {
 	bcachefs_metadata_version_min = 9,
@@ -1349,6 +1462,8 @@ static inline void SET_BCH_SB_BACKGROUND_COMPRESSION_TYPE(struct bch_sb *sb, __u
  * inline_data:			gates KEY_TYPE_inline_data
  * new_siphash:			gates BCH_STR_HASH_siphash
  * new_extent_overwrite:	gates BTREE_NODE_NEW_EXTENT_OVERWRITE
+ * key_rotation:		gates bch_sb_field_crypt.key_seq (the wrapped key's
+ *				nonce) and BSET_KEY_GEN/JSET_KEY_GEN
  */
 #define BCH_SB_FEATURES()			\
 	x(lz4,				0)	\
@@ -1374,7 +1489,8 @@ static inline void SET_BCH_SB_BACKGROUND_COMPRESSION_TYPE(struct bch_sb *sb, __u
 	x(casefolding,			20)	\
 	x(no_alloc_info,		21)	\
 	x(small_image,			22)	\
-	x(no_default_sb,		23)
+	x(no_default_sb,		23)	\
+	x(key_rotation,			24)
 
 #define BCH_SB_FEATURES_ALWAYS				\
 	(BIT_ULL(BCH_FEATURE_new_extent_overwrite)|	\
@@ -1403,7 +1519,8 @@ enum bch_sb_feature {
 	x(alloc_metadata,			1)	\
 	x(extents_above_btree_updates_done,	2)	\
 	x(bformat_overflow_done,		3)	\
-	x(no_stale_ptrs,			4)
+	x(no_stale_ptrs,			4)	\
+	x(subvol_quota,				5)
 
 enum bch_sb_compat {
 #define x(f, n) BCH_COMPAT_##f,
@@ -1827,6 +1944,8 @@ LE32_BITMASK(JSET_CSUM_TYPE,	struct jset, flags, 0, 4);
 LE32_BITMASK(JSET_BIG_ENDIAN,	struct jset, flags, 4, 5);
 LE32_BITMASK(JSET_NO_FLUSH,	struct jset, flags, 5, 6);
 LE32_BITMASK(JSET_HAS_OVERWRITES, struct jset, flags, 6, 7);
+/* Master key generation this entry is encrypted with, see BCH_CRYPT_ROTATING: */
+LE32_BITMASK(JSET_KEY_GEN,	struct jset, flags, 7, 8);
 
 #define BCH_JOURNAL_BUCKETS_MIN		8
 
@@ -1925,6 +2044,8 @@ LE32_BITMASK(BSET_CSUM_TYPE,	struct bset, flags, 0, 4);
 LE32_BITMASK(BSET_BIG_ENDIAN,	struct bset, flags, 4, 5);
 LE32_BITMASK(BSET_SEPARATE_WHITEOUTS,
 				struct bset, flags, 5, 6);
+/* Master key generation this bset is encrypted with, see BCH_CRYPT_ROTATING: */
+LE32_BITMASK(BSET_KEY_GEN,	struct bset, flags, 6, 7);
 
 /* Sector offset within the btree node: */
 LE32_BITMASK(BSET_OFFSET,	struct bset, flags, 16, 32);
@@ -63,6 +63,8 @@
 #define BCH_IOCTL_SUBVOLUME_CREATE_v2	_IOW(0xbc,	29, struct bch_ioctl_subvolume_v2)
 #define BCH_IOCTL_SUBVOLUME_DESTROY	_IOW(0xbc,	17, struct bch_ioctl_subvolume)
 #define BCH_IOCTL_SUBVOLUME_DESTROY_v2	_IOW(0xbc,	30, struct bch_ioctl_subvolume_v2)
+#define BCH_IOCTL_SUBVOLUME_ROLLBACK	_IOW(0xbc,	36, struct bch_ioctl_subvolume_rollback)
+#define BCH_IOCTL_SUBVOLUME_SET		_IOW(0xbc,	37, struct bch_ioctl_subvolume_set)
 
 #define BCH_IOCTL_DEV_USAGE_V2		_IOWR(0xbc,	18, struct bch_ioctl_dev_usage_v2)
 
@@ -75,6 +77,7 @@
 #define BCH_IOCTL_SNAPSHOT_TREE		_IOWR(0xbc,	33, struct bch_ioctl_snapshot_tree_query)
 #define BCH_IOCTL_QUERY_BTREE_KEYS	_IOWR(0xbc,	34, struct bch_ioctl_query_btree_keys)
 #define BCH_IOCTL_SNAPSHOT_TREE_v2	_IOWR(0xbc,	35, struct bch_ioctl_snapshot_tree_query_v2)
+#define BCH_IOCTL_KEY_ROTATE		_IOW(0xbc,	38, struct bch_ioctl_key_rotate)
 
 /* ioctl below act on a particular file, not the filesystem as a whole: */
 
@@ -93,6 +96,8 @@
  * ownership or CAP_FOWNER, like chattr.
  */
 #define BCHFS_IOC_CLEAR_DAMAGE		_IO(0xbc, 71)
+#define BCHFS_IOC_PREAD_RAW_v2		_IOWR(0xbc, 72, struct bch_ioctl_pread_raw_v2)
+#define BCHFS_IOC_RECONCILE_KICK	_IOW(0xbc, 73, struct bch_ioctl_reconcile_kick)
 
 /*
  * BCHFS_IOC_GET_DAMAGE: the accumulated damage record for this file - the
@@ -282,6 +287,14 @@ enum bch_data_ops {
 	BCH_DATA_OP_NR
 };
 
+/* bch_ioctl_data.flags: */
+
+/*
+ * Scrub: continue from the device's last checkpoint (bch_member.scrub_pos),
+ * if there's an unfinished scrub of the same data types; otherwise start over.
+ */
+#define BCH_DATA_RESUME		(1U << 0)
+
 /*
  * BCH_IOCTL_DATA: operations that walk and manipulate filesystem data (e.g.
  * scrub, rereplicate, migrate).
@@ -530,6 +543,70 @@ struct bch_ioctl_subvolume_v2 {
 #define BCH_SUBVOL_SNAPSHOT_CREATE	(1U << 0)
 #define BCH_SUBVOL_SNAPSHOT_RO		(1U << 1)
 
+/*
+ * BCH_IOCTL_SUBVOLUME_ROLLBACK: roll a subvolume back to one of its snapshots,
+ * in place.
+ *
+ * In a single transaction, the subvolume is switched to a new writable
+ * snapshot of @target_ptr, keeping its subvolume ID and its place in the
+ * namespace; the state it had before the rollback is kept as a new read-only
+ * snapshot at @save_ptr.
+ *
+ * Paths are resolved relative to @dirfd, as with BCH_IOCTL_SUBVOLUME_CREATE.
+ *
+ * @subvol_ptr	- path to the subvolume to roll back
+ * @target_ptr	- path to the snapshot to roll back to; must be in the same
+ *		  snapshot tree as the subvolume
+ * @save_ptr	- path at which to keep the pre-rollback state; must not be
+ *		  inside either subvolume
+ *
+ * Returns -EBUSY if files in the subvolume are still in use, or if the
+ * subvolume is mounted: a mounted subvolume has to be unmounted first, the
+ * rollback is not deferred to the next mount.
+ */
+struct bch_ioctl_subvolume_rollback {
+	__u32			flags;
+	__u32			dirfd;
+	__u64			subvol_ptr;
+	__u64			target_ptr;
+	__u64			save_ptr;
+	struct bch_ioctl_err_msg	err;
+};
+
+/*
+ * BCH_IOCTL_SUBVOLUME_SET: change properties of an existing subvolume
+ *
+ * @flags	- BCH_SUBVOL_SET_*: which of the fields below to set; the
+ *		  others are ignored
+ * @dirfd	- as with BCH_IOCTL_SUBVOLUME_CREATE
+ * @path_ptr	- path to the subvolume's root
+ * @ro		- read-only: when set, dirty pagecache is flushed first
+ * @retention	- subject to snapshot retention: see BCH_SUBVOLUME_RETENTION
+ * @label	- free-form label, NUL padded
+ * @quota_sectors	- limit on the subvolume's usage, in sectors: 0 for
+ *			  none; requires CAP_SYS_ADMIN
+ * @tree_quota_sectors	- limit on the usage of the whole snapshot tree the
+ *			  subvolume is in: 0 for none; requires CAP_SYS_ADMIN
+ */
+#define BCH_SUBVOL_SET_RO		(1U << 0)
+#define BCH_SUBVOL_SET_RETENTION	(1U << 1)
+#define BCH_SUBVOL_SET_LABEL		(1U << 2)
+#define BCH_SUBVOL_SET_QUOTA		(1U << 3)
+#define BCH_SUBVOL_SET_TREE_QUOTA	(1U << 4)
+
+struct bch_ioctl_subvolume_set {
+	__u32			flags;
+	__u32			dirfd;
+	__u64			path_ptr;
+	__u8			ro;
+	__u8			retention;
+	__u8			pad[6];
+	__u8			label[BCH_SUBVOL_LABEL_MAX];
+	__u64			quota_sectors;
+	__u64			tree_quota_sectors;
+	struct bch_ioctl_err_msg	err;
+};
+
 /*
  * BCH_IOCTL_FSCK_OFFLINE: run fsck from the 'bcachefs fsck' userspace command,
  * but with the kernel's implementation of fsck:
@@ -750,6 +827,27 @@ struct bch_ioctl_pread_raw {
 	struct bch_ioctl_err_msg	err;
 };
 
+/*
+ * BCHFS_IOC_PREAD_RAW_v2: adds BCH_PREAD_RAW_replica - read only the
+ * replica on device @dev, for checking replicas one by one. A checksum
+ * error is reported, not retried from another replica, reconstructed from
+ * erasure coding, or used to poison the extent: one bad copy says nothing
+ * about the others. Reading a range with no replica on @dev is an
+ * error.
+ */
+#define BCH_PREAD_RAW_replica			(1U << 1)
+
+struct bch_ioctl_pread_raw_v2 {
+	__u64				offset;
+	__u64				len;
+	__u64				buf;		/* userspace data buffer */
+	__u32				flags;		/* BCH_PREAD_RAW_* input flags */
+	__u32				errors;		/* output: BCH_PREAD_RAW_ERR_* */
+	__u32				dev;		/* with BCH_PREAD_RAW_replica */
+	__u32				pad;
+	struct bch_ioctl_err_msg	err;
+};
+
 /*
  * BCHFS_IOC_UNPOISON: clear the poison flag on extents in a file range.
  *
@@ -764,6 +862,32 @@ struct bch_ioctl_unpoison {
 	__u32				pad;
 };
 
+/*
+ * BCHFS_IOC_RECONCILE_KICK: process pending reconcile work for the given
+ * inodes ahead of other normal priority work.
+ *
+ * The inodes' extents with normal priority work are moved to high priority
+ * (BTREE_ID_reconcile_hipri) - persistently, until their work is done - and
+ * reconcile is woken up. High priority work isn't subject to
+ * reconcile_rate_limit or reconcile_window. Only data already indexed in
+ * BTREE_ID_reconcile_work is covered: extents reached through reflink
+ * pointers are indexed by their indirect extent, not the inode.
+ *
+ * May be issued on any file in the filesystem; requires CAP_SYS_ADMIN.
+ * Fails with EOPNOTSUPP if the filesystem's metadata version predates
+ * reconcile_kick.
+ *
+ * @inums	- pointer to @nr inode numbers, at most
+ *		  BCH_RECONCILE_KICK_BATCH_MAX per call
+ */
+#define BCH_RECONCILE_KICK_BATCH_MAX	4096
+
+struct bch_ioctl_reconcile_kick {
+	__u32				flags;		/* reserved, must be 0 */
+	__u32				nr;
+	__u64				inums;
+};
+
 /*
  * BCH_IOCTL_QUERY_BTREE_KEYS: read keys from a btree, range query
  *
@@ -827,4 +951,20 @@ struct bch_ioctl_query_btree_keys {
 	__u32			used;
 };
 
+/*
+ * BCH_IOCTL_KEY_ROTATE: start rotating the master encryption key
+ *
+ * Generates a new master key, and re-encrypts data and metadata with it in
+ * the background; progress is kept in the superblock, and survives remounts.
+ * If the master key is wrapped with a passphrase, the key derived from it
+ * must be in the keyring - the new master key is wrapped with it too.
+ *
+ * @flags	- must be 0
+ */
+struct bch_ioctl_key_rotate {
+	__u32				flags;
+	__u32				pad;
+	struct bch_ioctl_err_msg	err;
+};
+
 #endif /* _BCACHEFS_IOCTL_H */
@@ -263,8 +263,8 @@ pub struct BkeySC<'a> {
 include!(concat!(env!("OUT_DIR"), "/bkey_types_gen.rs"));
 
 impl<'a> BkeySC<'a> {
-    #[cfg(feature = "std")]
-    unsafe fn to_raw(&self) -> c::bkey_s_c {
+    /// The raw `c::bkey_s_c` for passing to C helpers (e.g. `bch2_inode_unpack`).
+    pub(crate) unsafe fn to_raw(&self) -> c::bkey_s_c {
         c::bkey_s_c {
             k: self.k,
             v: self.v,
@@ -150,7 +150,8 @@ static void try_read_btree_node(struct find_btree_nodes *f, struct bch_dev *ca,
 		struct nonce nonce = btree_nonce(&bn->keys, 0);
 		unsigned bytes = (void *) &bn->keys - (void *) &bn->flags;
 
-		bch2_encrypt(c, BSET_CSUM_TYPE(&bn->keys), nonce, &bn->flags, bytes);
+		bch2_encrypt_key(c, bch2_bset_key(c, &bn->keys), BSET_CSUM_TYPE(&bn->keys),
+				 nonce, &bn->flags, bytes);
 	}
 
 	if (btree_id_can_reconstruct(BTREE_NODE_ID(bn)))
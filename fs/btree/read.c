@@ -667,7 +667,8 @@ int bch2_btree_node_read_done(struct bch_fs *c, struct bch_dev *ca,
 					 b->written, sectors, ptr_written ?: btree_sectors(c)))
 				i->u64s = 0;
 			if (good_csum_type) {
-				struct bch_csum csum = csum_vstruct(c, BSET_CSUM_TYPE(i), nonce, b->data);
+				struct bch_csum csum = csum_vstruct_key(bch2_bset_key(c, i),
+									BSET_CSUM_TYPE(i), nonce, b->data);
 				bool csum_bad = bch2_crc_cmp(b->data->csum, csum);
 				if (csum_bad)
 					bch2_io_error(ca, BCH_MEMBER_ERROR_checksum);
@@ -731,7 +732,8 @@ int bch2_btree_node_read_done(struct bch_fs *c, struct bch_dev *ca,
 					 b->written, sectors, ptr_written ?: btree_sectors(c)))
 				i->u64s = 0;
 			if (good_csum_type) {
-				struct bch_csum csum = csum_vstruct(c, BSET_CSUM_TYPE(i), nonce, bne);
+				struct bch_csum csum = csum_vstruct_key(bch2_bset_key(c, i),
+									BSET_CSUM_TYPE(i), nonce, bne);
 				bool csum_bad = bch2_crc_cmp(bne->csum, csum);
 				if (ca && csum_bad)
 					bch2_io_error(ca, BCH_MEMBER_ERROR_checksum);
@@ -754,6 +756,7 @@ int bch2_btree_node_read_done(struct bch_fs *c, struct bch_dev *ca,
 
 		b->version_ondisk = min(b->version_ondisk,
 					le16_to_cpu(i->version));
+		btree_node_mark_key_gen(b, i);
 
 		ret = bch2_validate_bset(c, ca, b, i, b->written, READ, failed, err_msg);
 		if (ret)
@@ -1228,7 +1231,8 @@ static bool btree_node_scrub_check(struct bch_fs *c, struct btree_node *data, un
 
 		if (first) {
 			if (good_csum_type) {
-				struct bch_csum csum = csum_vstruct(c, BSET_CSUM_TYPE(i), nonce, data);
+				struct bch_csum csum = csum_vstruct_key(bch2_bset_key(c, i),
+									BSET_CSUM_TYPE(i), nonce, data);
 				if (bch2_crc_cmp(data->csum, csum)) {
 					bch2_csum_err_msg(err, BSET_CSUM_TYPE(i), data->csum, csum);
 					return false;
@@ -1238,7 +1242,8 @@ static bool btree_node_scrub_check(struct bch_fs *c, struct btree_node *data, un
 			written += vstruct_sectors(data, c->block_bits);
 		} else {
 			if (good_csum_type) {
-				struct bch_csum csum = csum_vstruct(c, BSET_CSUM_TYPE(i), nonce, bne);
+				struct bch_csum csum = csum_vstruct_key(bch2_bset_key(c, i),
+									BSET_CSUM_TYPE(i), nonce, bne);
 				if (bch2_crc_cmp(bne->csum, csum)) {
 					bch2_csum_err_msg(err, BSET_CSUM_TYPE(i), bne->csum, csum);
 					return false;
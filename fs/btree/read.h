@@ -78,16 +78,16 @@ static inline int bset_encrypt(struct bch_fs *c, struct bset *i, unsigned offset
 		struct btree_node *bn = container_of(i, struct btree_node, keys);
 		unsigned bytes = (void *) &bn->keys - (void *) &bn->flags;
 
-		ret = bch2_encrypt(c, BSET_CSUM_TYPE(i), nonce,
-				   &bn->flags, bytes);
+		ret = bch2_encrypt_key(c, bch2_bset_key(c, i), BSET_CSUM_TYPE(i),
+				       nonce, &bn->flags, bytes);
 		if (ret)
 			return ret;
 
 		nonce = nonce_add(nonce, round_up(bytes, CHACHA_BLOCK_SIZE));
 	}
 
-	return bch2_encrypt(c, BSET_CSUM_TYPE(i), nonce, i->_data,
-			    vstruct_end(i) - (void *) i->_data);
+	return bch2_encrypt_key(c, bch2_bset_key(c, i), BSET_CSUM_TYPE(i),
+				nonce, i->_data, vstruct_end(i) - (void *) i->_data);
 }
 
 void bch2_btree_node_drop_keys_outside_node(struct btree *);
@@ -866,6 +866,8 @@ enum btree_write_type {
 	x(need_rewrite)							\
 	x(need_rewrite_error)						\
 	x(need_rewrite_ptr_written_zero)				\
+	x(key_gen0)							\
+	x(key_gen1)							\
 	x(never_write)							\
 	x(pinned)							\
 	x(permanent)
@@ -991,6 +993,21 @@ static inline enum btree_node_rewrite_reason btree_node_rewrite_reason(struct bt
 	return BTREE_NODE_REWRITE_none;
 }
 
+/*
+ * Which master key generations this node has encrypted bsets under, on disk
+ * or being written - key rotation rewrites nodes that have the old one:
+ */
+static inline void btree_node_mark_key_gen(struct btree *b, struct bset *i)
+{
+	if (bch2_csum_type_is_encryption(BSET_CSUM_TYPE(i)))
+		set_bit(BTREE_NODE_key_gen0 + BSET_KEY_GEN(i), &b->flags);
+}
+
+static inline bool btree_node_has_key_gen(const struct btree *b, unsigned gen)
+{
+	return test_bit(BTREE_NODE_key_gen0 + gen, &b->flags);
+}
+
 static inline struct btree_write *btree_current_write(struct btree *b)
 {
 	return b->writes + btree_node_write_idx(b);
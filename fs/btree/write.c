@@ -460,6 +460,8 @@ do_write:
 	i->version = cpu_to_le16(c->sb.version);
 	SET_BSET_OFFSET(i, b->written);
 	SET_BSET_CSUM_TYPE(i, bch2_meta_checksum_type(c));
+	SET_BSET_KEY_GEN(i, bch2_key_gen(c));
+	btree_node_mark_key_gen(b, i);
 
 	if (bch2_csum_type_is_encryption(BSET_CSUM_TYPE(i)))
 		validate_before_checksum = true;
@@ -481,9 +483,9 @@ do_write:
 	nonce = btree_nonce(i, b->written << 9);
 
 	if (bn)
-		bn->csum = csum_vstruct(c, BSET_CSUM_TYPE(i), nonce, bn);
+		bn->csum = csum_vstruct_key(bch2_bset_key(c, i), BSET_CSUM_TYPE(i), nonce, bn);
 	else
-		bne->csum = csum_vstruct(c, BSET_CSUM_TYPE(i), nonce, bne);
+		bne->csum = csum_vstruct_key(bch2_bset_key(c, i), BSET_CSUM_TYPE(i), nonce, bne);
 
 	/* if we're not encrypting, check metadata after checksumming: */
 	if (!validate_before_checksum &&
@@ -68,6 +68,7 @@ const NEWTYPE_ENUM: &[&str] = &[
     "bch_data_type",
     "bch_jset_entry_type",
     "bch_kdf_types",
+    "bch_key_slot_type",
     "bch_opt_id",
     "bch_reconcile_accounting_type",
     "bch_sb_field_type",
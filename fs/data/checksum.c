@@ -119,18 +119,18 @@ void bch2_chacha20(const struct bch_key *key, struct nonce nonce,
 }
 
 static void bch2_poly1305_init(struct poly1305_desc_ctx *desc,
-			       struct bch_fs *c, struct nonce nonce)
+			       const struct bch_key *chacha20_key, struct nonce nonce)
 {
 	u8 key[POLY1305_KEY_SIZE] = { 0 };
 
 	nonce.d[3] ^= BCH_NONCE_POLY;
 
-	bch2_chacha20(&c->chacha20_key, nonce, key, sizeof(key));
+	bch2_chacha20(chacha20_key, nonce, key, sizeof(key));
 	poly1305_init(desc, key);
 }
 
-struct bch_csum bch2_checksum(struct bch_fs *c, unsigned type,
-			      struct nonce nonce, const void *data, size_t len)
+struct bch_csum bch2_checksum_key(const struct bch_key *key, unsigned type,
+				  struct nonce nonce, const void *data, size_t len)
 {
 	switch (type) {
 	case BCH_CSUM_none:
@@ -155,7 +155,7 @@ struct bch_csum bch2_checksum(struct bch_fs *c, unsigned type,
 		u8 digest[POLY1305_DIGEST_SIZE];
 		struct bch_csum ret = { 0 };
 
-		bch2_poly1305_init(&dctx, c, nonce);
+		bch2_poly1305_init(&dctx, key, nonce);
 		poly1305_update(&dctx, data, len);
 		poly1305_final(&dctx, digest);
 
@@ -167,8 +167,15 @@ struct bch_csum bch2_checksum(struct bch_fs *c, unsigned type,
 	}
 }
 
-int bch2_encrypt(struct bch_fs *c, unsigned type,
-		  struct nonce nonce, void *data, size_t len)
+/* With the current master key - for anything that can't predate a rotation: */
+struct bch_csum bch2_checksum(struct bch_fs *c, unsigned type,
+			      struct nonce nonce, const void *data, size_t len)
+{
+	return bch2_checksum_key(c ? bch2_cur_key(c) : NULL, type, nonce, data, len);
+}
+
+int bch2_encrypt_key(struct bch_fs *c, const struct bch_key *key, unsigned type,
+		     struct nonce nonce, void *data, size_t len)
 {
 	if (!bch2_csum_type_is_encryption(type))
 		return 0;
@@ -177,13 +184,19 @@ int bch2_encrypt(struct bch_fs *c, unsigned type,
 				    c, "attempting to encrypt without encryption key"))
 		return bch_err_throw(c, no_encryption_key);
 
-	bch2_chacha20(&c->chacha20_key, nonce, data, len);
+	bch2_chacha20(key, nonce, data, len);
 	return 0;
 }
 
+int bch2_encrypt(struct bch_fs *c, unsigned type,
+		  struct nonce nonce, void *data, size_t len)
+{
+	return bch2_encrypt_key(c, bch2_cur_key(c), type, nonce, data, len);
+}
+
 static struct bch_csum __bch2_checksum_bio(struct bch_fs *c, unsigned type,
-					   struct nonce nonce, struct bio *bio,
-					   struct bvec_iter *iter)
+					   struct bversion version, struct nonce nonce,
+					   struct bio *bio, struct bvec_iter *iter)
 {
 	struct bio_vec bv;
 
@@ -220,7 +233,7 @@ static struct bch_csum __bch2_checksum_bio(struct bch_fs *c, unsigned type,
 		u8 digest[POLY1305_DIGEST_SIZE];
 		struct bch_csum ret = { 0 };
 
-		bch2_poly1305_init(&dctx, c, nonce);
+		bch2_poly1305_init(&dctx, bch2_extent_key(c, version), nonce);
 
 #ifdef CONFIG_HIGHMEM
 		__bio_for_each_segment(bv, bio, *iter, *iter) {
@@ -244,15 +257,17 @@ static struct bch_csum __bch2_checksum_bio(struct bch_fs *c, unsigned type,
 }
 
 struct bch_csum bch2_checksum_bio(struct bch_fs *c, unsigned type,
-				  struct nonce nonce, struct bio *bio)
+				  struct bversion version, struct nonce nonce,
+				  struct bio *bio)
 {
 	struct bvec_iter iter = bio->bi_iter;
 
-	return __bch2_checksum_bio(c, type, nonce, bio, &iter);
+	return __bch2_checksum_bio(c, type, version, nonce, bio, &iter);
 }
 
 int __bch2_encrypt_bio(struct bch_fs *c, unsigned type,
-		     struct nonce nonce, struct bio *bio)
+		       struct bversion version, struct nonce nonce,
+		       struct bio *bio)
 {
 	struct bio_vec bv;
 	struct bvec_iter iter;
@@ -263,7 +278,7 @@ int __bch2_encrypt_bio(struct bch_fs *c, unsigned type,
 				    c, "attempting to encrypt without encryption key"))
 		return bch_err_throw(c, no_encryption_key);
 
-	bch2_chacha20_init(&chacha_state, &c->chacha20_key, nonce);
+	bch2_chacha20_init(&chacha_state, bch2_extent_key(c, version), nonce);
 
 	bio_for_each_segment(bv, bio, iter) {
 		void *p;
@@ -345,7 +360,7 @@ int bch2_rechecksum_bio(struct bch_fs *c, struct bio *bio,
 		iter.bi_size = i->len << 9;
 		if (mergeable || i->crc)
 			i->csum = __bch2_checksum_bio(c, i->csum_type,
-						      nonce, bio, &iter);
+						      version, nonce, bio, &iter);
 		else
 			bio_advance_iter(bio, &iter, i->len << 9);
 		nonce = nonce_add(nonce, i->len << 9);
@@ -356,7 +371,7 @@ int bch2_rechecksum_bio(struct bch_fs *c, struct bio *bio,
 			merged = bch2_checksum_merge(new_csum_type, merged,
 						     i->csum, i->len << 9);
 	else
-		merged = bch2_checksum_bio(c, crc_old.csum_type,
+		merged = bch2_checksum_bio(c, crc_old.csum_type, version,
 				extent_nonce(version, crc_old), bio);
 
 	if (bch2_crc_cmp(merged, crc_old.csum) && !c->opts.no_data_io) {
@@ -403,9 +418,9 @@ static int bch2_sb_crypt_validate(struct bch_sb *sb, struct bch_sb_field *f,
 {
 	struct bch_sb_field_crypt *crypt = field_to_type(f, crypt);
 
-	if (vstruct_bytes(&crypt->field) < sizeof(*crypt)) {
+	if (vstruct_bytes(&crypt->field) < offsetof(struct bch_sb_field_crypt, key_seq)) {
 		prt_printf(err, "wrong size (got %zu should be %zu)",
-		       vstruct_bytes(&crypt->field), sizeof(*crypt));
+		       vstruct_bytes(&crypt->field), offsetof(struct bch_sb_field_crypt, key_seq));
 		return -BCH_ERR_invalid_sb_crypt;
 	}
 
@@ -414,6 +429,23 @@ static int bch2_sb_crypt_validate(struct bch_sb *sb, struct bch_sb_field *f,
 		return -BCH_ERR_invalid_sb_crypt;
 	}
 
+	if (BCH_CRYPT_ROTATING(crypt)) {
+		size_t need = offsetofend(struct bch_sb_field_crypt, rotate_version);
+
+		if (vstruct_bytes(&crypt->field) < need) {
+			prt_printf(err, "key rotation in progress but field too small (got %zu should be %zu)",
+			       vstruct_bytes(&crypt->field), need);
+			return -BCH_ERR_invalid_sb_crypt;
+		}
+
+		if (!crypt->key_seq || !crypt->rotate_version) {
+			prt_printf(err, "key rotation in progress with key_seq %llu rotate_version %llu",
+				   le64_to_cpu(crypt->key_seq),
+				   le64_to_cpu(crypt->rotate_version));
+			return -BCH_ERR_invalid_sb_crypt;
+		}
+	}
+
 	return 0;
 }
 
@@ -428,6 +460,14 @@ static __cold void bch2_sb_crypt_to_text(struct printbuf *out,
 	prt_printf(out, "scrypt n:          %llu\n", BCH_KDF_SCRYPT_N(crypt));
 	prt_printf(out, "scrypt r:          %llu\n", BCH_KDF_SCRYPT_R(crypt));
 	prt_printf(out, "scrypt p:          %llu\n", BCH_KDF_SCRYPT_P(crypt));
+	prt_printf(out, "key seq:           %llu\n", bch2_crypt_key_seq(crypt));
+
+	if (bch2_crypt_rotating(crypt))
+		prt_printf(out, "rotating, version: %llu\n",
+			   le64_to_cpu(crypt->rotate_version));
+	if (bch2_crypt_key_journal_seq(crypt))
+		prt_printf(out, "key journal seq:   %llu\n",
+			   bch2_crypt_key_journal_seq(crypt));
 }
 
 const struct bch_sb_field_ops bch_sb_field_ops_crypt = {
@@ -435,6 +475,127 @@ const struct bch_sb_field_ops bch_sb_field_ops_crypt = {
 	.to_text	= bch2_sb_crypt_to_text,
 };
 
+static const char * const bch2_key_slot_types[] = {
+#define x(t, n) [n] = #t,
+	BCH_KEY_SLOT_TYPES()
+#undef x
+	NULL
+};
+
+static unsigned bch2_sb_key_slots_nr(struct bch_sb_field_key_slots *s)
+{
+	return (vstruct_bytes(&s->field) - sizeof(*s)) / sizeof(s->slots[0]);
+}
+
+static int bch2_sb_key_slots_validate(struct bch_sb *sb, struct bch_sb_field *f,
+				      enum bch_validate_flags flags, struct printbuf *err)
+{
+	struct bch_sb_field_key_slots *s = field_to_type(f, key_slots);
+
+	if ((vstruct_bytes(&s->field) - sizeof(*s)) % sizeof(s->slots[0])) {
+		prt_printf(err, "wrong size (got %zu, not a whole number of slots)",
+			   vstruct_bytes(&s->field));
+		return -BCH_ERR_invalid_sb_key_slots;
+	}
+
+	if (!bch2_sb_field_get(sb, crypt)) {
+		prt_printf(err, "key slots on a filesystem without encryption");
+		return -BCH_ERR_invalid_sb_key_slots;
+	}
+
+	for (unsigned i = 0; i < bch2_sb_key_slots_nr(s); i++) {
+		struct bch_key_slot *slot = &s->slots[i];
+
+		if (BCH_KEY_SLOT_TYPE(slot) >= BCH_KEY_SLOT_NR) {
+			prt_printf(err, "slot %u: bad type %llu", i, BCH_KEY_SLOT_TYPE(slot));
+			return -BCH_ERR_invalid_sb_key_slots;
+		}
+
+		if (BCH_KEY_SLOT_KDF_TYPE(slot) >= BCH_KDF_NR) {
+			prt_printf(err, "slot %u: bad kdf type %llu", i, BCH_KEY_SLOT_KDF_TYPE(slot));
+			return -BCH_ERR_invalid_sb_key_slots;
+		}
+	}
+
+	return 0;
+}
+
+static __cold void bch2_sb_key_slots_to_text(struct printbuf *out,
+					     struct bch_fs *c,
+					     struct bch_sb *sb,
+					     struct bch_sb_field *f)
+{
+	struct bch_sb_field_key_slots *s = field_to_type(f, key_slots);
+
+	for (unsigned i = 0; i < bch2_sb_key_slots_nr(s); i++) {
+		struct bch_key_slot *slot = &s->slots[i];
+		unsigned type = BCH_KEY_SLOT_TYPE(slot);
+
+		prt_printf(out, "%u:\t", i);
+		if (type < BCH_KEY_SLOT_NR)
+			prt_str(out, bch2_key_slot_types[type]);
+		else
+			prt_printf(out, "(unknown type %u)", type);
+		prt_printf(out, "\t%.*s\n",
+			   (int) strnlen((char *) slot->label, sizeof(slot->label)),
+			   (char *) slot->label);
+	}
+}
+
+const struct bch_sb_field_ops bch_sb_field_ops_key_slots = {
+	.validate	= bch2_sb_key_slots_validate,
+	.to_text	= bch2_sb_key_slots_to_text,
+};
+
+static unsigned bch2_sb_key_servers_nr(struct bch_sb_field_key_servers *s)
+{
+	return (vstruct_bytes(&s->field) - sizeof(*s)) / sizeof(s->servers[0]);
+}
+
+static int bch2_sb_key_servers_validate(struct bch_sb *sb, struct bch_sb_field *f,
+					enum bch_validate_flags flags, struct printbuf *err)
+{
+	struct bch_sb_field_key_servers *s = field_to_type(f, key_servers);
+
+	if ((vstruct_bytes(&s->field) - sizeof(*s)) % sizeof(s->servers[0])) {
+		prt_printf(err, "wrong size (got %zu, not a whole number of entries)",
+			   vstruct_bytes(&s->field));
+		return -BCH_ERR_invalid_sb_key_servers;
+	}
+
+	for (unsigned i = 0; i < bch2_sb_key_servers_nr(s); i++)
+		if (!s->servers[i].addr[0]) {
+			prt_printf(err, "entry %u: no address", i);
+			return -BCH_ERR_invalid_sb_key_servers;
+		}
+
+	return 0;
+}
+
+static __cold void bch2_sb_key_servers_to_text(struct printbuf *out,
+					       struct bch_fs *c,
+					       struct bch_sb *sb,
+					       struct bch_sb_field *f)
+{
+	struct bch_sb_field_key_servers *s = field_to_type(f, key_servers);
+
+	for (unsigned i = 0; i < bch2_sb_key_servers_nr(s); i++) {
+		struct bch_key_server *server = &s->servers[i];
+
+		prt_printf(out, "%.*s\t",
+			   (int) strnlen((char *) server->addr, sizeof(server->addr)),
+			   (char *) server->addr);
+		for (unsigned j = 0; j < sizeof(server->server_pk); j++)
+			prt_hex_byte(out, server->server_pk[j]);
+		prt_newline(out);
+	}
+}
+
+const struct bch_sb_field_ops bch_sb_field_ops_key_servers = {
+	.validate	= bch2_sb_key_servers_validate,
+	.to_text	= bch2_sb_key_servers_to_text,
+};
+
 #ifdef __KERNEL__
 static int __bch2_request_key(char *key_description, struct bch_key *key)
 {
@@ -536,12 +697,19 @@ int bch2_revoke_key(struct bch_sb *sb)
 }
 #endif
 
+/*
+ * Unwrap the master key and, if @old_key is non-NULL, the key a rotation in
+ * progress is rotating out - both are wrapped with the same user key:
+ */
 int bch2_decrypt_sb_key(struct bch_fs *c,
 			struct bch_sb_field_crypt *crypt,
-			struct bch_key *key)
+			struct bch_key *key,
+			struct bch_key *old_key)
 {
 	struct bch_encrypted_key sb_key = crypt->key;
+	struct bch_encrypted_key sb_old_key = old_key ? crypt->old_key : sb_key;
 	struct bch_key user_key;
+	u64 seq = bch2_crypt_key_seq(crypt);
 	int ret = 0;
 
 	/* is key encrypted? */
@@ -555,17 +723,32 @@ int bch2_decrypt_sb_key(struct bch_fs *c,
 	}
 
 	/* decrypt real key: */
-	bch2_chacha20(&user_key, bch2_sb_key_nonce(c), &sb_key, sizeof(sb_key));
+	bch2_chacha20(&user_key, __bch2_sb_key_nonce_seq(c->disk_sb.sb, seq),
+		      &sb_key, sizeof(sb_key));
 
 	if (bch2_key_is_encrypted(&sb_key)) {
 		bch_err(c, "incorrect encryption key");
 		ret = -EINVAL;
 		goto err;
 	}
+
+	if (old_key) {
+		bch2_chacha20(&user_key, __bch2_sb_key_nonce_seq(c->disk_sb.sb, seq - 1),
+			      &sb_old_key, sizeof(sb_old_key));
+
+		if (bch2_key_is_encrypted(&sb_old_key)) {
+			bch_err(c, "error decrypting key being rotated out");
+			ret = -EINVAL;
+			goto err;
+		}
+	}
 out:
 	*key = sb_key.key;
+	if (old_key)
+		*old_key = sb_old_key.key;
 err:
 	memzero_explicit(&sb_key, sizeof(sb_key));
+	memzero_explicit(&sb_old_key, sizeof(sb_old_key));
 	memzero_explicit(&user_key, sizeof(user_key));
 	return ret;
 }
@@ -592,7 +775,7 @@ int bch2_disable_encryption(struct bch_fs *c)
 		return 0;
 
 	struct bch_key key;
-	try(bch2_decrypt_sb_key(c, crypt, &key));
+	try(bch2_decrypt_sb_key(c, crypt, &key, NULL));
 
 	crypt->key.magic	= cpu_to_le64(BCH_KEY_MAGIC);
 	crypt->key.key		= key;
@@ -665,7 +848,7 @@ err:
 
 void bch2_fs_encryption_exit(struct bch_fs *c)
 {
-	memzero_explicit(&c->chacha20_key, sizeof(c->chacha20_key));
+	memzero_explicit(&c->chacha20_keys, sizeof(c->chacha20_keys));
 }
 
 int bch2_fs_encryption_init(struct bch_fs *c)
@@ -674,8 +857,15 @@ int bch2_fs_encryption_init(struct bch_fs *c)
 	if (!crypt)
 		return 0;
 
-	try(bch2_decrypt_sb_key(c, crypt, &c->chacha20_key));
+	unsigned gen = bch2_crypt_key_seq(crypt) & 1;
+	bool rotating = bch2_crypt_rotating(crypt);
+
+	try(bch2_decrypt_sb_key(c, crypt, &c->chacha20_keys[gen],
+				rotating ? &c->chacha20_keys[!gen] : NULL));
 
+	atomic64_set(&c->key_rotate, (u64) gen << 63 |
+		     (rotating ? le64_to_cpu(crypt->rotate_version) : 0));
+	c->key_journal_seq = bch2_crypt_key_journal_seq(crypt);
 	c->chacha20_key_set = true;
 	return 0;
 }
@@ -31,6 +31,66 @@ struct bch_csum bch2_checksum_merge(unsigned, struct bch_csum,
 #define BCH_NONCE_PRIO		cpu_to_le32(4 << 28)
 #define BCH_NONCE_POLY		cpu_to_le32(1 << 31)
 
+/*
+ * Key rotation: which master key an object is encrypted with. Extents go by
+ * version - everything written before the rotation started has a version below
+ * its rotate_version - and btree node bsets and journal entries record the key
+ * generation they were written with. Outside of a rotation there's just the one
+ * key.
+ *
+ * The generation and rotate_version are read from one word, so they're always
+ * seen together:
+ */
+static inline unsigned bch2_key_gen(struct bch_fs *c)
+{
+	return atomic64_read(&c->key_rotate) >> 63;
+}
+
+static inline u64 bch2_key_rotate_version(struct bch_fs *c)
+{
+	return atomic64_read(&c->key_rotate) & ~BIT_ULL(63);
+}
+
+static inline const struct bch_key *bch2_cur_key(struct bch_fs *c)
+{
+	return &c->chacha20_keys[bch2_key_gen(c)];
+}
+
+static inline bool bch2_extent_key_is_old(struct bch_fs *c, struct bversion version)
+{
+	return version.lo < bch2_key_rotate_version(c);
+}
+
+static inline const struct bch_key *bch2_extent_key(struct bch_fs *c,
+						    struct bversion version)
+{
+	u64 r = atomic64_read(&c->key_rotate);
+	unsigned gen = r >> 63;
+
+	if (version.lo < (r & ~BIT_ULL(63)))
+		gen ^= 1;
+	return &c->chacha20_keys[gen];
+}
+
+static inline const struct bch_key *__bch2_gen_key(struct bch_fs *c, unsigned gen)
+{
+	u64 r = atomic64_read(&c->key_rotate);
+
+	return &c->chacha20_keys[r & ~BIT_ULL(63) ? gen : r >> 63];
+}
+
+static inline const struct bch_key *bch2_bset_key(struct bch_fs *c, struct bset *i)
+{
+	return __bch2_gen_key(c, BSET_KEY_GEN(i));
+}
+
+static inline const struct bch_key *bch2_jset_key(struct bch_fs *c, struct jset *j)
+{
+	return __bch2_gen_key(c, JSET_KEY_GEN(j));
+}
+
+struct bch_csum bch2_checksum_key(const struct bch_key *, unsigned, struct nonce,
+				  const void *, size_t);
 struct bch_csum bch2_checksum(struct bch_fs *, unsigned, struct nonce,
 			     const void *, size_t);
 
@@ -45,6 +105,13 @@ struct bch_csum bch2_checksum(struct bch_fs *, unsigned, struct nonce,
 	bch2_checksum(_c, _type, _nonce, _start, vstruct_end(_i) - _start);\
 })
 
+#define csum_vstruct_key(_key, _type, _nonce, _i)			\
+({									\
+	const void *_start = ((const void *) (_i)) + sizeof((_i)->csum);\
+									\
+	bch2_checksum_key(_key, _type, _nonce, _start, vstruct_end(_i) - _start);\
+})
+
 static inline void bch2_csum_to_text(struct printbuf *out,
 				     enum bch_csum_type type,
 				     struct bch_csum csum)
@@ -76,11 +143,13 @@ int bch2_request_key(struct bch_sb *, struct bch_key *);
 int bch2_revoke_key(struct bch_sb *);
 #endif
 
+int bch2_encrypt_key(struct bch_fs *, const struct bch_key *, unsigned,
+		     struct nonce, void *data, size_t);
 int bch2_encrypt(struct bch_fs *, unsigned, struct nonce,
 		 void *data, size_t);
 
 struct bch_csum bch2_checksum_bio(struct bch_fs *, unsigned,
-				  struct nonce, struct bio *);
+				  struct bversion, struct nonce, struct bio *);
 
 int bch2_rechecksum_bio(struct bch_fs *, struct bio *, struct bversion,
 			struct bch_extent_crc_unpacked,
@@ -89,20 +158,23 @@ int bch2_rechecksum_bio(struct bch_fs *, struct bio *, struct bversion,
 			unsigned, unsigned, unsigned);
 
 int __bch2_encrypt_bio(struct bch_fs *, unsigned,
-		       struct nonce, struct bio *);
+		       struct bversion, struct nonce, struct bio *);
 
 static inline int bch2_encrypt_bio(struct bch_fs *c, unsigned type,
-				   struct nonce nonce, struct bio *bio)
+				   struct bversion version, struct nonce nonce,
+				   struct bio *bio)
 {
 	return bch2_csum_type_is_encryption(type)
-		? __bch2_encrypt_bio(c, type, nonce, bio)
+		? __bch2_encrypt_bio(c, type, version, nonce, bio)
 		: 0;
 }
 
 extern const struct bch_sb_field_ops bch_sb_field_ops_crypt;
+extern const struct bch_sb_field_ops bch_sb_field_ops_key_slots;
+extern const struct bch_sb_field_ops bch_sb_field_ops_key_servers;
 
 int bch2_decrypt_sb_key(struct bch_fs *, struct bch_sb_field_crypt *,
-			struct bch_key *);
+			struct bch_key *, struct bch_key *);
 
 #if 0
 int bch2_disable_encryption(struct bch_fs *);
@@ -231,28 +303,54 @@ static inline bool bch2_key_is_encrypted(struct bch_encrypted_key *key)
 	return le64_to_cpu(key->magic) != BCH_KEY_MAGIC;
 }
 
-static inline struct nonce __bch2_sb_key_nonce(struct bch_sb *sb)
+static inline u64 bch2_crypt_key_seq(struct bch_sb_field_crypt *crypt)
+{
+	return crypt &&
+		vstruct_bytes(&crypt->field) > offsetof(struct bch_sb_field_crypt, key_seq)
+		? le64_to_cpu(crypt->key_seq)
+		: 0;
+}
+
+static inline bool bch2_crypt_rotating(struct bch_sb_field_crypt *crypt)
+{
+	return BCH_CRYPT_ROTATING(crypt) &&
+		vstruct_bytes(&crypt->field) >=
+		offsetofend(struct bch_sb_field_crypt, rotate_version);
+}
+
+static inline u64 bch2_crypt_key_journal_seq(struct bch_sb_field_crypt *crypt)
+{
+	return crypt &&
+		vstruct_bytes(&crypt->field) >= sizeof(*crypt)
+		? le64_to_cpu(crypt->key_journal_seq)
+		: 0;
+}
+
+/*
+ * The nonce the master key is wrapped with: @seq is bch_sb_field_crypt.key_seq
+ * for crypt->key, or key_seq - 1 for crypt->old_key
+ */
+static inline struct nonce __bch2_sb_key_nonce_seq(struct bch_sb *sb, u64 seq)
 {
 	__le64 magic = __bch2_sb_magic(sb);
 
 	return (struct nonce) {{
-		[0] = 0,
-		[1] = 0,
+		[0] = cpu_to_le32(seq),
+		[1] = cpu_to_le32(seq >> 32),
 		[2] = ((__le32 *) &magic)[0],
 		[3] = ((__le32 *) &magic)[1],
 	}};
 }
 
-static inline struct nonce bch2_sb_key_nonce(struct bch_fs *c)
+static inline struct nonce __bch2_sb_key_nonce(struct bch_sb *sb)
 {
-	__le64 magic = bch2_sb_magic(c);
+	return __bch2_sb_key_nonce_seq(sb,
+			bch2_crypt_key_seq(bch2_sb_field_get(sb, crypt)));
+}
 
-	return (struct nonce) {{
-		[0] = 0,
-		[1] = 0,
-		[2] = ((__le32 *) &magic)[0],
-		[3] = ((__le32 *) &magic)[1],
-	}};
+static inline struct nonce bch2_sb_key_nonce(struct bch_fs *c)
+{
+	return __bch2_sb_key_nonce(c->disk_sb.sb);
 }
 
 #endif /* _BCACHEFS_CHECKSUM_H */
@@ -231,6 +231,13 @@ int bch2_bkey_pick_read_device(struct bch_fs *c, struct bkey_s_c k,
 		    p.ptr.dev != preferred_dev)
 			continue;
 
+		/*
+		 * Checking one replica: reconstructing it from parity would
+		 * hide the errors we're looking for
+		 */
+		if (flags & BCH_READ_single_replica)
+			p.has_ec = false;
+
 		struct bch_dev *ca = bch2_dev_rcu_noerror(c, p.ptr.dev);
 
 		if (unlikely(!ca && p.ptr.dev != BCH_SB_MEMBER_INVALID)) {
@@ -48,6 +48,24 @@ impl ExtentUnionField<c::bch_extent_stripe_ptr> for c::bch_extent_stripe_ptr {
     }
 }
 
+impl ExtentUnionField<c::bch_extent_flags> for c::bch_extent_flags {
+    unsafe fn as_union_ref(&self) -> &c::bch_extent_flags {
+        self
+    }
+    unsafe fn as_union_mut(&mut self) -> &mut c::bch_extent_flags {
+        self
+    }
+}
+
+impl ExtentUnionField<c::bch_extent_reconcile> for c::bch_extent_reconcile {
+    unsafe fn as_union_ref(&self) -> &c::bch_extent_reconcile {
+        self
+    }
+    unsafe fn as_union_mut(&mut self) -> &mut c::bch_extent_reconcile {
+        self
+    }
+}
+
 unsafe fn extent_union_field_ref<T, F: ExtentUnionField<T>>(field: &F) -> &T {
     unsafe { field.as_union_ref() }
 }
@@ -165,6 +183,25 @@ pub fn bkey_ptrs(k: &c::bkey_i) -> ExtentPtrIter<'_> {
     bkey_ptrs_sc(&BkeyValSC::from_bkey_i(k))
 }
 
+/// The extent's `BCH_EXTENT_FLAG_*` bits, as bch2_bkey_extent_flags(): a
+/// flags entry, if there is one, is always the first entry.
+pub fn bkey_extent_flags_sc(sc: &BkeyValSC<'_>) -> u64 {
+    match bkey_extent_entries_sc(sc).next() {
+        Some(e) if extent_entry_type(e) == c::bch_extent_entry_type::BCH_EXTENT_ENTRY_flags as u32 => {
+            let f: &c::bch_extent_flags = unsafe { extent_union_field_ref(&e.flags) };
+            f.flags()
+        }
+        _ => 0,
+    }
+}
+
+/// The extent's reconcile entry, if it has one, as bch2_bkey_reconcile_opts().
+pub fn bkey_reconcile_opts_sc<'a>(sc: &BkeyValSC<'a>) -> Option<&'a c::bch_extent_reconcile> {
+    bkey_extent_entries_sc(sc)
+        .find(|e| extent_entry_type(e) == c::bch_extent_entry_type::BCH_EXTENT_ENTRY_reconcile as u32)
+        .map(|e| unsafe { extent_union_field_ref(&e.reconcile) })
+}
+
 pub struct ExtentEntryIterMut<'a> {
     fs:       &'a Fs,
     cur:      *mut c::bch_extent_entry,
@@ -50,7 +50,8 @@ struct bch_io_failures {
 	x(must_bounce)			\
 	x(must_clone)			\
 	x(in_retry)			\
-	x(no_poison_check)
+	x(no_poison_check)		\
+	x(single_replica)
 
 enum __bch_read_flags {
 #define x(n)	__BCH_READ_##n,
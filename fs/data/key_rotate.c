@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: GPL-2.0
+/*
+ * Online rotation of the master encryption key.
+ *
+ * Starting a rotation generates a new master key, keeps the old one (still
+ * wrapped the way it was) in the crypt superblock field, and records a
+ * rotate_version: extents with a bversion below it are encrypted with the old
+ * key, everything written from then on gets the new one. Btree node bsets and
+ * journal entries record the key generation they were written with.
+ *
+ * Reconcile rewrites old extents with a new version (BCH_WRITE_rekey), and the
+ * worker here rewrites btree nodes with old bsets and keeps checking until
+ * nothing needs the old key, then drops it. The state is all in the superblock,
+ * so a rotation picks up where it left off after a remount.
+ */
+
+#include "bcachefs.h"
+
+#include "btree/bkey_methods.h"
+#include "btree/interior.h"
+#include "btree/iter.h"
+#include "btree/update.h"
+
+#include "data/checksum.h"
+#include "data/extents.h"
+#include "data/key_rotate.h"
+#include "data/reconcile/work.h"
+
+#include "journal/journal.h"
+#include "journal/reclaim.h"
+
+#include "sb/io.h"
+
+#include <linux/random.h>
+
+#define KEY_ROTATE_RECHECK_DELAY	(HZ * 60)
+
+int bch2_key_rotate_start(struct bch_fs *c, struct printbuf *err)
+{
+	struct bch_key user_key = {};
+	int ret = 0;
+
+	if (!enumerated_ref_tryget(&c->writes, BCH_WRITE_REF_key_rotate))
+		return bch_err_throw(c, erofs_no_writes);
+
+	scoped_guard(mutex_noio, &c->sb_lock) {
+		struct bch_sb_field_crypt *crypt = bch2_sb_field_get(c->disk_sb.sb, crypt);
+		if (!crypt || !c->chacha20_key_set) {
+			prt_printf(err, "filesystem is not encrypted\n");
+			ret = bch_err_throw(c, EINVAL_key_rotate_not_encrypted);
+			goto err;
+		}
+
+		if (bch2_crypt_rotating(crypt)) {
+			prt_printf(err, "a key rotation is already in progress\n");
+			ret = bch_err_throw(c, EBUSY_key_rotate_in_progress);
+			goto err;
+		}
+
+		u64 seq = bch2_crypt_key_seq(crypt) + 1;
+		unsigned gen = seq & 1;
+		bool wrapped = bch2_key_is_encrypted(&crypt->key);
+
+		if (wrapped) {
+			ret = bch2_request_key(c->disk_sb.sb, &user_key);
+			if (ret) {
+				prt_printf(err, "error requesting encryption key: %s\n",
+					   bch2_err_str(ret));
+				goto err;
+			}
+
+			/* The new key gets wrapped with this, so it had better be right: */
+			struct bch_encrypted_key check = crypt->key;
+			bch2_chacha20(&user_key, __bch2_sb_key_nonce_seq(c->disk_sb.sb, seq - 1),
+				      &check, sizeof(check));
+
+			bool good = !bch2_key_is_encrypted(&check) &&
+				!memcmp(&check.key, bch2_cur_key(c), sizeof(check.key));
+			memzero_explicit(&check, sizeof(check));
+
+			if (!good) {
+				prt_printf(err, "key in keyring doesn't unlock this filesystem\n");
+				ret = bch_err_throw(c, EPERM_key_rotate_wrong_key);
+				goto err;
+			}
+		}
+
+		crypt = bch2_sb_field_resize(&c->disk_sb, crypt,
+					     sizeof(*crypt) / sizeof(u64));
+		if (!crypt) {
+			ret = bch_err_throw(c, ENOSPC_sb_crypt);
+			goto err;
+		}
+
+		struct bch_encrypted_key old_key = crypt->key;
+		struct bch_encrypted_key new_key = { .magic = cpu_to_le64(BCH_KEY_MAGIC) };
+		get_random_bytes(&new_key.key, sizeof(new_key.key));
+
+		/* Not the current generation, and not rotating: nothing's using it */
+		c->chacha20_keys[gen] = new_key.key;
+
+		if (wrapped)
+			bch2_chacha20(&user_key, __bch2_sb_key_nonce_seq(c->disk_sb.sb, seq),
+				      &new_key, sizeof(new_key));
+
+		/*
+		 * Leave a gap: versions handed out between here and bumping
+		 * key_version below are still below rotate_version, and get the
+		 * old key:
+		 */
+		u64 rotate_version = atomic64_read(&c->key_version) + (1ULL << 32);
+
+		crypt->key		= new_key;
+		crypt->old_key		= old_key;
+		crypt->key_seq		= cpu_to_le64(seq);
+		crypt->rotate_version	= cpu_to_le64(rotate_version);
+		SET_BCH_CRYPT_ROTATING(crypt, true);
+		c->disk_sb.sb->features[0] |= cpu_to_le64(BIT_ULL(BCH_FEATURE_key_rotation));
+
+		memzero_explicit(&new_key, sizeof(new_key));
+
+		ret = bch2_write_super(c);
+		if (ret) {
+			crypt->key		= old_key;
+			crypt->key_seq		= cpu_to_le64(seq - 1);
+			crypt->rotate_version	= 0;
+			memzero_explicit(&crypt->old_key, sizeof(crypt->old_key));
+			SET_BCH_CRYPT_ROTATING(crypt, false);
+			memzero_explicit(&c->chacha20_keys[gen], sizeof(c->chacha20_keys[gen]));
+
+			prt_printf(err, "error writing superblock: %s\n", bch2_err_str(ret));
+			goto err;
+		}
+
+		atomic64_set(&c->key_rotate, (u64) gen << 63 | rotate_version);
+
+		s64 v = atomic64_read(&c->key_version);
+		while (v < rotate_version &&
+		       !atomic64_try_cmpxchg(&c->key_version, &v, rotate_version))
+			;
+	}
+
+	bch_info(c, "starting master key rotation");
+
+	ret = bch2_set_fs_needs_reconcile(c);
+	if (ret)
+		prt_printf(err, "error starting reconcile scan: %s\n", bch2_err_str(ret));
+
+	bch2_key_rotate_maybe_schedule(c);
+err:
+	memzero_explicit(&user_key, sizeof(user_key));
+	enumerated_ref_put(&c->writes, BCH_WRITE_REF_key_rotate);
+	return ret;
+}
+
+static int key_rotate_btree_nodes(struct btree_trans *trans, unsigned old_gen, u64 *nr)
+{
+	struct bch_fs *c = trans->c;
+
+	for (unsigned btree = 0; btree < btree_id_nr_alive(c); btree++)
+		for (unsigned level = 0; level < BTREE_MAX_DEPTH; level++)
+			try(for_each_btree_node(trans, iter, btree, POS_MIN, level, 0, b, ({
+				if (btree_node_has_key_gen(b, old_gen)) {
+					bch2_async_btree_op(c, b, ASYNC_BTREE_rewrite);
+					(*nr)++;
+				}
+
+				test_bit(BCH_FS_going_ro, &c->flags)
+					? bch_err_throw(c, erofs_no_writes)
+					: 0;
+			})));
+
+	return 0;
+}
+
+static int key_rotate_extent(struct btree_trans *trans, struct btree_iter *iter,
+			     struct bkey_s_c k, u64 *nr)
+{
+	struct bch_fs *c = trans->c;
+
+	if (test_bit(BCH_FS_going_ro, &c->flags))
+		return bch_err_throw(c, erofs_no_writes);
+
+	if (!bkey_extent_is_direct_data(k.k) ||
+	    !bch2_extent_key_is_old(c, k.k->bversion))
+		return 0;
+
+	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded p;
+	bool encrypted = false, dirty = false;
+
+	bkey_for_each_ptr_decode(k.k, ptrs, p, entry)
+		if (bch2_csum_type_is_encryption(p.crc.csum_type) &&
+		    !p.ptr.unwritten) {
+			encrypted = true;
+			dirty |= !p.ptr.cached;
+		}
+
+	if (!encrypted)
+		return 0;
+
+	/* Reconcile rekeys these: */
+	if (dirty) {
+		(*nr)++;
+		return 0;
+	}
+
+	/*
+	 * Nothing to rekey cached-only extents from that's any better than
+	 * reading them back in later - and once the old key is gone they can't
+	 * be read at all:
+	 */
+	struct bkey_i *n = errptr_try(bch2_bkey_make_mut(trans, iter, &k, 0));
+	bch2_bkey_drop_ptrs_noerror(bkey_i_to_s(n), p, entry, p.ptr.cached);
+	if (!bch2_bkey_can_read(c, bkey_i_to_s_c(n)))
+		bch2_set_bkey_error(c, n, KEY_TYPE_ERROR_no_valid_pointers_repair);
+	return 0;
+}
+
+/* How much is still encrypted with the key being rotated out? */
+static int key_rotate_check(struct bch_fs *c, u64 *nr)
+{
+	unsigned old_gen = bch2_key_gen(c) ^ 1;
+	CLASS(btree_trans, trans)(c);
+
+	*nr = 0;
+
+	try(key_rotate_btree_nodes(trans, old_gen, nr));
+
+	static const enum btree_id btrees[] = { BTREE_ID_extents, BTREE_ID_reflink };
+	for (unsigned i = 0; i < ARRAY_SIZE(btrees); i++)
+		try(for_each_btree_key_commit(trans, iter, btrees[i], POS_MIN,
+				BTREE_ITER_prefetch|BTREE_ITER_all_snapshots, k,
+				NULL, NULL, BCH_TRANS_COMMIT_no_enospc,
+			key_rotate_extent(trans, &iter, k, nr)));
+
+	return 0;
+}
+
+static int key_rotate_finish(struct bch_fs *c)
+{
+	unsigned gen = bch2_key_gen(c);
+	/*
+	 * The journal was flushed past everything written with the old key
+	 * (see bch2_key_rotate_work()), but those entries are still in journal
+	 * buckets until they're overwritten: journal read skips them, rather
+	 * than flagging checksum errors, once the key is gone:
+	 */
+	u64 journal_seq = c->journal.last_seq_ondisk;
+
+	scoped_guard(mutex_noio, &c->sb_lock) {
+		struct bch_sb_field_crypt *crypt =
+			bch2_sb_field_resize(&c->disk_sb, crypt,
+					     sizeof(*crypt) / sizeof(u64));
+		if (!crypt)
+			return bch_err_throw(c, ENOSPC_sb_crypt);
+
+		SET_BCH_CRYPT_ROTATING(crypt, false);
+		crypt->rotate_version = 0;
+		crypt->key_journal_seq = cpu_to_le64(journal_seq);
+		memzero_explicit(&crypt->old_key, sizeof(crypt->old_key));
+
+		try(bch2_write_super(c));
+	}
+
+	c->key_journal_seq = journal_seq;
+	atomic64_set(&c->key_rotate, (u64) gen << 63);
+	memzero_explicit(&c->chacha20_keys[gen ^ 1], sizeof(c->chacha20_keys[0]));
+
+	bch_info(c, "master key rotation complete");
+	return 0;
+}
+
+static void bch2_key_rotate_work(struct work_struct *work)
+{
+	struct bch_fs *c = container_of(work, struct bch_fs, key_rotate_work.work);
+
+	if (!bch2_key_rotate_version(c) ||
+	    !enumerated_ref_tryget(&c->writes, BCH_WRITE_REF_key_rotate))
+		return;
+
+	u64 nr = 0;
+	int ret = key_rotate_check(c, &nr);
+	if (!ret && !nr) {
+		/*
+		 * Nothing left that we can see: flush the journal, so that no
+		 * journal entry written with the old key is still needed - that
+		 * also writes out dirty btree nodes, with the new key - then
+		 * look again, in case we raced with writes that picked up the
+		 * old key just before the rotation started:
+		 */
+		bch2_journal_flush_all_pins(&c->journal);
+
+		ret = bch2_journal_meta(&c->journal) ?:
+			key_rotate_check(c, &nr);
+		if (!ret && !nr)
+			ret = key_rotate_finish(c);
+	}
+
+	if (ret && !bch2_err_matches(ret, EROFS))
+		bch_err_fn(c, ret);
+
+	if ((ret || nr) && !test_bit(BCH_FS_going_ro, &c->flags))
+		queue_delayed_work(system_long_wq, &c->key_rotate_work,
+				   KEY_ROTATE_RECHECK_DELAY);
+
+	enumerated_ref_put(&c->writes, BCH_WRITE_REF_key_rotate);
+}
+
+void bch2_key_rotate_stop(struct bch_fs *c)
+{
+	cancel_delayed_work_sync(&c->key_rotate_work);
+}
+
+void bch2_key_rotate_maybe_schedule(struct bch_fs *c)
+{
+	if (bch2_key_rotate_version(c))
+		queue_delayed_work(system_long_wq, &c->key_rotate_work, 0);
+}
+
+void bch2_fs_key_rotate_init_early(struct bch_fs *c)
+{
+	INIT_DELAYED_WORK(&c->key_rotate_work, bch2_key_rotate_work);
+}
@@ -0,0 +1,11 @@
+/* SPDX-License-Identifier: GPL-2.0 */
+#ifndef _BCACHEFS_KEY_ROTATE_H
+#define _BCACHEFS_KEY_ROTATE_H
+
+int bch2_key_rotate_start(struct bch_fs *, struct printbuf *);
+
+void bch2_key_rotate_stop(struct bch_fs *);
+void bch2_key_rotate_maybe_schedule(struct bch_fs *);
+void bch2_fs_key_rotate_init_early(struct bch_fs *);
+
+#endif /* _BCACHEFS_KEY_ROTATE_H */
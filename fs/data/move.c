@@ -33,6 +33,7 @@
 
 #include "sb/counters.h"
 #include "sb/io.h"
+#include "sb/members.h"
 
 #include "snapshots/snapshot.h"
 
@@ -984,6 +985,103 @@ static int scrub_pred(struct btree_trans *trans, void *_arg,
 	return true;
 }
 
+/*
+ * Scrub runs in chunks of 1/SCRUB_CHECKPOINT_CHUNKS of the device, recording
+ * progress in the member after each and when stopped: an interrupted scrub
+ * can be resumed, and we know when the device was last completely scrubbed.
+ */
+#define SCRUB_CHECKPOINT_CHUNKS		128
+
+static void scrub_checkpoint(struct bch_fs *c, struct bch_dev *ca,
+			     struct bch_move_stats *stats, u64 pos, bool done)
+{
+	u64 corrected	= atomic64_read(&stats->sectors_error_corrected);
+	u64 uncorrected	= atomic64_read(&stats->sectors_error_uncorrected);
+
+	guard(mutex_noio)(&c->sb_lock);
+	struct bch_member *m = bch2_members_v2_get_mut(c->disk_sb.sb, ca->dev_idx);
+
+	if (done) {
+		m->last_scrub_start			= m->scrub_start;
+		m->last_scrub_end			= cpu_to_le64(ktime_get_real_seconds());
+		m->last_scrub_data_types		= m->scrub_data_types;
+		m->last_scrub_sectors_corrected		= cpu_to_le64(corrected);
+		m->last_scrub_sectors_uncorrected	= cpu_to_le64(uncorrected);
+
+		m->scrub_start				= 0;
+		pos					= 0;
+	}
+
+	m->scrub_pos			= cpu_to_le64(pos);
+	m->scrub_sectors_done		= cpu_to_le64(done ? 0 : atomic64_read(&stats->sectors_seen));
+	m->scrub_sectors_corrected	= cpu_to_le64(done ? 0 : corrected);
+	m->scrub_sectors_uncorrected	= cpu_to_le64(done ? 0 : uncorrected);
+
+	bch2_write_super(c);
+}
+
+static int bch2_scrub_dev(struct bch_fs *c,
+			  struct bch_move_stats *stats,
+			  struct bch_ioctl_data *op)
+{
+	CLASS(bch2_dev_tryget_noerror, ca)(c, op->scrub.dev);
+	if (!ca)
+		return bch_err_throw(c, device_offline);
+
+	u64 dev_end		= bucket_to_sector(ca, ca->mi.nbuckets);
+	u64 chunk_buckets	= DIV_ROUND_UP(ca->mi.nbuckets, SCRUB_CHECKPOINT_CHUNKS);
+	u64 pos			= 0;
+
+	scoped_guard(mutex_noio, &c->sb_lock) {
+		struct bch_member *m = bch2_members_v2_get_mut(c->disk_sb.sb, ca->dev_idx);
+
+		if ((op->flags & BCH_DATA_RESUME) &&
+		    m->scrub_start &&
+		    le32_to_cpu(m->scrub_data_types) == op->scrub.data_types) {
+			pos = min(le64_to_cpu(m->scrub_pos), dev_end);
+
+			/* progress and error counts carry on from the checkpoint: */
+			atomic64_set(&stats->sectors_seen,		le64_to_cpu(m->scrub_sectors_done));
+			atomic64_set(&stats->sectors_error_corrected,	le64_to_cpu(m->scrub_sectors_corrected));
+			atomic64_set(&stats->sectors_error_uncorrected,	le64_to_cpu(m->scrub_sectors_uncorrected));
+		} else {
+			m->scrub_start			= cpu_to_le64(ktime_get_real_seconds());
+			m->scrub_pos			= 0;
+			m->scrub_sectors_done		= 0;
+			m->scrub_sectors_corrected	= 0;
+			m->scrub_sectors_uncorrected	= 0;
+			m->scrub_data_types		= cpu_to_le32(op->scrub.data_types);
+			bch2_write_super(c);
+		}
+	}
+
+	int ret = 0;
+	while (!ret && pos < dev_end) {
+		u64 end = min(bucket_to_sector(ca, sector_to_bucket(ca, pos) + chunk_buckets),
+			      dev_end);
+
+		stats->offset = pos;
+
+		ret = bch2_move_data_phys(c, ca->dev_idx, pos, end,
+					  op->scrub.data_types,
+					  NULL,
+					  stats,
+					  writepoint_hashed((unsigned long) current),
+					  false,
+					  scrub_pred, op);
+
+		/*
+		 * Stopped or failed partway: everything before the extent we
+		 * were on has been scrubbed - moving_ctxt_exit() waited for it.
+		 */
+		pos = !ret ? end : max(pos, stats->offset);
+
+		scrub_checkpoint(c, ca, stats, pos, !ret && pos >= dev_end);
+	}
+
+	return ret;
+}
+
 #include "journal/read.h"
 #include "btree/journal_overlay.h"
 
@@ -1216,13 +1314,7 @@ int bch2_data_job(struct bch_fs *c,
 		 */
 		bch2_btree_interior_updates_flush(c);
 
-		ret = bch2_move_data_phys(c, op->scrub.dev, 0, U64_MAX,
-					  op->scrub.data_types,
-					  NULL,
-					  stats,
-					  writepoint_hashed((unsigned long) current),
-					  false,
-					  scrub_pred, op) ?: ret;
+		ret = bch2_scrub_dev(c, stats, op);
 		break;
 
 	default:
@@ -718,7 +718,8 @@ static void bch2_rbio_retry(struct work_struct *work)
 	int orig_ret = rbio->ret;
 	CLASS(bch_io_failures, failed)();
 
-	flags &= ~BCH_READ_hard_require_read_device;
+	if (!(flags & BCH_READ_single_replica))
+		flags &= ~BCH_READ_hard_require_read_device;
 
 	event_inc_trace(c, data_read_retry, buf,
 			bch2_read_bio_to_text_atomic(&buf, rbio));
@@ -946,7 +947,7 @@ static noinline void bch2_rbio_narrow_crcs(struct bch_read_bio *rbio)
 static int bch2_rbio_decrypt(struct bch_fs *c, struct bch_read_bio *rbio,
 			     struct bch_extent_crc_unpacked crc, struct nonce nonce)
 {
-	return bch2_encrypt_bio(c, crc.csum_type, nonce, &rbio->bio)
+	return bch2_encrypt_bio(c, crc.csum_type, rbio->version, nonce, &rbio->bio)
 		? bch_err_throw(c, data_read_decrypt_err)
 		: 0;
 }
@@ -971,7 +972,7 @@ static int __bch2_read_endio_work(struct bch_read_bio *rbio)
 	    bch2_read_corrupt_device < 0)
 		bch2_maybe_corrupt_bio(src, bch2_read_corrupt_ratio);
 
-	csum = bch2_checksum_bio(c, crc.csum_type, nonce, src);
+	csum = bch2_checksum_bio(c, crc.csum_type, rbio->version, nonce, src);
 	bool csum_good = !bch2_crc_cmp(csum, rbio->pick.crc.csum) || c->opts.no_data_io;
 
 	/*
@@ -1213,6 +1214,9 @@ noinline __cold
 static void data_read_trace(struct bch_read_bio *rbio, struct bkey_s_c k)
 {
 	__event_trace(rbio->c, data_read, buf, ({
+		/* Fixed summary line, as for data_write: */
+		prt_printf(&buf, "inum %llu sectors %u\n",
+			   rbio->read_pos.inode, bio_sectors(&rbio->bio));
 		bch2_bkey_val_to_text(&buf, rbio->c, k);
 		prt_newline(&buf);
 		bch2_read_bio_to_text_atomic(&buf, rbio);
@@ -1472,7 +1476,9 @@ static noinline int read_extent_pick_err(struct btree_trans *trans,
 		/* We can only return errors directly in the retry path */
 		BUG_ON(!(flags & BCH_READ_in_retry));
 
-		try(maybe_poison_extent(trans, rbio, data_btree, k));
+		/* Other replicas weren't tried: */
+		if (!(flags & BCH_READ_single_replica))
+			try(maybe_poison_extent(trans, rbio, data_btree, k));
 	}
 
 	if (!(flags & BCH_READ_in_retry)) {
@@ -1770,7 +1776,9 @@ int bch2_read(struct btree_trans *trans, struct bch_read_bio *rbio,
 
 		ret = __bch2_read_extent(trans, rbio, bvec_iter, iter.pos,
 					 data_btree, k,
-					 offset_into_extent, failed, flags, -1);
+					 offset_into_extent, failed, flags,
+					 flags & BCH_READ_single_replica
+					 ? rbio->err_report->dev : -1);
 		swap(bvec_iter.bi_size, bytes);
 
 		if (ret)
@@ -22,6 +22,8 @@ struct bch_read_err_report {
 	struct mutex		lock;
 	u32			errors;
 	struct printbuf		msg;
+	/* BCH_READ_single_replica: the device to read from */
+	unsigned		dev;
 };
 
 struct bch_read_bio {
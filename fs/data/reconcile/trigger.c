@@ -30,7 +30,11 @@ int bch2_extent_reconcile_validate(struct bch_fs *c,
 			 c, extent_reconcile_bad_pending,
 			 "pending incorrectly set");
 
-	bkey_fsck_err_on(r->hipri && !(r->need_rb & BIT(BCH_RECONCILE_data_replicas)),
+	/* Kicked extents (BCHFS_IOC_RECONCILE_KICK) are hipri for any work: */
+	bkey_fsck_err_on(r->hipri &&
+			 !(r->need_rb & BIT(BCH_RECONCILE_data_replicas)) &&
+			 (!r->need_rb ||
+			  c->sb.version < bcachefs_metadata_version_reconcile_kick),
 			 c, extent_reconcile_bad_hipri,
 			 "hipri incorrectly set");
 
@@ -503,7 +507,11 @@ static int bch2_bkey_needs_reconcile(struct btree_trans *trans, struct bkey_s_c
 		if (!poisoned &&
 		    !btree &&
 		    !p.ptr.cached) {
-			if (p.crc.csum_type != csum_type)
+			/* Still encrypted with a key that's being rotated out? */
+			if (p.crc.csum_type != csum_type ||
+			    (bch2_csum_type_is_encryption(p.crc.csum_type) &&
+			     !p.ptr.unwritten &&
+			     bch2_extent_key_is_old(c, k.k->bversion)))
 				r.need_rb |= BIT(BCH_RECONCILE_data_checksum);
 
 			if (p.crc.compression_type != compression_type)
@@ -588,6 +596,11 @@ static int bch2_bkey_needs_reconcile(struct btree_trans *trans, struct bkey_s_c
 		*need_update_invalid_devs = 0;
 
 	const struct bch_extent_reconcile *old = bch2_bkey_ptrs_reconcile_opts(c, ptrs);
+
+	/* A kicked extent stays high priority until its work is done: */
+	if (old && old->hipri && r.need_rb)
+		r.hipri = 1;
+
 	if (old && !(old->need_rb & ~r.need_rb)) {
 		r.pending = old->pending;
 		if (r.hipri && !old->hipri)
@@ -693,6 +706,14 @@ static int new_needs_rb_allowed(struct btree_trans *trans,
 	    ctx == SET_NEEDS_RECONCILE_opt_change_indirect)
 		return 0;
 
+	/*
+	 * Extents written before a key rotation started need rekeying - a new
+	 * requirement no option change is behind:
+	 */
+	if ((new_need_rb & BIT(BCH_RECONCILE_data_checksum)) &&
+	    bch2_extent_key_is_old(c, k.k->bversion))
+		new_need_rb &= ~BIT(BCH_RECONCILE_data_checksum);
+
 	if ((new_need_rb & BIT(BCH_RECONCILE_erasure_code)) &&
 	    !bkey_has_ec(c, k)) {
 		/* Foreground writes are not initially erasure coded - and we
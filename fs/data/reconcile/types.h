@@ -5,6 +5,7 @@
 #include "btree/bbpos_types.h"
 #include "data/move_types.h"
 #include "init/progress.h"
+#include "util/util.h"
 
 #include <linux/mutex.h>
 #include <linux/rhashtable-types.h>
@@ -32,6 +33,9 @@ struct bch_fs_reconcile {
 	bool				scans_in_flight_init_done;
 	struct mutex			scans_in_flight_lock;
 
+	/* reconcile_rate_limit, for the main thread's keyed phases */
+	struct bch_ratelimit		rate;
+
 	bool				on_battery;
 #ifdef CONFIG_POWER_SUPPLY
 	struct notifier_block		power_notifier;
@@ -13,6 +13,7 @@
 #include "btree/update.h"
 #include "btree/write_buffer.h"
 
+#include "data/checksum.h"
 #include "data/compress.h"
 #include "data/copygc.h"
 #include "data/ec/create.h"
@@ -446,9 +447,12 @@ static int reconcile_set_data_opts(struct btree_trans *trans,
 
 	/*
 	 * we can't add/drop replicas from btree nodes incrementally, we always
-	 * need to be able to spill over to the whole fs
+	 * need to be able to spill over to the whole fs - nor restore
+	 * durability only where the target has room. (A kicked extent is hipri
+	 * too, but only has to go faster, not anywhere.)
 	 */
-	if (!r->hipri && !bkey_is_btree_ptr(k.k))
+	if (!(r->hipri && (r->need_rb & BIT(BCH_RECONCILE_data_replicas))) &&
+	    !bkey_is_btree_ptr(k.k))
 		data_opts->write_flags |= BCH_WRITE_only_specified_devs;
 
 	struct bkey_ptrs_c ptrs = bch2_bkey_ptrs_c(k);
@@ -624,11 +628,26 @@ static int reconcile_set_data_opts(struct btree_trans *trans,
 	}
 skip_ec:
 
+	/*
+	 * Key rotation: an extent still encrypted with the old master key gets
+	 * every replica rewritten at once, since they all share the version
+	 * that selects the key - see BCH_WRITE_rekey:
+	 */
+	bool rekey = false;
+	if ((r->need_rb & BIT(BCH_RECONCILE_data_checksum)) &&
+	    bch2_extent_key_is_old(c, k.k->bversion))
+		bkey_for_each_ptr_decode(k.k, ptrs, p, entry)
+			rekey |= bch2_csum_type_is_encryption(p.crc.csum_type) &&
+				!p.ptr.unwritten;
+	if (rekey)
+		data_opts->write_flags |= BCH_WRITE_rekey;
+
 	scoped_guard(rcu) {
 		unsigned ptr_bit = 1;
 		bkey_for_each_ptr_decode(k.k, ptrs, p, entry) {
 			if ((r->need_rb & BIT(BCH_RECONCILE_data_checksum)) &&
-			    p.crc.csum_type != csum_type)
+			    (p.crc.csum_type != csum_type ||
+			     (rekey && !p.ptr.cached)))
 				data_opts->ptrs_kill |= ptr_bit;
 
 			if ((r->need_rb & BIT(BCH_RECONCILE_background_compression)) &&
@@ -1367,6 +1386,55 @@ static int do_reconcile_scan(struct moving_context *ctxt,
 	return 0;
 }
 
+/*
+ * Kicking an extent sets hipri in its reconcile entry, the way pending is set:
+ * the trigger moves its work bit to reconcile_hipri, so it's persistent, and
+ * processed ahead of all normal priority work. Pending work stays pending -
+ * it's waiting on space, not its turn.
+ */
+static int reconcile_kick_extent(struct btree_trans *trans, struct btree_iter *iter,
+				 struct bkey_s_c k)
+{
+	struct bch_fs *c = trans->c;
+
+	if (rb_work_id(bch2_bkey_reconcile_opts(c, k)) != RECONCILE_WORK_normal)
+		return 0;
+
+	struct bkey_i *n = errptr_try(bch2_bkey_make_mut(trans, iter, &k, 0));
+	struct bch_extent_reconcile *r = (struct bch_extent_reconcile *)
+		bch2_bkey_reconcile_opts(c, bkey_i_to_s_c(n));
+	r->hipri = 1;
+	return 0;
+}
+
+/*
+ * Move the reconcile work of the given inodes to high priority. Only work
+ * already indexed by extent is covered - an option change still has to be
+ * propagated by its scan cookie first, and extents reached through reflink
+ * pointers are indexed by their indirect extent, not the inode.
+ */
+int bch2_reconcile_kick_inodes(struct bch_fs *c, const u64 *inums, unsigned nr)
+{
+	if (c->sb.version < bcachefs_metadata_version_reconcile_kick)
+		return bch_err_throw(c, EOPNOTSUPP_reconcile_kick_needs_upgrade);
+
+	CLASS(btree_trans, trans)(c);
+
+	for (unsigned i = 0; i < nr; i++) {
+		if (inums[i] < BCACHEFS_ROOT_INO)
+			continue;
+
+		try(for_each_btree_key_max_commit(trans, iter, BTREE_ID_extents,
+				POS(inums[i], 0), SPOS(inums[i], U64_MAX, U32_MAX),
+				BTREE_ITER_prefetch|BTREE_ITER_all_snapshots, k,
+				NULL, NULL, BCH_TRANS_COMMIT_no_enospc,
+			reconcile_kick_extent(trans, &iter, k)));
+	}
+
+	bch2_reconcile_wakeup(c);
+	return 0;
+}
+
 static bool reconcile_hipri_work_pending(struct bch_fs *c)
 {
 	struct disk_accounting_pos pos;
@@ -1406,9 +1474,14 @@ static void reconcile_wait(struct bch_fs *c, u32 kick)
 	 * wake_up_process() is either seen here or wakes the sleep - no lost
 	 * wakeups:
 	 */
+	u64 window_closed = bch2_reconcile_window_closed_secs(c);
+	unsigned long timeout = window_closed
+		? min_t(u64, window_closed * HZ, MAX_SCHEDULE_TIMEOUT)
+		: MAX_SCHEDULE_TIMEOUT;
+
 	set_current_state(TASK_INTERRUPTIBLE);
 	if (kick == READ_ONCE(r->kick))
-		bch2_kthread_io_clock_wait_once(clock, r->wait_iotime_end, MAX_SCHEDULE_TIMEOUT);
+		bch2_kthread_io_clock_wait_once(clock, r->wait_iotime_end, timeout);
 	__set_current_state(TASK_RUNNING);
 }
 
@@ -1469,10 +1542,66 @@ typedef struct {
 	struct closure		cl;
 
 	struct bch_move_stats	stats;
+	struct bch_ratelimit	rate;
 } reconcile_phys_thr;
 
 DEFINE_DARRAY(reconcile_phys_thr);
 
+/*
+ * Throttling - reconcile_rate_limit, reconcile_window - applies to normal
+ * priority work only: high priority work is restoring durability, and scan
+ * cookies have to be processed to find it. Nor do they apply to copygc, which
+ * frees space for foreground writes - throttling it would throttle them.
+ */
+static bool reconcile_phase_throttled(unsigned phase)
+{
+	return reconcile_phases[phase].priority != RECONCILE_WORK_hipri;
+}
+
+/* Seconds until reconcile_window next opens, 0 if it's open now */
+u64 bch2_reconcile_window_closed_secs(struct bch_fs *c)
+{
+	u32 w = c->opts.reconcile_window;
+	unsigned start	= BCH_TIME_WINDOW_START(w) * 60;
+	unsigned end	= BCH_TIME_WINDOW_END(w) * 60;
+	u32 now;
+
+	if (start == end)
+		return 0;
+
+	div_u64_rem(ktime_get_real_seconds(), 86400, &now);
+
+	bool open = start < end
+		? now >= start && now < end
+		: now >= start || now < end;
+
+	return open ? 0 : (start + 86400 - now) % 86400;
+}
+
+static bool reconcile_phase_paused(struct bch_fs *c, unsigned phase)
+{
+	return reconcile_phase_throttled(phase) &&
+		bch2_reconcile_window_closed_secs(c);
+}
+
+/*
+ * Rate limit for one of @nr_threads processing @phase concurrently: they
+ * split the configured rate evenly.
+ */
+static struct bch_ratelimit *reconcile_phase_rate(struct bch_fs *c,
+						  struct bch_ratelimit *rate,
+						  unsigned phase, unsigned nr_threads)
+{
+	u64 sectors_per_sec = c->opts.reconcile_rate_limit >> 9;
+
+	if (!sectors_per_sec || !reconcile_phase_throttled(phase))
+		return NULL;
+
+	rate->rate = max_t(u64, div_u64(sectors_per_sec, nr_threads), 1);
+	bch2_ratelimit_reset(rate);
+	return rate;
+}
+
 /*
  * Destructor ordering: closure_return() must be the last thing before the
  * function returns, but __cleanup destructors run after closure_return()
@@ -1491,7 +1620,7 @@ static CLOSURE_CALLBACK(do_reconcile_phys_thread)
 	struct bch_fs *c = thr->c;
 
 	struct moving_context ctxt;
-	bch2_moving_ctxt_init(&ctxt, c, NULL, &thr->stats,
+	bch2_moving_ctxt_init(&ctxt, c, thr->rate.rate ? &thr->rate : NULL, &thr->stats,
 			      writepoint_ptr(&c->allocator.reconcile_write_point),
 			      true);
 
@@ -1518,7 +1647,8 @@ static CLOSURE_CALLBACK(do_reconcile_phys_thread)
 
 	while (!bch2_move_ratelimit(&ctxt)) {
 		if (!bch2_reconcile_enabled(c) ||
-		    test_bit(BCH_FS_going_ro, &c->flags))
+		    test_bit(BCH_FS_going_ro, &c->flags) ||
+		    reconcile_phase_paused(c, thr->reconcile_phase))
 			break;
 
 		bch2_trans_begin(trans);
@@ -1556,8 +1686,10 @@ static int do_reconcile_phys(struct bch_fs *c, unsigned reconcile_phase)
 						.reconcile_phase	= reconcile_phase,
 						})));
 
-	darray_for_each(thrs, i)
+	darray_for_each(thrs, i) {
+		reconcile_phase_rate(c, &i->rate, reconcile_phase, thrs.nr);
 		closure_call(&i->cl, do_reconcile_phys_thread, system_unbound_wq, &cl);
+	}
 
 	closure_sync_unbounded(&cl);
 	return 0;
@@ -1675,6 +1807,7 @@ static int do_reconcile_phase_iter(struct reconcile_pass *p, u32 kick,
 	while (!bch2_move_ratelimit(ctxt) &&
 	       !test_bit(BCH_FS_going_ro, &c->flags) &&
 	       bch2_reconcile_enabled(c) &&
+	       !reconcile_phase_paused(c, r->phase) &&
 	       kick == r->kick) {
 		bch2_trans_begin(trans);
 
@@ -1744,6 +1877,8 @@ static int do_reconcile_phase(struct reconcile_pass *p, u32 kick)
 
 	bch2_btree_write_buffer_flush_sync(trans);
 
+	p->ctxt->rate = reconcile_phase_rate(trans->c, &r->rate, r->phase, 1);
+
 	switch (reconcile_phases[r->phase].type) {
 	case RECONCILE_PHASE_scan:
 		return do_reconcile_phase_iter(p, kick, do_reconcile_scan_key);
@@ -1831,6 +1966,14 @@ static int do_reconcile(struct moving_context *ctxt)
 			    bkey_deleted(&pending_cookie.k))
 				goto out;
 
+			/*
+			 * Outside reconcile_window: likewise, everything from
+			 * the first normal priority phase on waits for it to
+			 * open - reconcile_wait() wakes us then.
+			 */
+			if (reconcile_phase_paused(c, r->phase))
+				goto out;
+
 			ret = do_reconcile_phase(&pass, kick);
 			if (ret)
 				goto out;
@@ -1965,6 +2108,27 @@ __cold void bch2_reconcile_status_to_text(struct printbuf *out, struct bch_fs *c
 		}
 	}
 
+	if (c->opts.reconcile_rate_limit) {
+		prt_printf(out, "rate limit:\t");
+		prt_human_readable_u64(out, c->opts.reconcile_rate_limit);
+		prt_str(out, "/s\n");
+	}
+
+	if (c->opts.reconcile_window) {
+		u64 closed = bch2_reconcile_window_closed_secs(c);
+
+		prt_printf(out, "window (UTC):\t");
+		bch2_opt_to_text(out, c, c->disk_sb.sb, &bch2_opt_table[Opt_reconcile_window],
+				 c->opts.reconcile_window, 0);
+		if (closed) {
+			prt_str(out, ", closed, opens in ");
+			bch2_pr_time_units(out, closed * NSEC_PER_SEC);
+		} else {
+			prt_str(out, ", open");
+		}
+		prt_newline(out);
+	}
+
 	struct task_struct *t;
 	scoped_guard(rcu) {
 		t = rcu_dereference(c->reconcile.thread);
@@ -64,6 +64,9 @@ int bch2_set_fs_needs_reconcile(struct bch_fs *);
 
 int bch2_reconcile_scan_cookie_is_set(struct btree_trans *, u64);
 
+int bch2_reconcile_kick_inodes(struct bch_fs *, const u64 *, unsigned);
+u64 bch2_reconcile_window_closed_secs(struct bch_fs *);
+
 static inline void bch2_reconcile_wakeup(struct bch_fs *c)
 {
 	c->reconcile.kick++;
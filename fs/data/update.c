@@ -347,6 +347,16 @@ static int data_update_index_update_key(struct btree_trans *trans,
 	unsigned ptrs_conflict = bkey_ptr_conflicts_mask(c, bkey_i_to_s_c(insert), bkey_i_to_s_c(&new->k_i));
 	bch2_bkey_drop_ptrs_mask(c, insert, ptrs_conflict);
 
+	/*
+	 * Rekeyed for a key rotation: the new replicas were written with a new
+	 * version, which selects the new key - none of the existing replicas
+	 * can be read with it:
+	 */
+	if (u->op.flags & BCH_WRITE_rekey) {
+		bch2_bkey_drop_ptrs_noerror(bkey_i_to_s(insert), p, entry, true);
+		insert->k.bversion = new->k.bversion;
+	}
+
 	/* Now, merge newly written replicas:
 	 * Since these are appended to the end of @insert, they don't invalidate
 	 * our pointer masks:
@@ -659,7 +669,8 @@ void bch2_data_update_read_done(struct data_update *u)
 		     (bch2_bkey_extent_flags(bkey_i_to_s_c(u->k.k)) & BIT_ULL(BCH_EXTENT_FLAG_poisoned)))) {
 		struct nonce nonce = extent_nonce(rbio->version, crc);
 
-		crc.csum	= bch2_checksum_bio(c, crc.csum_type, nonce, &rbio->bio);
+		crc.csum	= bch2_checksum_bio(c, crc.csum_type, rbio->version,
+						    nonce, &rbio->bio);
 		rbio->ret	= 0;
 	}
 
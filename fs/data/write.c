@@ -1713,8 +1713,9 @@ static int bch2_write_op_decode(struct bch_write_op *op, struct bio *bio)
 		: bch2_bio_map_or_bounce(c, bio, READ);
 
 	if (encrypted)
-		try(bch2_encrypt(c, crc->csum_type, extent_nonce(op->version, *crc),
-				 src_buf.b, crc->compressed_size << 9));
+		try(bch2_encrypt_key(c, bch2_extent_key(c, op->version), crc->csum_type,
+				     extent_nonce(op->version, *crc),
+				     src_buf.b, crc->compressed_size << 9));
 
 	int ret = bch2_buf_uncompress(c, dst_buf.b, src_buf.b, *crc);
 	if (c->opts.no_data_io)
@@ -1750,6 +1751,31 @@ static noinline int bch2_write_prep_encoded_data(struct bch_write_op *op, struct
 
 	BUG_ON(bio_sectors(bio) != op->crc.compressed_size);
 
+	/*
+	 * Key rotation: re-encrypt the extent under the current master key. The
+	 * old key is selected by the old version number, so this needs a new
+	 * version (and thus a new nonce) - verify, decrypt with the old one,
+	 * then encrypt and checksum with the new one, before anything else
+	 * looks at the data:
+	 */
+	if ((op->flags & BCH_WRITE_rekey) &&
+	    bch2_csum_type_is_encryption(op->crc.csum_type)) {
+		struct nonce nonce = extent_nonce(op->version, op->crc);
+		csum = bch2_checksum_bio(c, op->crc.csum_type, op->version, nonce, bio);
+		if (bch2_crc_cmp(op->crc.csum, csum) && !c->opts.no_data_io)
+			goto csum_err;
+
+		try(bch2_encrypt_bio(c, op->crc.csum_type, op->version, nonce, bio));
+
+		op->version = (struct bversion) {
+			.lo = atomic64_inc_return(&c->key_version),
+		};
+		nonce = extent_nonce(op->version, op->crc);
+
+		try(bch2_encrypt_bio(c, op->crc.csum_type, op->version, nonce, bio));
+		op->crc.csum = bch2_checksum_bio(c, op->crc.csum_type, op->version, nonce, bio);
+	}
+
 	/* Can we just write the entire extent as is? */
 	if (op->crc.uncompressed_size == op->crc.live_size &&
 	    op->crc.uncompressed_size <= c->opts.encoded_extent_max >> 9 &&
@@ -1788,9 +1814,9 @@ static noinline int bch2_write_prep_encoded_data(struct bch_write_op *op, struct
 				 */
 				struct nonce nonce = extent_nonce(op->version, op->crc);
 				struct bch_csum new_csum =
-					bch2_checksum_bio(c, op->csum_type, nonce, bio);
+					bch2_checksum_bio(c, op->csum_type, op->version, nonce, bio);
 
-				csum = bch2_checksum_bio(c, op->crc.csum_type, nonce, bio);
+				csum = bch2_checksum_bio(c, op->crc.csum_type, op->version, nonce, bio);
 				if (bch2_crc_cmp(op->crc.csum, csum) && !c->opts.no_data_io)
 					goto csum_err;
 
@@ -1809,7 +1835,7 @@ static noinline int bch2_write_prep_encoded_data(struct bch_write_op *op, struct
 	if (crc_is_compressed(op->crc)) {
 		/* Last point we can still verify checksum: */
 		struct nonce nonce = extent_nonce(op->version, op->crc);
-		csum = bch2_checksum_bio(c, op->crc.csum_type, nonce, bio);
+		csum = bch2_checksum_bio(c, op->crc.csum_type, op->version, nonce, bio);
 		if (bch2_crc_cmp(op->crc.csum, csum) && !c->opts.no_data_io)
 			goto csum_err;
 
@@ -1864,11 +1890,11 @@ static noinline int bch2_write_prep_encoded_data(struct bch_write_op *op, struct
 	if (bch2_csum_type_is_encryption(op->crc.csum_type) &&
 	    (op->compression_opt || op->crc.csum_type != op->csum_type)) {
 		struct nonce nonce = extent_nonce(op->version, op->crc);
-		csum = bch2_checksum_bio(c, op->crc.csum_type, nonce, bio);
+		csum = bch2_checksum_bio(c, op->crc.csum_type, op->version, nonce, bio);
 		if (bch2_crc_cmp(op->crc.csum, csum) && !c->opts.no_data_io)
 			goto csum_err;
 
-		try(bch2_encrypt_bio(c, op->crc.csum_type, nonce, bio));
+		try(bch2_encrypt_bio(c, op->crc.csum_type, op->version, nonce, bio));
 
 		op->crc.csum_type = 0;
 		op->crc.csum = (struct bch_csum) { 0, 0 };
@@ -2047,12 +2073,12 @@ static int bch2_write_extent(struct bch_write_op *op, struct write_point *wp,
 			crc.live_size		= src_len >> 9;
 
 			swap(dst->bi_iter.bi_size, dst_len);
-			ret = bch2_encrypt_bio(c, op->csum_type,
+			ret = bch2_encrypt_bio(c, op->csum_type, version,
 					       extent_nonce(version, crc), dst);
 			if (ret)
 				goto err;
 
-			crc.csum = bch2_checksum_bio(c, op->csum_type,
+			crc.csum = bch2_checksum_bio(c, op->csum_type, version,
 					 extent_nonce(version, crc), dst);
 			crc.csum_type = op->csum_type;
 			swap(dst->bi_iter.bi_size, dst_len);
@@ -2684,16 +2710,31 @@ err:
 	bch2_write_done(op);
 }
 
+/*
+ * The first line is a fixed summary, for tools that attribute IO from the
+ * trace stream (bcachefs fs top) - keep it parseable:
+ */
+static void write_trace_summary(struct printbuf *out, struct bch_write_op *op)
+{
+	prt_printf(out, "inum %llu sectors %u\n", op->pos.inode, bio_sectors(&op->wbio.bio));
+}
+
 noinline __cold
 static void data_write_trace(struct bch_write_op *op)
 {
-	__event_trace(op->c, data_write, buf, bch2_write_op_to_text(&buf, op));
+	__event_trace(op->c, data_write, buf, ({
+		write_trace_summary(&buf, op);
+		bch2_write_op_to_text(&buf, op);
+	}));
 }
 
 noinline __cold
 static void data_update_write_trace(struct bch_write_op *op)
 {
-	__event_trace(op->c, data_update_write, buf, bch2_write_op_to_text(&buf, op));
+	__event_trace(op->c, data_update_write, buf, ({
+		write_trace_summary(&buf, op);
+		bch2_write_op_to_text(&buf, op);
+	}));
 }
 
 /**
@@ -28,7 +28,8 @@
 	x(move)				\
 	x(in_worker)			\
 	x(submitted)			\
-	x(convert_unwritten)
+	x(convert_unwritten)		\
+	x(rekey)
 
 enum __bch_write_flags {
 #define x(f)	__BCH_WRITE_##f,
@@ -102,7 +102,8 @@ __cold void bch2_btree_node_ondisk_to_text(struct printbuf *out, struct bch_fs *
 			}
 
 			nonce = btree_nonce(i, offset << 9);
-			csum = csum_vstruct(c, BSET_CSUM_TYPE(i), nonce, n_ondisk);
+			csum = csum_vstruct_key(bch2_bset_key(c, i),
+						BSET_CSUM_TYPE(i), nonce, n_ondisk);
 
 			if (bch2_crc_cmp(csum, n_ondisk->csum)) {
 				prt_printf(out, "invalid checksum\n");
@@ -127,7 +128,7 @@ __cold void bch2_btree_node_ondisk_to_text(struct printbuf *out, struct bch_fs *
 			}
 
 			nonce = btree_nonce(i, offset << 9);
-			csum = csum_vstruct(c, BSET_CSUM_TYPE(i), nonce, bne);
+			csum = csum_vstruct_key(bch2_bset_key(c, i), BSET_CSUM_TYPE(i), nonce, bne);
 
 			if (bch2_crc_cmp(csum, bne->csum)) {
 				prt_printf(out, "invalid checksum");
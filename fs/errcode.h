@@ -604,7 +604,26 @@
 	x(0,				shutdown_with_errors, 583)		\
 	x(BCH_ERR_shutdown_with_errors,	shutdown_with_errors_fixed, 584)	\
 	x(BCH_ERR_shutdown_with_errors,	shutdown_with_errors_unfixed, 585)	\
-	x(BCH_ERR_shutdown_with_errors,	shutdown_with_emergency_ro, 586)
+	x(BCH_ERR_shutdown_with_errors,	shutdown_with_emergency_ro, 586)	\
+	x(EINVAL,			EINVAL_subvol_rollback_bad_flags, 587)	\
+	x(EINVAL,			EINVAL_subvol_rollback_not_same_tree, 588) \
+	x(EINVAL,			EINVAL_subvol_rollback_save_inside, 589) \
+	x(EBUSY,			EBUSY_subvol_rollback_in_use, 590)	\
+	x(EINVAL,			EINVAL_subvol_set_bad_flags, 591)	\
+	x(EINVAL,			EINVAL_subvol_set_not_subvol_root, 592) \
+	x(EDQUOT,			EDQUOT_subvol_quota, 593)		\
+	x(EDQUOT,			EDQUOT_snapshot_tree_quota, 594)	\
+	x(EINVAL,			EINVAL_key_rotate_bad_flags, 595)	\
+	x(EINVAL,			EINVAL_key_rotate_not_encrypted, 596)	\
+	x(EBUSY,			EBUSY_key_rotate_in_progress, 597)	\
+	x(EPERM,			EPERM_key_rotate_wrong_key, 598)	\
+	x(BCH_ERR_invalid_sb,		invalid_sb_key_slots, 599)		\
+	x(BCH_ERR_invalid_sb,		invalid_sb_key_servers, 600)		\
+	x(ENOMEM,			ENOMEM_subvol_quotas_refresh, 601)	\
+	x(EINVAL,			EINVAL_journal_rewind_before_key_rotation, 602)	\
+	x(EOPNOTSUPP,			EOPNOTSUPP_subvol_set_needs_upgrade, 603)	\
+	x(EOPNOTSUPP,			EOPNOTSUPP_reconcile_kick_needs_upgrade, 604)	\
+	x(EBUSY,			EBUSY_subvol_rollback_mounted, 605)
 
 enum bch_errcode {
 	BCH_ERR_START		= 2048,
@@ -16,6 +16,19 @@ pub fn qstr(name: &[u8]) -> c::qstr {
     }
 }
 
+/// The name of a dirent key, casefolded or not, as stored.
+pub fn get_name<'a>(k: &'a c::bkey, d: &'a c::bch_dirent) -> &'a [u8] {
+    let d = c::bkey_s_c_dirent {
+        __bindgen_anon_1: c::bkey_s_c_dirent__bindgen_ty_1 {
+            __bindgen_anon_1: c::bkey_s_c_dirent__bindgen_ty_1__bindgen_ty_1 { k, v: d },
+        },
+    };
+    unsafe {
+        let name = c::bch2_dirent_get_name(d);
+        core::slice::from_raw_parts(name.name, name.__bindgen_anon_1.__bindgen_anon_1.len as usize)
+    }
+}
+
 pub fn lookup(
     fs:        &Fs,
     dir_inum:  c::subvol_inum,
@@ -204,6 +204,10 @@ static void bch2_inode_unpack_v2_error(struct bch_fs *c, struct bkey_s_c k,
 #undef  x
 	};
 
+	/* No filesystem to report to: a key read from userspace, via ioctl */
+	if (!c)
+		return;
+
 	u64 passes = 0;
 
 	if (fieldnr <= BCH_INODE_V2_FIELD_bi_nlink)
@@ -332,6 +336,10 @@ static void bch2_inode_unpack_v3_error(struct bch_fs *c, struct bkey_s_c k,
 #undef  x
 	};
 
+	/* No filesystem to report to: a key read from userspace, via ioctl */
+	if (!c)
+		return;
+
 	u64 passes = 0;
 
 	if (fieldnr <= BCH_INODE_V3_FIELD_bi_nlink)
@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: GPL-2.0
 
+use crate::btree::bkey::BkeySC;
 use crate::c;
 use crate::errcode::{ret_to_result_void as ret_to_result, BchError};
 use crate::fs::Fs;
@@ -17,6 +18,15 @@ pub fn find_by_inum(
     Ok(inode)
 }
 
+/// Unpack an inode key, of any inode key type, without a filesystem - for keys
+/// read via ioctl. Fields past a corrupt one read as zero, same as in the
+/// kernel, but go unreported.
+pub fn unpack(k: &BkeySC<'_>) -> c::bch_inode_unpacked {
+    let mut inode: c::bch_inode_unpacked = Default::default();
+    unsafe { c::bch2_inode_unpack(core::ptr::null_mut(), k.to_raw(), &mut inode) };
+    inode
+}
+
 pub fn init_early(fs: &Fs, inode: &mut c::bch_inode_unpacked) {
     unsafe { c::bch2_inode_init_early(fs.raw, inode) };
 }
@@ -174,6 +174,82 @@ int bch2_create_trans(struct btree_trans *trans,
 	return 0;
 }
 
+/*
+ * Roll the subvolume rooted at @root back to @target_subvolid, keeping the
+ * pre-rollback state as a read-only snapshot named @name in @dir:
+ */
+int bch2_subvolume_rollback_trans(struct btree_trans *trans,
+				  subvol_inum root,
+				  u32 target_subvolid,
+				  subvol_inum dir,
+				  struct bch_inode_unpacked *dir_u,
+				  const struct qstr *name,
+				  u32 *save_subvolid)
+{
+	struct bch_fs *c = trans->c;
+	CLASS(btree_iter_uninit, dir_iter)(trans);
+	CLASS(btree_iter_uninit, inode_iter)(trans);
+	struct bch_inode_unpacked old_root, new_root;
+	struct bch_subvolume dir_subvol;
+	u64 now = bch2_current_time(c);
+	u64 dir_offset;
+	u32 new_snapshot;
+
+	/* Both of these get new snapshot IDs below: */
+	if (dir.subvol == root.subvol ||
+	    dir.subvol == target_subvolid)
+		return bch_err_throw(c, EINVAL_subvol_rollback_save_inside);
+
+	try(bch2_subvolume_get(trans, dir.subvol, true, &dir_subvol));
+	if (BCH_SUBVOLUME_RO(&dir_subvol))
+		return -EROFS;
+
+	try(bch2_inode_peek(trans, &dir_iter, dir_u, dir, BTREE_ITER_intent));
+	try(bch2_inode_peek(trans, &inode_iter, &old_root, root, BTREE_ITER_intent));
+	try(bch2_inode_find_by_inum_trans(trans,
+				(subvol_inum) { target_subvolid, root.inum }, &new_root));
+
+	if (old_root.bi_subvol != root.subvol ||
+	    new_root.bi_subvol != target_subvolid)
+		return bch_err_throw(c, EINVAL_snapshot_not_subvol_root);
+
+	try(bch2_subvolume_rollback(trans, root.subvol, target_subvolid, dir.subvol,
+				    save_subvolid, &new_snapshot));
+
+	dir_u->bi_mtime = dir_u->bi_ctime = now;
+
+	try(bch2_dirent_create_snapshot(trans, dir.subvol,
+					le32_to_cpu(dir_subvol.snapshot), dir_u,
+					DT_SUBVOL, name, *save_subvolid,
+					&dir_offset, STR_HASH_must_create));
+	try(bch2_inode_write(trans, &dir_iter, dir_u));
+
+	/*
+	 * The rolled back root takes over the old root's place in the
+	 * namespace:
+	 */
+	new_root.bi_subvol		= root.subvol;
+	new_root.bi_parent_subvol	= old_root.bi_parent_subvol;
+	new_root.bi_dir			= old_root.bi_dir;
+	new_root.bi_dir_offset		= old_root.bi_dir_offset;
+	new_root.bi_ctime		= now;
+
+	/* and the old root, still in the old snapshot, now belongs to the copy: */
+	old_root.bi_subvol		= *save_subvolid;
+	old_root.bi_parent_subvol	= dir.subvol;
+	old_root.bi_dir			= dir_u->bi_inum;
+	old_root.bi_dir_offset		= dir_offset;
+
+	try(bch2_inode_write(trans, &inode_iter, &old_root));
+
+	bch2_btree_iter_set_snapshot(&inode_iter, new_snapshot);
+
+	try(bch2_btree_iter_traverse(&inode_iter));
+	try(bch2_inode_write(trans, &inode_iter, &new_root));
+
+	return 0;
+}
+
 int bch2_link_trans(struct btree_trans *trans,
 		    subvol_inum dir,  struct bch_inode_unpacked *dir_u,
 		    subvol_inum inum, struct bch_inode_unpacked *inode_u,
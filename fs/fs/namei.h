@@ -34,6 +34,10 @@ int bch2_create_trans(struct btree_trans *, subvol_inum,
 		      struct posix_acl *,
 		      subvol_inum, unsigned);
 
+int bch2_subvolume_rollback_trans(struct btree_trans *, subvol_inum, u32,
+				  subvol_inum, struct bch_inode_unpacked *,
+				  const struct qstr *, u32 *);
+
 int bch2_link_trans(struct btree_trans *,
 		    subvol_inum, struct bch_inode_unpacked *,
 		    subvol_inum, struct bch_inode_unpacked *,
@@ -11,6 +11,7 @@
 #include "btree/bkey_methods.h"
 #include "btree/iter.h"
 
+#include "data/key_rotate.h"
 #include "data/move.h"
 
 #include "fs/check.h"
@@ -406,7 +407,9 @@ static long bch2_ioctl_data(struct bch_fs *c,
 		goto put_ref;
 	}
 
-	if (arg.op >= BCH_DATA_OP_NR || arg.flags) {
+	if (arg.op >= BCH_DATA_OP_NR ||
+	    (arg.flags & ~BCH_DATA_RESUME) ||
+	    ((arg.flags & BCH_DATA_RESUME) && arg.op != BCH_DATA_OP_scrub)) {
 		ret = bch_err_throw(c, EINVAL_ioctl_data_bad_op);
 		goto put_ref;
 	}
@@ -788,6 +791,20 @@ static long bch2_ioctl_query_btree_keys(struct bch_fs *c,
 		copy_to_user_errcode(user_arg, &arg, sizeof(arg));
 }
 
+static long bch2_ioctl_key_rotate(struct bch_fs *c,
+				  struct bch_ioctl_key_rotate arg)
+{
+	if (!capable(CAP_SYS_ADMIN))
+		return bch_err_throw(c, EPERM_non_admin);
+
+	if (arg.flags || arg.pad)
+		return bch_err_throw(c, EINVAL_key_rotate_bad_flags);
+
+	CLASS(printbuf, err)();
+	int ret = bch2_key_rotate_start(c, &err);
+	return bch2_copy_ioctl_err_msg(&arg.err, &err, ret);
+}
+
 #define BCH_IOCTL(_name, _argtype)					\
 do {									\
 	_argtype i;							\
@@ -855,6 +872,8 @@ static long __bch2_fs_ioctl(struct bch_fs *c, unsigned cmd, void __user *arg)
 		return bch2_ioctl_query_counters(c, arg);
 	case BCH_IOCTL_QUERY_BTREE_KEYS:
 		return bch2_ioctl_query_btree_keys(c, arg);
+	case BCH_IOCTL_KEY_ROTATE:
+		BCH_IOCTL(key_rotate, struct bch_ioctl_key_rotate);
 	default:
 		return -ENOTTY;
 	}
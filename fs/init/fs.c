@@ -30,6 +30,7 @@
 #include "data/copygc.h"
 #include "data/ec/create.h"
 #include "data/ec/init.h"
+#include "data/key_rotate.h"
 #include "data/move.h"
 #include "data/nocow_locking.h"
 #include "data/read.h"
@@ -320,6 +321,7 @@ static void __bch2_fs_read_only(struct bch_fs *c)
 	u64 seq = 0;
 
 	bch2_maybe_schedule_btree_bitmap_gc_stop(c);
+	bch2_key_rotate_stop(c);
 	bch2_fs_ec_stop(c);
 	bch2_open_buckets_stop(c, NULL, true);
 	bch2_copygc_stop(c);
@@ -625,6 +627,8 @@ static int __bch2_fs_read_write(struct bch_fs *c, bool early)
 	bch2_do_pending_node_rewrites(c);
 	bch2_scrub_journal_do_repairs(c);
 	bch2_maybe_schedule_btree_bitmap_gc(c);
+	bch2_key_rotate_maybe_schedule(c);
+	bch2_subvol_quotas_refresh_async(c);
 	return 0;
 }
 
@@ -669,6 +673,7 @@ static void __bch2_fs_free(struct bch_fs *c)
 	bch2_free_pending_node_rewrites(c);
 	bch2_free_fsck_errs(c);
 	bch2_fs_vfs_exit(c);
+	bch2_fs_subvolumes_exit(c);
 	bch2_fs_snapshots_exit(c);
 	bch2_fs_replicas_exit(c);
 	bch2_fs_reconcile_exit(c);
@@ -1169,8 +1174,10 @@ static int bch2_fs_init(struct bch_fs *c, struct bch_sb *sb,
 	init_waitqueue_head(&c->ro_ref_wait);
 	mutex_init(&c->opt_change_lock);
 
-	for (unsigned i = 0; i < BCH_TIME_STAT_NR; i++)
+	for (unsigned i = 0; i < BCH_TIME_STAT_NR; i++) {
 		bch2_time_stats_init(&c->times[i]);
+		bch2_time_stats_hist_init(&c->times[i]);
+	}
 
 	bch2_fs_allocator_background_init(c);
 	bch2_fs_allocator_foreground_init(c);
@@ -1183,6 +1190,7 @@ static int bch2_fs_init(struct bch_fs *c, struct bch_sb *sb,
 	bch2_fs_errors_init_early(c);
 	bch2_fs_journal_init_early(&c->journal);
 	bch2_fs_journal_keys_init(c);
+	bch2_fs_key_rotate_init_early(c);
 	bch2_fs_move_init(c);
 	bch2_fs_nocow_locking_init_early(c);
 	bch2_fs_quota_init(c);
@@ -24,6 +24,7 @@
 #include "journal/init.h"
 #include "journal/journal.h"
 
+#include "sb/errors.h"
 #include "sb/io.h"
 
 #include "snapshots/snapshot.h"
@@ -120,6 +121,8 @@ static __cold void bch2_sb_recovery_passes_to_text(struct printbuf *out,
 
 		if (BCH_RECOVERY_PASS_NO_RATELIMIT(i))
 			prt_str(out, " (no ratelimit)");
+		if (BCH_RECOVERY_PASS_FOUND_ERRORS(i))
+			prt_str(out, " (found errors)");
 
 		prt_newline(out);
 	}
@@ -150,7 +153,8 @@ static struct recovery_pass_entry *bch2_sb_recovery_pass_entry(struct bch_fs *c,
 
 static void bch2_sb_recovery_pass_complete(struct bch_fs *c,
 					   enum bch_recovery_pass pass,
-					   s64 start_time)
+					   s64 start_time,
+					   bool found_errors)
 {
 	guard(mutex_noio)(&c->sb_lock);
 	struct bch_sb_field_ext *ext = bch2_sb_field_get(c->disk_sb.sb, ext);
@@ -167,6 +171,7 @@ static void bch2_sb_recovery_pass_complete(struct bch_fs *c,
 		e->last_run	= cpu_to_le64(end_time);
 		e->last_runtime	= cpu_to_le32(max(0, end_time - start_time));
 		SET_BCH_RECOVERY_PASS_NO_RATELIMIT(e, false);
+		SET_BCH_RECOVERY_PASS_FOUND_ERRORS(e, found_errors);
 	}
 
 	bch2_write_super(c);
@@ -577,6 +582,7 @@ static int bch2_run_recovery_pass(struct bch_fs *c, enum bch_recovery_pass pass)
 			   bch2_recovery_passes[pass]);
 
 	s64 start_time = ktime_get_real_seconds();
+	u64 errors_start = bch2_sb_errors_nr(c);
 	int ret = p->fn(c);
 	if (ret) {
 		if (!bch2_err_matches(ret, BCH_ERR_restart_recovery)) {
@@ -604,7 +610,8 @@ static int bch2_run_recovery_pass(struct bch_fs *c, enum bch_recovery_pass pass)
 	r->passes_failing = 0;
 
 	if (!test_bit(BCH_FS_error, &c->flags))
-		bch2_sb_recovery_pass_complete(c, pass, start_time);
+		bch2_sb_recovery_pass_complete(c, pass, start_time,
+					       bch2_sb_errors_nr(c) != errors_start);
 
 	return 0;
 }
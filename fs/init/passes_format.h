@@ -261,6 +261,8 @@ struct recovery_pass_entry {
 };
 
 LE32_BITMASK(BCH_RECOVERY_PASS_NO_RATELIMIT,	struct recovery_pass_entry, flags, 0, 1)
+/* Last successful run counted fsck errors (fixed or not): */
+LE32_BITMASK(BCH_RECOVERY_PASS_FOUND_ERRORS,	struct recovery_pass_entry, flags, 1, 2)
 
 struct bch_sb_field_recovery_passes {
 	struct bch_sb_field	field;
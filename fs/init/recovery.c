@@ -235,6 +235,23 @@ static void bch2_reconstruct_alloc(struct bch_fs *c)
 			kill_btree(c, i);
 }
 
+/*
+ * Recorded with the rewind, not after it completes: it's a forensic record of
+ * what was asked for, and a rewind that fails partway has still discarded
+ * whatever the journal already rewrote. Written out with the next superblock
+ * write, when we go read-write:
+ */
+void bch2_sb_record_journal_rewind(struct bch_fs *c, u64 from, u64 to)
+{
+	guard(mutex_noio)(&c->sb_lock);
+	struct bch_sb_field_ext *ext =
+		bch2_sb_field_get(c->disk_sb.sb, ext);
+
+	ext->journal_rewind_time	= cpu_to_le64(ktime_get_real_seconds());
+	ext->journal_rewind_from	= cpu_to_le64(from);
+	ext->journal_rewind_to		= cpu_to_le64(to);
+}
+
 void bch2_ignore_journal_rewind_errors(struct bch_fs *c)
 {
 	/*
@@ -862,6 +879,9 @@ use_clean:
 				journal_start.replay_end,
 				c->opts.journal_rewind));
 		bch2_ignore_journal_rewind_errors(c);
+		bch2_sb_record_journal_rewind(c,
+				journal_start.replay_end,
+				c->opts.journal_rewind);
 		try(bch2_journal_reread_for_rewind(c));
 	}
 
@@ -947,6 +967,9 @@ use_clean:
 					journal_start.replay_end,
 					rewind_seq));
 			bch2_ignore_journal_rewind_errors(c);
+			bch2_sb_record_journal_rewind(c,
+					journal_start.replay_end,
+					rewind_seq);
 
 			/*
 			 * Re-read journal buckets to pick up entries that
@@ -5,6 +5,7 @@
 int bch2_btree_lost_data(struct bch_fs *, struct printbuf *, enum btree_id);
 void bch2_set_btree_clean(struct bch_fs *, enum btree_id);
 void bch2_clear_btree_clean(struct bch_fs *, enum btree_id);
+void bch2_sb_record_journal_rewind(struct bch_fs *, u64, u64);
 void bch2_ignore_journal_rewind_errors(struct bch_fs *);
 
 int bch2_set_may_go_rw(struct bch_fs *);
@@ -97,7 +97,8 @@ static bool jset_csum_good(struct bch_fs *c, struct jset *j, struct bch_csum *cs
 		return false;
 	}
 
-	*csum = csum_vstruct(c, JSET_CSUM_TYPE(j), journal_nonce(j), j);
+	*csum = csum_vstruct_key(bch2_jset_key(c, j), JSET_CSUM_TYPE(j),
+				journal_nonce(j), j);
 	return !bch2_crc_cmp(j->csum, *csum);
 }
 
@@ -391,6 +392,13 @@ static int journal_read_bucket(struct bch_dev *ca,
 		struct bch_csum csum;
 		csum_good = jset_csum_good(c, j, &csum);
 
+		/*
+		 * Written with a master key that a key rotation has since
+		 * dropped: not needed, and not a device error either:
+		 */
+		if (!csum_good && le64_to_cpu(j->seq) < c->key_journal_seq)
+			goto next_block;
+
 		bch2_account_io_completion(ca, BCH_MEMBER_ERROR_checksum, 0, csum_good);
 
 		if (!csum_good) {
@@ -401,7 +409,8 @@ static int journal_read_bucket(struct bch_dev *ca,
 			saw_bad = true;
 		}
 
-		ret = bch2_encrypt(c, JSET_CSUM_TYPE(j), journal_nonce(j),
+		ret = bch2_encrypt_key(c, bch2_jset_key(c, j),
+			     JSET_CSUM_TYPE(j), journal_nonce(j),
 			     j->encrypted_start,
 			     vstruct_end(j) - (void *) j->encrypted_start);
 		bch2_fs_fatal_err_on(ret, c, "decrypting journal entry: %s", bch2_err_str(ret));
@@ -1253,6 +1262,13 @@ int bch2_journal_read(struct bch_fs *c, struct journal_start_info *info)
 					c->journal.rewind_seq);
 				return bch_err_throw(c, EINVAL_journal_rewind_before_discard);
 			}
+			if (c->opts.journal_rewind < c->key_journal_seq) {
+				bch_err(c, "cannot rewind to %llu: journal entries before %llu "
+					"were encrypted with a master key that has since been rotated out",
+					c->opts.journal_rewind,
+					c->key_journal_seq);
+				return bch_err_throw(c, EINVAL_journal_rewind_before_key_rotation);
+			}
 			drop_before = min(drop_before, c->opts.journal_rewind);
 			prt_printf(&buf, " (rewinding from %llu)", c->opts.journal_rewind);
 		}
@@ -781,6 +781,7 @@ static int bch2_journal_write_checksum(struct journal *j, struct journal_buf *w)
 	SET_JSET_BIG_ENDIAN(jset, CPU_BIG_ENDIAN);
 	SET_JSET_CSUM_TYPE(jset, bch2_meta_checksum_type(c));
 	SET_JSET_HAS_OVERWRITES(jset, w->has_overwrites);
+	SET_JSET_KEY_GEN(jset, bch2_key_gen(c));
 
 	if (bch2_csum_type_is_encryption(JSET_CSUM_TYPE(jset)))
 		validate_before_checksum = true;
@@ -792,14 +793,15 @@ static int bch2_journal_write_checksum(struct journal *j, struct journal_buf *w)
 	    (ret = bch2_jset_validate(c, NULL, jset, 0, WRITE)))
 		return ret;
 
-	ret = bch2_encrypt(c, JSET_CSUM_TYPE(jset), journal_nonce(jset),
+	ret = bch2_encrypt_key(c, bch2_jset_key(c, jset),
+		    JSET_CSUM_TYPE(jset), journal_nonce(jset),
 		    jset->encrypted_start,
 		    vstruct_end(jset) - (void *) jset->encrypted_start);
 	if (bch2_fs_fatal_err_on(ret, c, "encrypting journal entry: %s", bch2_err_str(ret)))
 		return ret;
 
-	jset->csum = csum_vstruct(c, JSET_CSUM_TYPE(jset),
-				  journal_nonce(jset), jset);
+	jset->csum = csum_vstruct_key(bch2_jset_key(c, jset), JSET_CSUM_TYPE(jset),
+				      journal_nonce(jset), jset);
 
 	if (!validate_before_checksum &&
 	    (ret = bch2_jset_validate(c, NULL, jset, 0, WRITE)))
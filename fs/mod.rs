@@ -119,7 +119,8 @@ pub mod c {
     }
     bitfield! {
         pub struct bch_crypt_flags(u64);
-        pub TYPE, _: 4, 0;
+        pub TYPE, _: 3, 0;
+        pub ROTATING, _: 4;
     }
     impl bch_sb_field_crypt {
         pub fn scrypt_flags(&self) -> Option<bch_scrypt_flags> {
@@ -132,6 +133,69 @@ pub mod c {
         pub fn key(&self) -> &bch_encrypted_key {
             &self.key
         }
+
+        /// Whether the field is long enough to have the key rotation fields
+        /// (key_seq onwards) - older filesystems' aren't.
+        fn has_bytes(&self, end: usize) -> bool {
+            u32::from_le(self.field.u64s) as usize * 8 >= end
+        }
+
+        /// Bumped by every key rotation, and mixed into the nonce the master
+        /// key is wrapped with: see bch_sb::key_nonce().
+        pub fn key_seq(&self) -> u64 {
+            let end = core::mem::offset_of!(bch_sb_field_crypt, key_seq) + 8;
+            if self.has_bytes(end) { u64::from_le(self.key_seq) } else { 0 }
+        }
+
+        /// A key rotation is in progress: old_key is the key being rotated
+        /// out, wrapped at key_seq() - 1.
+        pub fn rotating(&self) -> bool {
+            bch_crypt_flags(u64::from_le(self.flags)).ROTATING()
+                && self.has_bytes(core::mem::size_of::<Self>())
+        }
+
+        pub fn old_key(&self) -> Option<&bch_encrypted_key> {
+            self.rotating().then_some(&self.old_key)
+        }
+    }
+    bitfield! {
+        pub struct bch_key_slot_flags(u64);
+        pub TYPE, _: 3, 0;
+        pub KDF_TYPE, _: 7, 4;
+    }
+    impl bch_key_slot {
+        pub fn slot_type(&self) -> bch_key_slot_type {
+            bch_key_slot_type(bch_key_slot_flags(u64::from_le(self.flags)).TYPE() as u32)
+        }
+
+        /// The label, as bytes up to the first NUL.
+        pub fn label(&self) -> &[u8] {
+            let len = self.label.iter().position(|b| *b == 0).unwrap_or(self.label.len());
+            &self.label[..len]
+        }
+    }
+    impl bch_sb_field_key_slots {
+        pub fn slots(&self) -> &[bch_key_slot] {
+            let bytes = u32::from_le(self.field.u64s) as usize * 8;
+            let nr = bytes.saturating_sub(core::mem::size_of::<Self>())
+                / core::mem::size_of::<bch_key_slot>();
+            unsafe { self.slots.as_slice(nr) }
+        }
+    }
+    impl bch_key_server {
+        /// The server's host:port, as bytes up to the first NUL.
+        pub fn addr(&self) -> &[u8] {
+            let len = self.addr.iter().position(|b| *b == 0).unwrap_or(self.addr.len());
+            &self.addr[..len]
+        }
+    }
+    impl bch_sb_field_key_servers {
+        pub fn servers(&self) -> &[bch_key_server] {
+            let bytes = u32::from_le(self.field.u64s) as usize * 8;
+            let nr = bytes.saturating_sub(core::mem::size_of::<Self>())
+                / core::mem::size_of::<bch_key_server>();
+            unsafe { self.servers.as_slice(nr) }
+        }
     }
 
     // ── Encryption key material ─────────────────────────────────────
@@ -154,6 +218,18 @@ pub mod c {
         }
     }
 
+    impl Clone for bch_key_slot {
+        fn clone(&self) -> Self {
+            Self {
+                flags:      self.flags,
+                kdf_flags:  self.kdf_flags,
+                salt:       self.salt,
+                label:      self.label,
+                key:        self.key.clone(),
+            }
+        }
+    }
+
     impl bch_encrypted_key {
         pub const MAGIC: &[u8; 8] = b"bch**key";
 
@@ -197,6 +197,48 @@ static __cold void bch2_opt_fix_errors_to_text(struct printbuf *out,
 	.to_text = bch2_opt_fix_errors_to_text,		\
 }
 
+static int bch2_opt_time_window_parse(struct bch_fs *c, const char *val, u64 *res,
+				      struct printbuf *err)
+{
+	unsigned sh, sm, eh, em;
+	char extra;
+
+	if (!val || !strcmp(val, "none")) {
+		*res = 0;
+		return 0;
+	}
+
+	if (sscanf(val, "%u:%u-%u:%u%c", &sh, &sm, &eh, &em, &extra) != 4 ||
+	    sh > 23 || sm > 59 || eh > 23 || em > 59) {
+		if (err)
+			prt_printf(err, "%s: expected hh:mm-hh:mm or none", val);
+		return -BCH_ERR_opt_parse_error;
+	}
+
+	*res = BCH_TIME_WINDOW(sh * 60 + sm, eh * 60 + em);
+	return 0;
+}
+
+static __cold void bch2_opt_time_window_to_text(struct printbuf *out,
+					struct bch_fs *c,
+					struct bch_sb *sb,
+					u64 v)
+{
+	unsigned start = BCH_TIME_WINDOW_START(v);
+	unsigned end = BCH_TIME_WINDOW_END(v);
+
+	if (!v)
+		prt_str(out, "none");
+	else
+		prt_printf(out, "%02u:%02u-%02u:%02u",
+			   start / 60, start % 60, end / 60, end % 60);
+}
+
+#define bch2_opt_time_window (struct bch_opt_fn) {	\
+	.parse = bch2_opt_time_window_parse,		\
+	.to_text = bch2_opt_time_window_to_text,	\
+}
+
 const char * const bch2_d_types[BCH_DT_MAX] = {
 	[DT_UNKNOWN]	= "unknown",
 	[DT_FIFO]	= "fifo",
@@ -733,6 +775,8 @@ void bch2_opt_hook_post_set(struct bch_fs *c, struct bch_dev *ca, u64 inum,
 
 	switch (id) {
 	case Opt_reconcile_enabled:
+	case Opt_reconcile_rate_limit:
+	case Opt_reconcile_window:
 		bch2_reconcile_wakeup(c);
 		break;
 	case Opt_copygc_enabled:
@@ -901,7 +945,13 @@ u64 bch2_opt_from_sb(struct bch_sb *sb, enum bch_opt_id id, int dev_idx)
 			v = opt->get_sb(sb);
 		} else if (opt->get_ext) {
 			const struct bch_sb_field_ext *ext = bch2_sb_field_get(sb, ext);
-			v = ext ? opt->get_ext(ext) : 0;
+			/* Older superblocks may have a smaller field: missing members read as 0 */
+			struct bch_sb_field_ext padded = {};
+
+			if (ext)
+				memcpy(&padded, ext, min_t(size_t, vstruct_bytes(&ext->field),
+							   sizeof(padded)));
+			v = opt->get_ext(&padded);
 		} else {
 			v = 0;
 		}
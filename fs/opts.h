@@ -135,6 +135,11 @@ enum fsck_err_opts {
 #undef x
 };
 
+/* reconcile_window: minutes past midnight UTC, start | end << 11; 0 for none */
+#define BCH_TIME_WINDOW(_start, _end)	((_start) | ((_end) << 11))
+#define BCH_TIME_WINDOW_START(_v)	((unsigned) (_v) & 2047)
+#define BCH_TIME_WINDOW_END(_v)		((unsigned) (_v) >> 11)
+
 #define BCH_OPTS()							\
 	x(block_size,			u16,				\
 	  OPT_FS|OPT_FORMAT|						\
@@ -560,6 +565,19 @@ enum fsck_err_opts {
 	  OPT_BOOL(),							\
 	  BCH_SB_REBALANCE_AC_ONLY,		false,			\
 	  NULL,		"Enable reconcile while on mains power only\n")	\
+	x(reconcile_rate_limit,		u64,				\
+	  OPT_FS|OPT_MOUNT|OPT_RUNTIME|					\
+	  OPT_HUMAN_READABLE|OPT_SB_FIELD_SECTORS,			\
+	  OPT_UINT(0, (u64) U32_MAX << 9),				\
+	  BCH_SB_EXT_RECONCILE_RATE_LIMIT, 0,				\
+	  "rate",	"Maximum rate per second for normal priority\n"\
+			"reconcile work (not copygc); 0 for no limit")	\
+	x(reconcile_window,		u32,				\
+	  OPT_FS|OPT_MOUNT|OPT_RUNTIME,					\
+	  OPT_FN(bch2_opt_time_window),					\
+	  BCH_SB_EXT_RECONCILE_WINDOW,	0,				\
+	  "hh:mm-hh:mm", "Time of day (UTC) normal priority reconcile\n"\
+			"work (not copygc) may run in; none for any time")\
 	x(auto_snapshot_deletion,	u8,				\
 	  OPT_FS|OPT_MOUNT|OPT_RUNTIME,					\
 	  OPT_BOOL(),							\
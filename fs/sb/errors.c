@@ -333,6 +333,17 @@ void bch2_sb_error_count(struct bch_fs *c, enum bch_sb_error_id err)
 	darray_insert_item(e, i, n);
 }
 
+/* Total errors counted since mount, for noticing that something found errors: */
+u64 bch2_sb_errors_nr(struct bch_fs *c)
+{
+	guard(mutex)(&c->errors.counts_lock);
+
+	u64 nr = 0;
+	darray_for_each(c->errors.counts, i)
+		nr += i->nr;
+	return nr;
+}
+
 void bch2_sb_errors_from_cpu(struct bch_fs *c)
 {
 	guard(mutex)(&c->errors.counts_lock);
@@ -14,6 +14,7 @@ extern const struct bch_sb_field_ops bch_sb_field_ops_errors;
 extern const struct bch_sb_field_ops bch_sb_field_ops_errors_v2;
 
 void bch2_sb_error_count(struct bch_fs *, enum bch_sb_error_id);
+u64 bch2_sb_errors_nr(struct bch_fs *);
 
 /*
  * Block statuses we count separately: what the device said is the first
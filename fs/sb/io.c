@@ -1608,6 +1608,18 @@ static __cold void bch2_sb_ext_to_text(struct printbuf *out,
 		prt_bitflags(out, __bch2_btree_ids, le64_to_cpu(e->btrees_clean));
 		prt_newline(out);
 	}
+
+	/* Appended member - older superblocks may have a smaller field: */
+	if (vstruct_bytes(f) >= offsetof(struct bch_sb_field_ext, journal_rewind_to) +
+				sizeof(e->journal_rewind_to) &&
+	    e->journal_rewind_time) {
+		prt_printf(out, "Last journal rewind:\t");
+		bch2_prt_datetime(out, le64_to_cpu(e->journal_rewind_time));
+		prt_printf(out, ", %llu back to %llu",
+			   le64_to_cpu(e->journal_rewind_from),
+			   le64_to_cpu(e->journal_rewind_to));
+		prt_newline(out);
+	}
 }
 
 static const struct bch_sb_field_ops bch_sb_field_ops_ext = {
@@ -40,14 +40,21 @@ impl bch_sb {
         unsafe { c::bch2_sb_nr_devices(self) }
     }
 
-    /// Get the nonce used to encrypt the superblock
+    /// Get the nonce the master key in the crypt field is wrapped with
     pub fn nonce(&self) -> nonce {
+        self.key_nonce(self.crypt().map_or(0, |crypt| crypt.key_seq()))
+    }
+
+    /// The nonce a master key is wrapped with at key sequence number `seq`:
+    /// the crypt field's key_seq for its current key, key_seq - 1 for the key
+    /// a rotation in progress is rotating out (`__bch2_sb_key_nonce_seq()`).
+    pub fn key_nonce(&self, seq: u64) -> nonce {
         let [a, b, c, d, e, f, g, h, _rest @ ..] = self.uuid.b;
         // nonce.d is __le32, so keep the raw bytes.
         let dword1 = u32::from_ne_bytes([a, b, c, d]);
         let dword2 = u32::from_ne_bytes([e, f, g, h]);
         nonce {
-            d: [0, 0, dword1, dword2],
+            d: [(seq as u32).to_le(), ((seq >> 32) as u32).to_le(), dword1, dword2],
         }
     }
 }
@@ -315,6 +315,20 @@ __cold void bch2_member_to_text(struct printbuf *out,
 
 	if (m->flush_errors)
 		prt_printf(out, "Flush errors:\t%llu\n", le64_to_cpu(m->flush_errors));
+
+	if (m->scrub_start) {
+		prt_printf(out, "Scrub started:\t");
+		bch2_prt_datetime(out, le64_to_cpu(m->scrub_start));
+		prt_printf(out, ", reached sector %llu\n", le64_to_cpu(m->scrub_pos));
+	}
+
+	if (m->last_scrub_end) {
+		prt_printf(out, "Last scrub completed:\t");
+		bch2_prt_datetime(out, le64_to_cpu(m->last_scrub_end));
+		prt_printf(out, ", %llu sectors corrected, %llu uncorrected\n",
+			   le64_to_cpu(m->last_scrub_sectors_corrected),
+			   le64_to_cpu(m->last_scrub_sectors_uncorrected));
+	}
 }
 
 /*
@@ -85,6 +85,25 @@ struct bch_member {
 	 * the allocation path.
 	 */
 	__u8			failure_domain[32] __nonstring;
+
+	/*
+	 * Scrub progress, checkpointed as scrub runs so an interrupted scrub
+	 * can be resumed: scrub_pos is the device sector reached, scrub_start
+	 * is 0 if no scrub is in progress. Counters are in sectors.
+	 */
+	__le64			scrub_start;	/* time_t */
+	__le64			scrub_pos;
+	__le64			scrub_sectors_done;
+	__le64			scrub_sectors_corrected;
+	__le64			scrub_sectors_uncorrected;
+	__le32			scrub_data_types;
+	__le32			last_scrub_data_types;
+
+	/* The last scrub to cover the whole device: */
+	__le64			last_scrub_start;	/* time_t */
+	__le64			last_scrub_end;		/* time_t */
+	__le64			last_scrub_sectors_corrected;
+	__le64			last_scrub_sectors_uncorrected;
 };
 
 /*
@@ -5,6 +5,7 @@
 #define SUBVOL_POS_MIN		POS(0, 1)
 #define SUBVOL_POS_MAX		POS(0, S32_MAX)
 #define BCACHEFS_ROOT_SUBVOL	1
+#define BCH_SUBVOL_LABEL_MAX	32
 
 struct bch_subvolume {
 	struct bch_val		v;
@@ -25,6 +26,16 @@ struct bch_subvolume {
 
 	__le32			state;
 	__le32			pad;
+
+	/* Not NUL terminated if BCH_SUBVOL_LABEL_MAX long: */
+	__u8			label[BCH_SUBVOL_LABEL_MAX];
+
+	/*
+	 * Limit on the sectors visible in this subvolume - its snapshot node's
+	 * and its ancestors', as accounted - enforced when writes reserve
+	 * space; 0 for no limit:
+	 */
+	__le64			quota_sectors;
 };
 
 /*
@@ -54,6 +65,11 @@ LE32_BITMASK(BCH_SUBVOLUME_RO,		struct bch_subvolume, flags,  0,  1)
 LE32_BITMASK(BCH_SUBVOLUME_SNAP,	struct bch_subvolume, flags,  1,  2)
 /* Obsolete */
 LE32_BITMASK(BCH_SUBVOLUME_UNLINKED_OBSOLETE, struct bch_subvolume, flags,  2,  3)
+/*
+ * Subject to a snapshot retention policy: userspace may delete it once it
+ * expires. The kernel doesn't act on this:
+ */
+LE32_BITMASK(BCH_SUBVOLUME_RETENTION,	struct bch_subvolume, flags,  3,  4)
 
 struct bch_snapshot {
 	struct bch_val		v;
@@ -160,6 +176,8 @@ struct bch_snapshot_tree {
 	struct bch_val		v;
 	__le32			master_subvol;
 	__le32			root_snapshot;
+	/* Limit on the sectors of every node in the tree; 0 for no limit */
+	__le64			quota_sectors;
 };
 
 #endif /* _BCACHEFS_SNAPSHOT_FORMAT_H */
@@ -298,6 +298,9 @@ __cold void bch2_snapshot_tree_to_text(struct printbuf *out, struct bch_fs *c,
 	prt_printf(out, "subvol %u root snapshot %u",
 		   le32_to_cpu(t.v->master_subvol),
 		   le32_to_cpu(t.v->root_snapshot));
+
+	if (bkey_has_field(t.k, snapshot_tree, quota_sectors) && t.v->quota_sectors)
+		prt_printf(out, " quota %llu", le64_to_cpu(t.v->quota_sectors));
 }
 
 int bch2_snapshot_tree_validate(struct bch_fs *c, struct bkey_s_c k,
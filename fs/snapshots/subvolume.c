@@ -1,6 +1,9 @@
 // SPDX-License-Identifier: GPL-2.0
 
 #include "bcachefs.h"
+#include "bcachefs_ioctl.h"
+
+#include "alloc/accounting.h"
 
 #include "btree/key_cache.h"
 #include "btree/update.h"
@@ -434,6 +437,16 @@ int bch2_subvolume_validate(struct bch_fs *c, struct bkey_s_c k,
 				 c, subvol_pad_nonzero,
 				 "reserved pad field nonzero");
 
+	/*
+	 * label and quota_sectors came in together (subvolume_label_quota):
+	 * a key has both, or neither - older keys read as no label, no quota:
+	 */
+	bkey_fsck_err_on(bkey_val_bytes(k.k) > offsetof(struct bch_subvolume, label) &&
+			 !bkey_has_field(k.k, subvolume, quota_sectors),
+			 c, subvol_val_size_bad,
+			 "bad val size (%zu): partial label/quota fields",
+			 bkey_val_bytes(k.k));
+
 	/*
 	 * Commit-only checks - defense in depth, never applied to existing
 	 * keys (see bch2_snapshot_validate). The leaf check skips snapshot
@@ -500,10 +513,17 @@ __cold void bch2_subvolume_to_text(struct printbuf *out, struct bch_fs *c,
 		prt_printf(out, " ro");
 	if (BCH_SUBVOLUME_SNAP(s.v))
 		prt_printf(out, " snapshot");
+	if (BCH_SUBVOLUME_RETENTION(s.v))
+		prt_printf(out, " retention");
 
 	struct bch_subvolume v;
 	bkey_val_copy_pad(&v, s);
 
+	if (v.label[0])
+		prt_printf(out, " label %.*s", (int) sizeof(v.label), v.label);
+	if (v.quota_sectors)
+		prt_printf(out, " quota %llu", le64_to_cpu(v.quota_sectors));
+
 	u32 state = le32_to_cpu(v.state);
 	if (!state) {
 		/* Not upgraded: no state field, the obsolete flag is the truth */
@@ -944,6 +964,351 @@ int bch2_subvolume_create(struct btree_trans *trans, u64 inode,
 	return 0;
 }
 
+static struct bkey_i_subvolume *subvolume_get_mut(struct btree_trans *trans, u32 subvolid)
+{
+	struct bkey_i_subvolume *s =
+		bch2_bkey_get_mut_typed(trans, BTREE_ID_subvolumes, POS(0, subvolid),
+					BTREE_ITER_cached, subvolume);
+	int ret = PTR_ERR_OR_ZERO(s);
+	if (bch2_err_matches(ret, ENOENT))
+		ret = bch2_subvolume_missing(trans->c, subvolid) ?: ret;
+	return ret ? ERR_PTR(ret) : s;
+}
+
+/*
+ * Roll @subvolid back to the current state of @target_subvolid, in place.
+ *
+ * The subvolume keeps its ID and root inode, and is switched to a new
+ * writable snapshot node under the target's snapshot (the target moves to the
+ * sibling, as when snapshotting). The snapshot node the subvolume was using
+ * is a leaf, and is handed as is to a new read-only subvolume: that's the
+ * pre-rollback state, kept without creating any more nodes.
+ *
+ * The caller is responsible for the root inode and the new subvolume's
+ * dirent.
+ */
+int bch2_subvolume_rollback(struct btree_trans *trans,
+			    u32 subvolid, u32 target_subvolid,
+			    u32 save_parent_subvolid,
+			    u32 *save_subvolid,
+			    u32 *new_snapshotid)
+{
+	struct bch_fs *c = trans->c;
+
+	if (subvolid == target_subvolid)
+		return bch_err_throw(c, EINVAL_subvol_rollback_not_same_tree);
+
+	struct bkey_i_subvolume *subvol = errptr_try(subvolume_get_mut(trans, subvolid));
+	struct bkey_i_subvolume *target = errptr_try(subvolume_get_mut(trans, target_subvolid));
+
+	if (BCH_SUBVOLUME_RO(&subvol->v))
+		return -EROFS;
+
+	u32 old_snapshot	= le32_to_cpu(subvol->v.snapshot);
+	u32 target_snapshot	= le32_to_cpu(target->v.snapshot);
+
+	if (subvol->v.inode != target->v.inode ||
+	    bch2_snapshot_tree(c, old_snapshot) != bch2_snapshot_tree(c, target_snapshot))
+		return bch_err_throw(c, EINVAL_subvol_rollback_not_same_tree);
+
+	CLASS(btree_iter_uninit, save_iter)(trans);
+	int ret = bch2_bkey_get_empty_slot(trans, &save_iter,
+				BTREE_ID_subvolumes, POS_MIN, POS(0, U32_MAX));
+	if (ret == -BCH_ERR_ENOSPC_btree_slot)
+		ret = bch_err_throw(c, ENOSPC_subvolume_create);
+	if (ret)
+		return ret;
+
+	struct bkey_i_snapshot *old_snap =
+		errptr_try(bch2_bkey_get_mut_typed(trans, BTREE_ID_snapshots,
+						   POS(0, old_snapshot), 0, snapshot));
+	old_snap->v.subvol = cpu_to_le32(save_iter.pos.offset);
+
+	u32 new_nodes[2], snapshot_subvols[2] = { subvolid, target_subvolid };
+	try(bch2_snapshot_node_create(trans, target_snapshot, new_nodes,
+				      snapshot_subvols, 2));
+
+	subvol->v.snapshot = cpu_to_le32(new_nodes[0]);
+	target->v.snapshot = cpu_to_le32(new_nodes[1]);
+
+	struct bkey_i_subvolume *save = errptr_try(bch2_bkey_alloc(trans, &save_iter, 0, subvolume));
+
+	save->v.flags		= 0;
+	save->v.snapshot	= cpu_to_le32(old_snapshot);
+	save->v.inode		= subvol->v.inode;
+	save->v.creation_parent	= cpu_to_le32(subvolid);
+	save->v.fs_path_parent	= cpu_to_le32(save_parent_subvolid);
+	save->v.otime.lo	= cpu_to_le64(bch2_current_time(c));
+	save->v.otime.hi	= 0;
+
+	SET_BCH_SUBVOLUME_RO(&save->v, true);
+	SET_BCH_SUBVOLUME_SNAP(&save->v, true);
+	bch2_subvolume_state_set(&save->v, SUBVOLUME_STATE_live);
+
+	*save_subvolid	= save->k.p.offset;
+	*new_snapshotid	= new_nodes[0];
+	return 0;
+}
+
+/*
+ * Set the properties BCH_IOCTL_SUBVOLUME_SET may change, as selected by
+ * @fields (BCH_SUBVOL_SET_*):
+ */
+int bch2_subvolume_set(struct btree_trans *trans, u32 subvolid, u64 root_inum,
+		       const struct bch_ioctl_subvolume_set *arg)
+{
+	struct bkey_i_subvolume *s = errptr_try(subvolume_get_mut(trans, subvolid));
+
+	if (le64_to_cpu(s->v.inode) != root_inum)
+		return bch_err_throw(trans->c, EINVAL_subvol_set_not_subvol_root);
+
+	if (arg->flags & BCH_SUBVOL_SET_RO)
+		SET_BCH_SUBVOLUME_RO(&s->v, arg->ro);
+	if (arg->flags & BCH_SUBVOL_SET_RETENTION)
+		SET_BCH_SUBVOLUME_RETENTION(&s->v, arg->retention);
+	if (arg->flags & BCH_SUBVOL_SET_LABEL)
+		memcpy(s->v.label, arg->label, sizeof(s->v.label));
+	if (arg->flags & BCH_SUBVOL_SET_QUOTA)
+		s->v.quota_sectors = cpu_to_le64(arg->quota_sectors);
+
+	if (arg->flags & BCH_SUBVOL_SET_TREE_QUOTA) {
+		u32 tree = bch2_snapshot_tree(trans->c, le32_to_cpu(s->v.snapshot));
+		struct bkey_i_snapshot_tree *t =
+			errptr_try(bch2_bkey_get_mut_typed(trans, BTREE_ID_snapshot_trees,
+							   POS(0, tree), 0, snapshot_tree));
+
+		t->v.quota_sectors = cpu_to_le64(arg->tree_quota_sectors);
+	}
+	return 0;
+}
+
+/*
+ * Subvolume and snapshot tree quotas:
+ *
+ * Usage is the extents sectors counter of the per-snapshot accounting - what
+ * `bcachefs subvolume list` reports: for a subvolume, summed over its snapshot
+ * node and that node's ancestors, i.e. everything visible in it; for a tree,
+ * over every node in it.
+ *
+ * Write reservations check against c->snapshots.quotas, never the btree or
+ * the accounting: they're taken with btree locks held (fallocate), where
+ * neither a nested transaction nor mark_lock is allowed. The table is rebuilt
+ * from the btree and accounting when a limit is set, and by a worker when the
+ * filesystem goes read-write and when a reservation finds it more than a
+ * second old; reservations add what they reserve to it in the meantime.
+ * Usage as refreshed is as of the last commit, so a limit can be overshot by
+ * what was reserved but not yet written when the table was rebuilt - the same
+ * slack as the user/group/project quotas have.
+ */
+
+static u64 snapshot_sectors_locked(struct bch_fs *c, u32 id)
+{
+	struct disk_accounting_pos acc;
+	disk_accounting_key_init(acc, snapshot, .id = id, .btree = BTREE_ID_extents);
+
+	u64 v[3];
+	bch2_accounting_mem_read_locked(c, disk_accounting_pos_to_bpos(&acc), v, ARRAY_SIZE(v));
+	return v[2];
+}
+
+static u64 subvol_sectors(struct bch_fs *c, u32 snapshot)
+{
+	u64 sectors = 0;
+
+	guard(percpu_read_noio)(&c->capacity.mark_lock);
+	guard(rcu)();
+	struct snapshot_table *t = rcu_dereference(c->snapshots.table);
+
+	for (u32 id = snapshot; id; id = __bch2_snapshot_parent(t, id))
+		sectors += snapshot_sectors_locked(c, id);
+	return sectors;
+}
+
+static u64 snapshot_tree_sectors(struct bch_fs *c, u32 tree)
+{
+	u64 sectors = 0;
+
+	guard(percpu_read_noio)(&c->capacity.mark_lock);
+	guard(rcu)();
+	struct snapshot_table *t = rcu_dereference(c->snapshots.table);
+
+	for (size_t i = 0; t && i < t->nr; i++)
+		if (t->s[i].state == SNAPSHOT_ID_live &&
+		    t->s[i].tree == tree)
+			sectors += snapshot_sectors_locked(c, U32_MAX - i);
+	return sectors;
+}
+
+struct subvol_quota_limits {
+	u32	subvol;
+	u32	snapshot;
+	u32	tree;
+	u64	limit;
+	u64	tree_limit;
+};
+DEFINE_DARRAY_NAMED(subvol_quota_limits_list, struct subvol_quota_limits);
+
+static int subvol_quota_limits_get(struct btree_trans *trans, struct bkey_s_c k,
+				   subvol_quota_limits_list *l)
+{
+	struct bch_fs *c = trans->c;
+
+	if (k.k->type != KEY_TYPE_subvolume)
+		return 0;
+
+	struct bch_subvolume s;
+	bkey_val_copy_pad(&s, bkey_s_c_to_subvolume(k));
+	if (bch2_subvolume_state_compat(&s) != SUBVOLUME_STATE_live)
+		return 0;
+
+	u32 snapshot = le32_to_cpu(s.snapshot);
+	u32 tree = bch2_snapshot_tree(c, snapshot);
+	struct bch_snapshot_tree st = {};
+	if (tree)
+		try(bch2_snapshot_tree_lookup(trans, tree, &st));
+
+	struct subvol_quota_limits q = {
+		.subvol		= k.k->p.offset,
+		.snapshot	= snapshot,
+		.tree		= tree,
+		.limit		= le64_to_cpu(s.quota_sectors),
+		.tree_limit	= le64_to_cpu(st.quota_sectors),
+	};
+
+	return q.limit || q.tree_limit
+		? darray_push(l, q)
+		: 0;
+}
+
+/*
+ * Rebuild c->snapshots.quotas. Not from a transaction: summing usage takes
+ * mark_lock, which we can't block on with btree locks held.
+ */
+int bch2_subvol_quotas_refresh(struct bch_fs *c)
+{
+	if (!(c->sb.compat & BIT_ULL(BCH_COMPAT_subvol_quota)))
+		return 0;
+
+	guard(mutex)(&c->snapshots.quotas_lock);
+
+	CLASS(subvol_quota_limits_list, l)();
+	{
+		CLASS(btree_trans, trans)(c);
+		try(for_each_btree_key(trans, iter, BTREE_ID_subvolumes, POS_MIN, 0, k,
+				       subvol_quota_limits_get(trans, k, &l)));
+	}
+
+	/* At most one tree per subvolume; the table is sized for that: */
+	struct subvol_quota_table *new =
+		kvzalloc(struct_size(new, q, l.nr) +
+			 l.nr * sizeof(struct snapshot_tree_quota), GFP_KERNEL);
+	if (!new)
+		return bch_err_throw(c, ENOMEM_subvol_quotas_refresh);
+
+	new->nr		= l.nr;
+	new->trees	= (void *) &new->q[l.nr];
+
+	for (size_t i = 0; i < l.nr; i++) {
+		struct subvol_quota *q = &new->q[i];
+
+		q->subvol	= l.data[i].subvol;
+		q->limit	= l.data[i].limit;
+		atomic64_set(&q->used, q->limit
+			     ? subvol_sectors(c, l.data[i].snapshot) : 0);
+
+		if (!l.data[i].tree_limit)
+			continue;
+
+		for (size_t j = 0; j < new->nr_trees; j++)
+			if (new->trees[j].tree == l.data[i].tree) {
+				q->tree = &new->trees[j];
+				break;
+			}
+
+		if (!q->tree) {
+			struct snapshot_tree_quota *t = &new->trees[new->nr_trees++];
+
+			t->tree		= l.data[i].tree;
+			t->limit	= l.data[i].tree_limit;
+			atomic64_set(&t->used, snapshot_tree_sectors(c, t->tree));
+			q->tree = t;
+		}
+	}
+
+	struct subvol_quota_table *old =
+		rcu_replace_pointer(c->snapshots.quotas, new,
+				    lockdep_is_held(&c->snapshots.quotas_lock));
+	kvfree_rcu(old, rcu);
+
+	WRITE_ONCE(c->snapshots.quotas_refreshed, jiffies);
+	return 0;
+}
+
+static void bch2_subvol_quotas_refresh_work(struct work_struct *work)
+{
+	struct bch_fs *c = container_of(work, struct bch_fs,
+				snapshots.quotas_refresh_work);
+
+	int ret = bch2_subvol_quotas_refresh(c);
+	bch_err_fn(c, ret);
+
+	enumerated_ref_put(&c->writes, BCH_WRITE_REF_subvol_quota_refresh);
+}
+
+void bch2_subvol_quotas_refresh_async(struct bch_fs *c)
+{
+	if (!(c->sb.compat & BIT_ULL(BCH_COMPAT_subvol_quota)) ||
+	    work_pending(&c->snapshots.quotas_refresh_work) ||
+	    !enumerated_ref_tryget(&c->writes, BCH_WRITE_REF_subvol_quota_refresh))
+		return;
+
+	if (!queue_work(c->write_ref_wq, &c->snapshots.quotas_refresh_work))
+		enumerated_ref_put(&c->writes, BCH_WRITE_REF_subvol_quota_refresh);
+}
+
+static int subvol_quota_cmp(const void *_l, const void *_r)
+{
+	const u32 *l = _l;
+	const struct subvol_quota *r = _r;
+
+	return cmp_int(*l, r->subvol);
+}
+
+/*
+ * Called when a write in @subvolid reserves @sectors: returns -EDQUOT if that
+ * would take the subvolume or its snapshot tree over its limit.
+ *
+ * May be called with btree locks held: takes no locks but RCU.
+ */
+int bch2_subvol_quota_check(struct bch_fs *c, u32 subvolid, u64 sectors)
+{
+	/* No quota has ever been set: */
+	if (!(c->sb.compat & BIT_ULL(BCH_COMPAT_subvol_quota)))
+		return 0;
+
+	if (time_after(jiffies, READ_ONCE(c->snapshots.quotas_refreshed) + HZ))
+		bch2_subvol_quotas_refresh_async(c);
+
+	guard(rcu)();
+	struct subvol_quota_table *t = rcu_dereference(c->snapshots.quotas);
+	struct subvol_quota *q = t
+		? bsearch(&subvolid, t->q, t->nr, sizeof(t->q[0]), subvol_quota_cmp)
+		: NULL;
+	if (!q)
+		return 0;
+
+	if (q->limit && atomic64_read(&q->used) + sectors > q->limit)
+		return bch_err_throw(c, EDQUOT_subvol_quota);
+
+	if (q->tree && atomic64_read(&q->tree->used) + sectors > q->tree->limit)
+		return bch_err_throw(c, EDQUOT_snapshot_tree_quota);
+
+	atomic64_add(sectors, &q->used);
+	if (q->tree)
+		atomic64_add(sectors, &q->tree->used);
+	return 0;
+}
+
 int bch2_initialize_subvolumes(struct bch_fs *c)
 {
 	struct bkey_i_snapshot_tree	root_tree;
@@ -1005,9 +1370,17 @@ int bch2_fs_upgrade_for_subvolumes(struct bch_fs *c)
 			    __bch2_fs_upgrade_for_subvolumes(trans));
 }
 
+void bch2_fs_subvolumes_exit(struct bch_fs *c)
+{
+	kvfree(rcu_dereference_protected(c->snapshots.quotas, true));
+}
+
 void bch2_fs_subvolumes_init_early(struct bch_fs *c)
 {
 	INIT_WORK(&c->snapshots.wait_for_pagecache_and_delete_work,
 		  bch2_subvolume_wait_for_pagecache_and_delete);
+	INIT_WORK(&c->snapshots.quotas_refresh_work,
+		  bch2_subvol_quotas_refresh_work);
+	mutex_init(&c->snapshots.quotas_lock);
 }
 
@@ -190,10 +190,20 @@ febk_in_subvolume_in_trans_cond(struct btree_trans *trans,
 int bch2_subvolume_unlink(struct btree_trans *, u32);
 int bch2_subvolume_create(struct btree_trans *, u64, u32, u32, u32 *, u32 *,
 			  struct bch_subvolume *, bool);
+int bch2_subvolume_rollback(struct btree_trans *, u32, u32, u32,
+			    u32 *, u32 *);
+struct bch_ioctl_subvolume_set;
+int bch2_subvolume_set(struct btree_trans *, u32, u64,
+		       const struct bch_ioctl_subvolume_set *);
+
+int bch2_subvol_quotas_refresh(struct bch_fs *);
+void bch2_subvol_quotas_refresh_async(struct bch_fs *);
+int bch2_subvol_quota_check(struct bch_fs *, u32, u64);
 
 int bch2_initialize_subvolumes(struct bch_fs *);
 int bch2_fs_upgrade_for_subvolumes(struct bch_fs *);
 
+void bch2_fs_subvolumes_exit(struct bch_fs *);
 void bch2_fs_subvolumes_init_early(struct bch_fs *);
 
 #endif /* _BCACHEFS_SUBVOLUME_H */
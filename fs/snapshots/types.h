@@ -54,6 +54,40 @@ struct snapshot_table {
 #endif
 };
 
+/*
+ * Subvolume and snapshot tree quotas as write reservations check them: the
+ * limits mirrored from the btree, and usage as of the last refresh plus what
+ * has been reserved since. One entry per subvolume that has a limit, or is
+ * in a snapshot tree that has one, sorted by subvolume ID; the subvolumes in
+ * a tree all point to the one entry for it, so each sees the others' writes.
+ */
+struct snapshot_tree_quota {
+	u32			tree;
+	u64			limit;
+	atomic64_t		used;
+};
+
+struct subvol_quota {
+	u32			subvol;
+	u64			limit;
+	atomic64_t		used;
+	/* NULL if the subvolume's snapshot tree has no limit: */
+	struct snapshot_tree_quota *tree;
+};
+
+struct subvol_quota_table {
+	struct rcu_head		rcu;
+	size_t			nr;
+	size_t			nr_trees;
+	/* In the same allocation, after q[]: */
+	struct snapshot_tree_quota *trees;
+#ifndef RUST_BINDGEN
+	DECLARE_FLEX_ARRAY(struct subvol_quota, q);
+#else
+	struct subvol_quota	q[0];
+#endif
+};
+
 struct snapshot_interior_delete {
 	u32	id;
 	u32	live_child;
@@ -98,6 +132,11 @@ struct bch_fs_snapshots {
 	struct work_struct			wait_for_pagecache_and_delete_work;
 	snapshot_id_list			unlinked;
 	struct mutex				unlinked_lock;
+
+	struct subvol_quota_table __rcu		*quotas;
+	struct mutex				quotas_lock;
+	struct work_struct			quotas_refresh_work;
+	unsigned long				quotas_refreshed;
 };
 
 typedef struct {
@@ -4,6 +4,7 @@
 #include <linux/module.h>
 #include <linux/percpu.h>
 #include <linux/preempt.h>
+#include <linux/slab.h>
 #include <linux/time.h>
 #include <linux/spinlock.h>
 
@@ -87,6 +88,8 @@ static inline void time_stats_update_one(struct bch2_time_stats *stats,
 
 		if (quantiles)
 			quantiles_update(quantiles, duration);
+		if (stats->hist)
+			stats->hist->buckets[time_stats_hist_bucket(duration)]++;
 	}
 
 	if (stats->last_event && time_after64(end, stats->last_event)) {
@@ -188,6 +191,9 @@ void bch2_time_stats_reset(struct bch2_time_stats *stats)
 	unsigned offset = offsetof(struct bch2_time_stats, min_duration);
 	memset((void *) stats + offset, 0, sizeof(*stats) - offset);
 
+	if (stats->hist)
+		memset(stats->hist, 0, sizeof(*stats->hist));
+
 	if ((unsigned long) stats->buffer > TIME_STATS_NONPCPU) {
 		int cpu;
 		for_each_possible_cpu(cpu)
@@ -386,13 +392,30 @@ void bch2_time_stats_to_json(struct seq_buf *out, struct bch2_time_stats *stats,
 				seq_buf_printf(out, ", ");
 			last_q = q;
 		}
-		seq_buf_printf(out, "  ]\n");
+		seq_buf_printf(out, "  ]");
 	} else {
 		/* close between_ewma_ns without dumping further */
-		seq_buf_printf(out, "  }\n");
+		seq_buf_printf(out, "  }");
+	}
+
+	if (stats->hist) {
+		bool first = true;
+
+		/* [bucket start, count] for the nonempty buckets */
+		seq_buf_printf(out, ",\n  \"histogram_ns\": [");
+		for (unsigned i = 0; i < TIME_STATS_HIST_NR; i++) {
+			u64 nr = READ_ONCE(stats->hist->buckets[i]);
+
+			if (!nr)
+				continue;
+			seq_buf_printf(out, "%s\n    [%llu, %llu]", first ? "" : ",",
+				       time_stats_hist_bucket_start(i), nr);
+			first = false;
+		}
+		seq_buf_printf(out, "\n  ]");
 	}
 
-	seq_buf_printf(out, "}\n");
+	seq_buf_printf(out, "\n}\n");
 }
 
 void bch2_time_stats_exit(struct bch2_time_stats *stats)
@@ -400,6 +423,9 @@ void bch2_time_stats_exit(struct bch2_time_stats *stats)
 	if ((unsigned long) stats->buffer > TIME_STATS_NONPCPU)
 		free_percpu(stats->buffer);
 	stats->buffer = NULL;
+
+	kfree(stats->hist);
+	stats->hist = NULL;
 }
 
 void bch2_time_stats_init(struct bch2_time_stats *stats)
@@ -416,3 +442,12 @@ void bch2_time_stats_init_no_pcpu(struct bch2_time_stats *stats)
 	bch2_time_stats_init(stats);
 	stats->buffer = (struct time_stat_buffer __percpu *) TIME_STATS_NONPCPU;
 }
+
+/*
+ * Best effort: without the memory we just don't have percentiles for this
+ * one, which isn't worth failing a mount over.
+ */
+void bch2_time_stats_hist_init(struct bch2_time_stats *stats)
+{
+	stats->hist = kzalloc(sizeof(*stats->hist), GFP_KERNEL);
+}
@@ -60,6 +60,44 @@ struct quantiles {
 	}		entries[NR_QUANTILES];
 };
 
+/*
+ * Latency histogram: log-linear, 4 buckets per power of two - enough
+ * resolution for p99/p99.9, which mean and stddev can't give. Optional, as
+ * it's 1.2k per stats: bch2_time_stats_hist_init() for the stats worth it.
+ */
+#define TIME_STATS_HIST_SUB_BITS	2
+#define TIME_STATS_HIST_MAX_SHIFT	40	/* ~18 minutes; longer goes in the last bucket */
+#define TIME_STATS_HIST_NR						\
+	((TIME_STATS_HIST_MAX_SHIFT - TIME_STATS_HIST_SUB_BITS + 1) << TIME_STATS_HIST_SUB_BITS)
+
+struct time_stats_hist {
+	u64		buckets[TIME_STATS_HIST_NR];
+};
+
+static inline unsigned time_stats_hist_bucket(u64 v)
+{
+	if (v < (1U << TIME_STATS_HIST_SUB_BITS))
+		return v;
+
+	unsigned shift = fls64(v) - 1 - TIME_STATS_HIST_SUB_BITS;
+	unsigned idx = ((shift + 1) << TIME_STATS_HIST_SUB_BITS) |
+		((v >> shift) & ((1U << TIME_STATS_HIST_SUB_BITS) - 1));
+
+	return min_t(unsigned, idx, TIME_STATS_HIST_NR - 1);
+}
+
+/* Smallest value that lands in bucket @idx */
+static inline u64 time_stats_hist_bucket_start(unsigned idx)
+{
+	if (idx < (1U << TIME_STATS_HIST_SUB_BITS))
+		return idx;
+
+	unsigned shift = (idx >> TIME_STATS_HIST_SUB_BITS) - 1;
+
+	return (u64) ((1U << TIME_STATS_HIST_SUB_BITS) |
+		      (idx & ((1U << TIME_STATS_HIST_SUB_BITS) - 1))) << shift;
+}
+
 struct time_stat_buffer {
 	/*
 	 * Protects nr/entries on the owning cpu: irq/preempt disable on
@@ -80,6 +118,7 @@ struct bch2_time_stats {
 	spinlock_t	lock;
 	bool		have_quantiles;
 	struct time_stat_buffer __percpu *buffer;
+	struct time_stats_hist	*hist;
 	/* all fields are in nanoseconds */
 	u64             min_duration;
 	u64		max_duration;
@@ -164,6 +203,7 @@ void bch2_time_stats_to_json(struct seq_buf *, struct bch2_time_stats *,
 void bch2_time_stats_exit(struct bch2_time_stats *);
 void bch2_time_stats_init(struct bch2_time_stats *);
 void bch2_time_stats_init_no_pcpu(struct bch2_time_stats *);
+void bch2_time_stats_hist_init(struct bch2_time_stats *);
 
 static inline void bch2_time_stats_quantiles_exit(struct bch2_time_stats_quantiles *statq)
 {
@@ -172,6 +212,7 @@ static inline void bch2_time_stats_quantiles_exit(struct bch2_time_stats_quantil
 static inline void bch2_time_stats_quantiles_init(struct bch2_time_stats_quantiles *statq)
 {
 	bch2_time_stats_init(&statq->stats);
+	bch2_time_stats_hist_init(&statq->stats);
 	statq->stats.have_quantiles = true;
 	memset(&statq->quantiles, 0, sizeof(statq->quantiles));
 }
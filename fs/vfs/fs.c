@@ -2500,6 +2500,46 @@ void bch2_evict_subvolume_inodes(struct bch_fs *c, snapshot_id_list *s)
 	rcu_read_unlock();
 }
 
+/*
+ * bch2_evict_subvolume_inodes(), for a caller that can't wait for inodes to
+ * be closed: evicts what it can of one subvolume's inodes, and returns the
+ * number still in use.
+ *
+ * Pruning a dentry releases its parent, so we go again for as long as that
+ * makes progress.
+ */
+unsigned bch2_evict_subvolume_inodes_nowait(struct bch_fs *c, u32 subvol)
+{
+	unsigned in_use = UINT_MAX, prev;
+
+	do {
+		prev = in_use;
+		in_use = 0;
+
+		rcu_read_lock();
+		struct genradix_iter iter;
+		struct bch_inode_info *inode;
+
+		fast_list_for_each(&c->vfs.inodes, iter, inode) {
+			if (inode_inum(inode).subvol != subvol ||
+			    !igrab(&inode->v))
+				continue;
+
+			rcu_read_unlock();
+
+			d_mark_dontcache(&inode->v);
+			d_prune_aliases(&inode->v);
+			in_use += icount_read(&inode->v) > 1;
+			iput(&inode->v);
+
+			rcu_read_lock();
+		}
+		rcu_read_unlock();
+	} while (in_use && in_use < prev);
+
+	return in_use;
+}
+
 static int bch2_statfs(struct dentry *dentry, struct kstatfs *buf)
 {
 	struct super_block *sb = dentry->d_sb;
@@ -250,6 +250,7 @@ int bch2_setattr_nonsize(struct mnt_idmap *,
 int __bch2_unlink(struct inode *, struct dentry *, bool);
 
 void bch2_evict_subvolume_inodes(struct bch_fs *, snapshot_id_list *);
+unsigned bch2_evict_subvolume_inodes_nowait(struct bch_fs *, u32);
 
 int bch2_fiemap(struct inode *, struct fiemap_extent_info *, u64, u64);
 
@@ -275,6 +276,8 @@ static inline int bch2_inode_or_descendents_is_open(struct btree_trans *trans, s
 
 static inline void bch2_evict_subvolume_inodes(struct bch_fs *c,
 					       snapshot_id_list *s) {}
+static inline unsigned bch2_evict_subvolume_inodes_nowait(struct bch_fs *c,
+							  u32 subvol) { return 0; }
 
 static inline void bch2_fs_vfs_exit(struct bch_fs *c) {}
 static inline int bch2_fs_vfs_init(struct bch_fs *c) { return 0; }
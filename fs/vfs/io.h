@@ -7,6 +7,7 @@
 #include "alloc/buckets.h"
 #include "data/write_types.h"
 #include "fs/quota.h"
+#include "snapshots/subvolume.h"
 #include "vfs/fdm.h"
 #include "vfs/fs.h"
 
@@ -89,7 +90,7 @@ static inline void bch2_quota_reservation_put(struct bch_fs *c,
 	}
 }
 
-static inline int bch2_quota_reservation_add(struct bch_fs *c,
+static inline int __bch2_quota_reservation_add(struct bch_fs *c,
 				      struct bch_inode_info *inode,
 				      struct quota_res *res,
 				      u64 sectors,
@@ -121,10 +122,10 @@ static inline void bch2_quota_reservation_put(struct bch_fs *c,
 				       struct bch_inode_info *inode,
 				       struct quota_res *res) {}
 
-static inline int bch2_quota_reservation_add(struct bch_fs *c,
+static inline int __bch2_quota_reservation_add(struct bch_fs *c,
 				      struct bch_inode_info *inode,
 				      struct quota_res *res,
-				      unsigned sectors,
+				      u64 sectors,
 				      bool check_enospc)
 {
 	return 0;
@@ -132,6 +133,24 @@ static inline int bch2_quota_reservation_add(struct bch_fs *c,
 
 #endif
 
+/*
+ * Subvolume and snapshot tree quotas are checked here too, against the
+ * in-memory copy bch2_subvol_quota_check() keeps: this is called with btree
+ * locks held (fallocate), so it mustn't touch the btree.
+ */
+static inline int bch2_quota_reservation_add(struct bch_fs *c,
+				      struct bch_inode_info *inode,
+				      struct quota_res *res,
+				      u64 sectors,
+				      bool check_enospc)
+{
+	if (check_enospc &&
+	    !test_bit(EI_INODE_SNAPSHOT, &inode->ei_flags))
+		try(bch2_subvol_quota_check(c, inode_inum(inode).subvol, sectors));
+
+	return __bch2_quota_reservation_add(c, inode, res, sectors, check_enospc);
+}
+
 void __bch2_i_sectors_acct(struct bch_fs *, struct bch_inode_info *,
 			   struct quota_res *, s64);
 
@@ -15,10 +15,12 @@
 #include "alloc/accounting.h"
 #include "btree/write_buffer.h"
 #include "data/reconcile/trigger.h"
+#include "data/reconcile/work.h"
 #include "data/reflink_format.h"
 
 #include "init/chardev.h"
 #include "init/fs.h"
+#include "sb/members.h"
 
 #include "vfs/direct.h"
 #include "vfs/fs.h"
@@ -517,6 +519,272 @@ static long bch2_ioctl_subvolume_destroy_v2(struct bch_fs *c, struct file *filp,
 	return bch2_copy_ioctl_err_msg(&arg.err, &err, ret);
 }
 
+static long __bch2_ioctl_subvolume_rollback(struct bch_fs *c, struct file *filp,
+					    struct bch_ioctl_subvolume_rollback arg,
+					    struct printbuf *err)
+{
+	struct mnt_idmap *idmap = file_mnt_idmap(filp);
+	struct path subvol_path, target_path, save_path;
+	struct dentry *save_dentry;
+	subvol_inum root, target;
+	u32 save_subvolid = 0;
+	int ret;
+
+	if (arg.flags)
+		return bch_err_throw(c, EINVAL_subvol_rollback_bad_flags);
+
+	ret = user_path_at(arg.dirfd, (const char __user *)(unsigned long)arg.subvol_ptr,
+			   LOOKUP_FOLLOW, &subvol_path);
+	if (ret)
+		return ret;
+
+	if (subvol_path.dentry->d_sb->s_fs_info != c) {
+		prt_str(err, "subvolume not on this filesystem");
+		ret = -EXDEV;
+		goto err1;
+	}
+
+	/*
+	 * Everything in the subvolume is about to be replaced: same rule as for
+	 * snapshotting it, it has to be ours:
+	 */
+	if (!inode_owner_or_capable(idmap, d_inode(subvol_path.dentry))) {
+		ret = bch_err_throw(c, EPERM_non_admin_or_owner);
+		goto err1;
+	}
+
+	root = inode_inum(to_bch_ei(d_inode(subvol_path.dentry)));
+
+	/*
+	 * A mount of the subvolume pins its root directory for as long as it's
+	 * mounted, and the rollback can't swap that out from under it: say so,
+	 * instead of leaving it to the in use check below:
+	 */
+	if (subvol_path.dentry == subvol_path.mnt->mnt_root ||
+	    d_mountpoint(subvol_path.dentry)) {
+		prt_str(err, "subvolume is mounted; unmount it, then roll it back through a mount of its parent");
+		ret = bch_err_throw(c, EBUSY_subvol_rollback_mounted);
+		goto err1;
+	}
+
+	ret = user_path_at(arg.dirfd, (const char __user *)(unsigned long)arg.target_ptr,
+			   LOOKUP_FOLLOW, &target_path);
+	if (ret)
+		goto err1;
+
+	if (target_path.dentry->d_sb->s_fs_info != c) {
+		prt_str(err, "snapshot not on this filesystem");
+		ret = -EXDEV;
+		goto err2;
+	}
+
+	ret = inode_permission(idmap, d_inode(target_path.dentry), MAY_READ|MAY_EXEC);
+	if (ret)
+		goto err2;
+
+	target = inode_inum(to_bch_ei(d_inode(target_path.dentry)));
+
+	save_dentry = start_creating_user_path(arg.dirfd,
+			(const char __user *)(unsigned long)arg.save_ptr,
+			&save_path, 0);
+	ret = PTR_ERR_OR_ZERO(save_dentry);
+	if (ret)
+		goto err2;
+
+	struct bch_inode_info *dir = to_bch_ei(save_path.dentry->d_inode);
+
+	if (save_dentry->d_sb->s_fs_info != c) {
+		prt_str(err, "save path not on this filesystem");
+		ret = -EXDEV;
+		goto err3;
+	}
+
+	if (save_dentry->d_inode) {
+		ret = bch_err_throw(c, EEXIST_subvolume_create);
+		goto err3;
+	}
+
+	if (IS_DEADDIR(&dir->v)) {
+		ret = bch_err_throw(c, ENOENT_directory_dead);
+		goto err3;
+	}
+
+	ret =   inode_permission(idmap, &dir->v, MAY_WRITE|MAY_EXEC) ?:
+		security_path_mkdir(&save_path, save_dentry, S_IFDIR);
+	if (ret)
+		goto err3;
+
+	/* Our own references would keep the root inode in use: */
+	path_put(&subvol_path);
+	path_put(&target_path);
+
+	/*
+	 * As for snapshot creation: block new page-cache dirtiers and flush
+	 * existing dirty pages, so that the pre-rollback snapshot is
+	 * complete. Then nothing in the subvolume may stay cached - it would
+	 * be the pre-rollback version:
+	 */
+	percpu_down_write(&c->snapshots.create_lock);
+	scoped_guard(rwsem_read, &c->vfs_sb->s_umount)
+		sync_inodes_sb(c->vfs_sb);
+
+	unsigned in_use = bch2_evict_subvolume_inodes_nowait(c, root.subvol);
+	if (in_use) {
+		prt_printf(err, "%u files in subvolume still in use (open, a process's working directory, or the subvolume mounted elsewhere)",
+			   in_use);
+		ret = bch_err_throw(c, EBUSY_subvol_rollback_in_use);
+	} else {
+		struct bch_inode_unpacked dir_u;
+		CLASS(btree_trans, trans)(c);
+
+		guard(mutex)(&dir->ei_update_lock);
+		ret = commit_do(trans, NULL, NULL, 0,
+			bch2_subvolume_rollback_trans(trans, root, target.subvol,
+						      inode_inum(dir), &dir_u,
+						      &save_dentry->d_name,
+						      &save_subvolid));
+		if (!ret)
+			bch2_inode_update_after_write(trans, dir, &dir_u,
+						      ATTR_MTIME|ATTR_CTIME);
+	}
+	percpu_up_write(&c->snapshots.create_lock);
+
+	if (!ret) {
+		/* Anything looked up in the meantime saw the old snapshot: */
+		bch2_evict_subvolume_inodes_nowait(c, root.subvol);
+
+		struct inode *save_inode = bch2_vfs_inode_get(c,
+				(subvol_inum) { save_subvolid, root.inum }, __func__);
+
+		if (!IS_ERR(save_inode)) {
+			bch2_dentry_set_casefold_ops(save_dentry, save_inode);
+			d_instantiate(save_dentry, save_inode);
+			fsnotify_mkdir(&dir->v, save_dentry);
+		} else {
+			d_drop(save_dentry);
+		}
+	}
+
+	end_creating_path(&save_path, save_dentry);
+	return ret;
+err3:
+	end_creating_path(&save_path, save_dentry);
+err2:
+	path_put(&target_path);
+err1:
+	path_put(&subvol_path);
+	return ret;
+}
+
+static long bch2_ioctl_subvolume_rollback(struct bch_fs *c, struct file *filp,
+					  struct bch_ioctl_subvolume_rollback arg)
+{
+	CLASS(printbuf, err)();
+	long ret = __bch2_ioctl_subvolume_rollback(c, filp, arg, &err);
+	return bch2_copy_ioctl_err_msg(&arg.err, &err, ret);
+}
+
+static long __bch2_ioctl_subvolume_set(struct bch_fs *c, struct file *filp,
+				       struct bch_ioctl_subvolume_set arg,
+				       struct printbuf *err)
+{
+	struct path path;
+	int ret;
+
+	if (arg.flags & ~(BCH_SUBVOL_SET_RO|
+			  BCH_SUBVOL_SET_RETENTION|
+			  BCH_SUBVOL_SET_LABEL|
+			  BCH_SUBVOL_SET_QUOTA|
+			  BCH_SUBVOL_SET_TREE_QUOTA))
+		return bch_err_throw(c, EINVAL_subvol_set_bad_flags);
+
+	/* Owning a subvolume doesn't entitle you to more space: */
+	if ((arg.flags & (BCH_SUBVOL_SET_QUOTA|BCH_SUBVOL_SET_TREE_QUOTA)) &&
+	    !capable(CAP_SYS_ADMIN))
+		return bch_err_throw(c, EPERM_non_admin);
+
+	ret = user_path_at(arg.dirfd, (const char __user *)(unsigned long)arg.path_ptr,
+			   LOOKUP_FOLLOW, &path);
+	if (ret)
+		return ret;
+
+	if (path.dentry->d_sb->s_fs_info != c) {
+		prt_str(err, "path not on this filesystem");
+		ret = -EXDEV;
+		goto err;
+	}
+
+	struct inode *vinode = d_inode(path.dentry);
+	if (!inode_owner_or_capable(file_mnt_idmap(filp), vinode)) {
+		ret = bch_err_throw(c, EPERM_non_admin_or_owner);
+		goto err;
+	}
+
+	subvol_inum inum = inode_inum(to_bch_ei(vinode));
+	bool make_ro = (arg.flags & BCH_SUBVOL_SET_RO) && arg.ro;
+
+	/* Labels and quotas are in subvolume keys only from this version: */
+	if ((arg.flags & (BCH_SUBVOL_SET_LABEL|
+			  BCH_SUBVOL_SET_QUOTA|
+			  BCH_SUBVOL_SET_TREE_QUOTA)) &&
+	    c->sb.version < bcachefs_metadata_version_subvolume_label_quota) {
+		ret = bch_err_throw(c, EOPNOTSUPP_subvol_set_needs_upgrade);
+		goto err;
+	}
+
+	/*
+	 * Quotas are only checked once the superblock says one may be set -
+	 * so say so before setting it:
+	 */
+	if (((arg.flags & BCH_SUBVOL_SET_QUOTA) && arg.quota_sectors) ||
+	    ((arg.flags & BCH_SUBVOL_SET_TREE_QUOTA) && arg.tree_quota_sectors)) {
+		guard(mutex_noio)(&c->sb_lock);
+		if (!(c->sb.compat & BIT_ULL(BCH_COMPAT_subvol_quota))) {
+			c->disk_sb.sb->compat[0] |= cpu_to_le64(BIT_ULL(BCH_COMPAT_subvol_quota));
+			ret = bch2_write_super(c);
+			if (ret)
+				goto err;
+		}
+	}
+
+	/*
+	 * Going read-only: as for snapshot creation, flush dirty pagecache
+	 * first, and keep it from being dirtied again until we've committed:
+	 */
+	if (make_ro) {
+		percpu_down_write(&c->snapshots.create_lock);
+		scoped_guard(rwsem_read, &c->vfs_sb->s_umount)
+			sync_inodes_sb(c->vfs_sb);
+	}
+
+	{
+		CLASS(btree_trans, trans)(c);
+		ret = commit_do(trans, NULL, NULL, 0,
+			bch2_subvolume_set(trans, inum.subvol, inum.inum, &arg));
+	}
+
+	if (make_ro)
+		percpu_up_write(&c->snapshots.create_lock);
+
+	/* Reservations check the in-memory copy: */
+	if (!ret && (arg.flags & (BCH_SUBVOL_SET_QUOTA|BCH_SUBVOL_SET_TREE_QUOTA)))
+		ret = bch2_subvol_quotas_refresh(c);
+
+	if (bch2_err_matches(ret, BCH_ERR_EINVAL_subvol_set_not_subvol_root))
+		prt_str(err, "not a subvolume root");
+err:
+	path_put(&path);
+	return ret;
+}
+
+static long bch2_ioctl_subvolume_set(struct bch_fs *c, struct file *filp,
+				     struct bch_ioctl_subvolume_set arg)
+{
+	CLASS(printbuf, err)();
+	long ret = __bch2_ioctl_subvolume_set(c, filp, arg, &err);
+	return bch2_copy_ioctl_err_msg(&arg.err, &err, ret);
+}
+
 /*
  * Check if the current user can traverse from a child subvolume root
  * up to the parent subvolume, checking MAY_EXEC on each intermediate
@@ -1489,53 +1757,58 @@ static long bch2_ioc_propagate_reflink_p_opts(struct bch_fs *c,
 	}));
 }
 
-static long bch2_ioc_pread_raw(struct file *file,
-			       struct bch_inode_info *inode,
-			       struct bch_ioctl_pread_raw __user *uarg)
+static long __bch2_ioc_pread_raw(struct file *file,
+				 struct bch_inode_info *inode,
+				 struct bch_ioctl_pread_raw_v2 *arg,
+				 __u32 __user *uerrors)
 {
-	struct bch_ioctl_pread_raw arg;
 	struct bch_fs *c = file->f_inode->i_sb->s_fs_info;
 
-	if (copy_from_user(&arg, uarg, sizeof(arg)))
-		return -EFAULT;
-	if (arg.flags & ~BCH_PREAD_RAW_no_poison_check)
+	if (arg->flags & ~(BCH_PREAD_RAW_no_poison_check|BCH_PREAD_RAW_replica))
 		return -EINVAL;
-	if (arg.err.pad)
+	if (arg->pad || arg->err.pad)
 		return -EINVAL;
-	if (!arg.len)
+	if ((arg->flags & BCH_PREAD_RAW_replica) &&
+	    !bch2_dev_exists(c, arg->dev))
+		return bch_err_throw(c, ENOENT_dev_idx_not_found);
+	if (!arg->len)
 		return 0;
 	if (!(file->f_flags & O_DIRECT))
 		return -EINVAL;
 	if (!inode_owner_or_capable(file_mnt_idmap(file), &inode->v))
 		return bch_err_throw(c, EPERM_non_admin_or_owner);
 
-	loff_t pos = arg.offset;
-	int ret = rw_verify_area(READ, file, &pos, arg.len);
+	loff_t pos = arg->offset;
+	int ret = rw_verify_area(READ, file, &pos, arg->len);
 	if (ret)
 		return ret;
 
 	struct iov_iter iter;
-	import_ubuf(ITER_DEST, (void __user *)(unsigned long)arg.buf, arg.len, &iter);
+	import_ubuf(ITER_DEST, (void __user *)(unsigned long)arg->buf, arg->len, &iter);
 
 	struct kiocb kiocb;
 	init_sync_kiocb(&kiocb, file);
-	kiocb.ki_pos = arg.offset;
+	kiocb.ki_pos = arg->offset;
 
 	enum bch_read_flags read_flags = 0;
-	if (arg.flags & BCH_PREAD_RAW_no_poison_check)
+	if (arg->flags & BCH_PREAD_RAW_no_poison_check)
 		read_flags |= BCH_READ_no_poison_check;
+	if (arg->flags & BCH_PREAD_RAW_replica)
+		read_flags |= BCH_READ_single_replica|
+			BCH_READ_hard_require_read_device;
 
 	struct bch_read_err_report err_report;
 	mutex_init(&err_report.lock);
 	err_report.errors = 0;
 	err_report.msg = (struct printbuf) PRINTBUF;
+	err_report.dev = arg->dev;
 
 	ret = bch2_direct_IO_read(&kiocb, &iter, read_flags, &err_report);
 
-	if (copy_to_user(&uarg->errors, &err_report.errors, sizeof(err_report.errors)))
+	if (copy_to_user(uerrors, &err_report.errors, sizeof(err_report.errors)))
 		ret = -EFAULT;
 
-	int err = bch2_copy_ioctl_err_msg(&arg.err, &err_report.msg, ret < 0 ? ret : 0);
+	int err = bch2_copy_ioctl_err_msg(&arg->err, &err_report.msg, ret < 0 ? ret : 0);
 	if (err && !ret)
 		ret = err;
 
@@ -1543,6 +1816,40 @@ static long bch2_ioc_pread_raw(struct file *file,
 	return ret;
 }
 
+static long bch2_ioc_pread_raw(struct file *file,
+			       struct bch_inode_info *inode,
+			       struct bch_ioctl_pread_raw __user *uarg)
+{
+	struct bch_ioctl_pread_raw arg;
+
+	if (copy_from_user(&arg, uarg, sizeof(arg)))
+		return -EFAULT;
+	if (arg.flags & ~BCH_PREAD_RAW_no_poison_check)
+		return -EINVAL;
+
+	struct bch_ioctl_pread_raw_v2 arg_v2 = {
+		.offset	= arg.offset,
+		.len	= arg.len,
+		.buf	= arg.buf,
+		.flags	= arg.flags,
+		.err	= arg.err,
+	};
+
+	return __bch2_ioc_pread_raw(file, inode, &arg_v2, &uarg->errors);
+}
+
+static long bch2_ioc_pread_raw_v2(struct file *file,
+				  struct bch_inode_info *inode,
+				  struct bch_ioctl_pread_raw_v2 __user *uarg)
+{
+	struct bch_ioctl_pread_raw_v2 arg;
+
+	if (copy_from_user(&arg, uarg, sizeof(arg)))
+		return -EFAULT;
+
+	return __bch2_ioc_pread_raw(file, inode, &arg, &uarg->errors);
+}
+
 static int bch2_unpoison_extent(struct btree_trans *trans, struct btree_iter *iter,
 			       struct bkey_s_c k)
 {
@@ -1608,6 +1915,31 @@ static long bch2_ioc_unpoison(struct bch_fs *c, struct file *file,
 	}));
 }
 
+static long bch2_ioc_reconcile_kick(struct bch_fs *c,
+				    struct bch_ioctl_reconcile_kick __user *uarg)
+{
+	struct bch_ioctl_reconcile_kick arg;
+
+	if (!capable(CAP_SYS_ADMIN))
+		return -EPERM;
+
+	if (copy_from_user(&arg, uarg, sizeof(arg)))
+		return -EFAULT;
+	if (arg.flags || arg.nr > BCH_RECONCILE_KICK_BATCH_MAX)
+		return -EINVAL;
+	if (!arg.nr)
+		return 0;
+
+	u64 *inums __free(kvfree) = kvmalloc_array(arg.nr, sizeof(inums[0]), GFP_KERNEL);
+	if (!inums)
+		return -ENOMEM;
+
+	if (copy_from_user(inums, u64_to_user_ptr(arg.inums), arg.nr * sizeof(inums[0])))
+		return -EFAULT;
+
+	return bch2_reconcile_kick_inodes(c, inums, arg.nr);
+}
+
 long bch2_fs_file_ioctl(struct file *file, unsigned cmd, unsigned long arg)
 {
 	struct bch_inode_info *inode = file_bch_inode(file);
@@ -1696,6 +2028,24 @@ long bch2_fs_file_ioctl(struct file *file, unsigned cmd, unsigned long arg)
 		break;
 	}
 
+	case BCH_IOCTL_SUBVOLUME_ROLLBACK: {
+		struct bch_ioctl_subvolume_rollback i;
+
+		ret = copy_from_user(&i, (void __user *) arg, sizeof(i))
+			? -EFAULT
+			: bch2_ioctl_subvolume_rollback(c, file, i);
+		break;
+	}
+
+	case BCH_IOCTL_SUBVOLUME_SET: {
+		struct bch_ioctl_subvolume_set i;
+
+		ret = copy_from_user(&i, (void __user *) arg, sizeof(i))
+			? -EFAULT
+			: bch2_ioctl_subvolume_set(c, file, i);
+		break;
+	}
+
 	case BCH_IOCTL_SUBVOLUME_LIST:
 		ret = bch2_ioctl_subvolume_list(c, file,
 				(struct bch_ioctl_subvol_readdir __user *) arg);
@@ -1721,11 +2071,21 @@ long bch2_fs_file_ioctl(struct file *file, unsigned cmd, unsigned long arg)
 				(struct bch_ioctl_pread_raw __user *) arg);
 		break;
 
+	case BCHFS_IOC_PREAD_RAW_v2:
+		ret = bch2_ioc_pread_raw_v2(file, inode,
+				(struct bch_ioctl_pread_raw_v2 __user *) arg);
+		break;
+
 	case BCHFS_IOC_UNPOISON:
 		ret = bch2_ioc_unpoison(c, file, inode,
 				(struct bch_ioctl_unpoison __user *) arg);
 		break;
 
+	case BCHFS_IOC_RECONCILE_KICK:
+		ret = bch2_ioc_reconcile_kick(c,
+				(struct bch_ioctl_reconcile_kick __user *) arg);
+		break;
+
 	default:
 		ret = bch2_fs_ioctl(c, cmd, (void __user *) arg);
 		break;
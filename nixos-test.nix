@@ -24,8 +24,17 @@ self': {
           size = 4096;
           driveConfig.deviceExtraOpts.serial = "test-disk";
         }
+        {
+          size = 1024;
+          driveConfig.deviceExtraOpts.serial = "scrub-disk";
+        }
       ];
 
+      # scrub --resume: reads are slowed down with dm-delay, so that the
+      # first scrub is reliably stopped partway
+      boot.kernelModules = [ "dm_delay" ];
+      environment.systemPackages = [ pkgs.lvm2 ];
+
       boot.supportedFilesystems.bcachefs = true;
       boot.bcachefs.package = self'.packages.bcachefs-tools;
     };
@@ -37,5 +46,39 @@ self': {
       "mkdir /mnt",
       "mount /dev/disk/by-id/virtio-test-disk /mnt",
     )
+
+    # Scrub stopped partway, then continued with --resume: the checkpoint in
+    # the member has to survive the stop, and the resumed scrub finish it
+    disk = "/dev/disk/by-id/virtio-scrub-disk"
+    size = machine.succeed(f"blockdev --getsz {disk}").strip()
+
+    def scrub_table(delay_ms):
+        machine.succeed(
+            "dmsetup suspend --nolockfs scrub",
+            f"dmsetup reload scrub --table '0 {size} delay {disk} 0 {delay_ms}'",
+            "dmsetup resume scrub",
+        )
+
+    machine.succeed(
+        f"dmsetup create scrub --table '0 {size} delay {disk} 0 0'",
+        "mkfs.bcachefs /dev/mapper/scrub",
+        "mkdir /mnt-scrub",
+        "mount /dev/mapper/scrub /mnt-scrub",
+        "dd if=/dev/urandom of=/mnt-scrub/data bs=1M count=256 conv=fsync",
+    )
+
+    scrub_table(200)
+    out = machine.succeed("bcachefs scrub --max-duration 3s /mnt-scrub 2>&1")
+    assert "continue with --resume" in out, out
+
+    status = machine.succeed("bcachefs scrub status /mnt-scrub")
+    assert "never" in status and "since" in status, status
+
+    scrub_table(0)
+    out = machine.succeed("bcachefs scrub --resume /mnt-scrub 2>&1")
+    assert "Resuming scrub" in out, out
+
+    status = machine.succeed("bcachefs scrub status /mnt-scrub")
+    assert "never" not in status and "since" not in status, status
   '';
 }
@@ -5,6 +5,7 @@ mod device_multipath;
 mod device_scan;
 mod fs_context;
 mod key;
+mod key_server;
 mod dump_stack;
 mod logging;
 mod prompt;
@@ -290,11 +291,14 @@ fn main() -> ExitCode {
 
     let args: Vec<String> = std::env::args().collect();
 
-    // Handle symlink invocations (mkfs.bcachefs, fsck.bcachefs, mount.bcachefs, etc.)
+    // Handle symlink invocations (mkfs.bcachefs, fsck.bcachefs, mount.bcachefs,
+    // bcachefs-generator, etc.)
     let symlink_cmd: Option<&str> = if args[0].contains("mkfs") {
         Some("format")
     } else if args[0].contains("fsck") {
         Some("fsck")
+    } else if args[0].contains("generator") {
+        Some("systemd-generator")
     } else if args[0].contains("mount.fuse") {
         Some("fusemount")
     } else if args[0].contains("mount") {
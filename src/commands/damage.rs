@@ -7,16 +7,26 @@
 // accumulated error list (unioned across ancestor snapshots) as
 // bch_sb_error_id, printed with the same names fsck and the superblock
 // error counters use.
+//
+// Export and restore add the data side: the byte ranges of a damaged file
+// that sit in poisoned extents (see wrappers::file_extents). Restore
+// rewrites exactly those ranges from a backup copy - a fresh write
+// replaces the poisoned extent - and then clears the damage record. The
+// backup must match the file everywhere the file can still be read, so we
+// don't patch one version of a file with another.
 
 use std::mem;
 use std::os::fd::OwnedFd;
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bch_bindgen::c;
 use clap::{Parser, Subcommand};
 
-use crate::util::{open_dir, sb_error_name};
+use crate::util::{open_dir, path_subvol, sb_error_name};
+use crate::wrappers::file_extents::{file_extents, open_fs_of};
+use crate::wrappers::handle::BcachefsHandle;
 use crate::wrappers::ioctl::{ioctl_none, ioctl_ptr, ioctl_rw, IoctlBuf,
     BCHFS_IOC_CLEAR_DAMAGE, BCHFS_IOC_GET_DAMAGE, BCHFS_IOC_READDIR_FLAGS};
 
@@ -55,6 +65,33 @@ enum Subcommands {
 
         path: PathBuf,
     },
+
+    /// List every damaged file under a directory, with its subvolume,
+    /// errors and the byte ranges in poisoned extents
+    Export {
+        /// JSON output
+        #[arg(long)]
+        json: bool,
+
+        /// Directory to export (default: current directory)
+        path: Option<PathBuf>,
+    },
+
+    /// Rewrite the poisoned ranges of damaged files from a backup copy -
+    /// which must match them everywhere else - then clear their damage
+    /// records
+    Restore {
+        /// Root of the backup tree: the same layout as <path>
+        #[arg(long = "from")]
+        from: PathBuf,
+
+        /// Show what would be restored, without writing
+        #[arg(short = 'n', long = "dry-run")]
+        dry_run: bool,
+
+        /// Directory (or single file) to restore
+        path: PathBuf,
+    },
 }
 
 // ---- Ioctl layer ----
@@ -287,12 +324,291 @@ fn cmd_clear(path: &Path, recursive: bool) -> Result<()> {
     Ok(())
 }
 
+// ---- Export and restore ----
+
+/// A damaged file, as export reports it:
+struct DamagedFile {
+    /// Relative to the exported directory
+    name:   PathBuf,
+    subvol: u64,
+    inum:   u64,
+    size:   u64,
+    errors: Vec<DamageEntry>,
+    /// Byte ranges [start, end) in poisoned extents, sorted and merged
+    ranges: Vec<(u64, u64)>,
+}
+
+/// Byte ranges of the file's data in poisoned extents, merged:
+fn poisoned_ranges(handle: &BcachefsHandle, subvol: u64, inum: u64) -> Result<Vec<(u64, u64)>> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for e in file_extents(handle, subvol, inum, 0, u64::MAX)? {
+        if !e.poisoned {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.1 >= e.start => last.1 = last.1.max(e.end),
+            _ => ranges.push((e.start, e.end)),
+        }
+    }
+    Ok(ranges)
+}
+
+fn damaged_file(handle: &BcachefsHandle, root: &Path, name: PathBuf) -> Result<DamagedFile> {
+    let path = root.join(&name);
+    let meta = std::fs::symlink_metadata(&path)
+        .with_context(|| format!("statting {}", path.display()))?;
+    let subvol = path_subvol(&path).map(|(_, subvol)| subvol)
+        .ok_or_else(|| anyhow!("{}: no subvolume reported", path.display()))?;
+
+    let fd: OwnedFd = std::fs::File::open(&path)
+        .with_context(|| format!("opening {}", path.display()))?
+        .into();
+    let errors = get_damage(&fd).context("BCHFS_IOC_GET_DAMAGE")?;
+
+    let ranges = if meta.is_file() {
+        poisoned_ranges(handle, subvol, meta.ino())?
+    } else {
+        Vec::new()
+    };
+
+    Ok(DamagedFile { name, subvol, inum: meta.ino(), size: meta.len(), errors, ranges })
+}
+
+/// Every damaged file under @path (or @path itself, if it's a file):
+fn damaged_files(handle: &BcachefsHandle, path: &Path) -> Result<Vec<DamagedFile>> {
+    if !std::fs::metadata(path)
+        .with_context(|| format!("statting {}", path.display()))?
+        .is_dir()
+    {
+        return Ok(vec![damaged_file(handle, Path::new(""), path.to_path_buf())?]);
+    }
+
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = open_dir(path)?;
+    let flags = c::BCH_READDIR_damaged | c::BCH_READDIR_recursive;
+    let mut ret = Vec::new();
+
+    let mut pos = [0u64; 2];
+    loop {
+        let entries = readdir_flags(&dir, flags, &mut pos)
+            .context("BCHFS_IOC_READDIR_FLAGS")?;
+        if entries.is_empty() {
+            return Ok(ret);
+        }
+
+        for e in entries {
+            let name = PathBuf::from(std::ffi::OsStr::from_bytes(&e.name));
+            match damaged_file(handle, path, name) {
+                Ok(f) => ret.push(f),
+                Err(err) => eprintln!("{err:#}"),
+            }
+        }
+    }
+}
+
+fn damaged_file_to_json(root: &Path, f: &DamagedFile) -> serde_json::Value {
+    serde_json::json!({
+        "path":   root.join(&f.name),
+        "subvol": f.subvol,
+        "inum":   f.inum,
+        "size":   f.size,
+        "errors": f.errors.iter().map(|e| serde_json::json!({
+            "id":         e.id,
+            "name":       sb_error_name(e.id),
+            "nr":         e.nr,
+            "first_seen": e.first,
+            "last_seen":  e.last,
+        })).collect::<Vec<_>>(),
+        "damaged_ranges": f.ranges.iter().map(|(s, e)| serde_json::json!({
+            "offset": s,
+            "len":    e - s,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+fn cmd_export(path: &Path, json: bool) -> Result<()> {
+    let root = path.canonicalize()
+        .with_context(|| format!("resolving {}", path.display()))?;
+    let handle = open_fs_of(&root)?;
+
+    let files = damaged_files(&handle, &root)?;
+
+    if json {
+        let v: Vec<_> = files.iter().map(|f| damaged_file_to_json(&root, f)).collect();
+        println!("{}", serde_json::to_string_pretty(&v)?);
+        return Ok(());
+    }
+
+    for f in &files {
+        println!("{} (subvol {}, inode {}): {}",
+                 root.join(&f.name).display(), f.subvol, f.inum,
+                 f.errors.iter().map(|e| sb_error_name(e.id))
+                     .collect::<Vec<_>>().join(" "));
+        for (s, e) in &f.ranges {
+            println!("  damaged {s}-{e} ({} bytes)", e - s);
+        }
+    }
+    Ok(())
+}
+
+/// Copy [start, end) from @src to @dst, clamped to the file size.
+fn copy_range(src: &std::fs::File, dst: &std::fs::File, start: u64, end: u64) -> std::io::Result<()> {
+    let mut buf = vec![0u8; 1 << 20];
+    let mut off = start;
+    while off < end {
+        let n = ((end - off) as usize).min(buf.len());
+        let n = src.read_at(&mut buf[..n], off)?;
+        if n == 0 {
+            break;
+        }
+        dst.write_all_at(&buf[..n], off)?;
+        off += n as u64;
+    }
+    Ok(())
+}
+
+/// First offset in [start, end) at which @a and @b differ, if any.
+fn compare_range(a: &std::fs::File, b: &std::fs::File, start: u64, end: u64)
+    -> std::io::Result<Option<u64>>
+{
+    let mut buf_a = vec![0u8; 1 << 20];
+    let mut buf_b = vec![0u8; 1 << 20];
+    let mut off = start;
+    while off < end {
+        let n = ((end - off) as usize).min(buf_a.len());
+        a.read_exact_at(&mut buf_a[..n], off)?;
+        b.read_exact_at(&mut buf_b[..n], off)?;
+        if let Some(i) = buf_a[..n].iter().zip(&buf_b[..n]).position(|(x, y)| x != y) {
+            return Ok(Some(off + i as u64));
+        }
+        off += n as u64;
+    }
+    Ok(None)
+}
+
+/// First offset outside the damaged ranges - the only data we can still read
+/// - at which the file and its backup differ, if any.
+fn compare_undamaged(file: &std::fs::File, backup: &std::fs::File, f: &DamagedFile)
+    -> std::io::Result<Option<u64>>
+{
+    let mut pos = 0;
+    for &(s, e) in f.ranges.iter().chain(std::iter::once(&(f.size, f.size))) {
+        let s = s.min(f.size);
+        if pos < s {
+            if let Some(off) = compare_range(file, backup, pos, s)? {
+                return Ok(Some(off));
+            }
+        }
+        pos = pos.max(e);
+    }
+    Ok(None)
+}
+
+fn restore_file(path: &Path, backup_path: &Path, f: &DamagedFile, dry_run: bool) -> Result<()> {
+    if f.ranges.is_empty() {
+        bail!("{}: no damaged data ranges (metadata damage only); not cleared",
+              path.display());
+    }
+
+    let src = std::fs::File::open(backup_path)
+        .with_context(|| format!("opening backup {}", backup_path.display()))?;
+    let src_size = src.metadata()?.len();
+    if src_size != f.size {
+        bail!("{}: size {} differs from backup {} ({}): not the same version of the file",
+              path.display(), f.size, backup_path.display(), src_size);
+    }
+
+    /*
+     * Same size isn't the same version: everything outside the damaged
+     * ranges has to match too, or we'd patch one version with another.
+     */
+    let cur = std::fs::File::open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    if let Some(off) = compare_undamaged(&cur, &src, f)
+        .with_context(|| format!("comparing {} with {}", path.display(), backup_path.display()))?
+    {
+        bail!("{}: differs from backup {} at offset {off}, outside the damaged ranges: \
+               not the same version of the file",
+              path.display(), backup_path.display());
+    }
+
+    for (s, e) in &f.ranges {
+        println!("{}: restoring {s}-{e} from {}", path.display(), backup_path.display());
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let dst = std::fs::OpenOptions::new().write(true).open(path)
+        .with_context(|| format!("opening {} for write", path.display()))?;
+    for (s, e) in &f.ranges {
+        copy_range(&src, &dst, *s, *e)
+            .with_context(|| format!("restoring {}", path.display()))?;
+    }
+    dst.sync_data()
+        .with_context(|| format!("syncing {}", path.display()))?;
+
+    clear_fd(&dst.into())
+        .with_context(|| format!("clearing damage on {}", path.display()))
+}
+
+fn cmd_restore(path: &Path, backup: &Path, dry_run: bool) -> Result<()> {
+    let path = path.canonicalize()
+        .with_context(|| format!("resolving {}", path.display()))?;
+    let handle = open_fs_of(&path)?;
+
+    let files = damaged_files(&handle, &path)?;
+    let is_dir = path.is_dir();
+    let mut failed = 0u64;
+    let mut skipped = 0u64;
+
+    for f in &files {
+        /* A directory restores from the same layout under --from; a single
+         * file from --from itself: */
+        let (dst, src) = if is_dir {
+            (path.join(&f.name), backup.join(&f.name))
+        } else {
+            (path.clone(), backup.to_path_buf())
+        };
+
+        /*
+         * A damaged directory's damage is to its entries, not to data we
+         * could copy back: nothing to restore from the backup.
+         */
+        if std::fs::symlink_metadata(&dst).is_ok_and(|m| m.is_dir()) {
+            println!("{}: directory, no data to restore: skipped; check its entries \
+                      against the backup and clear it with damage clear", dst.display());
+            skipped += 1;
+            continue;
+        }
+
+        if let Err(err) = restore_file(&dst, &src, f, dry_run) {
+            eprintln!("{err:#}");
+            failed += 1;
+        }
+    }
+
+    if skipped != 0 {
+        println!("skipped {skipped} damaged directories");
+    }
+
+    if failed != 0 {
+        bail!("failed to restore {failed} of {} files", files.len());
+    }
+    Ok(())
+}
+
 pub fn damage(cli: Cli) -> Result<()> {
     match cli.subcommands {
         Some(Subcommands::Ls { recursive, path }) =>
             cmd_ls(&path.unwrap_or_else(|| ".".into()), recursive),
         Some(Subcommands::Clear { recursive, path }) =>
             cmd_clear(&path, recursive),
+        Some(Subcommands::Export { json, path }) =>
+            cmd_export(&path.unwrap_or_else(|| ".".into()), json),
+        Some(Subcommands::Restore { from, dry_run, path }) =>
+            cmd_restore(&path, &from, dry_run),
         None =>
             cmd_show(&cli.path.context("a path, or the ls subcommand, is required")?),
     }
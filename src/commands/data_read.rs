@@ -3,14 +3,22 @@
 // O_DIRECT read with detailed error information. Reports checksum,
 // IO, decompression, and EC errors via a bitmask plus kernel error
 // messages. With --no-poison-check, reads data from poisoned extents.
+//
+// --replica reads one device's copy only; --all-replicas reads every copy
+// of each extent in the range in turn and compares them, to tell which
+// devices hold good data before unpoisoning or dropping a device.
 
-use std::fs::OpenOptions;
-use std::os::unix::fs::OpenOptionsExt;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
 
+use anyhow::{anyhow, bail};
 use bch_bindgen::c;
 use clap::Parser;
 
-use crate::wrappers::ioctl::{ioctl_rw, BCHFS_IOC_PREAD_RAW};
+use crate::util::path_subvol;
+use crate::wrappers::file_extents::{file_extents, open_fs_of};
+use crate::wrappers::ioctl::{ioctl_rw, BCHFS_IOC_PREAD_RAW, BCHFS_IOC_PREAD_RAW_v2};
 
 const SECTOR_SIZE: u64 = 512;
 
@@ -27,6 +35,13 @@ const SECTOR_SIZE: u64 = 512;
 /// marked as poisoned due to checksum failures. This is useful for data
 /// recovery — the data may be corrupt, but it's what's on disk.
 ///
+/// With --replica, reads only the copy on one device: a checksum error
+/// is reported rather than retried from another replica, and doesn't
+/// poison the extent. --all-replicas does that for every copy of each
+/// extent in the range and reports which devices hold good copies.
+/// Cached copies are labelled as such and don't count as bad: they may be
+/// stale.
+///
 /// Without any flags, behaves like a normal O_DIRECT read but with
 /// better error diagnostics.
 pub struct Cli {
@@ -49,12 +64,114 @@ pub struct Cli {
     #[arg(long)]
     no_poison_check: bool,
 
+    /// Read only the replica on this device (index)
+    #[arg(long, conflicts_with = "all_replicas")]
+    replica: Option<u32>,
+
+    /// Read and verify every replica of each extent in the range, and
+    /// report which devices hold good and bad copies
+    #[arg(long, conflicts_with = "output")]
+    all_replicas: bool,
+
     /// Hex dump width
     #[arg(long, default_value = "16")]
     width: usize,
 }
 
+/// Sector aligned buffer, for O_DIRECT:
+struct AlignedBuf {
+    ptr:    *mut u8,
+    layout: std::alloc::Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> anyhow::Result<Self> {
+        let layout = std::alloc::Layout::from_size_align(len.max(1), SECTOR_SIZE as usize)?;
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            bail!("failed to allocate {} byte aligned buffer", len);
+        }
+        Ok(AlignedBuf { ptr, layout })
+    }
+
+    fn as_slice(&self, len: usize) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Result of one BCHFS_IOC_PREAD_RAW call:
+struct RawRead {
+    ret:    std::io::Result<i32>,
+    /// BCH_PREAD_RAW_ERR_*
+    errors: u32,
+    msg:    String,
+}
+
+fn read_errors_str(errors: u32) -> String {
+    let mut v = Vec::new();
+    if errors & (1 << 0) != 0 { v.push("checksum"); }
+    if errors & (1 << 1) != 0 { v.push("io"); }
+    if errors & (1 << 2) != 0 { v.push("decompression"); }
+    if errors & (1 << 3) != 0 { v.push("ec_reconstruct"); }
+    v.join(", ")
+}
+
+fn pread_raw(file: &File, buf: &AlignedBuf, offset: u64, len: u64,
+             no_poison_check: bool, replica: Option<u32>) -> RawRead {
+    let mut err_msg_buf = vec![0u8; 4096];
+    let err = c::bch_ioctl_err_msg {
+        msg_ptr: err_msg_buf.as_mut_ptr() as u64,
+        msg_len: err_msg_buf.len() as u32,
+        pad:     0,
+    };
+    let flags = if no_poison_check { c::BCH_PREAD_RAW_no_poison_check } else { 0 };
+
+    let mut arg = c::bch_ioctl_pread_raw_v2 {
+        offset,
+        len,
+        buf:    buf.ptr as u64,
+        flags:  flags | if replica.is_some() { c::BCH_PREAD_RAW_replica } else { 0 },
+        errors: 0,
+        dev:    replica.unwrap_or(0),
+        pad:    0,
+        err,
+    };
+
+    let mut ret = ioctl_rw::<BCHFS_IOC_PREAD_RAW_v2>(file, &mut arg);
+    let mut errors = arg.errors;
+
+    /* Older kernels: v1 does everything but --replica */
+    if replica.is_none() && ret.as_ref().is_err_and(|e| e.raw_os_error() == Some(libc::ENOTTY)) {
+        let mut arg = c::bch_ioctl_pread_raw {
+            offset,
+            len,
+            buf:    buf.ptr as u64,
+            flags,
+            errors: 0,
+            err,
+        };
+        ret = ioctl_rw::<BCHFS_IOC_PREAD_RAW>(file, &mut arg);
+        errors = arg.errors;
+    }
+
+    let err_len = err_msg_buf.iter().position(|&b| b == 0).unwrap_or(0);
+    RawRead {
+        ret,
+        errors,
+        msg: String::from_utf8_lossy(&err_msg_buf[..err_len]).into_owned(),
+    }
+}
+
 fn cmd_data_read(cli: Cli) -> anyhow::Result<()> {
+    if cli.all_replicas {
+        return cmd_data_read_all_replicas(&cli);
+    }
     cmd_data_read_inner(&cli)
 }
 
@@ -68,53 +185,25 @@ fn cmd_data_read_inner(cli: &Cli) -> anyhow::Result<()> {
         .custom_flags(libc::O_DIRECT)
         .open(&cli.file)?;
 
-    // Allocate aligned buffer
-    let layout = std::alloc::Layout::from_size_align(cli.len as usize, SECTOR_SIZE as usize)?;
-    let buf = unsafe { std::alloc::alloc_zeroed(layout) };
-    if buf.is_null() {
-        anyhow::bail!("failed to allocate {} byte aligned buffer", cli.len);
-    }
-
-    let mut err_msg_buf = vec![0u8; 4096];
-
-    let mut arg = c::bch_ioctl_pread_raw {
-        offset: cli.offset,
-        len:    cli.len,
-        buf:    buf as u64,
-        flags:  if cli.no_poison_check { 1 << 0 } else { 0 },
-        errors: 0,
-        err: c::bch_ioctl_err_msg {
-            msg_ptr: err_msg_buf.as_mut_ptr() as u64,
-            msg_len: err_msg_buf.len() as u32,
-            pad:     0,
-        },
-    };
-
-    let ret = ioctl_rw::<BCHFS_IOC_PREAD_RAW>(&file, &mut arg);
+    let buf = AlignedBuf::new(cli.len as usize)?;
+    let r = pread_raw(&file, &buf, cli.offset, cli.len, cli.no_poison_check, cli.replica);
 
     // Show error info
-    if arg.errors != 0 {
-        let mut errors = Vec::new();
-        if arg.errors & (1 << 0) != 0 { errors.push("checksum"); }
-        if arg.errors & (1 << 1) != 0 { errors.push("io"); }
-        if arg.errors & (1 << 2) != 0 { errors.push("decompression"); }
-        if arg.errors & (1 << 3) != 0 { errors.push("ec_reconstruct"); }
-        eprintln!("errors: {}", errors.join(", "));
+    if r.errors != 0 {
+        eprintln!("errors: {}", read_errors_str(r.errors));
     }
 
     // Show error message from kernel
-    let err_len = err_msg_buf.iter().position(|&b| b == 0).unwrap_or(0);
-    if err_len > 0 {
-        let msg = String::from_utf8_lossy(&err_msg_buf[..err_len]);
-        eprintln!("kernel: {}", msg);
+    if !r.msg.is_empty() {
+        eprintln!("kernel: {}", r.msg);
     }
 
-    if let Err(errno) = &ret {
+    if let Err(errno) = &r.ret {
         eprintln!("ioctl returned error: {}", errno);
         // Still dump whatever data we got — that's the point
     }
 
-    let data = unsafe { std::slice::from_raw_parts(buf, cli.len as usize) };
+    let data = buf.as_slice(cli.len as usize);
 
     if let Some(ref path) = cli.output {
         std::fs::write(path, data)?;
@@ -145,9 +234,124 @@ fn cmd_data_read_inner(cli: &Cli) -> anyhow::Result<()> {
         }
     }
 
-    unsafe { std::alloc::dealloc(buf, layout); }
+    if r.ret.is_err() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// How one replica of an extent read back:
+enum ReplicaState {
+    Good,
+    /// Passed its checksum but doesn't match the first good copy - only
+    /// possible for unchecksummed data:
+    Differs,
+    Bad(String),
+}
+
+fn cmd_data_read_all_replicas(cli: &Cli) -> anyhow::Result<()> {
+    if (cli.offset | cli.len) & (SECTOR_SIZE - 1) != 0 {
+        bail!("offset and len must be sector-aligned ({} bytes)", SECTOR_SIZE);
+    }
+
+    let path = Path::new(&cli.file);
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)?;
+    let meta = file.metadata()?;
+    let subvol = path_subvol(path).map(|(_, subvol)| subvol)
+        .ok_or_else(|| anyhow!("{}: not on a bcachefs filesystem", cli.file))?;
+    let handle = open_fs_of(path)?;
+
+    /* --len 0 (or past EOF): to the end of the file */
+    let file_end = meta.len().next_multiple_of(SECTOR_SIZE);
+    let end = if cli.len == 0 { file_end } else { (cli.offset + cli.len).min(file_end) };
+
+    let extents = file_extents(&handle, subvol, meta.ino(), cli.offset, end)?;
+    if extents.is_empty() {
+        println!("no data extents in {}-{}", cli.offset, end);
+        return Ok(());
+    }
+
+    const CHUNK: u64 = 1 << 20;
+    let buf = AlignedBuf::new(CHUNK as usize)?;
+    let mut good_buf = vec![0u8; CHUNK as usize];
+    let mut nr_bad = 0u64;
+
+    for e in &extents {
+        println!("{}-{} ({} bytes){}", e.start, e.end, e.end - e.start,
+                 if e.poisoned { " poisoned" } else { "" });
+
+        /*
+         * Cached copies last, so that a stale one - its bucket reused since -
+         * isn't what the others are compared against; they're reported, but
+         * don't count towards the verdict:
+         */
+        let mut states: Vec<(u32, ReplicaState)> =
+            e.devs.iter().map(|&dev| (dev, ReplicaState::Good)).collect();
+        states.sort_by_key(|(dev, _)| e.cached.contains(dev));
+
+        let mut off = e.start;
+        while off < e.end {
+            let len = (e.end - off).min(CHUNK);
+            let mut have_good = false;
+
+            for (dev, state) in &mut states {
+                if !matches!(state, ReplicaState::Good) {
+                    continue;
+                }
+
+                let r = pread_raw(&file, &buf, off, len, true, Some(*dev));
+                if let Err(err) = &r.ret {
+                    let mut why = read_errors_str(r.errors);
+                    if why.is_empty() {
+                        why = err.to_string();
+                    }
+                    if !r.msg.is_empty() {
+                        why = format!("{why}: {}", r.msg.trim_end());
+                    }
+                    *state = ReplicaState::Bad(why);
+                    continue;
+                }
+
+                let data = buf.as_slice(len as usize);
+                if !have_good {
+                    good_buf[..len as usize].copy_from_slice(data);
+                    have_good = true;
+                } else if data != &good_buf[..len as usize] {
+                    *state = ReplicaState::Differs;
+                }
+            }
+
+            off += len;
+        }
+
+        for (dev, state) in &states {
+            let cached = if e.cached.contains(dev) { " (cached, may be stale)" } else { "" };
+            match state {
+                ReplicaState::Good    => println!("  dev {dev}{cached}: good"),
+                ReplicaState::Differs => println!("  dev {dev}{cached}: checksum ok, but differs from other copies"),
+                ReplicaState::Bad(why) => println!("  dev {dev}{cached}: bad ({why})"),
+            }
+        }
+
+        states.retain(|(dev, _)| !e.cached.contains(dev));
+        let good: Vec<String> = states.iter()
+            .filter(|(_, s)| matches!(s, ReplicaState::Good))
+            .map(|(dev, _)| dev.to_string())
+            .collect();
+        if good.len() != states.len() {
+            nr_bad += 1;
+        }
+        if good.is_empty() {
+            println!("  no good copy");
+        }
+    }
 
-    if ret.is_err() {
+    if nr_bad != 0 {
+        println!("{} of {} extents have bad or mismatched copies", nr_bad, extents.len());
         std::process::exit(1);
     }
 
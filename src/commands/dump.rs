@@ -17,30 +17,32 @@ use bcachefs_kernel::POS_MIN;
 use crate::qcow2::{self, Qcow2Image, Ranges, range_add, ranges_sort};
 use crate::wrappers::super_io::vstruct_bytes_sb;
 
-// Crypto for the sanitize path. These drive the wrapped bch2_encrypt /
-// bch2_checksum (and bset_encrypt) over the same byte ranges the kernel's
+// Crypto for the sanitize path. These drive the wrapped bch2_encrypt_key /
+// bch2_checksum_key (and bset_encrypt) over the same byte ranges the kernel's
 // bset_encrypt() and csum_vstruct() macro cover, so the metadata dump can
 // decrypt, edit, and re-checksum bsets and journal entries without a C shim.
 // @vstruct_bytes is the whole vstruct's byte length; the checksum covers
 // everything after the leading csum field, and journal encryption everything
 // after the encrypted_start marker. Encryption is symmetric, so the same call
-// both decrypts and re-encrypts.
+// both decrypts and re-encrypts. While a key rotation is in progress the key
+// depends on the generation the entry was written with, so pick it the same
+// way the kernel does.
 
 fn jset_encrypt(fs: &Fs, j: *mut c::jset, csum_type: u32, vstruct_bytes: usize) -> i32 {
     let off = std::mem::offset_of!(c::jset, encrypted_start);
     unsafe {
-        c::bch2_encrypt(fs.raw, csum_type, c::journal_nonce(j),
-                        (j as *mut u8).add(off) as *mut core::ffi::c_void,
-                        vstruct_bytes - off)
+        c::bch2_encrypt_key(fs.raw, c::bch2_jset_key(fs.raw, j), csum_type, c::journal_nonce(j),
+                            (j as *mut u8).add(off) as *mut core::ffi::c_void,
+                            vstruct_bytes - off)
     }
 }
 
 fn jset_csum_set(fs: &Fs, j: *mut c::jset, csum_type: u32, vstruct_bytes: usize) {
     let off = std::mem::size_of::<c::bch_csum>();
     let csum = unsafe {
-        c::bch2_checksum(fs.raw, csum_type, c::journal_nonce(j),
-                         (j as *const u8).add(off) as *const core::ffi::c_void,
-                         vstruct_bytes - off)
+        c::bch2_checksum_key(c::bch2_jset_key(fs.raw, j), csum_type, c::journal_nonce(j),
+                             (j as *const u8).add(off) as *const core::ffi::c_void,
+                             vstruct_bytes - off)
     };
     unsafe { (*j).csum = csum };
 }
@@ -51,9 +53,9 @@ fn bset_csum_set(fs: &Fs, node: *mut u8, i: *mut c::bset, offset: u32,
                  csum_type: u32, vstruct_bytes: usize) {
     let off = std::mem::size_of::<c::bch_csum>();
     let csum = unsafe {
-        c::bch2_checksum(fs.raw, csum_type, c::btree_nonce(i, offset),
-                         node.add(off) as *const core::ffi::c_void,
-                         vstruct_bytes - off)
+        c::bch2_checksum_key(c::bch2_bset_key(fs.raw, i), csum_type, c::btree_nonce(i, offset),
+                             node.add(off) as *const core::ffi::c_void,
+                             vstruct_bytes - off)
     };
     unsafe { *(node as *mut c::bch_csum) = csum };
 }
@@ -0,0 +1,486 @@
+//! `bcachefs fs exporter` - Prometheus/OpenMetrics metrics for mounted
+//! filesystems.
+//!
+//! Everything here is read fresh on every scrape, from the same sources the
+//! interactive tools use: query_accounting for `fs usage`, the counters ioctl
+//! and io_done for `fs top`, and the time_stats JSON for `fs timestats`. There
+//! is no state between scrapes, so rates are the scraper's business - counters
+//! are exported as counters, and Prometheus's rate() does the rest.
+//!
+//! Every sample is labelled with the filesystem UUID, and per-device samples
+//! with the device name as `fs usage` would print it.
+
+use std::fmt::{Display, Write as _};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bcachefs_kernel::sb::io::COUNTERS;
+use bcachefs_kernel::opts::{prt_compression_type, prt_data_type, prt_reconcile_type};
+use bcachefs_kernel::util::printbuf::Printbuf;
+use clap::Parser;
+use log::{error, info, warn};
+
+use crate::commands::DeviceNameArgs;
+use crate::commands::fs_usage_history;
+use crate::commands::timestats::{
+    find_all_sysfs_dirs, read_device_latency_stats, read_time_stats, TimeStats,
+};
+use crate::commands::top::{read_counters, read_device_io};
+use crate::wrappers::accounting::{data_type_is_empty, disk_accounting_type, DiskAccountingKind};
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::sysfs::{self, DevInfo, DeviceNameMode, sysfs_path_from_fd};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Scrapes come every few seconds; usage history wants a sample an hour
+const USAGE_HISTORY_INTERVAL: u64 = 60 * 60;
+
+/// Serve Prometheus/OpenMetrics metrics for mounted filesystems
+#[derive(Parser, Debug)]
+#[command(about,
+    long_about = "Serves OpenMetrics text on /metrics for scraping by \
+Prometheus or anything else that speaks its exposition format: space \
+accounting (capacity, replicas, compression, reconcile work), per-device usage \
+and IO, event counters and time stats, labelled by filesystem UUID and device \
+name. Everything is read fresh on each scrape.")]
+pub struct Cli {
+    /// Address to listen on: host:port, or a path for a unix socket
+    #[arg(short, long, default_value = "127.0.0.1:9436")]
+    listen: String,
+
+    /// Leave out time stats, which are most of the output
+    #[arg(long)]
+    no_time_stats: bool,
+
+    /// Also record usage history for `fs usage --history`, at most hourly
+    #[arg(long)]
+    usage_history: bool,
+
+    #[command(flatten)]
+    device_names: DeviceNameArgs,
+
+    /// Filesystems to export: paths, devices or UUIDs (default: all mounted)
+    filesystems: Vec<String>,
+}
+
+// OpenMetrics text format
+
+/// One metric family. OpenMetrics wants all of a family's samples together,
+/// under a single TYPE/HELP header, so samples are collected per family and
+/// written out at the end.
+struct Family {
+    name:    &'static str,
+    kind:    &'static str,
+    help:    &'static str,
+    samples: String,
+}
+
+#[derive(Default)]
+struct Metrics {
+    families: Vec<Family>,
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics {
+    fn sample(&mut self, name: &'static str, kind: &'static str, help: &'static str,
+              labels: &[(&str, &str)], value: impl Display) {
+        let i = match self.families.iter().position(|f| f.name == name) {
+            Some(i) => i,
+            None => {
+                self.families.push(Family { name, kind, help, samples: String::new() });
+                self.families.len() - 1
+            }
+        };
+        let f = &mut self.families[i];
+
+        let suffix = if kind == "counter" { "_total" } else { "" };
+        let labels: Vec<String> = labels.iter()
+            .map(|(k, v)| format!("{k}=\"{}\"", escape_label(v)))
+            .collect();
+        let _ = writeln!(f.samples, "{name}{suffix}{{{}}} {value}", labels.join(","));
+    }
+
+    fn gauge(&mut self, name: &'static str, help: &'static str,
+             labels: &[(&str, &str)], value: impl Display) {
+        self.sample(name, "gauge", help, labels, value)
+    }
+
+    fn counter(&mut self, name: &'static str, help: &'static str,
+               labels: &[(&str, &str)], value: impl Display) {
+        self.sample(name, "counter", help, labels, value)
+    }
+
+    fn to_text(&self) -> String {
+        let mut out = String::new();
+        for f in &self.families {
+            let _ = writeln!(out, "# TYPE {} {}", f.name, f.kind);
+            let _ = writeln!(out, "# HELP {} {}", f.name, f.help);
+            out.push_str(&f.samples);
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+fn to_str(f: impl FnOnce(&mut Printbuf)) -> String {
+    let mut buf = Printbuf::new();
+    f(&mut buf);
+    buf.as_str().to_string()
+}
+
+fn dev_name(devs: &[DevInfo], idx: u32) -> String {
+    devs.iter()
+        .find(|d| d.idx == idx)
+        .map_or_else(|| format!("dev-{idx}"), |d| d.dev.clone())
+}
+
+fn ns_to_secs(ns: u64) -> f64 {
+    ns as f64 / 1e9
+}
+
+// Collection
+
+fn accounting(m: &mut Metrics, handle: &BcachefsHandle, uuid: &str, devs: &[DevInfo]) -> Result<()> {
+    let r = handle.query_accounting(
+        disk_accounting_type::replicas.bit() |
+        disk_accounting_type::persistent_reserved.bit() |
+        disk_accounting_type::compression.bit() |
+        disk_accounting_type::reconcile_work.bit())
+        .map_err(|e| anyhow!("query_accounting: {e}"))?;
+
+    let fs = [("uuid", uuid)];
+    m.gauge("bcachefs_capacity_bytes", "Filesystem capacity", &fs, r.capacity << 9);
+    m.gauge("bcachefs_used_bytes", "Space used", &fs, r.used << 9);
+    m.gauge("bcachefs_online_reserved_bytes", "Space reserved by in-flight writes",
+            &fs, r.online_reserved << 9);
+
+    for e in &r.entries {
+        match e.pos.decode() {
+            DiskAccountingKind::PersistentReserved { nr_replicas } => {
+                let nr = nr_replicas.to_string();
+                m.gauge("bcachefs_persistent_reserved_bytes", "Space reserved by fallocate",
+                        &[("uuid", uuid), ("nr_replicas", &nr)], e.counter(0) << 9);
+            }
+            DiskAccountingKind::Replicas { data_type, nr_devs, nr_required, devs: dev_list } => {
+                let dt = to_str(|b| prt_data_type(b, data_type));
+                let required = nr_required.to_string();
+                let dev_list: Vec<String> = dev_list[..nr_devs as usize].iter()
+                    .map(|&i| dev_name(devs, i as u32))
+                    .collect();
+                let dev_list = dev_list.join(",");
+
+                m.gauge("bcachefs_replicas_bytes", "Space used, by data type and replica set",
+                        &[("uuid", uuid), ("data_type", &dt), ("nr_required", &required),
+                          ("devices", &dev_list)],
+                        e.counter(0) << 9);
+            }
+            DiskAccountingKind::Compression { compression_type } => {
+                let t = to_str(|b| prt_compression_type(b, compression_type));
+                let l = [("uuid", uuid), ("type", t.as_str())];
+
+                m.gauge("bcachefs_compression_extents", "Extents, by compression type",
+                        &l, e.counter(0));
+                m.gauge("bcachefs_compression_uncompressed_bytes",
+                        "Uncompressed size of data, by compression type", &l, e.counter(1) << 9);
+                m.gauge("bcachefs_compression_compressed_bytes",
+                        "Compressed size of data, by compression type", &l, e.counter(2) << 9);
+            }
+            DiskAccountingKind::ReconcileWork { work_type } => {
+                let t = to_str(|b| prt_reconcile_type(b, work_type));
+
+                for (i, kind) in ["data", "metadata"].into_iter().enumerate() {
+                    m.gauge("bcachefs_reconcile_work_bytes", "Pending reconcile work",
+                            &[("uuid", uuid), ("type", &t), ("kind", kind)],
+                            e.counter(i) << 9);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn devices(m: &mut Metrics, handle: &BcachefsHandle, uuid: &str, devs: &[DevInfo]) {
+    for d in devs {
+        let label = d.label.as_deref().unwrap_or("");
+        let l = [("uuid", uuid), ("device", d.dev.as_str()), ("label", label)];
+
+        m.gauge("bcachefs_device_online", "Whether the device is online", &l, d.online as u8);
+
+        if !d.online {
+            continue;
+        }
+
+        let u = match handle.dev_usage(d.idx) {
+            Ok(u) => u,
+            Err(e) => {
+                warn!("{uuid}: usage for device {}: {e}", d.dev);
+                continue;
+            }
+        };
+
+        m.gauge("bcachefs_device_capacity_bytes", "Device capacity", &l,
+                u.capacity_sectors() << 9);
+        m.gauge("bcachefs_device_used_bytes", "Space used on the device", &l,
+                u.used_sectors() << 9);
+
+        for (t, dt) in u.iter_typed() {
+            let name = to_str(|b| prt_data_type(b, t));
+            let l = [("uuid", uuid), ("device", d.dev.as_str()), ("data_type", name.as_str())];
+
+            let sectors = if data_type_is_empty(t) {
+                dt.buckets * u.bucket_size as u64
+            } else {
+                dt.sectors
+            };
+
+            m.gauge("bcachefs_device_data_bytes", "Space on the device, by data type", &l,
+                    sectors << 9);
+            m.gauge("bcachefs_device_buckets", "Buckets on the device, by data type", &l,
+                    dt.buckets);
+            m.gauge("bcachefs_device_fragmented_bytes",
+                    "Unused space in partially used buckets, by data type", &l,
+                    dt.fragmented << 9);
+        }
+    }
+}
+
+fn counters(m: &mut Metrics, handle: &BcachefsHandle, uuid: &str) -> Result<()> {
+    let nr_stable = COUNTERS.iter().map(|c| c.stable_id).max().unwrap_or(0) + 1;
+    let vals = read_counters(handle.ioctl_fd(), 0, nr_stable)?;
+
+    for c in COUNTERS {
+        let Some(&v) = vals.get(c.stable_id as usize) else { continue };
+        let l = [("uuid", uuid), ("counter", c.name)];
+
+        if c.is_sectors {
+            m.counter("bcachefs_counter_bytes", "Event counters measured in bytes", &l, v << 9);
+        } else {
+            m.counter("bcachefs_counter", "Event counters", &l, v);
+        }
+    }
+
+    Ok(())
+}
+
+fn device_io(m: &mut Metrics, sysfs_path: &Path, uuid: &str, name_mode: DeviceNameMode) {
+    for d in read_device_io(sysfs_path, name_mode) {
+        let Some((dev, data_type)) = d.label.rsplit_once('/') else { continue };
+
+        for (dir, v) in [("read", d.read_bytes), ("write", d.write_bytes)] {
+            m.counter("bcachefs_device_io_bytes", "IO done, by device and data type",
+                      &[("uuid", uuid), ("device", dev), ("data_type", data_type),
+                        ("direction", dir)],
+                      v);
+        }
+    }
+}
+
+fn time_stats_one(m: &mut Metrics, labels: &[(&str, &str)], s: &TimeStats, device: bool) {
+    let (count, secs, stat) = if device {
+        ("bcachefs_device_latency_count", "bcachefs_device_latency_seconds",
+         "bcachefs_device_latency_stat_seconds")
+    } else {
+        ("bcachefs_time_stats_count", "bcachefs_time_stats_seconds",
+         "bcachefs_time_stats_stat_seconds")
+    };
+
+    m.counter(count, "Number of events timed", labels, s.count);
+    m.counter(secs, "Total time spent", labels, ns_to_secs(s.duration_ns.total));
+
+    for (name, ns) in [("min",            s.duration_ns.min),
+                       ("max",            s.duration_ns.max),
+                       ("mean",           s.duration_ns.mean),
+                       ("stddev",         s.duration_ns.stddev),
+                       ("recent_mean",    s.duration_ewma_ns.mean),
+                       ("recent_stddev",  s.duration_ewma_ns.stddev)] {
+        let mut l = labels.to_vec();
+        l.push(("stat", name));
+        m.gauge(stat, "Duration statistics, since mount and recent", &l, ns_to_secs(ns));
+    }
+}
+
+fn time_stats(m: &mut Metrics, sysfs_path: &Path, uuid: &str, name_mode: DeviceNameMode)
+    -> Result<()>
+{
+    for e in read_time_stats(sysfs_path)? {
+        time_stats_one(m, &[("uuid", uuid), ("name", &e.name)], &e.stats, false);
+    }
+
+    for e in read_device_latency_stats(sysfs_path, name_mode)? {
+        let Some((dev, dir)) = e.name.rsplit_once('/') else { continue };
+        time_stats_one(m, &[("uuid", uuid), ("device", dev), ("direction", dir)],
+                       &e.stats, true);
+    }
+
+    Ok(())
+}
+
+/// One filesystem's metrics. A section that fails is left out with a warning
+/// rather than failing the scrape: a partial scrape beats none.
+fn scrape_fs(m: &mut Metrics, cli: &Cli, sysfs_path: &Path) -> Result<()> {
+    let uuid = sysfs_path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name_mode = cli.device_names.name_mode();
+
+    let handle = BcachefsHandle::open(&uuid)
+        .map_err(|e| anyhow!("opening filesystem {uuid}: {e}"))?;
+    let devs = sysfs::fs_get_devices(sysfs_path, name_mode)?;
+
+    if let Err(e) = accounting(m, &handle, &uuid, &devs) {
+        warn!("{uuid}: {e:#}");
+    }
+    devices(m, &handle, &uuid, &devs);
+    if let Err(e) = counters(m, &handle, &uuid) {
+        warn!("{uuid}: counters: {e:#}");
+    }
+    device_io(m, sysfs_path, &uuid, name_mode);
+    if !cli.no_time_stats {
+        if let Err(e) = time_stats(m, sysfs_path, &uuid, name_mode) {
+            warn!("{uuid}: time stats: {e:#}");
+        }
+    }
+    if cli.usage_history {
+        if let Err(e) = usage_history(&handle, &devs) {
+            warn!("{uuid}: usage history: {e:#}");
+        }
+    }
+
+    Ok(())
+}
+
+fn usage_history(handle: &BcachefsHandle, devs: &[DevInfo]) -> Result<()> {
+    let uuid = uuid::Uuid::from_bytes(handle.uuid()).hyphenated().to_string();
+    let path = fs_usage_history::default_history_path(&uuid);
+    let sample = fs_usage_history::take_sample(handle, devs)?;
+
+    if fs_usage_history::record(&path, &uuid, sample, USAGE_HISTORY_INTERVAL)? {
+        info!("recorded usage sample in {}", path.display());
+    }
+    Ok(())
+}
+
+/// Resolved per scrape, so that filesystems mounted after we started show up.
+fn sysfs_paths(cli: &Cli) -> Result<Vec<PathBuf>> {
+    if cli.filesystems.is_empty() {
+        return find_all_sysfs_dirs();
+    }
+
+    cli.filesystems.iter()
+        .map(|fs| {
+            let handle = BcachefsHandle::open(fs)
+                .with_context(|| format!("opening filesystem '{fs}'"))?;
+            sysfs_path_from_fd(handle.sysfs_fd())
+        })
+        .collect()
+}
+
+fn scrape(cli: &Cli) -> String {
+    let mut m = Metrics::default();
+
+    match sysfs_paths(cli) {
+        Ok(paths) => for path in paths {
+            if let Err(e) = scrape_fs(&mut m, cli, &path) {
+                warn!("{e:#}");
+            }
+        },
+        Err(e) => warn!("{e:#}"),
+    }
+
+    m.to_text()
+}
+
+fn fs_exporter(cli: Cli) -> Result<()> {
+    use tiny_http::{Header, Method, Response, Server};
+
+    // main() leaves SIGPIPE at SIG_DFL for the sake of pipelines; a scraper
+    // hanging up mid-response must not kill a long-running server.
+    unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN); }
+
+    let server = if cli.listen.contains('/') {
+        let path = Path::new(&cli.listen);
+
+        /* A stale socket from a previous run is ours to replace; anything
+         * else at that path is not */
+        match std::fs::symlink_metadata(path) {
+            Ok(md) if md.file_type().is_socket() =>
+                std::fs::remove_file(path)
+                    .with_context(|| format!("removing stale socket {}", path.display()))?,
+            Ok(_) => bail!("{}: exists and is not a socket", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("{}", path.display())),
+        }
+        Server::http_unix(path)
+    } else {
+        Server::http(&cli.listen)
+    }.map_err(|e| anyhow!("listening on {}: {e}", cli.listen))?;
+
+    info!("serving metrics on {}", cli.listen);
+
+    let content_type = Header::from_bytes("Content-Type", CONTENT_TYPE)
+        .map_err(|_| anyhow!("bad header"))?;
+
+    for request in server.incoming_requests() {
+        let response = match (request.method(), request.url()) {
+            (Method::Get, "/metrics") =>
+                Response::from_string(scrape(&cli)).with_header(content_type.clone()),
+            (Method::Get, "/") =>
+                Response::from_string("bcachefs exporter: metrics are at /metrics\n"),
+            _ => Response::from_string("not found\n").with_status_code(404),
+        };
+
+        if let Err(e) = request.respond(response) {
+            error!("error sending response: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+pub const CMD: super::CmdDef = typed_cmd!("exporter",
+    "Serve OpenMetrics metrics for Prometheus", Cli, fs_exporter);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_label_escapes() {
+        assert_eq!(escape_label("sda"), "sda");
+        assert_eq!(escape_label(r#"a"b"#), r#"a\"b"#);
+        assert_eq!(escape_label(r"a\b"), r"a\\b");
+        assert_eq!(escape_label("a\nb"), r"a\nb");
+        /* The backslash goes first, or the others' escapes would be doubled */
+        assert_eq!(escape_label("\\\"\n"), r#"\\\"\n"#);
+    }
+
+    #[test]
+    fn to_text_groups_families() {
+        let mut m = Metrics::default();
+        m.gauge("a_bytes", "A", &[("uuid", "u1")], 1);
+        m.counter("b", "B", &[("uuid", "u1"), ("dev", "sd\"a")], 2);
+        m.gauge("a_bytes", "A", &[("uuid", "u2")], 3);
+
+        assert_eq!(m.to_text(), "\
+# TYPE a_bytes gauge
+# HELP a_bytes A
+a_bytes{uuid=\"u1\"} 1
+a_bytes{uuid=\"u2\"} 3
+# TYPE b counter
+# HELP b B
+b_total{uuid=\"u1\",dev=\"sd\\\"a\"} 2
+# EOF
+");
+    }
+
+    #[test]
+    fn to_text_empty() {
+        assert_eq!(Metrics::default().to_text(), "# EOF\n");
+    }
+}
@@ -15,9 +15,15 @@
 //! domains, so with none configured this is the per device view.
 //!
 //! Limits: this is aggregate - it says how much data is exposed, not which
-//! extents (that's reconcile's job to find and fix). Extents whose copies
+//! extents; --files walks the extents btree for the files behind a domain's
+//! number (see fs_failure_domains_files.rs). Extents whose copies
 //! are partly in stripes (nr_required = 0 entries) are protected by their
 //! stripes and reported via the stripe entries.
+//!
+//! What-if mode (--remove, --set-failure-domain, --add) applies device changes
+//! to the same entries in memory, then reports the resulting table and how
+//! much data reconcile would have to rewrite to restore durability and
+//! failure domain separation. Nothing on the filesystem is touched.
 
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write as FmtWrite;
@@ -38,7 +44,10 @@ use bcachefs_kernel::util::printbuf::Printbuf;
 domain, the data that would be lost if it failed - too few copies or stripe \
 blocks left to reconstruct - and the data that would survive degraded. Lost \
 data means failure domain separation is being violated. Devices with no \
-failure domain set are their own failure domains.",
+failure domain set are their own failure domains.\n\n\
+With --remove, --set-failure-domain or --add, simulates the change first and \
+reports how much data reconcile would have to rewrite to restore durability \
+and failure domain separation; the filesystem is not modified.",
     disable_help_flag = true)]
 pub struct Cli {
     /// Print help
@@ -59,10 +68,31 @@ pub struct Cli {
     #[arg(long = "demo", hide = true)]
     demo: Option<String>,
 
+    /// What if: remove a device (name, label or index); its copies are
+    /// dropped and re-replicated elsewhere. May be given more than once
+    #[arg(long, value_name = "DEV")]
+    remove: Vec<String>,
+
+    /// What if: move a device to another failure domain ("none" to clear).
+    /// May be given more than once
+    #[arg(long, value_name = "DEV=DOMAIN")]
+    set_failure_domain: Vec<String>,
+
+    /// What if: add an empty device in a failure domain ("none" for its own
+    /// domain). May be given more than once
+    #[arg(long, value_name = "DOMAIN")]
+    add: Vec<String>,
+
+    /// List the files with data that losing this failure domain (or device)
+    /// would lose, from the extents btree. Works on unmounted filesystems too
+    #[arg(long, value_name = "DOMAIN", conflicts_with_all = ["demo", "remove", "set_failure_domain", "add"])]
+    files: Option<String>,
+
     #[command(flatten)]
     device_names: DeviceNameArgs,
 
-    /// Filesystem mountpoint
+    /// Filesystem mountpoint; with --files, a member device or UUID of an
+    /// unmounted filesystem
     #[arg(default_value = ".")]
     mountpoint: String,
 }
@@ -226,6 +256,175 @@ fn domain_rows(e: &Exposure, devs: &[DevInfo]) -> Vec<Row> {
     rows
 }
 
+// ── What-if: hypothetical device changes ─────────────────────────────
+
+/// Device changes to simulate, resolved against the device list:
+#[derive(Default)]
+struct WhatIf {
+    remove: Vec<u8>,
+    set_domain: Vec<(u8, Option<String>)>,
+    add: Vec<Option<String>>,
+    /// One line per change, for the report:
+    changes: Vec<String>,
+}
+
+/// A failure domain argument: "none" (or empty) means no failure domain.
+fn domain_arg(s: &str) -> Option<String> {
+    (!s.is_empty() && s != "none").then(|| s.to_string())
+}
+
+/// Find a device by index, device name (with or without /dev/) or label.
+pub(super) fn find_dev<'a>(devs: &'a [DevInfo], s: &str) -> Result<&'a DevInfo> {
+    let name = s.strip_prefix("/dev/").unwrap_or(s);
+
+    devs.iter().find(|d| s.parse::<u32>() == Ok(d.idx))
+        .or_else(|| devs.iter().find(|d| d.dev == s || d.dev == name))
+        .or_else(|| devs.iter().find(|d| d.label.as_deref() == Some(s)))
+        .ok_or_else(|| anyhow!("no device '{}' in this filesystem", s))
+}
+
+impl WhatIf {
+    fn new(cli: &Cli, devs: &[DevInfo]) -> Result<WhatIf> {
+        let mut w = WhatIf::default();
+
+        for s in &cli.remove {
+            let d = find_dev(devs, s)?;
+            w.remove.push(d.idx as u8);
+            w.changes.push(format!("remove {}", d.dev));
+        }
+
+        for s in &cli.set_failure_domain {
+            let (dev, domain) = s.split_once('=')
+                .ok_or_else(|| anyhow!("--set-failure-domain: expected DEV=DOMAIN, got '{}'", s))?;
+            let d = find_dev(devs, dev)?;
+            let domain = domain_arg(domain);
+
+            w.changes.push(format!("move {} to failure domain {}",
+                                   d.dev, domain.as_deref().unwrap_or("none")));
+            w.set_domain.push((d.idx as u8, domain));
+        }
+
+        for s in &cli.add {
+            let domain = domain_arg(s);
+
+            w.changes.push(match &domain {
+                Some(fd) => format!("add a device in failure domain {}", fd),
+                None     => "add a device with no failure domain".to_string(),
+            });
+            w.add.push(domain);
+        }
+
+        Ok(w)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The device list after the changes. Added devices are empty, online,
+    /// and get the next free indices; they're named new0, new1...
+    fn devs(&self, devs: &[DevInfo]) -> Vec<DevInfo> {
+        let mut ret: Vec<DevInfo> = devs.iter()
+            .filter(|d| !self.remove.contains(&(d.idx as u8)))
+            .cloned()
+            .collect();
+
+        for (idx, domain) in &self.set_domain {
+            for d in ret.iter_mut().filter(|d| d.idx == *idx as u32) {
+                d.failure_domain = domain.clone();
+            }
+        }
+
+        let next = devs.iter().map(|d| d.idx + 1).max().unwrap_or(0);
+        for (i, domain) in self.add.iter().enumerate() {
+            ret.push(DevInfo {
+                idx: next + i as u32,
+                dev: format!("new{}", i),
+                label: None,
+                failure_domain: domain.clone(),
+                durability: 1,
+                online: true,
+            });
+        }
+
+        ret
+    }
+}
+
+/// What reconcile would have to rewrite to restore durability and failure
+/// domain separation after a what-if change, in sectors:
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+struct Moves {
+    /// Copies on removed devices, re-replicated elsewhere:
+    rereplicate: u64,
+    /// Copies moved out of failure domains that hold more than one, as far
+    /// as there are domains to spread across:
+    respread: u64,
+    /// Data readable only via removed devices: removing them without
+    /// evacuating first (e.g. when they've failed) loses it:
+    stranded: u64,
+}
+
+/// Apply @w to the entries: copies on removed devices are dropped, and the
+/// failure domains are those of @new_devs. Returns the resulting exposure -
+/// the state before reconcile has run - and what reconcile would move.
+///
+/// An entry of n copies wants min(n, nr_domains) distinct failure domains;
+/// re-replicated copies are placed in missing domains first, so only the
+/// shortfall beyond them is counted as respread.
+fn what_if(e: &Exposure, w: &WhatIf, new_devs: &[DevInfo]) -> (Exposure, Moves) {
+    /* a device with no failure domain is a domain of its own: */
+    let domain_of = |idx: u8| -> (Option<&str>, u8) {
+        match new_devs.iter().find(|d| d.idx == idx as u32) {
+            Some(DevInfo { failure_domain: Some(fd), .. }) => (Some(fd.as_str()), 0),
+            _ => (None, idx),
+        }
+    };
+
+    let nr_domains = new_devs.iter()
+        .map(|d| domain_of(d.idx as u8))
+        .collect::<HashSet<_>>()
+        .len();
+
+    let mut entries = Vec::new();
+    let mut m = Moves::default();
+
+    for i in &e.entries {
+        let nr_copies = i.devs.len();
+        let per_copy = i.sectors / nr_copies as u64;
+
+        let remaining: Vec<u8> = i.devs.iter()
+            .copied()
+            .filter(|d| !w.remove.contains(d))
+            .collect();
+        let nr_removed = nr_copies - remaining.len();
+
+        let nr_distinct = remaining.iter()
+            .map(|&d| domain_of(d))
+            .collect::<HashSet<_>>()
+            .len();
+        let nr_respread = nr_copies.min(nr_domains)
+            .saturating_sub(nr_distinct + nr_removed);
+
+        /* nothing left to copy from: lost, not moved */
+        if nr_removed > i.tolerates as usize {
+            m.stranded += i.sectors;
+            continue;
+        }
+
+        m.rereplicate   += nr_removed as u64 * per_copy;
+        m.respread      += nr_respread as u64 * per_copy;
+
+        entries.push(Entry {
+            devs: remaining,
+            tolerates: i.tolerates - nr_removed as u8,
+            sectors: i.sectors,
+        });
+    }
+
+    (Exposure::from_entries(entries), m)
+}
+
 // ── Output ───────────────────────────────────────────────────────────
 
 fn header_to_text(out: &mut Printbuf, e: &Exposure, devs: &[DevInfo]) {
@@ -313,6 +512,81 @@ fn report_to_text(out: &mut Printbuf, e: &Exposure, devs: &[DevInfo]) {
     });
 }
 
+/// The what-if header: the changes, and what reconcile would have to do.
+fn what_if_to_text(out: &mut Printbuf, w: &WhatIf, m: &Moves) {
+    writeln!(out, "What if:").unwrap();
+    for c in &w.changes {
+        writeln!(out, "  {}", c).unwrap();
+    }
+
+    write!(out, "\nReconcile would rewrite: ").unwrap();
+    out.units_sectors(m.rereplicate + m.respread);
+    writeln!(out).unwrap();
+
+    out.aligned(|sub| {
+        write!(sub, "  copies on removed devices\t").unwrap();
+        sub.units_sectors(m.rereplicate);
+        write!(sub, "\r\n").unwrap();
+        write!(sub, "  copies sharing a failure domain\t").unwrap();
+        sub.units_sectors(m.respread);
+        write!(sub, "\r\n").unwrap();
+    });
+
+    if m.stranded != 0 {
+        write!(out, "Only readable via removed devices - evacuate, don't force remove: ").unwrap();
+        out.units_sectors(m.stranded);
+        writeln!(out).unwrap();
+    }
+
+    writeln!(out, "\nAfter the change, before reconcile:").unwrap();
+}
+
+fn what_if_to_json(w: &WhatIf, m: &Moves) -> serde_json::Value {
+    serde_json::json!({
+        "changes":              w.changes,
+        "rewrite_bytes":        (m.rereplicate + m.respread) << 9,
+        "rereplicate_bytes":    m.rereplicate << 9,
+        "respread_bytes":       m.respread << 9,
+        "stranded_bytes":       m.stranded << 9,
+    })
+}
+
+/// The report, for the filesystem as it is or with the what-if changes
+/// applied. @uuid is None for demo scenarios.
+fn report(out: &mut Printbuf, cli: &Cli, e: Exposure, devs: Vec<DevInfo>, uuid: Option<String>) -> Result<()> {
+    let w = WhatIf::new(cli, &devs)?;
+
+    let (e, devs, moves) = if w.is_empty() {
+        (e, devs, None)
+    } else {
+        let new_devs = w.devs(&devs);
+        let (e, m) = what_if(&e, &w, &new_devs);
+        (e, new_devs, Some(m))
+    };
+
+    if cli.json {
+        let mut j = report_to_json(&e, &devs);
+        if let Some(uuid) = uuid {
+            j["filesystem"] = uuid.into();
+        }
+        if let Some(m) = &moves {
+            j["what_if"] = what_if_to_json(&w, m);
+        }
+        writeln!(out, "{:#}", j).unwrap();
+        return Ok(());
+    }
+
+    if let Some(uuid) = uuid {
+        writeln!(out, "Filesystem: {}", uuid).unwrap();
+    }
+    if let Some(m) = &moves {
+        what_if_to_text(out, &w, m);
+    }
+    header_to_text(out, &e, &devs);
+    report_to_text(out, &e, &devs);
+    Ok(())
+}
+
 fn fs_failure_domains_to_text(out: &mut Printbuf, cli: &Cli, name_mode: DeviceNameMode) -> Result<()> {
     let path = &cli.mountpoint;
 
@@ -328,17 +602,7 @@ fn fs_failure_domains_to_text(out: &mut Printbuf, cli: &Cli, name_mode: DeviceNa
     let e = Exposure::new(&acct_refs);
     let uuid = uuid::Uuid::from_bytes(handle.uuid());
 
-    if cli.json {
-        let mut j = report_to_json(&e, &devs);
-        j["filesystem"] = uuid.hyphenated().to_string().into();
-        writeln!(out, "{:#}", j).unwrap();
-        return Ok(());
-    }
-
-    writeln!(out, "Filesystem: {}", uuid.hyphenated()).unwrap();
-    header_to_text(out, &e, &devs);
-    report_to_text(out, &e, &devs);
-    Ok(())
+    report(out, cli, e, devs, Some(uuid.hyphenated().to_string()))
 }
 
 // ── Demo scenarios - synthetic replica sets, no filesystem needed ────
@@ -476,18 +740,17 @@ fn fs_failure_domains(cli: Cli) -> Result<()> {
     out.set_human_readable(cli.human_readable);
     let name_mode = cli.device_names.name_mode();
 
-    if let Some(name) = &cli.demo {
+    if let Some(domain) = &cli.files {
+        super::fs_failure_domains_files::fs_failure_domain_files(
+            &mut out, &cli.mountpoint, domain, name_mode, cli.json)?;
+    } else if let Some(name) = &cli.demo {
         let (entries, devs) = demo::scenario(name)
             .ok_or_else(|| anyhow!("unknown scenario '{}' (have: domains, no-domains, partly-off)", name))?;
 
-        let e = Exposure::from_entries(entries);
-        if cli.json {
-            writeln!(out, "{:#}", report_to_json(&e, &devs)).unwrap();
-        } else {
+        if !cli.json {
             writeln!(out, "Demo scenario: {}", name).unwrap();
-            header_to_text(&mut out, &e, &devs);
-            report_to_text(&mut out, &e, &devs);
         }
+        report(&mut out, &cli, Exposure::from_entries(entries), devs, None)?;
     } else {
         fs_failure_domains_to_text(&mut out, &cli, name_mode)?;
     }
@@ -599,4 +862,47 @@ mod tests {
         assert_eq!(rows[0].nr_devs, None);
         assert_eq!(rows[0].score, Score { lost: 0, degraded: 100 });
     }
+
+    #[test]
+    fn what_if_relabel_and_remove() {
+        let devs = rack_devs();
+
+        /* dev1 to rack0: entries {0,1} and {1,2} now double up in rack0, and
+         * with only rack0 and rack1 left (dev3), one copy of each moves: */
+        let w = WhatIf {
+            set_domain: vec![(1, Some("rack0".to_string()))],
+            changes: vec!["move dev1".to_string()],
+            ..Default::default()
+        };
+        let (e, m) = what_if(&cross_rack(), &w, &w.devs(&devs));
+        assert_eq!(m, Moves { rereplicate: 0, respread: 100, stranded: 0 });
+        assert_eq!(domain_rows(&e, &w.devs(&devs))[0].score.lost, 200);
+
+        /* removing dev0: one copy of each of its two entries rewritten: */
+        let w = WhatIf {
+            remove: vec![0],
+            changes: vec!["remove dev0".to_string()],
+            ..Default::default()
+        };
+        let (e, m) = what_if(&cross_rack(), &w, &w.devs(&devs));
+        assert_eq!(m, Moves { rereplicate: 100, respread: 0, stranded: 0 });
+        assert_eq!(e.total, 400);
+        assert_eq!(e.score(&[1]).lost, 100);
+    }
+
+    #[test]
+    fn what_if_stranded_is_not_rereplicated() {
+        let devs = rack_devs();
+
+        /* removing dev0 and dev1: {0,1} has no copy left, and counts only
+         * as stranded; {0,3} and {1,2} each re-replicate one copy: */
+        let w = WhatIf {
+            remove: vec![0, 1],
+            changes: vec!["remove dev0".to_string(), "remove dev1".to_string()],
+            ..Default::default()
+        };
+        let (e, m) = what_if(&cross_rack(), &w, &w.devs(&devs));
+        assert_eq!(m, Moves { rereplicate: 100, respread: 0, stranded: 100 });
+        assert_eq!(e.total, 300);
+    }
 }
@@ -0,0 +1,409 @@
+//! bcachefs fs failure-domains --files: which files a failure domain's loss
+//! would take out.
+//!
+//! The failure-domains table comes from the replicas accounting, which says
+//! how much data is exposed but not where. This walks the extents
+//! themselves: an extent is exposed when every dirty pointer it has is on a
+//! device in the domain. Indirect (reflinked) extents are found first, in the
+//! reflink btree, then charged to every file whose reflink pointers reference
+//! them. Erasure coded extents are protected by their stripes and skipped, as
+//! in the table.
+//!
+//! Online, keys come through BCH_IOCTL_QUERY_BTREE_KEYS and paths are walked
+//! up the inode backpointers within each subvolume, prefixed with the
+//! subvolume's own path; offline, keys come from BtreeIter and paths from
+//! bch2_inum_snapshot_to_path(). Either way an extent is reported in the
+//! snapshot it was written in: a file in a snapshotted subvolume may show up
+//! once per snapshot holding its own copy of the data.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use bcachefs_kernel::btree::bkey::{BkeySC, BkeyValSC, POS_MIN, SPOS_MAX};
+use bcachefs_kernel::btree::iter::{lockrestart_do, BtreeIter, BtreeIterFlags, BtreeTrans};
+use bcachefs_kernel::c;
+use bcachefs_kernel::data::extents::{bkey_extent_entries_sc, bkey_ptrs_sc, extent_entry_type};
+use bcachefs_kernel::fs::Fs;
+use bcachefs_kernel::opt_set;
+use bcachefs_kernel::util::printbuf::Printbuf;
+use bch_bindgen::c::bch_degraded_actions;
+
+use crate::device_scan::OpenedFs;
+use crate::wrappers::file_extents::{snapshot_info, subvol_root_inode};
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::online_iter::{OnlineBtreeIter, OnlineIterFlags};
+use crate::wrappers::sb_display::member_alive;
+use crate::wrappers::snapshot_diff::PathResolver;
+use crate::wrappers::sysfs::{self, DeviceNameMode, DevInfo};
+
+use super::fs_failure_domains::find_dev;
+
+/// Where the keys come from: the kernel, or the filesystem opened here.
+enum Source<'a> {
+    Online(&'a BcachefsHandle),
+    Offline(&'a Fs),
+}
+
+impl Source<'_> {
+    /// Every key in @btree, in all snapshots:
+    fn for_each_key(&self, btree: c::btree_id, mut f: impl FnMut(BkeySC<'_>)) -> Result<()> {
+        match self {
+            Source::Online(handle) => {
+                let mut iter = OnlineBtreeIter::new(handle, btree, 0, POS_MIN, SPOS_MAX,
+                                                    OnlineIterFlags::ALL_SNAPSHOTS);
+                iter.for_each(|k| {
+                    f(k);
+                    ControlFlow::Continue(())
+                }).map_err(|e| anyhow!("BCH_IOCTL_QUERY_BTREE_KEYS: {e}"))
+            }
+            Source::Offline(fs) => {
+                let trans = BtreeTrans::new(fs);
+                let mut iter = BtreeIter::new(&trans, btree, POS_MIN,
+                    BtreeIterFlags::ALL_SNAPSHOTS | BtreeIterFlags::PREFETCH);
+                iter.for_each(&trans, |k| {
+                    f(k);
+                    ControlFlow::Continue(())
+                })?;
+                Ok(())
+            }
+        }
+    }
+}
+
+// ── The domain ───────────────────────────────────────────────────────
+
+/// The devices of a mounted filesystem's members, from sysfs: as the
+/// failure-domains table sees them.
+fn online_devs(handle: &BcachefsHandle, name_mode: DeviceNameMode) -> Result<Vec<DevInfo>> {
+    let sysfs_path = sysfs::sysfs_path_from_fd(handle.sysfs_fd())?;
+    sysfs::fs_get_devices(&sysfs_path, name_mode)
+}
+
+/// The same, from the superblock members: offline there's no sysfs, and the
+/// only device names are those of the devices we opened.
+fn offline_devs(fs: &Fs) -> Vec<DevInfo> {
+    let Some(members) = bcachefs_kernel::sb::members::members_v2(fs.sb()) else {
+        return Vec::new();
+    };
+
+    (0..members.nr_devices())
+        .filter_map(|idx| Some((idx, members.get(idx)?)))
+        .filter(|(_, m)| member_alive(m))
+        .map(|(idx, m)| {
+            let ca = fs.dev_get(idx);
+            let fd = &m.failure_domain;
+            let fd = String::from_utf8_lossy(&fd[..fd.iter().position(|&b| b == 0).unwrap_or(fd.len())])
+                .into_owned();
+
+            DevInfo {
+                idx,
+                dev: ca.as_ref()
+                    .map(|ca| ca.name().to_string_lossy().into_owned())
+                    .unwrap_or_else(|| format!("dev-{}", idx)),
+                label: None,
+                failure_domain: (!fd.is_empty()).then_some(fd),
+                durability: 1,
+                online: ca.is_some(),
+            }
+        })
+        .collect()
+}
+
+/// The devices of failure domain @name - or, for a device, the domain it's
+/// in (itself, if it has none).
+fn domain_devs<'a>(devs: &'a [DevInfo], name: &str) -> Result<(String, Vec<&'a DevInfo>)> {
+    let domain = if devs.iter().any(|d| d.failure_domain.as_deref() == Some(name)) {
+        name.to_string()
+    } else {
+        let d = find_dev(devs, name)
+            .map_err(|_| anyhow!("no failure domain or device '{}' in this filesystem", name))?;
+        match &d.failure_domain {
+            Some(fd) => fd.clone(),
+            None => return Ok((d.dev.clone(), vec![d])),
+        }
+    };
+
+    let members = devs.iter()
+        .filter(|d| d.failure_domain.as_deref() == Some(domain.as_str()))
+        .collect();
+    Ok((domain, members))
+}
+
+// ── Finding the exposed extents ──────────────────────────────────────
+
+/// Whether an extent's data would be gone with the devices in @domain: it
+/// has dirty pointers, they're all in the domain, and no stripe backs them.
+fn extent_exposed(val: &BkeyValSC<'_>, domain: &HashSet<u32>) -> bool {
+    let stripe_ptr = c::bch_extent_entry_type::BCH_EXTENT_ENTRY_stripe_ptr as u32;
+    if bkey_extent_entries_sc(val).any(|e| extent_entry_type(e) == stripe_ptr) {
+        return false;
+    }
+
+    let mut dirty = bkey_ptrs_sc(val).filter(|p| p.cached() == 0).peekable();
+    dirty.peek().is_some() && dirty.all(|p| domain.contains(&(p.dev() as u32)))
+}
+
+/// Exposed sectors per file, keyed by (inode, snapshot).
+type Exposed = BTreeMap<(u64, u32), u64>;
+
+/// Exposed indirect extents, as sorted, disjoint [start, end) sector ranges
+/// of the reflink btree:
+fn exposed_indirect(src: &Source<'_>, domain: &HashSet<u32>) -> Result<Vec<(u64, u64)>> {
+    let mut ret = Vec::new();
+
+    src.for_each_key(c::btree_id::reflink, |k| {
+        let val = k.v();
+        if matches!(val, BkeyValSC::reflink_v(..)) && extent_exposed(&val, domain) {
+            ret.push((k.k.p.offset - k.k.size as u64, k.k.p.offset));
+        }
+    })?;
+
+    Ok(ret)
+}
+
+/// How much of [start, end) the sorted ranges in @r cover:
+fn overlap(r: &[(u64, u64)], start: u64, end: u64) -> u64 {
+    let first = r.partition_point(|&(_, e)| e <= start);
+
+    r[first..].iter()
+        .take_while(|&&(s, _)| s < end)
+        .map(|&(s, e)| e.min(end) - s.max(start))
+        .sum()
+}
+
+fn exposed_files(src: &Source<'_>, domain: &HashSet<u32>) -> Result<Exposed> {
+    let indirect = exposed_indirect(src, domain)?;
+    let mut ret = Exposed::new();
+
+    src.for_each_key(c::btree_id::extents, |k| {
+        let val = k.v();
+        let size = k.k.size as u64;
+
+        let sectors = match &val {
+            BkeyValSC::extent(..) if extent_exposed(&val, domain) => size,
+            BkeyValSC::reflink_p(_, v) if !indirect.is_empty() => {
+                overlap(&indirect, v.idx(), v.idx() + size)
+            }
+            _ => 0,
+        };
+
+        if sectors != 0 {
+            *ret.entry((k.k.p.inode, k.k.p.snapshot)).or_default() += sectors;
+        }
+    })?;
+
+    Ok(ret)
+}
+
+// ── Paths ────────────────────────────────────────────────────────────
+
+/// Online path lookups: per snapshot, the subvolume it's seen through and a
+/// resolver within it.
+struct OnlinePaths<'h> {
+    handle:    &'h BcachefsHandle,
+    /// snapshot -> (subvolume path, resolver), None if it has no subvolume:
+    snapshots: HashMap<u32, Option<(String, PathResolver<'h>)>>,
+    /// (snapshot, subvolume) of every subvolume, read on first use:
+    subvols:   Option<Vec<(u32, u32)>>,
+    /// snapshot -> parent, 0 for a root:
+    parents:   HashMap<u32, u32>,
+}
+
+impl<'h> OnlinePaths<'h> {
+    fn new(handle: &'h BcachefsHandle) -> Self {
+        OnlinePaths { handle, snapshots: HashMap::new(), subvols: None, parents: HashMap::new() }
+    }
+
+    /// The subvolume a snapshot's keys are seen through. Interior nodes of
+    /// the snapshot tree have none of their own: their keys are shared by
+    /// their descendants, so find a subvolume whose snapshot descends from it
+    /// - in any branch, some of which may have had their subvolumes deleted.
+    fn snapshot_subvol(&mut self, id: u32) -> Result<Option<u32>> {
+        let Some(s) = snapshot_info(self.handle, id)? else { return Ok(None) };
+
+        let subvol = u32::from_le(s.subvol);
+        if subvol != 0 {
+            return Ok(Some(subvol));
+        }
+
+        if self.subvols.is_none() {
+            let mut subvols = Vec::new();
+            let mut iter = OnlineBtreeIter::new(self.handle, c::btree_id::subvolumes, 0,
+                                                POS_MIN, SPOS_MAX, OnlineIterFlags::default());
+            iter.for_each(|k| {
+                if let BkeyValSC::subvolume(_, v) = k.v() {
+                    subvols.push((u32::from_le(v.snapshot), k.k.p.offset as u32));
+                }
+                ControlFlow::Continue(())
+            }).map_err(|e| anyhow!("BCH_IOCTL_QUERY_BTREE_KEYS: {e}"))?;
+            self.subvols = Some(subvols);
+        }
+
+        /* Parents have higher IDs than their children: stop once past @id */
+        for &(snapshot, subvol) in self.subvols.as_deref().unwrap_or_default() {
+            let mut i = snapshot;
+            while i != 0 && i < id {
+                i = match self.parents.get(&i) {
+                    Some(&parent) => parent,
+                    None => {
+                        let parent = snapshot_info(self.handle, i)?
+                            .map_or(0, |s| u32::from_le(s.parent));
+                        self.parents.insert(i, parent);
+                        parent
+                    }
+                };
+            }
+            if i == id {
+                return Ok(Some(subvol));
+            }
+        }
+        Ok(None)
+    }
+
+    fn path(&mut self, inum: u64, snapshot: u32) -> Result<Option<String>> {
+        if !self.snapshots.contains_key(&snapshot) {
+            let v = match self.snapshot_subvol(snapshot)? {
+                Some(subvol) => Some((
+                    self.handle.subvolume_to_path(subvol)
+                        .map_err(|e| anyhow!("BCH_IOCTL_SUBVOLUME_TO_PATH: {e}"))?,
+                    PathResolver::new(self.handle, snapshot, subvol_root_inode(self.handle, subvol as u64)?),
+                )),
+                None => None,
+            };
+            self.snapshots.insert(snapshot, v);
+        }
+
+        let Some((subvol_path, resolver)) = self.snapshots.get_mut(&snapshot).unwrap() else {
+            return Ok(None);
+        };
+
+        Ok(resolver.path(inum)?.map(|p| {
+            let p = PathBuf::from("/").join(&*subvol_path).join(p);
+            p.to_string_lossy().into_owned()
+        }))
+    }
+}
+
+/// Offline: bch2_inum_snapshot_to_path(), which reports a path it can't
+/// finish as "(disconnected ...)" rather than failing.
+fn offline_path(fs: &Fs, inum: u64, snapshot: u32) -> Result<String> {
+    let trans = BtreeTrans::new(fs);
+    let mut buf = Printbuf::new();
+
+    lockrestart_do(&trans, |t| {
+        let ret = unsafe {
+            c::bch2_inum_snapshot_to_path(t.raw(), inum, snapshot, std::ptr::null_mut(), buf.as_raw())
+        };
+        t.result(ret)?.done(())
+    }).map_err(|e| anyhow!("inode {}:{}: {}", inum, snapshot, e))?;
+
+    Ok(buf.as_str().to_string())
+}
+
+// ── Output ───────────────────────────────────────────────────────────
+
+struct File {
+    path:     Option<String>,
+    inum:     u64,
+    snapshot: u32,
+    sectors:  u64,
+}
+
+fn resolve(src: &Source<'_>, exposed: Exposed) -> Result<Vec<File>> {
+    let mut online = match src {
+        Source::Online(handle) => Some(OnlinePaths::new(handle)),
+        Source::Offline(_) => None,
+    };
+
+    let mut ret = Vec::with_capacity(exposed.len());
+    for ((inum, snapshot), sectors) in exposed {
+        let path = match (src, &mut online) {
+            (_, Some(p))              => p.path(inum, snapshot)?,
+            (Source::Offline(fs), _)  => Some(offline_path(fs, inum, snapshot)?),
+            _                         => None,
+        };
+        ret.push(File { path, inum, snapshot, sectors });
+    }
+
+    /* Most exposed first: */
+    ret.sort_by(|a, b| b.sectors.cmp(&a.sectors).then(a.path.cmp(&b.path)));
+    Ok(ret)
+}
+
+fn files_to_text(out: &mut Printbuf, domain: &str, devs: &[&DevInfo], files: &[File]) {
+    let names: Vec<&str> = devs.iter().map(|d| d.dev.as_str()).collect();
+    writeln!(out, "Failure domain {}: {}", domain, names.join(" ")).unwrap();
+
+    if files.is_empty() {
+        writeln!(out, "No data lost if it fails").unwrap();
+        return;
+    }
+
+    write!(out, "Lost if it fails: ").unwrap();
+    out.units_sectors(files.iter().map(|f| f.sectors).sum());
+    writeln!(out, " in {} files\n", files.len()).unwrap();
+
+    out.aligned(|sub| {
+        writeln!(sub, "lost\r  path").unwrap();
+
+        for f in files {
+            sub.units_sectors(f.sectors);
+            match &f.path {
+                Some(p) => writeln!(sub, "\r  {}", p).unwrap(),
+                None    => writeln!(sub, "\r  (inode {}, snapshot {}: no path)", f.inum, f.snapshot).unwrap(),
+            }
+        }
+    });
+}
+
+fn files_to_json(domain: &str, devs: &[&DevInfo], files: &[File]) -> serde_json::Value {
+    serde_json::json!({
+        "domain":       domain,
+        "devices":      devs.iter().map(|d| d.dev.as_str()).collect::<Vec<_>>(),
+        "lost_bytes":   files.iter().map(|f| f.sectors).sum::<u64>() << 9,
+        "files":        files.iter().map(|f| serde_json::json!({
+            "path":     f.path,
+            "inode":    f.inum,
+            "snapshot": f.snapshot,
+            "bytes":    f.sectors << 9,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+/// --files: @path is a mountpoint, or - unmounted - a member device or UUID.
+pub fn fs_failure_domain_files(out: &mut Printbuf, path: &str, domain: &str,
+                               name_mode: DeviceNameMode, json: bool) -> Result<()> {
+    let mut fs_opts = c::bch_opts::default();
+    opt_set!(fs_opts, noexcl, 1);
+    opt_set!(fs_opts, nochanges, 1);
+    opt_set!(fs_opts, read_only, 1);
+    opt_set!(fs_opts, norecovery, 1);
+    opt_set!(fs_opts, degraded, bch_degraded_actions::BCH_DEGRADED_very as u8);
+    opt_set!(fs_opts, errors, c::bch_error_actions::BCH_ON_ERROR_continue as u8);
+
+    let opened = crate::device_scan::open_online_or_offline(&[PathBuf::from(path)], fs_opts)
+        .map_err(|e| anyhow!("opening filesystem '{}': {}", path, e))?;
+
+    let (src, devs) = match &opened {
+        OpenedFs::Online(handle) => (Source::Online(handle), online_devs(handle, name_mode)?),
+        OpenedFs::Offline(fs)    => (Source::Offline(fs), offline_devs(fs)),
+    };
+    if devs.is_empty() {
+        bail!("no member devices found");
+    }
+
+    let (domain, members) = domain_devs(&devs, domain)?;
+    let idxs: HashSet<u32> = members.iter().map(|d| d.idx).collect();
+
+    let files = resolve(&src, exposed_files(&src, &idxs)?)?;
+
+    if json {
+        writeln!(out, "{:#}", files_to_json(&domain, &members, &files)).unwrap();
+    } else {
+        files_to_text(out, &domain, &members, &files);
+    }
+    Ok(())
+}
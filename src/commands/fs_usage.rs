@@ -1,10 +1,13 @@
 use std::fmt::Write as FmtWrite;
+use std::path::PathBuf;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use bch_bindgen::c;
 use clap::Parser;
 
 use crate::commands::DeviceNameArgs;
+use crate::commands::fs_usage_history;
+use crate::commands::timestats::find_all_sysfs_dirs;
 use crate::wrappers::accounting::{
     AccountingEntry, DiskAccountingKind, data_type, data_type_is_empty, disk_accounting_type,
 };
@@ -30,7 +33,9 @@ enum Field {
 Output modes: replicas (data/metadata replication), btree (per-btree \
 space), compression (ratios and savings), rebalance_work (pending \
 reconcile work), devices (per-device breakdown). Use -f to select \
-specific fields, -a for all, -h for human-readable sizes.",
+specific fields, -a for all, -h for human-readable sizes. \
+--record appends a sample to a usage history (from a timer), and --history \
+shows the trend and forecasts when each device and label fills.",
     disable_help_flag = true)]
 pub struct Cli {
     /// Print help
@@ -52,12 +57,92 @@ pub struct Cli {
     #[command(flatten)]
     device_names: DeviceNameArgs,
 
-    /// Filesystem mountpoints
-    #[arg(default_value = ".")]
+    /// Show usage history and forecast when it fills, from recorded samples
+    #[arg(long, conflicts_with = "record")]
+    history: bool,
+
+    /// Append a sample to the usage history, printing nothing
+    #[arg(long)]
+    record: bool,
+
+    /// Usage history file [default: /var/lib/bcachefs/usage-<UUID>.jsonl]
+    #[arg(long, value_name = "FILE")]
+    history_file: Option<PathBuf>,
+
+    /// Days of history to forecast from
+    #[arg(long, value_name = "DAYS", default_value = "30")]
+    window: u64,
+
+    /// Filesystem mountpoints (default: the current directory, or with
+    /// --record, every mounted filesystem)
     mountpoints: Vec<String>,
 }
 
+fn history_path(cli: &Cli, handle: &BcachefsHandle) -> PathBuf {
+    cli.history_file.clone().unwrap_or_else(|| {
+        let uuid = uuid::Uuid::from_bytes(handle.uuid()).hyphenated().to_string();
+        fs_usage_history::default_history_path(&uuid)
+    })
+}
+
+fn record_usage(cli: &Cli, path: &str) -> Result<()> {
+    let handle = BcachefsHandle::open(path)
+        .map_err(|e| anyhow!("opening filesystem '{}': {}", path, e))?;
+    let sysfs_path = sysfs::sysfs_path_from_fd(handle.sysfs_fd())?;
+    let devs = sysfs::fs_get_devices(&sysfs_path, cli.device_names.name_mode())?;
+
+    let uuid = uuid::Uuid::from_bytes(handle.uuid()).hyphenated().to_string();
+    let sample = fs_usage_history::take_sample(&handle, &devs)?;
+    fs_usage_history::record(&history_path(cli, &handle), &uuid, sample, 0)?;
+    Ok(())
+}
+
 fn fs_usage(cli: Cli) -> Result<()> {
+    if cli.history_file.is_some() &&
+       (cli.mountpoints.len() > 1 || (cli.record && cli.mountpoints.is_empty())) {
+        return Err(anyhow!("--history-file is for a single filesystem"));
+    }
+
+    if cli.record {
+        let mountpoints = if !cli.mountpoints.is_empty() {
+            cli.mountpoints.clone()
+        } else {
+            find_all_sysfs_dirs()?.iter()
+                .filter_map(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .collect()
+        };
+
+        /* Keep going: one filesystem's failure shouldn't cost the others a sample */
+        let mut ret = Ok(());
+        for path in &mountpoints {
+            if let Err(e) = record_usage(&cli, path) {
+                eprintln!("{}: {:#}", path, e);
+                ret = Err(anyhow!("failed to record usage of some filesystems"));
+            }
+        }
+        return ret;
+    }
+
+    let mountpoints = if cli.mountpoints.is_empty() {
+        vec![".".to_string()]
+    } else {
+        cli.mountpoints.clone()
+    };
+
+    if cli.history {
+        for path in &mountpoints {
+            let handle = BcachefsHandle::open(path)
+                .map_err(|e| anyhow!("opening filesystem '{}': {}", path, e))?;
+            let history = history_path(&cli, &handle);
+
+            let mut out = Printbuf::new();
+            out.set_human_readable(cli.human_readable);
+            fs_usage_history::history_to_text(&mut out, &history, cli.window)
+                .with_context(|| format!("no usage history for '{}' (recorded by `bcachefs fs usage --record`)", path))?;
+            print!("{}", out);
+        }
+        return Ok(());
+    }
 
     let fields: Vec<Field> = if cli.all {
         vec![Field::Replicas, Field::Btree, Field::Compression,
@@ -68,7 +153,7 @@ fn fs_usage(cli: Cli) -> Result<()> {
         cli.fields
     };
 
-    for path in &cli.mountpoints {
+    for path in &mountpoints {
         let mut out = Printbuf::new();
         out.set_human_readable(cli.human_readable);
         let name_mode = cli.device_names.name_mode();
@@ -0,0 +1,474 @@
+//! Usage history for `fs usage --history`: capacity planning needs trends,
+//! and accounting is only ever a point in time.
+//!
+//! Each filesystem gets a small file of JSON lines - a HistoryHeader, then one
+//! UsageSample per line - appended by `fs usage --record` (from
+//! bcachefs-usage-history.timer) or by `fs exporter --usage-history`. It stays
+//! small by thinning: everything from the last week, one sample a day before
+//! that.
+//!
+//! Forecasts are a least-squares fit of used space against time, per
+//! filesystem, per label group and per device, over a window of recent
+//! history.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use bcachefs_kernel::util::printbuf::Printbuf;
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::wrappers::accounting::{data_type, disk_accounting_type, DiskAccountingKind};
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::sysfs::DevInfo;
+
+const HISTORY_DIR: &str = "/var/lib/bcachefs";
+const HISTORY_VERSION: u32 = 1;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Past this age, samples are thinned to one a day
+const THIN_AFTER: u64 = 7 * DAY;
+
+/// Rows in the daily trend table
+const TREND_DAYS: usize = 14;
+
+#[derive(Serialize, Deserialize)]
+struct HistoryHeader {
+    bcachefs_usage_history: u32,
+    uuid:                   String,
+}
+
+/// Sizes are in sectors, as accounting reports them.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct UsageSample {
+    t:            u64,              // unix time, seconds
+    capacity:     u64,
+    used:         u64,
+    /// Data before replication and parity, from replicas accounting
+    data:         u64,
+    /// From compression accounting
+    compressed:   u64,
+    uncompressed: u64,
+    devs:         Vec<DevSample>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct DevSample {
+    idx:      u32,
+    dev:      String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label:    Option<String>,
+    capacity: u64,
+    used:     u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub(crate) fn default_history_path(uuid: &str) -> PathBuf {
+    Path::new(HISTORY_DIR).join(format!("usage-{uuid}.jsonl"))
+}
+
+pub(crate) fn take_sample(handle: &BcachefsHandle, devs: &[DevInfo]) -> Result<UsageSample> {
+    let result = handle.query_accounting(
+        disk_accounting_type::replicas.bit() |
+        disk_accounting_type::compression.bit())
+        .map_err(|e| anyhow!("query_accounting ioctl failed (kernel too old?): {}", e))?;
+
+    let mut data = 0;
+    let mut compressed = 0;
+    let mut uncompressed = 0;
+
+    for entry in &result.entries {
+        match entry.pos.decode() {
+            DiskAccountingKind::Replicas { data_type, nr_devs, nr_required, .. } => {
+                if data_type == data_type::cached || nr_devs == 0 { continue }
+
+                // Replicated: nr_devs copies. Erasure coded: nr_required of
+                // nr_devs blocks are data.
+                let sectors = entry.counter(0);
+                data += if nr_required > 1 {
+                    sectors * nr_required as u64 / nr_devs as u64
+                } else {
+                    sectors / nr_devs as u64
+                };
+            }
+            DiskAccountingKind::Compression { .. } => {
+                uncompressed += entry.counter(1);
+                compressed   += entry.counter(2);
+            }
+            _ => {}
+        }
+    }
+
+    let mut dev_samples = Vec::new();
+    for dev in devs.iter().filter(|d| d.online) {
+        let usage = handle.dev_usage(dev.idx)
+            .map_err(|e| anyhow!("getting usage for device {}: {}", dev.idx, e))?;
+        let hidden = usage.hidden_sectors();
+
+        dev_samples.push(DevSample {
+            idx:      dev.idx,
+            dev:      dev.dev.clone(),
+            label:    dev.label.clone(),
+            capacity: usage.capacity_sectors() - hidden,
+            used:     usage.used_sectors() - hidden,
+        });
+    }
+
+    Ok(UsageSample {
+        t: now(),
+        capacity: result.capacity,
+        used: result.used,
+        data,
+        compressed,
+        uncompressed,
+        devs: dev_samples,
+    })
+}
+
+fn load(path: &Path) -> Result<(HistoryHeader, Vec<UsageSample>)> {
+    let f = fs::File::open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    let mut lines = std::io::BufReader::new(f).lines();
+
+    let first = lines.next()
+        .ok_or_else(|| anyhow!("{}: empty usage history", path.display()))??;
+    let header: HistoryHeader = serde_json::from_str(&first)
+        .with_context(|| format!("{}: not a bcachefs usage history", path.display()))?;
+
+    // Skip what we can't parse rather than losing the rest of the history
+    let samples = lines
+        .map_while(|l| l.ok())
+        .filter_map(|l| serde_json::from_str(&l).ok())
+        .collect();
+
+    Ok((header, samples))
+}
+
+/// Everything from the last week, and the first sample of each day before that
+fn thin(samples: &mut Vec<UsageSample>, now: u64) {
+    let mut last_day = None;
+    samples.retain(|s| {
+        if s.t + THIN_AFTER >= now { return true }
+        let day = s.t / DAY;
+        let keep = last_day != Some(day);
+        last_day = Some(day);
+        keep
+    });
+}
+
+/// Append a sample, unless the last one is less than `min_interval` seconds
+/// old. The file is rewritten (and renamed over) rather than appended to, as
+/// it's thinned as it goes. Returns whether the sample was recorded.
+pub(crate) fn record(path: &Path, uuid: &str, sample: UsageSample, min_interval: u64) -> Result<bool> {
+    let mut samples = Vec::new();
+
+    if path.exists() {
+        let (header, existing) = load(path)?;
+        if header.uuid != uuid {
+            return Err(anyhow!("{} is the usage history of filesystem {}, not {}",
+                               path.display(), header.uuid, uuid));
+        }
+        if existing.last().is_some_and(|last| last.t + min_interval > sample.t) {
+            return Ok(false);
+        }
+        samples = existing;
+    } else if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("creating {}", dir.display()))?;
+    }
+
+    let now = sample.t;
+    samples.push(sample);
+    thin(&mut samples, now);
+
+    let header = HistoryHeader {
+        bcachefs_usage_history: HISTORY_VERSION,
+        uuid: uuid.to_string(),
+    };
+
+    let mut out = serde_json::to_string(&header)?;
+    out.push('\n');
+    for s in &samples {
+        out.push_str(&serde_json::to_string(s)?);
+        out.push('\n');
+    }
+
+    /* Unique per process, so that concurrent recorders (the exporter and a
+     * cron job, say) can't write into each other's temp file: the last
+     * rename wins, and a sample may be lost, but the file stays whole */
+    let tmp = path.with_extension(format!("jsonl.tmp.{}", std::process::id()));
+    let mut f = fs::File::create(&tmp)
+        .with_context(|| format!("creating {}", tmp.display()))?;
+    f.write_all(out.as_bytes())
+        .and_then(|_| f.sync_all())
+        .with_context(|| format!("writing {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .with_context(|| format!("renaming {} to {}", tmp.display(), path.display()))?;
+
+    Ok(true)
+}
+
+// Forecasting
+
+/// Least-squares slope of (time, value), in units per second
+fn growth_rate(points: &[(u64, u64)]) -> Option<f64> {
+    if points.len() < 2 { return None }
+
+    let n = points.len() as f64;
+    let t0 = points[0].0 as f64;
+    let (mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0);
+    for &(t, v) in points {
+        let x = t as f64 - t0;
+        let y = v as f64;
+        sx  += x;
+        sy  += y;
+        sxx += x * x;
+        sxy += x * y;
+    }
+
+    let d = n * sxx - sx * sx;
+    (d > 0.0).then(|| (n * sxy - sx * sy) / d)
+}
+
+fn fmt_date(t: u64) -> String {
+    Local.timestamp_opt(t as i64, 0)
+        .single()
+        .map(|d| d.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| t.to_string())
+}
+
+fn fmt_days(secs: f64) -> String {
+    let days = secs / DAY as f64;
+    if days < 1.0 {
+        "<1d".to_string()
+    } else if days > 3650.0 {
+        ">10y".to_string()
+    } else {
+        format!("{:.0}d", days)
+    }
+}
+
+fn prt_signed_sectors(out: &mut Printbuf, v: i64) {
+    if v < 0 {
+        write!(out, "-").unwrap();
+    }
+    out.units_sectors(v.unsigned_abs());
+}
+
+/// One forecast row: `points` are (time, used), oldest first
+fn forecast_row(out: &mut Printbuf, name: &str, capacity: u64, points: &[(u64, u64)], now: u64) {
+    let Some(&(_, used)) = points.last() else { return };
+
+    write!(out, "{}:\t", name).unwrap();
+    out.units_sectors(capacity);
+    write!(out, "\r").unwrap();
+    out.units_sectors(used);
+    write!(out, "\r").unwrap();
+
+    match growth_rate(points) {
+        Some(rate) => {
+            prt_signed_sectors(out, (rate * DAY as f64) as i64);
+            write!(out, "\r").unwrap();
+
+            if rate > 0.0 {
+                let secs = capacity.saturating_sub(used) as f64 / rate;
+                write!(out, "{}\r{}\r", fmt_days(secs), fmt_date(now + secs as u64)).unwrap();
+            } else {
+                write!(out, "-\r-\r").unwrap();
+            }
+        }
+        None => write!(out, "?\r\r\r").unwrap(),
+    }
+    out.newline();
+}
+
+/// The group a device label belongs to, for targets: "hdd.hdd1" is in "hdd"
+fn label_group(label: &str) -> &str {
+    label.rsplit_once('.').map_or(label, |(group, _)| group)
+}
+
+pub(crate) fn history_to_text(out: &mut Printbuf, path: &Path, window_days: u64) -> Result<()> {
+    let (header, samples) = load(path)?;
+    let now = now();
+
+    writeln!(out, "Filesystem: {}", header.uuid).unwrap();
+
+    let (Some(first), Some(latest)) = (samples.first(), samples.last()) else {
+        writeln!(out, "No usage samples in {} yet", path.display()).unwrap();
+        return Ok(());
+    };
+    writeln!(out, "History: {} samples, {} to {}",
+             samples.len(), fmt_date(first.t), fmt_date(latest.t)).unwrap();
+
+    // Daily trend: the last sample of each day
+    let mut daily: BTreeMap<u64, &UsageSample> = BTreeMap::new();
+    for s in &samples {
+        daily.insert(s.t / DAY, s);
+    }
+    let daily: Vec<&UsageSample> = daily.into_values().collect();
+
+    out.aligned(|sub| {
+        write!(sub, "\nDate\tUsed\rChange\rData\r\n").unwrap();
+
+        let start = daily.len().saturating_sub(TREND_DAYS);
+        for (i, s) in daily.iter().enumerate().skip(start) {
+            write!(sub, "{}:\t", fmt_date(s.t)).unwrap();
+            sub.units_sectors(s.used);
+            write!(sub, "\r").unwrap();
+            if i > 0 {
+                prt_signed_sectors(sub, s.used as i64 - daily[i - 1].used as i64);
+            }
+            write!(sub, "\r").unwrap();
+            sub.units_sectors(s.data);
+            write!(sub, "\r\n").unwrap();
+        }
+    });
+
+    let window: Vec<&UsageSample> = samples.iter()
+        .filter(|s| s.t + window_days * DAY >= latest.t)
+        .collect();
+    if window.len() < 2 {
+        write!(out, "\nNot enough history in the last {} days to forecast; samples are \
+                     recorded by `bcachefs fs usage --record`\n", window_days).unwrap();
+        return Ok(());
+    }
+
+    // Label groups and devices, as of the latest sample
+    let mut groups: BTreeMap<&str, Vec<u32>> = BTreeMap::new();
+    for d in &latest.devs {
+        if let Some(label) = &d.label {
+            groups.entry(label_group(label)).or_default().push(d.idx);
+        }
+    }
+
+    let dev_points = |idxs: &[u32]| -> Vec<(u64, u64)> {
+        window.iter()
+            .map(|s| (s.t, s.devs.iter()
+                .filter(|d| idxs.contains(&d.idx))
+                .map(|d| d.used)
+                .sum()))
+            .collect()
+    };
+
+    out.aligned(|sub| {
+        write!(sub, "\nForecast ({} days):\tSize\rUsed\rPer day\rFull in\rFull by\r\n",
+               window_days).unwrap();
+
+        let points: Vec<(u64, u64)> = window.iter().map(|s| (s.t, s.used)).collect();
+        forecast_row(sub, "filesystem", latest.capacity, &points, now);
+
+        for (group, idxs) in &groups {
+            let capacity = latest.devs.iter()
+                .filter(|d| idxs.contains(&d.idx))
+                .map(|d| d.capacity)
+                .sum();
+            forecast_row(sub, &format!("label {}", group), capacity, &dev_points(idxs), now);
+        }
+
+        for d in &latest.devs {
+            forecast_row(sub, &format!("device {}", d.dev), d.capacity, &dev_points(&[d.idx]), now);
+        }
+    });
+
+    // What's left, in terms of data rather than disk: replication and
+    // compression as they are now
+    if latest.data > 0 {
+        let replication = latest.used as f64 / latest.data as f64;
+        let saved = latest.uncompressed.saturating_sub(latest.compressed);
+        let compression = (latest.data + saved) as f64 / latest.data as f64;
+        let free = latest.capacity.saturating_sub(latest.used);
+        let room = (free as f64 / replication * compression) as u64;
+
+        out.newline();
+        write!(out, "Data: ").unwrap();
+        out.units_sectors(latest.data);
+        write!(out, ", {:.2}x on disk with replication", replication).unwrap();
+        if saved > 0 {
+            write!(out, ", compressed {:.2}:1", compression).unwrap();
+        }
+        out.newline();
+
+        write!(out, "Free: ").unwrap();
+        out.units_sectors(free);
+        write!(out, ", room for about ").unwrap();
+        out.units_sectors(room);
+        write!(out, " more data at that rate").unwrap();
+        out.newline();
+
+        let data_points: Vec<(u64, u64)> = window.iter().map(|s| (s.t, s.data)).collect();
+        if let Some(rate) = growth_rate(&data_points) {
+            write!(out, "Data growth: ").unwrap();
+            prt_signed_sectors(out, (rate * DAY as f64) as i64);
+            write!(out, " per day").unwrap();
+            out.newline();
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(t: u64) -> UsageSample {
+        UsageSample {
+            t, capacity: 0, used: 0, data: 0, compressed: 0, uncompressed: 0, devs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn growth_rate_fits_a_line() {
+        assert_eq!(growth_rate(&[]), None);
+        assert_eq!(growth_rate(&[(100, 5)]), None);
+        /* All at the same time: no slope to fit */
+        assert_eq!(growth_rate(&[(100, 5), (100, 7)]), None);
+
+        assert_eq!(growth_rate(&[(1000, 10), (1010, 30), (1020, 50)]), Some(2.0));
+        assert_eq!(growth_rate(&[(0, 50), (10, 50)]), Some(0.0));
+        assert_eq!(growth_rate(&[(0, 50), (10, 40)]), Some(-1.0));
+
+        /* Least squares, not just the endpoints */
+        let r = growth_rate(&[(0, 0), (1, 3), (2, 2), (3, 5)]).unwrap();
+        assert!((r - 1.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn thin_keeps_last_week_and_one_a_day_before() {
+        let now = 100 * DAY;
+        let ts = [
+            80 * DAY + 10, 80 * DAY + 20, 80 * DAY + 30,   /* old: first of the day kept */
+            81 * DAY + 5,
+            now - THIN_AFTER - 1,                          /* just too old */
+            now - THIN_AFTER,                              /* just recent enough */
+            now - 60, now - 30, now,
+        ];
+        let mut samples: Vec<_> = ts.iter().map(|&t| sample(t)).collect();
+        thin(&mut samples, now);
+
+        let kept: Vec<u64> = samples.iter().map(|s| s.t).collect();
+        assert_eq!(kept, [80 * DAY + 10, 81 * DAY + 5, now - THIN_AFTER - 1,
+                          now - THIN_AFTER, now - 60, now - 30, now]);
+    }
+
+    #[test]
+    fn thin_same_day_across_the_cutoff() {
+        /* The day of the cutoff: the old half is thinned on its own */
+        let now = 100 * DAY + DAY / 2;
+        let cutoff = now - THIN_AFTER;
+        let mut samples = vec![sample(cutoff - 20), sample(cutoff - 10), sample(cutoff + 10)];
+        thin(&mut samples, now);
+
+        let kept: Vec<u64> = samples.iter().map(|s| s.t).collect();
+        assert_eq!(kept, [cutoff - 20, cutoff + 10]);
+    }
+}
@@ -0,0 +1,232 @@
+//! bcachefs journal-rewind - roll a filesystem back to an earlier journal
+//! entry: the last resort after a bad upgrade or a bug that wrote garbage.
+//!
+//! First a read-only open that reads the journal only (nochanges): the
+//! target is resolved to a flush entry inside the rewind window - the same
+//! window journal_rewind_info shows - and every transaction that the rewind
+//! would discard is printed, list_journal style. A dry run stops there.
+//!
+//! Then, once confirmed, the filesystem is opened again with
+//! journal_rewind=<seq>: recovery replays up to the target, runs fsck (alloc
+//! info is stale past the rewind point) and records the rewind in the
+//! superblock ext section. The superblock seq goes up on every write, so if it
+//! moved between the two opens - the filesystem was mounted in between - the
+//! preview no longer describes what the rewind would discard, and we stop
+//! before starting the filesystem.
+
+use anyhow::{anyhow, bail, Result};
+use bch_bindgen::c;
+use bcachefs_kernel::opt_set;
+use clap::Parser;
+
+use crate::commands::journal_rewind_info::{
+    fmt_secs, jset_datetime, jset_seq, parse_secs, rewind_candidates, rewind_floor,
+    JournalEntries,
+};
+use crate::commands::list_journal::{list_journal_run, JournalFilter};
+
+/// Rewind target, as given on the command line:
+enum Target {
+    Seq(u64),
+    /// Seconds since the epoch:
+    Time(u64),
+}
+
+/// A seq, or a UTC datetime as journal_rewind_info prints them.
+fn parse_target(s: &str) -> Result<Target> {
+    if let Ok(seq) = s.parse::<u64>() {
+        return Ok(Target::Seq(seq));
+    }
+
+    if let Some(t) = parse_secs(s) {
+        return Ok(Target::Time(t));
+    }
+
+    bail!("invalid rewind target '{}': want a journal seq or 'YYYY-MM-DD HH:MM[:SS]' (UTC)", s)
+}
+
+/// Pick the flush entry to rewind to: exactly the seq asked for, or the
+/// newest one written at or before the time asked for.
+fn resolve_target(target: &Target, candidates: &[(u64, Option<u64>)]) -> Result<(u64, Option<u64>)> {
+    match *target {
+        Target::Seq(seq) => candidates.iter()
+            .find(|(s, _)| *s == seq)
+            .copied()
+            .ok_or_else(|| anyhow!("seq {} is not a flush entry within the rewind window \
+                                    (see bcachefs journal_rewind_info)", seq)),
+        Target::Time(t) => candidates.iter()
+            .rev()
+            .find(|(_, dt)| dt.is_some_and(|dt| dt <= t))
+            .copied()
+            .ok_or_else(|| anyhow!("no flush entry within the rewind window at or before {}",
+                                   fmt_secs(t))),
+    }
+}
+
+/// Rewind the journal to an earlier flush entry, discarding later updates
+#[derive(Parser, Debug)]
+#[command(name = "journal-rewind")]
+pub struct Cli {
+    /// Journal seq or UTC datetime (YYYY-MM-DD HH:MM[:SS]) to rewind to
+    #[arg(short = 't', long = "to")]
+    to: String,
+
+    /// Show what would be discarded, then exit
+    #[arg(short = 'n', long = "dry-run")]
+    dry_run: bool,
+
+    /// Print keys in the preview without their values
+    #[arg(long = "no-values")]
+    no_values: bool,
+
+    /// Don't ask for confirmation
+    #[arg(short = 'y', long)]
+    yes: bool,
+
+    /// Additional mount options
+    #[arg(short = 'o')]
+    opts: Vec<String>,
+
+    /// Verbose mount output
+    #[arg(short = 'v', long)]
+    verbose: bool,
+
+    /// Devices
+    #[arg(required = true)]
+    devices: Vec<String>,
+}
+
+fn mount_opts(cli: &Cli) -> Result<c::bch_opts> {
+    let mut opts = bcachefs_kernel::opts::parse_mount_opts_vec(&cli.opts, false)
+        .map_err(|e| anyhow!("error parsing options: {}", crate::wrappers::bch_err_str(e.raw())))?;
+    if cli.verbose {
+        opt_set!(opts, verbose, 1);
+    }
+    Ok(opts)
+}
+
+/// Resolve the target in the journal @c has read and print what goes.
+/// Returns the seq to rewind to.
+fn rewind_preview(cli: &Cli, c: *mut c::bch_fs) -> Result<u64> {
+    let target = parse_target(&cli.to)?;
+
+    let je = JournalEntries::collect(c);
+    let entries = je.as_slice();
+    let Some(latest) = entries.iter().map(|&ep| unsafe { &*ep }).max_by_key(|p| jset_seq(p)) else {
+        bail!("no journal entries found");
+    };
+    let latest_seq = jset_seq(latest);
+
+    let (floor_seq, fell_back) = rewind_floor(entries, latest);
+    if fell_back {
+        eprintln!("warning: most recent journal entry has no rewind_limit sub-entry; \
+                   rewind window may extend past what discards have preserved");
+    }
+
+    let candidates = rewind_candidates(entries, floor_seq, latest_seq);
+    let (seq, dt) = resolve_target(&target, &candidates)?;
+
+    if seq == latest_seq {
+        bail!("seq {} is the newest journal entry: nothing to rewind", seq);
+    }
+
+    let f = JournalFilter {
+        filtering: true,
+        log: true,
+        bkey_val: !cli.no_values,
+        ..Default::default()
+    };
+
+    println!("Updates in journal entries {}-{} that will be discarded:", seq + 1, latest_seq);
+    list_journal_run(c, &f, false, seq + 1, latest_seq, None, None)?;
+
+    println!();
+    print!("Rewinding to journal entry {}", seq);
+    if let Some(dt) = dt {
+        print!(" ({})", fmt_secs(dt));
+    }
+    println!(", from {}{}", latest_seq,
+             jset_datetime(latest).map(|t| format!(" ({})", fmt_secs(t))).unwrap_or_default());
+    println!("Discards {} journal entries; fsck will run afterwards",
+             entries.iter().filter(|&&ep| unsafe { jset_seq(&*ep) } > seq).count());
+
+    Ok(seq)
+}
+
+fn sb_seq(c: *mut c::bch_fs) -> u64 {
+    u64::from_le(unsafe { (*(*c).disk_sb.sb).seq })
+}
+
+/// Read the journal only, and preview the rewind. Returns the seq to rewind
+/// to, and the superblock seq it was previewed at.
+fn rewind_preview_open(cli: &Cli, devs: &[std::path::PathBuf]) -> Result<(u64, u64)> {
+    let mut opts = mount_opts(cli)?;
+    opt_set!(opts, noexcl, 1);
+    opt_set!(opts, nochanges, 1);
+    opt_set!(opts, norecovery, 1);
+    opt_set!(opts, read_only, 1);
+    opt_set!(opts, degraded, c::bch_degraded_actions::BCH_DEGRADED_very as u8);
+    opt_set!(opts, errors, c::bch_error_actions::BCH_ON_ERROR_continue as u8);
+    opt_set!(opts, fix_errors, c::fsck_err_opts::FSCK_FIX_yes as u8);
+    opt_set!(opts, retain_recovery_info, 1);
+    opt_set!(opts, read_journal_only, 1);
+    opt_set!(opts, read_entire_journal, 1);
+
+    let fs = crate::device_scan::open_scan(devs, opts)
+        .map_err(|e| anyhow!("error opening {}: {}", cli.devices[0], e))?;
+
+    Ok((rewind_preview(cli, fs.raw)?, sb_seq(fs.raw)))
+}
+
+fn cmd_journal_rewind(cli: Cli) -> Result<()> {
+    let devs: Vec<std::path::PathBuf> = cli.devices.iter().map(std::path::PathBuf::from).collect();
+
+    let (seq, previewed_sb_seq) = rewind_preview_open(&cli, &devs)?;
+
+    if cli.dry_run {
+        return Ok(());
+    }
+
+    if cli.yes {
+        println!("Rewinding");
+    } else {
+        print!("Rewind? ");
+        if !unsafe { c::ask_yn() } {
+            return Ok(());
+        }
+    }
+
+    let mut opts = mount_opts(&cli)?;
+    opt_set!(opts, nostart, 1);
+    opt_set!(opts, journal_rewind, seq);
+    opt_set!(opts, fix_errors, c::fsck_err_opts::FSCK_FIX_yes as u8);
+
+    let fs = crate::device_scan::open_scan(&devs, opts)
+        .map_err(|e| anyhow!("error opening {}: {}", cli.devices[0], e))?;
+
+    if sb_seq(fs.raw) != previewed_sb_seq {
+        bail!("{} was written to since the preview (superblock seq {} -> {}): \
+               not rewinding; run journal-rewind again",
+              cli.devices[0], previewed_sb_seq, sb_seq(fs.raw));
+    }
+
+    let ret = unsafe { c::bch2_fs_start(fs.raw) };
+    if ret != 0 {
+        bail!("error rewinding {}: {}", cli.devices[0], crate::wrappers::bch_err_str(ret));
+    }
+
+    let ret = fs.exit();
+    if ret != 0 {
+        bail!("error shutting down filesystem: {}", crate::wrappers::bch_err_str(ret));
+    }
+
+    println!("Journal rewound to {}", seq);
+    Ok(())
+}
+
+pub const CMD: super::CmdDef = typed_cmd!(
+    "journal-rewind",
+    "Rewind the journal to an earlier entry, discarding later updates",
+    Cli,
+    cmd_journal_rewind
+);
@@ -9,16 +9,16 @@ use clap::Parser;
 
 // ---- RAII wrapper, same shape as list_journal ----
 
-struct JournalEntries {
+pub(crate) struct JournalEntries {
     inner: c::rust_journal_entries,
 }
 
 impl JournalEntries {
-    fn collect(c_fs: *mut c::bch_fs) -> Self {
+    pub(crate) fn collect(c_fs: *mut c::bch_fs) -> Self {
         Self { inner: unsafe { c::rust_collect_journal_entries(c_fs) } }
     }
 
-    fn as_slice(&self) -> &[*mut c::journal_replay] {
+    pub(crate) fn as_slice(&self) -> &[*mut c::journal_replay] {
         if self.inner.entries.is_null() || self.inner.nr == 0 {
             &[]
         } else {
@@ -60,13 +60,13 @@ fn entry_datetime_seconds(entry: &c::jset_entry) -> u64 {
 
 // ---- per-replay accessors ----
 
-fn jset_seq(p: &c::journal_replay) -> u64 {
+pub(crate) fn jset_seq(p: &c::journal_replay) -> u64 {
     u64::from_le(p.j.seq)
 }
 
 /// Scan a jset for its first BCH_JSET_ENTRY_datetime sub-entry and return
 /// seconds-since-epoch, or None if absent.
-fn jset_datetime(p: &c::journal_replay) -> Option<u64> {
+pub(crate) fn jset_datetime(p: &c::journal_replay) -> Option<u64> {
     for e in jset_entries(&p.j) {
         if entry_type(e) == journal_entry_type::datetime {
             return Some(entry_datetime_seconds(e));
@@ -76,7 +76,7 @@ fn jset_datetime(p: &c::journal_replay) -> Option<u64> {
 }
 
 /// Scan a jset for its first BCH_JSET_ENTRY_rewind_limit and return the seq.
-fn jset_rewind_limit(p: &c::journal_replay) -> Option<u64> {
+pub(crate) fn jset_rewind_limit(p: &c::journal_replay) -> Option<u64> {
     for e in jset_entries(&p.j) {
         if entry_type(e) == journal_entry_type::rewind_limit {
             return Some(entry_rewind_limit_seq(e));
@@ -85,13 +85,64 @@ fn jset_rewind_limit(p: &c::journal_replay) -> Option<u64> {
     None
 }
 
-fn fmt_secs(secs: u64) -> String {
+pub(crate) fn fmt_secs(secs: u64) -> String {
     match Utc.timestamp_opt(secs as i64, 0) {
         chrono::LocalResult::Single(dt) => dt.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
         _ => format!("(invalid: {secs})"),
     }
 }
 
+/// Inverse of fmt_secs: a UTC datetime, seconds optional, 'T' or ' ' between
+/// date and time, trailing "UTC" optional.
+pub(crate) fn parse_secs(s: &str) -> Option<u64> {
+    let t = s.trim().trim_end_matches("UTC").trim();
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|fmt| chrono::NaiveDateTime::parse_from_str(t, fmt).ok())
+        .map(|dt| dt.and_utc().timestamp().max(0) as u64)
+}
+
+// ---- the rewind window ----
+
+/// The rewind floor comes from the latest entry's rewind_limit sub-entry.
+/// If absent (older fs format / not yet written): fall back to lowest seq
+/// present on disk - the bool says we did, so callers can warn.
+pub(crate) fn rewind_floor(entries: &[*mut c::journal_replay], latest: &c::journal_replay) -> (u64, bool) {
+    match jset_rewind_limit(latest) {
+        Some(s) => (s, false),
+        None => {
+            let lo = entries.iter()
+                .map(|&ep| unsafe { jset_seq(&*ep) })
+                .min()
+                .unwrap_or(0);
+            (lo, true)
+        }
+    }
+}
+
+/// Flush entries within [floor, latest], sorted by seq, with their
+/// datetimes: each is a valid rewind target.
+pub(crate) fn rewind_candidates(
+    entries: &[*mut c::journal_replay],
+    floor_seq: u64,
+    latest_seq: u64,
+) -> Vec<(u64, Option<u64>)> {
+    let mut candidates: Vec<(u64, Option<u64>)> = Vec::new();
+    for &ep in entries {
+        let p = unsafe { &*ep };
+        let s = jset_seq(p);
+        if s < floor_seq || s > latest_seq {
+            continue;
+        }
+        if jset_no_flush(&p.j) {
+            continue;
+        }
+        candidates.push((s, jset_datetime(p)));
+    }
+    candidates.sort_by_key(|(s, _)| *s);
+    candidates
+}
+
 // ---- CLI ----
 
 /// Show journal rewind candidates: range of safe rewind seqs and the
@@ -157,19 +208,7 @@ fn cmd_journal_rewind_info(cli: Cli) -> Result<()> {
     let latest_seq = jset_seq(latest_p);
     let latest_dt = jset_datetime(latest_p);
 
-    // The rewind floor comes from the latest entry's rewind_limit sub-entry.
-    // If absent (older fs format / not yet written): fall back to lowest
-    // seq present on disk and warn.
-    let (floor_seq, fell_back) = match jset_rewind_limit(latest_p) {
-        Some(s) => (s, false),
-        None => {
-            let lo = entries.iter()
-                .map(|&ep| unsafe { jset_seq(&*ep) })
-                .min()
-                .unwrap();
-            (lo, true)
-        }
-    };
+    let (floor_seq, fell_back) = rewind_floor(entries, latest_p);
 
     let mut out = String::new();
 
@@ -188,20 +227,7 @@ fn cmd_journal_rewind_info(cli: Cli) -> Result<()> {
     writeln!(out).unwrap();
     writeln!(out).unwrap();
 
-    // Walk entries in window, filter to flush entries.
-    let mut candidates: Vec<(u64, Option<u64>)> = Vec::new();
-    for &ep in entries {
-        let p = unsafe { &*ep };
-        let s = jset_seq(p);
-        if s < floor_seq || s > latest_seq {
-            continue;
-        }
-        if jset_no_flush(&p.j) {
-            continue;
-        }
-        candidates.push((s, jset_datetime(p)));
-    }
-    candidates.sort_by_key(|(s, _)| *s);
+    let candidates = rewind_candidates(entries, floor_seq, latest_seq);
 
     let total_entries_in_window = entries.iter()
         .map(|&ep| unsafe { jset_seq(&*ep) })
@@ -245,3 +271,22 @@ pub const CMD: super::CmdDef = typed_cmd!(
     Cli,
     cmd_journal_rewind_info
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secs_parse() {
+        /* 2024-03-01 12:34:56 UTC */
+        let t = 1709296496;
+
+        assert_eq!(parse_secs(&fmt_secs(t)), Some(t));
+        assert_eq!(parse_secs("2024-03-01 12:34:56"), Some(t));
+        assert_eq!(parse_secs("2024-03-01T12:34:56"), Some(t));
+        assert_eq!(parse_secs("2024-03-01 12:34"), Some(t - 56));
+        assert_eq!(parse_secs("  2024-03-01T12:34 UTC "), Some(t - 56));
+        assert_eq!(parse_secs("2024-03-01"), None);
+        assert_eq!(parse_secs("12345"), None);
+    }
+}
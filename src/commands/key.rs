@@ -1,14 +1,24 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, bail, Context, Result};
-use bcachefs_kernel::c::{bch_key, bch_encrypted_key};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bcachefs_kernel::c::{
+    bch_key, bch_encrypted_key, bch_key_server, bch_key_slot, bch_key_slot_type,
+    bch_sb_field_key_servers, bch_sb_field_key_slots,
+};
 use bch_bindgen::c;
 use bcachefs_kernel::fs::Fs;
 use bcachefs_kernel::opt_set;
 use bch_bindgen::sb::io as sb_io;
+use bcachefs_kernel::sb::io::{SbBuf, SbField};
 use clap::Parser;
 
-use crate::key::{sb_is_encrypted, KeyHandle, Keyring, Passphrase, PassphraseCorrect};
+use crate::key::{
+    key_servers, key_slot_new, key_slot_server, key_slots, keyfile_unlock, recovery_key_new,
+    sb_is_encrypted, wrap_key, wrapping_key_new, KeyHandle, Keyring, Passphrase,
+    PassphraseCorrect,
+};
+use crate::key_server;
+use crate::wrappers::handle::BcachefsHandle;
 
 // ---- unlock ----
 
@@ -27,6 +37,11 @@ pub struct UnlockCli {
     #[arg(short, long)]
     file: Option<PathBuf>,
 
+    /// Keyfile, or directory of keyfiles, to unlock with via the
+    /// filesystem's keyfile slots (disables passphrase prompt)
+    #[arg(long, conflicts_with = "file")]
+    keyfile: Option<PathBuf>,
+
     /// Device
     device: String,
 }
@@ -62,11 +77,13 @@ fn cmd_unlock(cli: UnlockCli) -> Result<()> {
         bail!("{} is not encrypted", cli.device);
     }
 
-    let passphrase_correct = match cli.file {
-        Some(ref file) => Passphrase::read_from_file(file)?
+    let passphrase_correct = match (cli.file, cli.keyfile) {
+        (Some(ref file), _) => Passphrase::read_from_file(file)?
             .check(&sb)
             .ok_or_else(|| anyhow!("incorrect passphrase"))?,
-        None => Passphrase::ask_and_check(&sb)?,
+        (_, Some(ref keyfile)) => keyfile_unlock(&sb, keyfile)?
+            .ok_or_else(|| anyhow!("no keyfile slot matches {}", keyfile.display()))?,
+        _ => Passphrase::ask_and_check(&sb)?,
     };
     KeyHandle::new(&passphrase_correct, cli.keyring)?;
 
@@ -94,9 +111,9 @@ fn open_nostart(devs: &[PathBuf]) -> Result<Fs> {
 /// Open filesystem, verify encryption is enabled, and obtain the raw key.
 ///
 /// If the key is encrypted (passphrase-protected), prompts for and verifies
-/// the current passphrase. If the key is unencrypted (formatted with
-/// --no_passphrase), reads the raw key directly.
-fn open_and_verify(devs: &[PathBuf]) -> Result<(Fs, bch_key)> {
+/// the current passphrase, and also returns what it unlocked. If the key is
+/// unencrypted (formatted with --no_passphrase), reads the raw key directly.
+fn open_and_verify(devs: &[PathBuf]) -> Result<(Fs, bch_key, Option<PassphraseCorrect>)> {
     let fs = open_nostart(devs)?;
     let sb_handle = fs.sb_handle();
 
@@ -105,24 +122,68 @@ fn open_and_verify(devs: &[PathBuf]) -> Result<(Fs, bch_key)> {
     }
 
     if sb_is_encrypted(sb_handle) {
-        let PassphraseCorrect { cleartext_sb_key, .. } =
-            Passphrase::ask_and_check(sb_handle)?;
-        Ok((fs, cleartext_sb_key.into_key()))
+        let unlocked = Passphrase::ask_and_check(sb_handle)?;
+        let raw_key = unlocked.cleartext_sb_key.clone().into_key();
+        Ok((fs, raw_key, Some(unlocked)))
     } else {
         let raw_key = sb_handle.sb().crypt().unwrap().key().key.clone();
-        Ok((fs, raw_key))
+        Ok((fs, raw_key, None))
+    }
+}
+
+/// With key slots, the passphrase is one of them: the one `unlocked` came in
+/// through, which has to be a passphrase slot to be changed or removed as one.
+fn passphrase_slot(fs: &Fs, unlocked: Option<&PassphraseCorrect>) -> Result<Option<usize>> {
+    let Some(idx) = unlocked.and_then(|u| u.slot) else {
+        return Ok(None);
+    };
+
+    let slot_type = key_slots(fs.sb_handle().sb())[idx].slot_type();
+    ensure!(slot_type == bch_key_slot_type::BCH_KEY_SLOT_passphrase,
+            "that unlocked key slot {idx}, a {} slot: enter the passphrase to change",
+            slot_type_str(slot_type));
+    Ok(Some(idx))
+}
+
+/// The key a key rotation in progress is rotating out, unwrapped - the
+/// filesystem was opened with both.
+fn old_raw_key(fs: &Fs) -> Option<bch_key> {
+    let crypt = fs.sb_handle().sb().crypt()?;
+    if !crypt.rotating() {
+        return None;
     }
+
+    let old_gen = (crypt.key_seq() & 1) as usize ^ 1;
+    Some(unsafe { (*fs.raw).chacha20_keys[old_gen].clone() })
 }
 
-/// Write a new encrypted key to the crypt superblock field.
+/// Write new encrypted keys to the crypt superblock field: `old_key` is the
+/// key being rotated out, if a key rotation is in progress.
 ///
 /// # Safety
 /// Caller must hold sb_lock.
-unsafe fn set_crypt_key(fs: &Fs, key: c::bch_encrypted_key) {
+unsafe fn set_crypt_key(fs: &Fs, key: c::bch_encrypted_key, old_key: Option<c::bch_encrypted_key>) {
     let disk_sb = &mut (*fs.raw).disk_sb;
     let crypt: &mut c::bch_sb_field_crypt = bcachefs_kernel::sb::io::sb_field_get_mut(disk_sb)
         .expect("filesystem has no crypt field");
     crypt.key = key;
+    if let Some(old_key) = old_key {
+        crypt.old_key = old_key;
+    }
+}
+
+/// Wrap the master key, and the key a rotation in progress is rotating out,
+/// with `wrapping_key`.
+///
+/// # Safety
+/// Caller must hold sb_lock.
+unsafe fn rewrap_crypt_key(fs: &Fs, raw_key: bch_key, wrapping_key: &bch_key) {
+    let sb = fs.sb_handle();
+    let seq = sb.sb().crypt().unwrap().key_seq();
+
+    let key = wrap_key(sb, wrapping_key.clone(), raw_key, seq);
+    let old_key = old_raw_key(fs).map(|old| wrap_key(sb, wrapping_key.clone(), old, seq - 1));
+    set_crypt_key(fs, key, old_key);
 }
 
 // ---- set-passphrase ----
@@ -136,15 +197,36 @@ pub struct SetPassphraseCli {
 }
 
 fn cmd_set_passphrase(cli: SetPassphraseCli) -> Result<()> {
-    let (fs, raw_key) = open_and_verify(&parse_device_list(&cli.devices))?;
+    let (fs, raw_key, unlocked) = open_and_verify(&parse_device_list(&cli.devices))?;
+    let slot = passphrase_slot(&fs, unlocked.as_ref())?;
 
     let new_passphrase = Passphrase::ask_for_new_passphrase()
         .context("reading new passphrase")?;
 
+    // Replace just the passphrase's key slot: the others hold their own copies
+    // of the key the master key is wrapped with, which doesn't change.
+    if let (Some(idx), Some(unlocked)) = (slot, unlocked) {
+        let mut slots = key_slots(fs.sb_handle().sb()).to_vec();
+        let label = String::from_utf8_lossy(slots[idx].label()).into_owned();
+        slots[idx] = key_slot_new(bch_key_slot_type::BCH_KEY_SLOT_passphrase, &label,
+                                  new_passphrase.get().to_bytes(), &unlocked.passphrase_key)?;
+
+        let servers = key_servers(fs.sb_handle().sb()).to_vec();
+        unsafe { set_key_slots(&fs, slots, servers)?; }
+        fs.write_super();
+
+        println!("Changed the passphrase in key slot {idx}");
+        return Ok(());
+    }
+
     let encrypted_key = new_passphrase.encrypt_key(fs.sb_handle(), raw_key);
+    let encrypted_old_key = old_raw_key(&fs).map(|old| {
+        let seq = fs.sb_handle().sb().crypt().unwrap().key_seq();
+        new_passphrase.encrypt_key_seq(fs.sb_handle(), old, seq - 1)
+    });
 
     unsafe {
-        set_crypt_key(&fs, encrypted_key);
+        set_crypt_key(&fs, encrypted_key, encrypted_old_key);
         c::bch2_revoke_key(fs.sb_handle().sb);
     }
     fs.write_super();
@@ -163,14 +245,430 @@ pub struct RemovePassphraseCli {
 }
 
 fn cmd_remove_passphrase(cli: RemovePassphraseCli) -> Result<()> {
-    let (fs, raw_key) = open_and_verify(&parse_device_list(&cli.devices))?;
+    let (fs, raw_key, unlocked) = open_and_verify(&parse_device_list(&cli.devices))?;
+
+    // The passphrase is one key slot of several: remove just it, and the
+    // others still unlock the filesystem.
+    let mut slots = key_slots(fs.sb_handle().sb()).to_vec();
+    if let Some(idx) = passphrase_slot(&fs, unlocked.as_ref())? {
+        if slots.len() > 1 {
+            slots.remove(idx);
+
+            let servers = key_servers(fs.sb_handle().sb()).to_vec();
+            unsafe { set_key_slots(&fs, slots, servers)?; }
+            fs.write_super();
+
+            println!("Removed key slot {idx}: the filesystem's other key slots still unlock it");
+            return Ok(());
+        }
+    }
+
+    let old_key = old_raw_key(&fs).map(bch_encrypted_key::new_unencrypted);
+
+    unsafe { set_crypt_key(&fs, bch_encrypted_key::new_unencrypted(raw_key), old_key); }
+
+    if !slots.is_empty() {
+        unsafe { set_key_slots(&fs, Vec::new(), Vec::new())?; }
+    }
+    fs.write_super();
+
+    Ok(())
+}
+
+// ---- key slots ----
+
+/// Open a filesystem for changing its key slots, and prompt for a passphrase
+/// (or recovery key) that unlocks it: key slots wrap the key the master key is
+/// wrapped with, and the first one turns the passphrase into a key slot too.
+fn open_and_unlock(devs: &[PathBuf]) -> Result<(Fs, Passphrase, PassphraseCorrect)> {
+    let fs = open_nostart(devs)?;
+    let sb_handle = fs.sb_handle();
+
+    if sb_handle.sb().crypt().is_none() {
+        bail!("Filesystem does not have encryption enabled");
+    }
+
+    if !sb_is_encrypted(sb_handle) {
+        bail!("Filesystem has no passphrase for key slots to unlock: set one first (bcachefs set-passphrase)");
+    }
+
+    let (passphrase, unlocked) = Passphrase::ask_current(sb_handle)?;
+    Ok((fs, passphrase, unlocked))
+}
+
+/// Replace the contents of a superblock field that's an array of `T`, deleting
+/// the field if there are none.
+///
+/// # Safety
+/// Caller must hold sb_lock.
+unsafe fn set_sb_field_array<F: SbField, T>(
+    fs: &Fs,
+    items: Vec<T>,
+    array: impl FnOnce(&mut F, usize) -> &mut [T],
+) -> Result<()> {
+    let disk_sb = &mut (*fs.raw).disk_sb;
+
+    if items.is_empty() {
+        bcachefs_kernel::c::bch2_sb_field_delete(disk_sb, F::FIELD_TYPE);
+        return Ok(());
+    }
+
+    let bytes = std::mem::size_of::<F>() + items.len() * std::mem::size_of::<T>();
+    let f: &mut F = bcachefs_kernel::sb::io::sb_field_resize(disk_sb, bytes.div_ceil(8) as u32)
+        .ok_or_else(|| anyhow!("no room in the superblock for {} key slots", items.len()))?;
+
+    let nr = items.len();
+    for (dst, src) in array(f, nr).iter_mut().zip(items) {
+        std::ptr::write(dst, src);
+    }
+    Ok(())
+}
+
+/// Replace the key slots, and the key server entries for network-bound ones.
+///
+/// # Safety
+/// Caller must hold sb_lock.
+unsafe fn set_key_slots(
+    fs: &Fs,
+    slots: Vec<bch_key_slot>,
+    mut servers: Vec<bch_key_server>,
+) -> Result<()> {
+    // A key server entry only means anything for its slot:
+    servers.retain(|s| slots.iter().any(|slot| slot.salt == s.slot_salt));
+
+    set_sb_field_array::<bch_sb_field_key_slots, _>(fs, slots,
+        |f, nr| f.slots.as_mut_slice(nr))?;
+    set_sb_field_array::<bch_sb_field_key_servers, _>(fs, servers,
+        |f, nr| f.servers.as_mut_slice(nr))
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum SlotType {
+    /// Another passphrase
+    Passphrase,
+    /// The contents of a file
+    Keyfile,
+    /// A generated recovery key, printed once; entered like a passphrase
+    Recovery,
+    /// A secret only a key server can compute (see bcachefs key-server)
+    Network,
+}
+
+impl From<SlotType> for bch_key_slot_type {
+    fn from(t: SlotType) -> Self {
+        match t {
+            SlotType::Passphrase    => bch_key_slot_type::BCH_KEY_SLOT_passphrase,
+            SlotType::Keyfile       => bch_key_slot_type::BCH_KEY_SLOT_keyfile,
+            SlotType::Recovery      => bch_key_slot_type::BCH_KEY_SLOT_recovery,
+            SlotType::Network       => bch_key_slot_type::BCH_KEY_SLOT_network,
+        }
+    }
+}
+
+fn slot_type_str(t: bch_key_slot_type) -> &'static str {
+    match t {
+        bch_key_slot_type::BCH_KEY_SLOT_passphrase  => "passphrase",
+        bch_key_slot_type::BCH_KEY_SLOT_keyfile     => "keyfile",
+        bch_key_slot_type::BCH_KEY_SLOT_recovery    => "recovery",
+        bch_key_slot_type::BCH_KEY_SLOT_network     => "network",
+        _                                           => "unknown",
+    }
+}
+
+/// Read a keyfile, creating it with random contents if it doesn't exist yet.
+fn keyfile_read_or_create(path: &Path) -> Result<zeroize::Zeroizing<Vec<u8>>> {
+    use std::io::{Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if path.exists() {
+        let secret = zeroize::Zeroizing::new(std::fs::read(path)
+            .with_context(|| format!("reading keyfile {}", path.display()))?);
+        ensure!(!secret.is_empty(), "keyfile {} is empty", path.display());
+        return Ok(secret);
+    }
+
+    let mut secret = zeroize::Zeroizing::new(vec![0u8; 64]);
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut secret)?;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o400)
+        .open(path)
+        .and_then(|mut f| f.write_all(&secret))
+        .with_context(|| format!("creating keyfile {}", path.display()))?;
+
+    println!("Created keyfile {}", path.display());
+    Ok(secret)
+}
 
-    unsafe { set_crypt_key(&fs, bch_encrypted_key::new_unencrypted(raw_key)); }
+#[derive(Parser, Debug)]
+#[command(about = "Add a passphrase, keyfile or recovery key that unlocks an existing encrypted (unmounted) filesystem")]
+pub struct AddSlotCli {
+    /// What unlocks the new slot
+    #[arg(short, long, value_enum, default_value = "passphrase")]
+    r#type: SlotType,
+
+    /// Keyfile for a keyfile slot; created with random contents if it
+    /// doesn't exist
+    #[arg(short, long, required_if_eq("type", "keyfile"))]
+    keyfile: Option<PathBuf>,
+
+    /// Key server for a network slot: host, or host:port
+    #[arg(short, long, required_if_eq("type", "network"))]
+    server: Option<String>,
+
+    /// The key server's public key, in hex (bcachefs key-server --show-key):
+    /// if not given, it's fetched from the server and printed, to be checked
+    #[arg(long)]
+    server_key: Option<String>,
+
+    /// Label, to tell slots apart (and remove them by)
+    #[arg(short, long, default_value = "")]
+    label: String,
+
+    /// Devices (colon-separated or multiple arguments)
+    #[arg(required = true)]
+    devices: Vec<String>,
+}
+
+fn cmd_add_slot(cli: AddSlotCli) -> Result<()> {
+    let (fs, passphrase, unlocked) = open_and_unlock(&parse_device_list(&cli.devices))?;
+
+    let mut slots = key_slots(fs.sb_handle().sb()).to_vec();
+
+    // The first key slot: the master key gets wrapped with a new random key,
+    // instead of the one derived from the passphrase, and the passphrase
+    // becomes key slot 0 - so that it and the other slots can be changed or
+    // removed independently.
+    let first = slots.is_empty();
+    let wrapping_key = if first {
+        let key = wrapping_key_new()?;
+        slots.push(key_slot_new(bch_key_slot_type::BCH_KEY_SLOT_passphrase, "",
+                                passphrase.get().to_bytes(), &key)?);
+        key
+    } else {
+        unlocked.passphrase_key.clone()
+    };
+
+    let mut recovery_key = None;
+    let mut server = None;
+    let secret: zeroize::Zeroizing<Vec<u8>> = match cli.r#type {
+        SlotType::Passphrase => {
+            let p = Passphrase::ask_for_new_passphrase()
+                .context("reading new passphrase")?;
+            zeroize::Zeroizing::new(p.get().to_bytes().to_vec())
+        }
+        SlotType::Keyfile => keyfile_read_or_create(cli.keyfile.as_deref().unwrap())?,
+        SlotType::Recovery => {
+            let key = recovery_key_new()?;
+            let secret = zeroize::Zeroizing::new(key.as_bytes().to_vec());
+            recovery_key = Some(key);
+            secret
+        }
+        SlotType::Network => {
+            let addr = key_server::normalize_addr(cli.server.as_deref().unwrap());
+            ensure!(addr.len() < 64, "key server address too long: {addr}");
+
+            let server_pk = match cli.server_key {
+                Some(ref k) => key_server::hex_decode(k)?,
+                None => {
+                    let k = key_server::fetch_server_key(&addr)?;
+                    println!("Key server {addr} has public key {}", key_server::hex_encode(&k));
+                    println!("Check it against bcachefs key-server --show-key on the server");
+                    k
+                }
+            };
+
+            // Don't add a slot we can't unlock:
+            let (client_pk, secret) = key_server::provision(&server_pk)?;
+            let check = key_server::recover(&addr, &server_pk, &client_pk)?;
+            ensure!(*check == *secret, "key server {addr} gave back the wrong secret");
+
+            let mut entry = bch_key_server {
+                slot_salt:  [0; 16],
+                server_pk,
+                client_pk,
+                addr:       [0; 64],
+            };
+            entry.addr[..addr.len()].copy_from_slice(addr.as_bytes());
+            server = Some(entry);
+
+            zeroize::Zeroizing::new(secret.to_vec())
+        }
+    };
+
+    let slot = key_slot_new(cli.r#type.into(), &cli.label, &secret, &wrapping_key)?;
+
+    let mut servers = key_servers(fs.sb_handle().sb()).to_vec();
+    if let Some(mut entry) = server {
+        entry.slot_salt = slot.salt;
+        servers.push(entry);
+    }
+
+    slots.push(slot);
+    let nr = slots.len();
+
+    unsafe {
+        set_key_slots(&fs, slots, servers)?;
+        if first {
+            rewrap_crypt_key(&fs, unlocked.cleartext_sb_key.clone().into_key(), &wrapping_key);
+            c::bch2_revoke_key(fs.sb_handle().sb);
+        }
+    }
+    fs.write_super();
+
+    if first {
+        println!("The passphrase is now key slot 0");
+    }
+    println!("Added key slot {}", nr - 1);
+    if let Some(key) = recovery_key {
+        println!("Recovery key - keep it somewhere safe, it is not shown again:");
+        println!("    {}", key.as_str());
+    }
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Remove a key slot from an existing encrypted (unmounted) filesystem")]
+pub struct RemoveSlotCli {
+    /// Slot to remove: its number, or its label
+    slot: String,
+
+    /// Devices (colon-separated or multiple arguments)
+    #[arg(required = true)]
+    devices: Vec<String>,
+}
+
+fn cmd_remove_slot(cli: RemoveSlotCli) -> Result<()> {
+    let (fs, _, _) = open_and_unlock(&parse_device_list(&cli.devices))?;
+
+    let mut slots = key_slots(fs.sb_handle().sb()).to_vec();
+
+    let idx = match cli.slot.parse::<usize>() {
+        Ok(i) if i < slots.len() => i,
+        _ => {
+            let mut matches = slots.iter().enumerate()
+                .filter(|(_, s)| s.label() == cli.slot.as_bytes())
+                .map(|(i, _)| i);
+            let i = matches.next()
+                .ok_or_else(|| anyhow!("no key slot {}", cli.slot))?;
+            ensure!(matches.next().is_none(),
+                    "more than one key slot labelled {}: remove by number", cli.slot);
+            i
+        }
+    };
+
+    // With key slots, the master key is only wrapped with a key they hold:
+    ensure!(slots.len() > 1,
+            "key slot {idx} is the only one left, and the only way to unlock the filesystem: \
+             use bcachefs remove-passphrase to store the key unencrypted instead");
+    slots.remove(idx);
+
+    let servers = key_servers(fs.sb_handle().sb()).to_vec();
+    unsafe { set_key_slots(&fs, slots, servers)?; }
     fs.write_super();
 
+    println!("Removed key slot {idx}");
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "List the key slots of an encrypted filesystem")]
+pub struct ListSlotsCli {
+    /// Device
+    device: String,
+}
+
+fn cmd_list_slots(cli: ListSlotsCli) -> Result<()> {
+    let sb = sb_io::read_super(Path::new(&cli.device))
+        .with_context(|| format!("Error opening {}", cli.device))?;
+
+    if sb.sb().crypt().is_none() {
+        bail!("{} is not encrypted", cli.device);
+    }
+
+    if !sb_is_encrypted(&sb) {
+        println!("No passphrase: the key is stored unencrypted");
+        return Ok(());
+    }
+
+    println!("{:<6}{:<12}LABEL", "SLOT", "TYPE");
+    if key_slots(sb.sb()).is_empty() {
+        println!("{:<6}{:<12}(the passphrase the key is wrapped with)", "-", "passphrase");
+    }
+    for (i, slot) in key_slots(sb.sb()).iter().enumerate() {
+        print!("{:<6}{:<12}{}", i, slot_type_str(slot.slot_type()),
+               String::from_utf8_lossy(slot.label()));
+        if let Some(server) = key_slot_server(sb.sb(), slot) {
+            print!(" (key server {})", String::from_utf8_lossy(server.addr()));
+        }
+        println!();
+    }
+    Ok(())
+}
+
+// ---- key rotate ----
+
+#[derive(Parser, Debug)]
+#[command(about = "Generate a new master encryption key and re-encrypt the filesystem with it, online")]
+pub struct RotateCli {
+    /// Report whether a key rotation is in progress, then exit
+    #[arg(short, long)]
+    status: bool,
+
+    /// Filesystem: mountpoint, member device or UUID
+    filesystem: String,
+}
+
+fn cmd_key_rotate(cli: RotateCli) -> Result<()> {
+    let handle = BcachefsHandle::open_if_mounted(&cli.filesystem)?
+        .ok_or_else(|| anyhow!("{} is not mounted: key rotation runs on a mounted filesystem", cli.filesystem))?;
+
+    let buf = handle.read_super()
+        .map_err(|e| anyhow!("reading superblock: {}", e))?;
+    let sb = SbBuf::from_bytes(&buf)
+        .map_err(|e| anyhow!("reading superblock: {}", e))?;
+    let sb = sb.sb();
+
+    let crypt = sb.crypt()
+        .ok_or_else(|| anyhow!("{} is not encrypted", cli.filesystem))?;
+
+    if cli.status {
+        if crypt.rotating() {
+            println!("Key rotation in progress (key generation {})", crypt.key_seq());
+        } else {
+            println!("No key rotation in progress (key generation {})", crypt.key_seq());
+        }
+        return Ok(());
+    }
+
+    // The new key gets wrapped with the passphrase, which the kernel takes
+    // from the keyring, same as at mount time:
+    if crypt.key().is_encrypted() && KeyHandle::new_from_search(&sb.uuid()).is_err() {
+        bail!("passphrase for {} is not in the keyring: run bcachefs unlock on a member device first",
+              cli.filesystem);
+    }
+
+    handle.key_rotate()
+        .map_err(|e| anyhow!("starting key rotation: {}", e))?;
+
+    println!("Key rotation started: data is re-encrypted in the background");
+    println!("Check progress with bcachefs key rotate --status, or bcachefs reconcile status");
     Ok(())
 }
 
 pub const CMD_UNLOCK: super::CmdDef = typed_cmd!("unlock", "Unlock an encrypted filesystem", UnlockCli, cmd_unlock);
 pub const CMD_SET_PASSPHRASE: super::CmdDef = typed_cmd!("set-passphrase", "Set or change encryption passphrase", SetPassphraseCli, cmd_set_passphrase);
 pub const CMD_REMOVE_PASSPHRASE: super::CmdDef = typed_cmd!("remove-passphrase", "Remove encryption passphrase", RemovePassphraseCli, cmd_remove_passphrase);
+pub const CMD_KEY_ROTATE: super::CmdDef = typed_cmd!("rotate", "Rotate the master encryption key", RotateCli, cmd_key_rotate);
+pub const CMD_KEY_ADD_SLOT: super::CmdDef = typed_cmd!("add-slot", "Add a passphrase, keyfile or recovery key slot", AddSlotCli, cmd_add_slot);
+pub const CMD_KEY_REMOVE_SLOT: super::CmdDef = typed_cmd!("remove-slot", "Remove a key slot", RemoveSlotCli, cmd_remove_slot);
+pub const CMD_KEY_LIST_SLOTS: super::CmdDef = typed_cmd!("list-slots", "List key slots", ListSlotsCli, cmd_list_slots);
+
+pub const CMD_KEY: super::CmdDef = super::CmdDef {
+    name: "key", about: "Manage encryption keys", aliases: &[],
+    kind: super::CmdKind::Group { children: &[
+        &CMD_KEY_ROTATE, &CMD_KEY_ADD_SLOT, &CMD_KEY_REMOVE_SLOT, &CMD_KEY_LIST_SLOTS,
+    ]},
+};
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+
+use crate::key_server;
+
+#[derive(Parser, Debug)]
+#[command(about = "Run a key server for network-bound key slots",
+          long_about = "Run a key server for network-bound key slots.\n\n\
+Filesystems with a network key slot (bcachefs key add-slot --type network) can \
+be unlocked with mount -k network by any machine that can reach this server. \
+The server keeps no state besides its own key, and answers anyone who can \
+reach it: run it where only the machines it unlocks can.\n\n\
+The server sees every slot secret it serves - there is no blinding - so \
+anyone who controls it, or has its private key, can unlock every network \
+slot provisioned against it: run it on a trusted machine.\n\n\
+This is a reference implementation, for testing and simple setups; the \
+protocol is documented in src/key_server.rs.")]
+pub struct Cli {
+    /// Address to listen on
+    #[arg(short, long, default_value_t = format!("0.0.0.0:{}", key_server::DEFAULT_PORT))]
+    listen: String,
+
+    /// The server's private key; generated if it doesn't exist
+    #[arg(short, long, default_value = "/var/lib/bcachefs/key-server.key")]
+    key: PathBuf,
+
+    /// Print the server's public key, then exit
+    #[arg(long)]
+    show_key: bool,
+}
+
+fn cmd_key_server(cli: Cli) -> Result<()> {
+    let (pk, sk) = key_server::server_key_load(&cli.key)?;
+
+    if cli.show_key {
+        println!("{}", key_server::hex_encode(&pk));
+        return Ok(());
+    }
+
+    println!("Listening on {}, public key {}", cli.listen, key_server::hex_encode(&pk));
+    key_server::serve(&cli.listen, &pk, &sk)
+}
+
+pub const CMD: super::CmdDef = typed_cmd!("key-server", "Run a key server for network-bound key slots", Cli, cmd_key_server);
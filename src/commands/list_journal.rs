@@ -4,13 +4,14 @@ use anyhow::{anyhow, bail, Result};
 use bch_bindgen::c;
 use bcachefs_kernel::{BbposRange, bbpos_range_parse};
 use bcachefs_kernel::journal::{
-    jset_entries, jset_entry_keys, entry_type, entry_btree_id, entry_log_str_eq,
+    jset_entries, jset_entry_keys, entry_type, entry_btree_id, entry_log_msg, entry_log_str_eq,
     entry_type_is_known, journal_entry_type, jset_vstruct_bytes, jset_vstruct_sectors, jset_no_flush,
 };
 use bcachefs_kernel::opt_set;
 use clap::Parser;
 
 use bcachefs_kernel::util::printbuf::Printbuf;
+use crate::commands::journal_rewind_info::{fmt_secs, jset_datetime, parse_secs};
 use crate::util::read_flag_list;
 
 // ---- RAII wrapper for C-allocated journal entries array ----
@@ -115,6 +116,15 @@ pub(crate) struct JournalFilter {
     pub(crate) btree_filter: u64,
     pub(crate) transaction: TransactionMsgFilter,
     pub(crate) key: TransactionKeyFilter,
+    /// Inode numbers: match transactions updating any of them, in the
+    /// btrees keyed by inode (extents, inodes, dirents, xattrs).
+    pub(crate) inodes: Vec<u64>,
+    /// Time range, seconds since the epoch. Goes by jset datetime entries;
+    /// entries without one (non flush writes) take the last datetime seen.
+    pub(crate) since: Option<u64>,
+    pub(crate) until: Option<u64>,
+    /// Instead of transactions, print one line per update to this key.
+    pub(crate) follow_key: Option<c::bbpos>,
     pub(crate) bkey_val: bool,
 }
 
@@ -134,6 +144,10 @@ impl Default for JournalFilter {
             btree_filter: !0u64,
             transaction: TransactionMsgFilter { sign: 0, patterns: Vec::new() },
             key: TransactionKeyFilter { ranges: Vec::new() },
+            inodes: Vec::new(),
+            since: None,
+            until: None,
+            follow_key: None,
             bkey_val: true,
         }
     }
@@ -225,8 +239,41 @@ fn transaction_matches_transaction_filter(
     true
 }
 
+/// The inode a key belongs to, for btrees keyed by inode number:
+fn bkey_inum(btree: c::btree_id, k: &c::bkey_i) -> Option<u64> {
+    match btree {
+        c::btree_id::extents
+            | c::btree_id::dirents
+            | c::btree_id::xattrs => Some(k.k.p.inode),
+        c::btree_id::inodes => Some(k.k.p.offset),
+        _ => None,
+    }
+}
+
+fn entry_matches_inodes(entry: &c::jset_entry, inodes: &[u64]) -> bool {
+    if entry.level != 0 || !entry_is_print_key(entry) {
+        return false;
+    }
+    let Some(btree) = entry_btree_id(entry) else { return false };
+
+    jset_entry_keys(entry).any(|k| bkey_inum(btree, k).is_some_and(|i| inodes.contains(&i)))
+}
+
+/// Glob match, '*' only - enough for function names:
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
 fn entry_matches_msg_filter(f: &TransactionMsgFilter, entry: &c::jset_entry) -> bool {
-    f.patterns.iter().any(|p| entry_log_str_eq(entry, p))
+    f.patterns.iter().any(|p| if p.contains('*') {
+        glob_match(p.as_bytes(), entry_log_msg(entry))
+    } else {
+        entry_log_str_eq(entry, p)
+    })
 }
 
 fn entry_is_log_only(entries: &[&c::jset_entry]) -> bool {
@@ -274,6 +321,12 @@ fn should_print_transaction(
         return false;
     }
 
+    if !f.inodes.is_empty()
+        && !entries.iter().skip(1).any(|e| entry_matches_inodes(e, &f.inodes))
+    {
+        return false;
+    }
+
     true
 }
 
@@ -500,6 +553,75 @@ fn journal_replay_print(c_fs: *mut c::bch_fs, f: &JournalFilter, p: &c::journal_
     print_buf(&buf, blacklisted);
 }
 
+/// --follow-key: one line per update to the key, naming the transaction that
+/// made it. Overwrite entries carry the value being replaced. Of the filters,
+/// only the transaction (-t) and time filters apply: the rest select keys,
+/// and clap refuses them alongside a single key.
+fn journal_replay_follow_key(
+    c_fs: *mut c::bch_fs,
+    f: &JournalFilter,
+    p: &c::journal_replay,
+    pos: &c::bbpos,
+    time: Option<u64>,
+) {
+    let mut buf = Printbuf::new();
+    let seq = u64::from_le(p.j.seq);
+    let blacklisted = p.ignore_blacklisted
+        || unsafe { c::bch2_journal_seq_is_blacklisted(c_fs, seq, false) };
+    let mut origin: &[u8] = b"";
+    let mut origin_matches = true;
+
+    for entry in jset_entries(&p.j) {
+        if entry_is_transaction_start(entry) {
+            origin = entry_log_msg(entry);
+            origin_matches = f.transaction.patterns.is_empty()
+                || entry_matches_msg_filter(&f.transaction, entry) == (f.transaction.sign >= 0);
+            continue;
+        }
+
+        if !origin_matches {
+            continue;
+        }
+
+        let t = entry_type(entry);
+        if entry.level != 0
+            || !matches!(t, journal_entry_type::btree_keys
+                         | journal_entry_type::write_buffer_keys
+                         | journal_entry_type::overwrite)
+            || entry_btree_id(entry) != Some(pos.btree)
+        {
+            continue;
+        }
+
+        for k in jset_entry_keys(entry) {
+            let mut k_pos = k.k.p;
+            if pos.pos.snapshot == 0 {
+                k_pos.snapshot = 0;
+            }
+            if k_pos != pos.pos {
+                continue;
+            }
+
+            write!(buf, "{:<10} {:<23} {:<32} {} ",
+                   seq,
+                   time.map(fmt_secs).unwrap_or_else(|| "(no datetime)".into()),
+                   String::from_utf8_lossy(origin),
+                   if t == journal_entry_type::overwrite { "old" } else { "new" }).unwrap();
+            unsafe {
+                if f.bkey_val {
+                    c::bch2_bkey_val_to_text(buf.as_raw(), c_fs,
+                                             c::bkey_s_c { k: &k.k, v: &k.v });
+                } else {
+                    c::bch2_bkey_to_text(buf.as_raw(), &k.k);
+                }
+            }
+            buf.newline();
+        }
+    }
+
+    print_buf(&buf, blacklisted);
+}
+
 fn print_buf(buf: &Printbuf, blacklisted: bool) {
     use std::io::Write;
     let s = buf.as_str();
@@ -628,7 +750,7 @@ pub struct Cli {
     #[arg(short = 'b', long, allow_hyphen_values = true)]
     btree: Option<String>,
 
-    /// Filter transactions by function (+/-fn1,fn2)
+    /// Filter transactions by function (+/-fn1,fn2; prefix, or glob with *)
     #[arg(short = 't', long, allow_hyphen_values = true)]
     transaction: Option<String>,
 
@@ -636,6 +758,24 @@ pub struct Cli {
     #[arg(short = 'k', long, allow_hyphen_values = true)]
     key: Vec<String>,
 
+    /// Filter by inode number (inum,...): transactions updating its
+    /// extents, inode, dirents or xattrs
+    #[arg(short = 'i', long, value_delimiter = ',')]
+    inode: Vec<u64>,
+
+    /// Only entries written at or after this UTC datetime (YYYY-MM-DD HH:MM[:SS])
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Only entries written at or before this UTC datetime (YYYY-MM-DD HH:MM[:SS])
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Print the update history of one key (btree:pos), one line per update
+    #[arg(long = "follow-key",
+          conflicts_with_all = ["datetime", "headers_only", "btree", "key", "inode"])]
+    follow_key: Option<String>,
+
     /// Print bkey values (true/false)
     #[arg(short = 'V', long = "bkey-val")]
     bkey_val: Option<String>,
@@ -715,6 +855,10 @@ fn cmd_list_journal(cli: Cli) -> Result<()> {
         key: TransactionKeyFilter {
             ranges: Vec::new(),
         },
+        inodes: Vec::new(),
+        since: None,
+        until: None,
+        follow_key: None,
         bkey_val: true,
     };
 
@@ -750,6 +894,21 @@ fn cmd_list_journal(cli: Cli) -> Result<()> {
         f.filtering = true;
     }
 
+    if !cli.inode.is_empty() {
+        f.inodes = cli.inode.clone();
+        f.filtering = true;
+    }
+
+    let parse_time = |s: &str| parse_secs(s)
+        .ok_or_else(|| anyhow!("error parsing datetime (want YYYY-MM-DD HH:MM[:SS], UTC): {s}"));
+    f.since = cli.since.as_deref().map(parse_time).transpose()?;
+    f.until = cli.until.as_deref().map(parse_time).transpose()?;
+
+    if let Some(ref key_arg) = cli.follow_key {
+        f.follow_key = Some(key_arg.parse::<c::bbpos>()
+            .map_err(|e| anyhow!("{}: {}", e, key_arg))?);
+    }
+
     if let Some(ref bkey_val_arg) = cli.bkey_val {
         f.bkey_val = parse_bool_val(bkey_val_arg)?;
     }
@@ -823,6 +982,7 @@ pub(crate) fn list_journal_run(
     // Main iteration
     let mut seq = 0u64;
     let last_seq_ondisk = unsafe { (*c_fs).journal.last_seq_ondisk };
+    let mut time: Option<u64> = None;
 
     for &ep in entries {
         if interrupted.is_some_and(|i| i()) {
@@ -833,6 +993,20 @@ pub(crate) fn list_journal_run(
         let p = unsafe { &*ep };
         let p_seq = u64::from_le(p.j.seq);
 
+        // Tracked across skipped entries, so the time filter sees the
+        // last datetime written before an entry that lacks one:
+        if let Some(t) = jset_datetime(p) {
+            time = Some(t);
+        }
+
+        if f.since.is_some_and(|since| !time.is_some_and(|t| t >= since)) {
+            continue;
+        }
+
+        if f.until.is_some_and(|until| time.is_some_and(|t| t > until)) {
+            break;
+        }
+
         if p_seq < min_seq_to_print {
             continue;
         }
@@ -886,7 +1060,10 @@ pub(crate) fn list_journal_run(
             continue;
         }
 
-        journal_replay_print(c_fs, &f, p);
+        match &f.follow_key {
+            Some(pos) => journal_replay_follow_key(c_fs, f, p, pos, time),
+            None => journal_replay_print(c_fs, f, p),
+        }
     }
 
     Ok(())
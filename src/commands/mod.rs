@@ -95,15 +95,20 @@ pub mod device;
 pub mod dump;
 pub mod format;
 pub mod format_util;
+pub mod fs_exporter;
 pub mod fs_failure_domains;
+pub mod fs_failure_domains_files;
 pub mod fs_usage;
+pub mod fs_usage_history;
 pub mod fsck;
 #[cfg(feature = "fuse")]
 pub mod fusemount;
 pub mod image;
 pub mod key;
+pub mod key_server;
 pub mod kill_btree_node;
 pub mod kvdb;
+pub mod journal_rewind;
 pub mod journal_rewind_info;
 pub mod list;
 pub mod list_journal;
@@ -111,13 +116,18 @@ pub mod migrate;
 pub mod mount;
 pub mod opts;
 pub mod reconcile;
+pub mod reconcile_path;
+pub mod reconcile_throttle;
 pub mod recover_super;
 pub mod recovery_pass;
 pub mod scrub;
 pub mod set_option;
 pub mod strip_alloc;
 pub mod subvolume;
+pub mod subvolume_autosnap;
+pub mod subvolume_send;
 pub mod super_cmd;
+pub mod systemd_generator;
 pub mod timestats;
 pub mod top;
 pub mod unpoison;
@@ -214,7 +224,9 @@ pub fn defers_shrinkers(name: &str) -> bool {
 
 static FS_CMD: CmdDef = CmdDef {
     name: "fs", about: "Manage a running filesystem", aliases: &[],
-    kind: CmdKind::Group { children: &[&fs_usage::CMD, &fs_failure_domains::CMD, &top::CMD, &timestats::CMD] },
+    kind: CmdKind::Group { children: &[
+        &fs_usage::CMD, &fs_failure_domains::CMD, &top::CMD, &timestats::CMD, &fs_exporter::CMD,
+    ] },
 };
 
 // ── Version (no module, trivial) ─────────────────────────────────────
@@ -242,13 +254,17 @@ pub const COMMAND_GROUPS: &[GroupDef] = &[
         #[cfg(feature = "fuse")]
         &fusemount::CMD,
         &wait_devices::CMD,
+        &systemd_generator::CMD,
     ]},
-    GroupDef { heading: "Repair",                   commands: &[&fsck::CMD, &journal_rewind_info::CMD, &recovery_pass::CMD, &damage::CMD] },
+    GroupDef { heading: "Repair",                   commands: &[&fsck::CMD, &journal_rewind::CMD, &journal_rewind_info::CMD, &recovery_pass::CMD, &damage::CMD] },
     GroupDef { heading: "Running filesystem",       commands: &[&FS_CMD] },
     GroupDef { heading: "Devices",                  commands: &[&device::CMD] },
     GroupDef { heading: "Subvolumes and snapshots", commands: &[&subvolume::CMD] },
     GroupDef { heading: "Filesystem data",          commands: &[&reconcile::CMD, &scrub::CMD] },
-    GroupDef { heading: "Encryption",               commands: &[&key::CMD_UNLOCK, &key::CMD_SET_PASSPHRASE, &key::CMD_REMOVE_PASSPHRASE] },
+    GroupDef { heading: "Encryption", commands: &[
+        &key::CMD_UNLOCK, &key::CMD_SET_PASSPHRASE, &key::CMD_REMOVE_PASSPHRASE,
+        &key::CMD_KEY, &key_server::CMD,
+    ]},
     GroupDef { heading: "Migrate",                  commands: &[&migrate::CMD_MIGRATE, &migrate::CMD_MIGRATE_SUPERBLOCK] },
     GroupDef { heading: "File options",             commands: &[&attr::CMD_SETATTR, &attr::CMD_GETATTR, &attr::CMD_REFLINK_PROPAGATE] },
     GroupDef { heading: "Debug", commands: &[
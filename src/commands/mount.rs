@@ -19,7 +19,7 @@ use crate::wrappers::handle::BcachefsHandle;
 
 use crate::{
     fs_context::{self, FsContext, Level, Message},
-    key::{KeyHandle, Keyring, Passphrase, UnlockPolicy},
+    key::{keyfile_unlock, KeyHandle, Keyring, Passphrase, UnlockPolicy},
     logging,
 };
 
@@ -371,6 +371,12 @@ pub(crate) struct ParsedMountOptions {
     /// fuser equivalent are omitted here but still apply via `flags`.
     #[cfg(feature = "fuse")]
     pub fuse_options: Vec<fuser::MountOption>,
+    /// `key_location=`: -k/--key_location, for fstab entries - mount(8)
+    /// passes the helper nothing but options. Not `x-` prefixed, since mount(8)
+    /// keeps those from helpers.
+    pub unlock_policy: Option<UnlockPolicy>,
+    /// `keyfile=`: --keyfile, likewise.
+    pub keyfile:      Option<PathBuf>,
 }
 
 /// Parse a comma-separated mount option string, splitting kernel mount flags
@@ -412,6 +418,15 @@ pub(crate) fn parse_mountflag_options(options: impl AsRef<str>) -> ParsedMountOp
             // Userspace-only fstab options - not passed to the kernel:
             "auto" | "noauto" | "nofail" | "_netdev"
             | "user" | "nouser" | "users" | "group" | "owner" => {}
+            o if o.starts_with("key_location=") => {
+                let v = &o["key_location=".len()..];
+                match <UnlockPolicy as clap::ValueEnum>::from_str(v, true) {
+                    Ok(p)  => parsed.unlock_policy = Some(p),
+                    Err(_) => warn!("ignoring unknown unlock policy {v:?}"),
+                }
+            }
+            o if o.starts_with("keyfile=") =>
+                parsed.keyfile = Some(PathBuf::from(&o["keyfile=".len()..])),
             o if o.starts_with("x-") || o.starts_with("comment=") => {}
             o => fs_opts.push(o),
         }
@@ -423,7 +438,10 @@ pub(crate) fn parse_mountflag_options(options: impl AsRef<str>) -> ParsedMountOp
 
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+
     use super::{is_splitbrain, parse_mountflag_options};
+    use crate::key::UnlockPolicy;
     use bcachefs_kernel::c;
     use bcachefs_kernel::errcode::BchError;
 
@@ -459,14 +477,39 @@ mod tests {
         assert_eq!(p.fs_opts, None);
         assert_eq!(p.flags, 0);
     }
+
+    #[test]
+    fn parse_mountflag_options_takes_key_options() {
+        let p = parse_mountflag_options("noatime,key_location=keyfile,keyfile=/etc/keys/fs,version_upgrade=none");
+
+        assert!(matches!(p.unlock_policy, Some(UnlockPolicy::Keyfile)));
+        assert_eq!(p.keyfile, Some(PathBuf::from("/etc/keys/fs")));
+        assert_eq!(p.fs_opts.as_deref(), Some("version_upgrade=none"));
+        assert_ne!(p.flags & libc::MS_NOATIME, 0);
+
+        /* Case insensitive, as on the command line */
+        let p = parse_mountflag_options("key_location=Wait");
+        assert!(matches!(p.unlock_policy, Some(UnlockPolicy::Wait)));
+        assert_eq!(p.fs_opts, None);
+
+        /* An unknown policy is dropped with a warning, not passed on */
+        let p = parse_mountflag_options("key_location=bogus");
+        assert!(p.unlock_policy.is_none());
+        assert_eq!(p.fs_opts, None);
+    }
 }
 
 /// If a user explicitly specifies `unlock_policy` or `passphrase_file` then use
 /// that without falling back to other mechanisms. If these options are not
-/// used, then search for the key or ask for it.
-fn handle_unlock(cli: &Cli, sb: &bch_sb_handle) -> Result<KeyHandle> {
-    if let Some(policy) = cli.unlock_policy.as_ref() {
-        return policy.apply(sb);
+/// used, then search for the key, try `keyfile` if given, or ask for it.
+///
+/// The key_location= and keyfile= mount options stand in for the command line
+/// options when those aren't given.
+fn handle_unlock(cli: &Cli, parsed: &ParsedMountOptions, sb: &bch_sb_handle) -> Result<KeyHandle> {
+    let keyfile = cli.keyfile.as_deref().or(parsed.keyfile.as_deref());
+
+    if let Some(policy) = cli.unlock_policy.as_ref().or(parsed.unlock_policy.as_ref()) {
+        return policy.apply(sb, keyfile);
     }
 
     if let Some(path) = cli.passphrase_file.as_deref() {
@@ -481,6 +524,12 @@ fn handle_unlock(cli: &Cli, sb: &bch_sb_handle) -> Result<KeyHandle> {
         return Ok(handle);
     }
 
+    if let Some(path) = keyfile {
+        if let Some(passphrase_correct) = keyfile_unlock(sb, path)? {
+            return KeyHandle::new(&passphrase_correct, Keyring::User);
+        }
+    }
+
     let passphrase_correct = Passphrase::ask_and_check(sb)?;
     KeyHandle::new(&passphrase_correct, Keyring::User)
 }
@@ -556,7 +605,7 @@ fn cmd_mount_inner(cli: &Cli) -> Result<()> {
 
     let first_sb = &sbs[0].1;
     if unsafe { bch_bindgen::c::bch2_sb_is_encrypted(first_sb.sb) } {
-        handle_unlock(cli, first_sb)?;
+        handle_unlock(cli, &parsed, first_sb)?;
     }
 
     if let Some(mountpoint) = cli.mountpoint.as_deref() {
@@ -663,6 +712,12 @@ pub struct Cli {
     #[arg(long)]
     passphrase_file: Option<PathBuf>,
 
+    /// Keyfile, or directory of keyfiles, to try against the filesystem's
+    /// keyfile slots before prompting for a passphrase. With `-k keyfile`,
+    /// overrides the default of /etc/bcachefs/keys.
+    #[arg(long)]
+    keyfile: Option<PathBuf>,
+
     /// Passphrase policy to use in case of an encrypted filesystem. If not
     /// specified, the password will be searched for in the keyring. If not
     /// found, the password will be prompted or read from stdin, depending on
@@ -13,15 +13,18 @@ use crossterm::{
     terminal::{self, ClearType},
 };
 
+use super::reconcile_path::{is_fs_root, reconcile_kick_path, reconcile_path_status_to_text};
+use super::reconcile_throttle::reconcile_throttle_to_text;
 use crate::util::run_tui;
 use crate::wrappers::accounting::{disk_accounting_type, reconcile_accounting_type, DiskAccountingKind};
+use crate::wrappers::file_extents::open_fs_of;
 use crate::wrappers::handle::BcachefsHandle;
 use bcachefs_kernel::util::printbuf::Printbuf;
 use crate::wrappers::sysfs;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
 #[clap(rename_all = "snake_case")]
-enum ReconcileType {
+pub(super) enum ReconcileType {
     Replicas,
     Checksum,
     ErasureCode,
@@ -33,7 +36,7 @@ enum ReconcileType {
 }
 
 impl ReconcileType {
-    fn as_c(self) -> c::bch_reconcile_accounting_type {
+    pub(super) fn as_c(self) -> c::bch_reconcile_accounting_type {
         match self {
             Self::Replicas     => reconcile_accounting_type::replicas,
             Self::Checksum     => reconcile_accounting_type::checksum,
@@ -58,6 +61,9 @@ impl ReconcileType {
 }
 
 /// Show status of background reconciliation
+///
+/// Given a file or directory below the mountpoint, also summarises the
+/// pending work for the files under it, by reading their extents.
 #[derive(Parser, Debug)]
 #[command(about = "Show reconcile status", disable_help_flag = true)]
 pub struct StatusCli {
@@ -69,7 +75,7 @@ pub struct StatusCli {
     #[arg(short = 't', long = "types", value_delimiter = ',', value_enum)]
     types: Vec<ReconcileType>,
 
-    /// Filesystem mountpoint
+    /// Filesystem mountpoint, or a file or directory within it
     #[arg(default_value = ".")]
     filesystem: String,
 }
@@ -91,18 +97,25 @@ pub struct WaitCli {
     filesystem: String,
 }
 
-/// Query reconcile accounting and format status.
-/// Returns true if any work is pending.
-fn reconcile_status_to_text(
-    out: &mut Printbuf,
-    handle: &BcachefsHandle,
-    sysfs_path: &std::path::Path,
-    types: &[ReconcileType],
-) -> Result<bool> {
-    let scan_pending = std::fs::read_to_string(sysfs_path.join("reconcile_scan_pending"))
-        .map(|s| s.trim().parse::<u64>().unwrap_or(0))
-        .unwrap_or(0);
+/// Process pending work for the files under a path first
+///
+/// Makes the files' pending work high priority, alongside evacuating and
+/// rereplicating: ahead of all other work, and not throttled. Only work
+/// already found by the option change's scan is covered - the scan itself
+/// always runs first - and reflinked data is not.
+#[derive(Parser, Debug)]
+#[command(about = "Prioritise reconcile work for files under a path", disable_help_flag = true)]
+pub struct KickCli {
+    /// Print help
+    #[arg(long = "help", action = clap::ArgAction::Help)]
+    _help: (),
 
+    /// File or directory
+    path: String,
+}
+
+/// [data_sectors, metadata_sectors] of pending work per reconcile type
+fn reconcile_work_sectors(handle: &BcachefsHandle) -> Result<Vec<[u64; 2]>> {
     let result = handle.query_accounting(disk_accounting_type::reconcile_work.bit())
         .map_err(|e| anyhow!("query_accounting: {}", e))?;
 
@@ -120,6 +133,24 @@ fn reconcile_status_to_text(
         }
     }
 
+    Ok(v)
+}
+
+/// Query reconcile accounting and format status.
+/// Returns true if any work is pending.
+fn reconcile_status_to_text(
+    out: &mut Printbuf,
+    handle: &BcachefsHandle,
+    sysfs_path: &std::path::Path,
+    types: &[ReconcileType],
+) -> Result<bool> {
+    let scan_pending = std::fs::read_to_string(sysfs_path.join("reconcile_scan_pending"))
+        .map(|s| s.trim().parse::<u64>().unwrap_or(0))
+        .unwrap_or(0);
+
+    let v = reconcile_work_sectors(handle)?;
+    let nr = v.len();
+
     writeln!(out, "Scan pending:\t{}", scan_pending).unwrap();
     write!(out, "\tdata\rmetadata\r\n").unwrap();
 
@@ -151,8 +182,9 @@ fn cmd_reconcile_status(cli: StatusCli) -> Result<()> {
         cli.types
     };
 
-    let handle = BcachefsHandle::open(&cli.filesystem)
-        .map_err(|e| anyhow!("opening filesystem '{}': {}", cli.filesystem, e))?;
+    /* may be a file: BcachefsHandle::open() would take that for an image */
+    let path = std::path::Path::new(&cli.filesystem);
+    let handle = open_fs_of(path)?;
     let sysfs_path = sysfs::sysfs_path_from_fd(handle.sysfs_fd())?;
 
     let mut out = Printbuf::new();
@@ -161,6 +193,25 @@ fn cmd_reconcile_status(cli: StatusCli) -> Result<()> {
 
     out.newline();
 
+    /*
+     * Throttling applies to normal priority work - everything but high
+     * priority, pending (waiting on devices) and stripes:
+     */
+    let throttled: u64 = reconcile_work_sectors(&handle)?
+        .iter()
+        .take(reconcile_accounting_type::target.0 as usize + 1)
+        .map(|s| s[0] + s[1])
+        .sum();
+    if let Err(e) = reconcile_throttle_to_text(&mut out, &handle, throttled) {
+        eprintln!("{e}");
+    }
+    out.newline();
+
+    if !is_fs_root(path)? {
+        reconcile_path_status_to_text(&mut out, &handle, path, &types)?;
+        out.newline();
+    }
+
     // Append kernel reconcile_status from sysfs
     if let Ok(status) = std::fs::read_to_string(sysfs_path.join("reconcile_status")) {
         write!(out, "{}", status).unwrap();
@@ -171,6 +222,12 @@ fn cmd_reconcile_status(cli: StatusCli) -> Result<()> {
     Ok(())
 }
 
+fn cmd_reconcile_kick(cli: KickCli) -> Result<()> {
+    let nr = reconcile_kick_path(std::path::Path::new(&cli.path))?;
+    println!("kicked {} files under {}", nr, cli.path);
+    Ok(())
+}
+
 fn cmd_reconcile_wait(cli: WaitCli) -> Result<()> {
 
     let types = if cli.types.is_empty() {
@@ -250,7 +307,8 @@ fn reconcile_wait_headless(
 
 pub const CMD_STATUS: super::CmdDef = typed_cmd!("status", "Show reconcile status", StatusCli, cmd_reconcile_status);
 pub const CMD_WAIT: super::CmdDef = typed_cmd!("wait", "Wait for reconcile to complete", WaitCli, cmd_reconcile_wait);
+pub const CMD_KICK: super::CmdDef = typed_cmd!("kick", "Prioritise reconcile work for files under a path", KickCli, cmd_reconcile_kick);
 pub const CMD: super::CmdDef = super::CmdDef {
     name: "reconcile", about: "Reconcile filesystem data", aliases: &[],
-    kind: super::CmdKind::Group { children: &[&CMD_STATUS, &CMD_WAIT] },
+    kind: super::CmdKind::Group { children: &[&CMD_STATUS, &CMD_WAIT, &CMD_KICK, &super::reconcile_throttle::CMD] },
 };
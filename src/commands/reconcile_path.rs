@@ -0,0 +1,219 @@
+//! Reconcile work for the files under a path: `reconcile status <path>` and
+//! `reconcile kick <path>`.
+//!
+//! Reconcile's accounting is filesystem-wide, by work type - nothing indexes
+//! pending work by directory. So we walk the files under the path and read
+//! each one's extents: an extent with work outstanding carries a
+//! bch_extent_reconcile, whose need_rb bits say which options its data
+//! doesn't match yet. Reflinked data is resolved to the indirect extents
+//! behind it, as file_extents() does.
+//!
+//! An option change doesn't reach the extents until its scan has run, so a
+//! file whose inode still has a scan cookie in reconcile_scan is reported as
+//! awaiting scan: its extents may not show the work yet.
+//!
+//! Kicking passes the files' inode numbers to BCHFS_IOC_RECONCILE_KICK, which
+//! marks their extents with normal priority work as high priority: their work
+//! moves to reconcile_hipri, alongside evacuate/rereplicate work, and stays
+//! there until done. Reflinked data isn't covered, since it's indexed by
+//! indirect extent rather than by inode.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bch_bindgen::c;
+use bcachefs_kernel::btree::bkey::pos;
+use bcachefs_kernel::util::printbuf::Printbuf;
+
+use super::reconcile::ReconcileType;
+use crate::util::path_subvol;
+use crate::wrappers::accounting::reconcile_accounting_type;
+use crate::wrappers::file_extents::file_extents;
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::ioctl::{ioctl_w, BCHFS_IOC_RECONCILE_KICK};
+use crate::wrappers::online_iter::{OnlineBtreeIter, OnlineIterFlags};
+
+/* Scan cookies below this are fs/metadata/device cookies, not inodes */
+const BCACHEFS_ROOT_INO: u64 = 4096;
+
+/// Whether @path is the root of the filesystem it's on - the whole
+/// filesystem, which the accounting already covers.
+pub(super) fn is_fs_root(path: &Path) -> Result<bool> {
+    let path = path.canonicalize()
+        .with_context(|| format!("resolving {}", path.display()))?;
+
+    let Some(parent) = path.parent() else { return Ok(true) };
+    Ok(fs::metadata(parent)?.dev() != fs::metadata(&path)?.dev())
+}
+
+/// Regular files under @path as (subvolume, inode number), each once.
+/// Symlinks aren't followed and other filesystems aren't entered;
+/// subvolumes are, since bcachefs reports the same device for all of them.
+fn files_under(path: &Path) -> Result<Vec<(u64, u64)>> {
+    let subvol_of = |p: &Path| path_subvol(p).map(|(_, subvol)| subvol)
+        .ok_or_else(|| anyhow!("{}: not on a bcachefs filesystem", p.display()));
+
+    let meta = fs::symlink_metadata(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    if !meta.is_dir() {
+        return Ok(if meta.is_file() {
+            vec![(subvol_of(path)?, meta.ino())]
+        } else {
+            Vec::new()
+        });
+    }
+
+    let dev = meta.dev();
+    let mut seen = HashSet::new();
+    let mut ret = Vec::new();
+    let mut dirs = vec![path.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let subvol = subvol_of(&dir)?;
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("{}: {}", dir.display(), e);
+                continue;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let Ok(meta) = entry.metadata() else { continue };
+            if meta.dev() != dev {
+                continue;
+            }
+
+            if meta.is_dir() {
+                dirs.push(entry.path());
+            } else if meta.is_file() && seen.insert((subvol, meta.ino())) {
+                ret.push((subvol, meta.ino()));
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Inodes with an option change not yet propagated to their extents.
+fn inode_scan_cookies(handle: &BcachefsHandle) -> Result<HashSet<u64>> {
+    let mut iter = OnlineBtreeIter::new(handle, c::btree_id::reconcile_scan, 0,
+        pos(0, BCACHEFS_ROOT_INO), pos(0, u64::MAX), OnlineIterFlags::default());
+
+    let mut ret = HashSet::new();
+    while let Some(k) = iter.next().map_err(|e| anyhow!("querying reconcile_scan btree: {e}"))? {
+        ret.insert(k.k.p.offset);
+    }
+    Ok(ret)
+}
+
+#[derive(Default)]
+struct PathStatus {
+    files:         u64,
+    files_pending: u64,
+    awaiting_scan: u64,
+    /// Sectors by reconcile accounting type, as the reconcile_work counters
+    sectors:       Vec<u64>,
+}
+
+fn path_status(handle: &BcachefsHandle, path: &Path) -> Result<PathStatus> {
+    let cookies = inode_scan_cookies(handle)?;
+    let mut ret = PathStatus {
+        sectors: vec![0; reconcile_accounting_type::nr.0 as usize],
+        ..Default::default()
+    };
+
+    for (subvol, inum) in files_under(path)? {
+        ret.files += 1;
+        if cookies.contains(&inum) {
+            ret.awaiting_scan += 1;
+        }
+
+        let mut pending = false;
+        for e in file_extents(handle, subvol, inum, 0, u64::MAX)? {
+            let r = e.reconcile;
+            let sectors = (e.end - e.start) >> 9;
+
+            /* need_rb bits are in the same order as the first accounting types */
+            for bit in 0..5 {
+                if r.need_rb & (1 << bit) != 0 {
+                    ret.sectors[bit] += sectors;
+                }
+            }
+            if r.hipri {
+                ret.sectors[reconcile_accounting_type::high_priority.0 as usize] += sectors;
+            }
+            if r.pending {
+                ret.sectors[reconcile_accounting_type::pending.0 as usize] += sectors;
+            }
+            pending |= r.need_rb != 0;
+        }
+
+        if pending {
+            ret.files_pending += 1;
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Pending reconcile work for the files under @path.
+/// Returns true if any work is pending.
+pub(super) fn reconcile_path_status_to_text(
+    out: &mut Printbuf,
+    handle: &BcachefsHandle,
+    path: &Path,
+    types: &[ReconcileType],
+) -> Result<bool> {
+    let s = path_status(handle, path)?;
+
+    writeln!(out, "{}:", path.display()).unwrap();
+    writeln!(out, "  files:\t{}", s.files).unwrap();
+    writeln!(out, "  with pending work:\t{}", s.files_pending).unwrap();
+    writeln!(out, "  awaiting option scan:\t{}", s.awaiting_scan).unwrap();
+
+    for t in types {
+        /* stripes aren't file data */
+        if *t == ReconcileType::Stripes {
+            continue;
+        }
+
+        write!(out, "  ").unwrap();
+        bcachefs_kernel::opts::prt_reconcile_type(out, t.as_c());
+        write!(out, ":\t").unwrap();
+        out.units_sectors(s.sectors[t.as_c().0 as usize]);
+        out.newline();
+    }
+    out.tabstop_align();
+
+    Ok(s.files_pending != 0 || s.awaiting_scan != 0)
+}
+
+/// Make the pending work of the files under @path high priority. Returns the
+/// number of inodes kicked.
+pub(super) fn reconcile_kick_path(path: &Path) -> Result<usize> {
+    let mut inums: Vec<u64> = files_under(path)?.into_iter().map(|(_, inum)| inum).collect();
+    inums.sort_unstable();
+    inums.dedup();
+
+    /* the ioctl goes to whatever filesystem the fd is on */
+    let file = fs::File::open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+
+    for batch in inums.chunks(c::BCH_RECONCILE_KICK_BATCH_MAX as usize) {
+        let arg = c::bch_ioctl_reconcile_kick {
+            flags: 0,
+            nr:    batch.len() as u32,
+            inums: batch.as_ptr() as u64,
+        };
+
+        ioctl_w::<BCHFS_IOC_RECONCILE_KICK>(&file, &arg)
+            .map_err(|e| anyhow!("BCHFS_IOC_RECONCILE_KICK: {e}"))?;
+    }
+
+    Ok(inums.len())
+}
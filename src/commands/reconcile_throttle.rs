@@ -0,0 +1,261 @@
+//! Reconcile throttling: `reconcile throttle`, and the throttle section of
+//! `reconcile status`.
+//!
+//! The limits are the reconcile_rate_limit and reconcile_window filesystem
+//! options, so they're set through sysfs like any other runtime option and
+//! persist in the superblock. Both apply to normal priority work only:
+//! evacuating and rereplicating - restoring durability - is never held back.
+//! Nor is copygc, which isn't reconcile at all: it frees space for
+//! foreground writes, and throttling it would throttle them.
+//!
+//! The kernel keeps the window in UTC, since it has no notion of a local
+//! timezone; we convert from local time when setting it, at the current
+//! offset - a window set in winter is an hour off in summer.
+
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Local;
+use clap::Parser;
+
+use crate::util::parse_human_size;
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::sysfs;
+use bcachefs_kernel::util::printbuf::Printbuf;
+
+const MINS_PER_DAY: u32 = 24 * 60;
+
+/// Limit background reconcile to a rate and a daily time window
+///
+/// With no options, shows the current limits. Evacuating and rereplicating
+/// are never throttled. The window is given in local time unless --utc is
+/// passed, and is stored in UTC: it does not follow daylight saving changes.
+#[derive(Parser, Debug)]
+#[command(about = "Throttle background reconcile", disable_help_flag = true)]
+pub struct ThrottleCli {
+    /// Print help
+    #[arg(long = "help", action = clap::ArgAction::Help)]
+    _help: (),
+
+    /// Maximum rate per second, e.g. 200M; 0 for unlimited
+    #[arg(short = 'r', long)]
+    rate: Option<String>,
+
+    /// Only run between these times, e.g. 22:00-06:00; "none" to always run
+    #[arg(short = 'w', long)]
+    window: Option<String>,
+
+    /// Window times are UTC, not local time
+    #[arg(long)]
+    utc: bool,
+
+    /// Filesystem mountpoint
+    #[arg(default_value = ".")]
+    filesystem: String,
+}
+
+/// A daily window, in minutes past midnight; may wrap past midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Window {
+    start: u32,
+    end:   u32,
+}
+
+impl Window {
+    fn shift(self, mins: i32) -> Self {
+        let f = |v: u32| (v as i32 + mins).rem_euclid(MINS_PER_DAY as i32) as u32;
+        Window { start: f(self.start), end: f(self.end) }
+    }
+
+    /// Seconds the window stays open from @now, or until it opens if closed,
+    /// and whether it's open; @now is seconds past midnight.
+    fn next_change(self, now: u32) -> (u32, bool) {
+        let (start, end) = (self.start * 60, self.end * 60);
+        let until = |t: u32| (t + 86400 - now) % 86400;
+
+        let open = if start < end {
+            now >= start && now < end
+        } else {
+            now >= start || now < end
+        };
+
+        if open { (until(end), true) } else { (until(start), false) }
+    }
+}
+
+fn parse_hhmm(s: &str) -> Option<u32> {
+    let (h, m) = s.trim().split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    (h < 24 && m < 60).then_some(h * 60 + m)
+}
+
+/// Parse "hh:mm-hh:mm", or "none" for no window.
+fn parse_window(s: &str) -> Result<Option<Window>> {
+    if s == "none" {
+        return Ok(None);
+    }
+
+    let w = s.split_once('-')
+        .and_then(|(a, b)| Some(Window { start: parse_hhmm(a)?, end: parse_hhmm(b)? }))
+        .ok_or_else(|| anyhow!("invalid window {s}: expected hh:mm-hh:mm or none"))?;
+
+    if w.start == w.end {
+        bail!("invalid window {s}: start and end are the same");
+    }
+    Ok(Some(w))
+}
+
+fn window_to_string(w: Window) -> String {
+    format!("{:02}:{:02}-{:02}:{:02}", w.start / 60, w.start % 60, w.end / 60, w.end % 60)
+}
+
+/// Current local time's offset from UTC, in minutes.
+fn local_offset_mins() -> i32 {
+    Local::now().offset().local_minus_utc() / 60
+}
+
+/// Wall clock seconds to do @work_secs of work when only running inside
+/// @window, starting at @now (seconds past midnight UTC).
+fn projected_secs(work_secs: u64, window: Option<Window>, now: u32) -> u64 {
+    let Some(window) = window else { return work_secs };
+
+    let mut left = work_secs;
+    let mut now = now;
+    let mut elapsed = 0u64;
+
+    /* each day has at most one open span, so this is at most two steps a day */
+    while left != 0 {
+        let (secs, open) = window.next_change(now);
+        let secs = if open { secs.min(left.min(u32::MAX as u64) as u32) } else { secs };
+
+        if open {
+            left -= secs as u64;
+        }
+        elapsed += secs as u64;
+        now = (now + secs) % 86400;
+    }
+
+    elapsed
+}
+
+struct Throttle {
+    /// Bytes per second, 0 if unlimited
+    rate:   u64,
+    /// UTC
+    window: Option<Window>,
+}
+
+fn read_throttle(handle: &BcachefsHandle) -> Result<Throttle> {
+    let read = |name: &str| sysfs::read_sysfs_fd_str(handle.sysfs_fd(), &format!("options/{name}"))
+        .map_err(|e| anyhow!("reading {name}: {e} (kernel too old?)"));
+
+    Ok(Throttle {
+        rate:   parse_human_size(&read("reconcile_rate_limit")?)?,
+        window: parse_window(&read("reconcile_window")?)?,
+    })
+}
+
+fn now_secs_utc() -> u32 {
+    (chrono::Utc::now().timestamp().rem_euclid(86400)) as u32
+}
+
+fn throttle_to_text(out: &mut Printbuf, t: &Throttle) {
+    write!(out, "Rate limit:\t").unwrap();
+    if t.rate != 0 {
+        writeln!(out, "{}/s", crate::util::fmt_bytes_human(t.rate)).unwrap();
+    } else {
+        writeln!(out, "none").unwrap();
+    }
+
+    write!(out, "Window:\t").unwrap();
+    match t.window {
+        Some(w) => {
+            let (secs, open) = w.next_change(now_secs_utc());
+            writeln!(out, "{} UTC ({} local), {} for {}h{:02}m",
+                     window_to_string(w),
+                     window_to_string(w.shift(local_offset_mins())),
+                     if open { "open" } else { "closed" },
+                     secs / 3600, secs / 60 % 60).unwrap();
+        }
+        None => writeln!(out, "none").unwrap(),
+    }
+}
+
+/// Throttle state, and when @pending_sectors of work would be done at the
+/// configured rate. Pending work is an upper bound - an extent needing
+/// several changes is counted once for each - so the projection is too.
+pub(super) fn reconcile_throttle_to_text(
+    out: &mut Printbuf,
+    handle: &BcachefsHandle,
+    pending_sectors: u64,
+) -> Result<()> {
+    let t = read_throttle(handle)?;
+
+    throttle_to_text(out, &t);
+
+    if t.rate != 0 && pending_sectors != 0 {
+        let work = (pending_sectors << 9).div_ceil(t.rate);
+        let secs = projected_secs(work, t.window, now_secs_utc());
+        let done = Local::now() + chrono::Duration::seconds(secs as i64);
+
+        writeln!(out, "Done by, at most:\t{}", done.format("%Y-%m-%d %H:%M")).unwrap();
+    }
+    out.tabstop_align();
+    Ok(())
+}
+
+fn cmd_reconcile_throttle(cli: ThrottleCli) -> Result<()> {
+    let handle = BcachefsHandle::open(&cli.filesystem)
+        .map_err(|e| anyhow!("opening filesystem '{}': {}", cli.filesystem, e))?;
+
+    let set = |name: &str, v: &str| sysfs::sysfs_write_str(handle.sysfs_fd(), &format!("options/{name}"), v)
+        .map_err(|e| anyhow!("setting {name}: {e}"));
+
+    if let Some(rate) = &cli.rate {
+        parse_human_size(rate)?;
+        set("reconcile_rate_limit", rate)?;
+    }
+
+    if let Some(window) = &cli.window {
+        let w = parse_window(window)?
+            .map(|w| if cli.utc { w } else { w.shift(-local_offset_mins()) });
+
+        set("reconcile_window", &w.map_or("none".to_string(), window_to_string))?;
+    }
+
+    let mut out = Printbuf::new();
+    throttle_to_text(&mut out, &read_throttle(&handle)?);
+    out.tabstop_align();
+    print!("{}", out);
+    Ok(())
+}
+
+pub const CMD: super::CmdDef = typed_cmd!("throttle", "Throttle background reconcile", ThrottleCli, cmd_reconcile_throttle);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_parse() {
+        assert_eq!(parse_window("22:00-06:30").unwrap(), Some(Window { start: 1320, end: 390 }));
+        assert_eq!(parse_window("none").unwrap(), None);
+        assert!(parse_window("24:00-06:00").is_err());
+        assert!(parse_window("06:00-06:00").is_err());
+        assert!(parse_window("22:00").is_err());
+    }
+
+    #[test]
+    fn window_projection() {
+        let w = Some(Window { start: 22 * 60, end: 6 * 60 });
+
+        /* no window: just the work */
+        assert_eq!(projected_secs(3600, None, 0), 3600);
+        /* inside the window, finishes before it closes */
+        assert_eq!(projected_secs(3600, w, 23 * 3600), 3600);
+        /* starting at noon: wait ten hours, then an hour of work */
+        assert_eq!(projected_secs(3600, w, 12 * 3600), 11 * 3600);
+        /* ten hours of work: eight tonight, two tomorrow night */
+        assert_eq!(projected_secs(10 * 3600, w, 22 * 3600), 26 * 3600);
+    }
+}
@@ -1,10 +1,14 @@
+use std::ffi::CStr;
 use std::fmt::Write;
+use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bcachefs_kernel::c;
 use bcachefs_kernel::opt_set;
+use bcachefs_kernel::sb::io::SbBuf;
 use clap::Parser;
 
+use crate::device_scan::OpenedFs;
 use crate::util::read_flag_list;
 
 use bcachefs_kernel::util::printbuf::Printbuf;
@@ -21,12 +25,154 @@ pub struct RecoveryPassCli {
     #[arg(short = 'u', long = "unset")]
     unset: Vec<String>,
 
-    /// Device path(s)
+    /// List every recovery pass: scheduled, last run, runtime, errors found
+    #[arg(short = 'l', long = "list", conflicts_with_all = ["set", "unset"])]
+    list: bool,
+
+    /// JSON output (with --list)
+    #[arg(long = "json", requires = "list")]
+    json: bool,
+
+    /// Device path(s), or a mountpoint with --list
     #[arg(required = true)]
     devices: Vec<String>,
 }
 
+// ── Pass history, from the ext and recovery_passes superblock sections ──
+
+/// What the superblock records about one recovery pass.
+struct PassStatus {
+    name:         String,
+    scheduled:    bool,
+    /// Seconds since the epoch; None if it has never completed:
+    last_run:     Option<u64>,
+    /// Seconds:
+    last_runtime: u32,
+    found_errors: bool,
+    no_ratelimit: bool,
+}
+
+/// Passes in the order we run them, with their stable (on disk) ids.
+fn recovery_passes() -> Vec<(String, u32)> {
+    let mut ret = Vec::new();
+
+    /* NULL terminated: */
+    for i in 0.. {
+        let name = unsafe { *c::bch2_recovery_passes.as_ptr().add(i) };
+        if name.is_null() {
+            break;
+        }
+
+        let name = unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned();
+        let stable = unsafe { c::bch2_recovery_passes_to_stable(1u64 << i) };
+        ret.push((name, stable.trailing_zeros()));
+    }
+
+    ret
+}
+
+fn passes_status(sb: &c::bch_sb) -> Vec<PassStatus> {
+    let scheduled = sb.field::<c::bch_sb_field_ext>()
+        .map_or(0, |ext| u64::from_le(ext.recovery_passes_required[0]));
+
+    let entries: &[c::recovery_pass_entry] = match sb.field::<c::bch_sb_field_recovery_passes>() {
+        Some(r) => {
+            let bytes = u32::from_le(r.field.u64s) as usize * 8;
+            let nr = bytes.saturating_sub(std::mem::size_of::<c::bch_sb_field>()) /
+                std::mem::size_of::<c::recovery_pass_entry>();
+            unsafe { r.start.as_slice(nr) }
+        }
+        None => &[],
+    };
+
+    recovery_passes().into_iter().map(|(name, stable)| {
+        let e = entries.get(stable as usize);
+        let last_run = e.map(|e| u64::from_le(e.last_run)).filter(|&t| t != 0);
+
+        PassStatus {
+            name,
+            scheduled:    stable < 64 && scheduled & (1u64 << stable) != 0,
+            last_run,
+            last_runtime: e.filter(|_| last_run.is_some())
+                .map_or(0, |e| u32::from_le(e.last_runtime)),
+            no_ratelimit: e.is_some_and(|e| e.bch_recovery_pass_no_ratelimit()),
+            found_errors: e.is_some_and(|e| e.bch_recovery_pass_found_errors()),
+        }
+    }).collect()
+}
+
+fn fmt_time(t: u64) -> String {
+    chrono::DateTime::from_timestamp(t as i64, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| t.to_string())
+}
+
+fn passes_to_text(out: &mut Printbuf, passes: &[PassStatus]) {
+    out.aligned(|sub| {
+        writeln!(sub, "pass\tscheduled\tlast run\truntime\rerrors\r").unwrap();
+
+        for p in passes {
+            write!(sub, "{}\t{}\t", p.name, if p.scheduled { "yes" } else { "" }).unwrap();
+            match p.last_run {
+                Some(t) => write!(sub, "{}\t{}s\r{}\r",
+                                  fmt_time(t), p.last_runtime,
+                                  if p.found_errors { "found" } else { "none" }).unwrap(),
+                None    => write!(sub, "never\t\r\r").unwrap(),
+            }
+            if p.no_ratelimit {
+                write!(sub, " (no ratelimit)").unwrap();
+            }
+            writeln!(sub).unwrap();
+        }
+    });
+}
+
+fn passes_to_json(passes: &[PassStatus]) -> serde_json::Value {
+    passes.iter().map(|p| serde_json::json!({
+        "pass":             p.name,
+        "scheduled":        p.scheduled,
+        "last_run":         p.last_run,
+        "last_runtime_secs": p.last_run.map(|_| p.last_runtime),
+        "found_errors":     p.last_run.map(|_| p.found_errors),
+        "no_ratelimit":     p.no_ratelimit,
+    })).collect()
+}
+
+fn cmd_recovery_pass_list(cli: &RecoveryPassCli) -> Result<()> {
+    let devs: Vec<PathBuf> = cli.devices.iter().map(PathBuf::from).collect();
+
+    let mut fs_opts = c::bch_opts::default();
+    opt_set!(fs_opts, noexcl, 1);
+    opt_set!(fs_opts, nochanges, 1);
+    opt_set!(fs_opts, read_only, 1);
+    opt_set!(fs_opts, nostart, 1);
+
+    let passes = match crate::device_scan::open_online_or_offline(&devs, fs_opts)? {
+        OpenedFs::Online(handle) => {
+            let buf = handle.read_super()
+                .map_err(|e| anyhow!("reading superblock: {}", e))?;
+            let sb = SbBuf::from_bytes(&buf)
+                .map_err(|e| anyhow!("reading superblock: {}", e))?;
+            passes_status(sb.sb())
+        }
+        OpenedFs::Offline(fs) => passes_status(unsafe { &*(*fs.raw).disk_sb.sb }),
+    };
+
+    if cli.json {
+        println!("{:#}", passes_to_json(&passes));
+    } else {
+        let mut out = Printbuf::new();
+        passes_to_text(&mut out, &passes);
+        print!("{}", out);
+    }
+
+    Ok(())
+}
+
 fn cmd_recovery_pass(cli: RecoveryPassCli) -> Result<()> {
+    if cli.list {
+        return cmd_recovery_pass_list(&cli);
+    }
 
     let mut passes_to_set: u64 = 0;
     let mut passes_to_unset: u64 = 0;
@@ -5,13 +5,15 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bch_bindgen::c::{
-    bch_ioctl_data, bch_ioctl_data_event_ret, bch_ioctl_data_progress,
+    self, bch_ioctl_data, bch_ioctl_data_event_ret, bch_ioctl_data_progress,
     bch_ioctl_data__bindgen_ty_1__bindgen_ty_1 as ScrubArgs,
 };
 use bch_bindgen::accounting::data_type;
-use clap::Parser;
+use bcachefs_kernel::sb::io::SbBuf;
+use chrono::{Local, TimeZone};
+use clap::{Parser, Subcommand};
 
 use crate::commands::DeviceNameArgs;
 use crate::util::{fmt_bytes_human, fmt_sectors_human};
@@ -45,9 +47,11 @@ fn read_data_event(fd: &mut std::fs::File) -> io::Result<(u8, u8, bch_ioctl_data
     Ok((event_type, event_ret, p))
 }
 
-fn start_scrub(ioctl_fd: std::os::fd::BorrowedFd, dev_idx: u32, data_types: u32) -> Result<std::fs::File> {
+fn start_scrub(ioctl_fd: std::os::fd::BorrowedFd, dev_idx: u32, data_types: u32,
+               resume: bool) -> Result<std::fs::File> {
     let mut cmd = bch_ioctl_data {
         op: bch_bindgen::c::bch_data_ops::BCH_DATA_OP_scrub as u16,
+        flags: if resume { c::BCH_DATA_RESUME } else { 0 },
         ..Default::default()
     };
     // bch_ioctl_data's op-params union is emitted as either a native Rust union or
@@ -63,7 +67,11 @@ fn start_scrub(ioctl_fd: std::os::fd::BorrowedFd, dev_idx: u32, data_types: u32)
         p.add(1).write(data_types);
     }
 
-    let ret = ioctl_w::<BCH_IOCTL_DATA>(ioctl_fd, &cmd)?;
+    let ret = match ioctl_w::<BCH_IOCTL_DATA>(ioctl_fd, &cmd) {
+        Err(e) if resume && e.raw_os_error() == Some(libc::EINVAL) =>
+            bail!("starting scrub: {e} (kernel too old for --resume?)"),
+        r => r?,
+    };
     Ok(unsafe { std::fs::File::from_raw_fd(ret) })
 }
 
@@ -104,21 +112,127 @@ impl ScrubDev {
     }
 }
 
+/// Verify checksums and correct errors, if possible
+///
+/// Progress is checkpointed in each device's superblock entry as scrub runs,
+/// so an interrupted scrub can be continued with --resume.
 #[derive(Parser, Debug)]
-#[command(about = "Verify checksums and correct errors, if possible")]
+#[command(about = "Verify checksums and correct errors, if possible",
+          args_conflicts_with_subcommands = true,
+          subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    subcommands: Option<Subcommands>,
+
     /// Check metadata only
     #[arg(short, long)]
     metadata: bool,
 
+    /// Continue each device's interrupted scrub, if it has one
+    #[arg(short, long)]
+    resume: bool,
+
+    /// Stop after this long (e.g. 30m, 4h), to be continued with --resume
+    #[arg(long, value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+
     #[command(flatten)]
     device_names: DeviceNameArgs,
 
     /// Filesystem path or device
-    filesystem: String,
+    #[arg(required = true)]
+    filesystem: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Subcommands {
+    /// Show scrub progress, and the last completed scrub, of each device
+    Status {
+        #[command(flatten)]
+        device_names: DeviceNameArgs,
+
+        /// Filesystem path or device
+        #[arg(default_value = ".")]
+        filesystem: String,
+    },
+}
+
+/// A duration: a number with an optional s, m, h or d suffix (seconds if none).
+fn parse_duration(s: &str) -> Result<Duration> {
+    let t = s.trim();
+    let (n, mult) = match t.char_indices().last() {
+        Some((i, 's')) => (&t[..i], 1),
+        Some((i, 'm')) => (&t[..i], 60),
+        Some((i, 'h')) => (&t[..i], 3600),
+        Some((i, 'd')) => (&t[..i], 86400),
+        _              => (t, 1),
+    };
+
+    n.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(mult))
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("invalid duration: {s}"))
+}
+
+fn fmt_time(t: u64) -> String {
+    match Local.timestamp_opt(t as i64, 0) {
+        chrono::LocalResult::Single(dt) => dt.format("%Y-%m-%d %H:%M").to_string(),
+        _ => format!("(invalid: {t})"),
+    }
+}
+
+fn scrub_status(filesystem: &str, device_names: DeviceNameArgs) -> Result<()> {
+    let handle = BcachefsHandle::open(filesystem)
+        .with_context(|| format!("opening filesystem '{}'", filesystem))?;
+
+    let sysfs_path = sysfs_path_from_fd(handle.sysfs_fd())?;
+    let devices = fs_get_devices(&sysfs_path, device_names.name_mode())?;
+
+    let buf = handle.read_super()
+        .map_err(|e| anyhow!("reading superblock: {}", e))?;
+    let sb = SbBuf::from_bytes(&buf)
+        .map_err(|e| anyhow!("reading superblock: {}", e))?;
+
+    let metadata_only = 1u32 << u32::from(data_type::btree);
+
+    println!("{:<16} {:<18} {:>12} {:>12}  in progress",
+        "device", "last completed", "corrected", "uncorrected");
+
+    for dev in &devices {
+        let m = unsafe { c::bch2_sb_member_get(sb.sb() as *const _ as *mut _, dev.idx as i32) };
+        let le = |v: u64| u64::from_le(v);
+
+        let last = if le(m.last_scrub_end as u64) != 0 {
+            let meta = if u32::from_le(m.last_scrub_data_types as u32) == metadata_only { " (m)" } else { "" };
+            format!("{:<18} {:>12} {:>12}",
+                fmt_time(le(m.last_scrub_end as u64)) + meta,
+                fmt_sectors_human(le(m.last_scrub_sectors_corrected as u64)),
+                fmt_sectors_human(le(m.last_scrub_sectors_uncorrected as u64)))
+        } else {
+            format!("{:<18} {:>12} {:>12}", "never", "", "")
+        };
+
+        let dev_sectors = le(m.nbuckets as u64) * u16::from_le(m.bucket_size as u16) as u64;
+        let in_progress = if le(m.scrub_start as u64) != 0 {
+            format!("{}% since {}",
+                le(m.scrub_pos as u64) * 100 / dev_sectors.max(1),
+                fmt_time(le(m.scrub_start as u64)))
+        } else {
+            String::new()
+        };
+
+        println!("{:<16} {}  {}", dev.dev, last, in_progress);
+    }
+
+    Ok(())
 }
 
 fn scrub(cli: Cli) -> Result<()> {
+    if let Some(Subcommands::Status { device_names, filesystem }) = cli.subcommands {
+        return scrub_status(&filesystem, device_names);
+    }
+
+    let filesystem = cli.filesystem.expect("required by clap");
 
     unsafe {
         libc::signal(libc::SIGINT,
@@ -131,8 +245,8 @@ fn scrub(cli: Cli) -> Result<()> {
         !0u32
     };
 
-    let handle = BcachefsHandle::open(&cli.filesystem)
-        .with_context(|| format!("opening filesystem '{}'", cli.filesystem))?;
+    let handle = BcachefsHandle::open(&filesystem)
+        .with_context(|| format!("opening filesystem '{}'", filesystem))?;
 
     let sysfs_path = sysfs_path_from_fd(handle.sysfs_fd())?;
     let name_mode = cli.device_names.name_mode();
@@ -149,14 +263,14 @@ fn scrub(cli: Cli) -> Result<()> {
             .map(|d| d.dev.clone())
             .unwrap_or_else(|| format!("dev-{}", dev_idx));
 
-        let fd = start_scrub(ioctl_fd, dev_idx as u32, data_types)?;
+        let fd = start_scrub(ioctl_fd, dev_idx as u32, data_types, cli.resume)?;
         scrub_devs.push(ScrubDev {
             name, progress_fd: Some(fd),
             done: 0, corrected: 0, uncorrected: 0, total: 0, ret_status: 0,
         });
     } else {
         for dev in &devices {
-            let fd = start_scrub(ioctl_fd, dev.idx, data_types)?;
+            let fd = start_scrub(ioctl_fd, dev.idx, data_types, cli.resume)?;
             scrub_devs.push(ScrubDev {
                 name: dev.dev.clone(), progress_fd: Some(fd),
                 done: 0, corrected: 0, uncorrected: 0, total: 0, ret_status: 0,
@@ -165,13 +279,15 @@ fn scrub(cli: Cli) -> Result<()> {
     }
 
     let dev_names: Vec<&str> = scrub_devs.iter().map(|d| d.name.as_str()).collect();
-    println!("Starting scrub on {} devices: {}",
+    println!("{} scrub on {} devices: {}",
+        if cli.resume { "Resuming" } else { "Starting" },
         scrub_devs.len(), dev_names.join(" "));
 
     println!("{:<16} {:>12} {:>12} {:>12} {:>12} {:>6}",
         "device", "checked", "corrected", "uncorrected", "total", "");
 
     let mut exit_code = 0i32;
+    let started = Instant::now();
     let mut last = Instant::now();
     let mut first = true;
     let live_output = io::stdout().is_terminal();
@@ -230,7 +346,8 @@ fn scrub(cli: Cli) -> Result<()> {
         }
 
         let interrupted = INTERRUPTED.load(Ordering::Relaxed);
-        if live_output || all_done || interrupted {
+        let timed_out = cli.max_duration.is_some_and(|d| started.elapsed() >= d);
+        if live_output || all_done || interrupted || timed_out {
             let stdout = io::stdout();
             let mut out = stdout.lock();
 
@@ -253,10 +370,14 @@ fn scrub(cli: Cli) -> Result<()> {
             break;
         }
 
-        if interrupted {
+        if interrupted || timed_out {
             writeln!(io::stdout())?;
-            eprintln!("Interrupted");
-            exit_code |= 1;
+            if interrupted {
+                eprintln!("Interrupted; continue with --resume");
+                exit_code |= 1;
+            } else {
+                eprintln!("Reached --max-duration; continue with --resume");
+            }
 
             // Parallelize kthread_stop() so we don't block on each thread serially
             let stops: Vec<_> = scrub_devs
@@ -284,7 +405,38 @@ fn scrub(cli: Cli) -> Result<()> {
 
 pub const CMD: super::CmdDef = typed_cmd!(
     "scrub",
-    "Verify data checksums; affected paths are logged to dmesg",
+    "Verify data checksums, resumably; affected paths are logged to dmesg",
     Cli,
     scrub
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_parse() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("4h").unwrap(), Duration::from_secs(4 * 3600));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("4x").is_err());
+    }
+
+    #[test]
+    fn duration_parse_edges() {
+        assert_eq!(parse_duration("45s").unwrap(), Duration::from_secs(45));
+        assert_eq!(parse_duration("0").unwrap(), Duration::ZERO);
+        assert_eq!(parse_duration(" 2h ").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("-5m").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("30 m").is_err());
+        assert!(parse_duration("10ms").is_err());
+        /* Overflows in the multiply: an error, not a panic */
+        assert!(parse_duration(&format!("{}d", u64::MAX / 1000)).is_err());
+        assert_eq!(parse_duration(&format!("{}", u64::MAX)).unwrap(),
+                   Duration::from_secs(u64::MAX));
+    }
+}
@@ -2,19 +2,28 @@ use std::{collections::{HashMap, HashSet}, ffi::CStr, mem, os::fd::OwnedFd,
     path::{Path, PathBuf}, process::ExitCode};
 use chrono::{Local, TimeZone};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bch_bindgen::c::{
-    BCH_SUBVOL_SNAPSHOT_RO, bch_ioctl_snapshot_node, bch_ioctl_snapshot_node_v2,
+    BCH_SUBVOL_SNAPSHOT_RO, BCH_SUBVOL_SET_LABEL, BCH_SUBVOL_SET_QUOTA,
+    BCH_SUBVOL_SET_RETENTION, BCH_SUBVOL_SET_RO, BCH_SUBVOL_SET_TREE_QUOTA,
+    bch_ioctl_snapshot_node, bch_ioctl_snapshot_node_v2,
     bch_ioctl_snapshot_tree_query, bch_ioctl_snapshot_tree_query_v2,
     bch_ioctl_subvol_dirent, bch_ioctl_subvol_readdir, bch_ioctl_subvol_to_path,
+    bch_ioctl_subvolume_set, bch_subvolume,
 };
 use clap::{Parser, Subcommand, ValueEnum};
 
-use crate::util::{fmt_sectors_human, fmt_bytes_human, fmt_num_human, open_dir, subvol_root};
+use super::subvolume_autosnap::cmd_autosnap;
+use super::subvolume_send::{cmd_receive, cmd_send, subvol_of};
+use crate::util::{fmt_sectors_human, fmt_bytes_human, fmt_num_human, open_dir,
+    parse_human_size, subvol_root};
+use crate::wrappers::file_extents::{snapshot_info, snapshot_tree_info, subvol_info,
+    subvol_root_inode, subvol_snapshot};
 use crate::wrappers::handle::BcachefsHandle;
 use crate::wrappers::ioctl::{ioctl_ptr, ioctl_rw, Ioctl, IoctlBuf,
     BCH_IOCTL_SNAPSHOT_TREE, BCH_IOCTL_SNAPSHOT_TREE_v2,
     BCH_IOCTL_SUBVOLUME_LIST, BCH_IOCTL_SUBVOLUME_TO_PATH};
+use crate::wrappers::snapshot_diff::{snapshot_diff, PathResolver, SnapshotPair, DT_SUBVOL};
 
 // ---- CLI definitions ----
 
@@ -73,6 +82,29 @@ space as either diverges. Snapshots are writable by default; use \
         dest:      PathBuf,
     },
 
+    /// Roll a subvolume back to one of its snapshots, in place
+    #[command(long_about = "Replaces the contents of a subvolume with a new \
+writable snapshot of SNAPSHOT, in a single transaction. The subvolume keeps \
+its ID and its path, so mounts with subvol= and anything else that refers to \
+it by ID still point at it. Its state from before the rollback is kept as a \
+read-only snapshot, next to the subvolume by default.\n\n\
+SNAPSHOT must be a snapshot of the same subvolume. Fails with EBUSY if files \
+in the subvolume are still open, or if the subvolume is mounted: the rollback \
+is not staged for the next mount, so unmount it first and roll it back through \
+a mount of its parent.")]
+    Rollback {
+        /// Where to keep the pre-rollback state
+        /// [default: SUBVOL.pre-rollback-<time>]
+        #[arg(long)]
+        save: Option<PathBuf>,
+
+        /// Subvolume to roll back
+        subvol: PathBuf,
+
+        /// Snapshot to roll back to
+        snapshot: PathBuf,
+    },
+
     /// List subvolumes
     #[command(visible_aliases = ["ls"],
         long_about = "Lists subvolumes in a bcachefs filesystem. Output \
@@ -148,6 +180,139 @@ trees for nested subvolumes too.")]
 	/// Directory in a mounted filesystem
         target: PathBuf,
     },
+
+    /// Show everything about one subvolume
+    #[command(long_about = "Shows all fields of a subvolume's key - subvolume \
+and snapshot IDs, root inode, creation time, the subvolume it was snapshotted \
+from, the subvolume its path is in, flags, state and label - its disk usage, \
+both its own and cumulative with the snapshots it shares data with, and the \
+quotas on it and on its snapshot tree.")]
+    Show {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Subvolume root
+        path: PathBuf,
+    },
+
+    /// Change properties of an existing subvolume
+    #[command(long_about = "Changes properties of a subvolume or snapshot after \
+creation. Properties are given as property=value:\n\n\
+  ro=true|false          read-only; flushes dirty data first when set\n\
+  retention=true|false   subject to snapshot retention: may be deleted by \
+`subvolume autosnap` once expired\n\
+  label=TEXT             free-form label, up to 32 bytes; empty to clear\n\
+  quota=SIZE|none        limit on the subvolume's cumulative usage - what \
+`subvolume list` reports - enforced at write time (EDQUOT)\n\
+  tree_quota=SIZE|none   limit on the usage of the whole snapshot tree: the \
+subvolume and all its snapshots\n\n\
+Setting quotas requires CAP_SYS_ADMIN.")]
+    Set {
+        /// Subvolume root
+        path: PathBuf,
+
+        /// property=value
+        #[arg(required = true)]
+        properties: Vec<String>,
+    },
+
+    /// Take scheduled snapshots and delete expired ones
+    #[command(long_about = "Takes a read-only snapshot of each subvolume \
+unless it already has one from the current period, and deletes the snapshots \
+its retention policy no longer keeps, waiting until their space has been \
+reclaimed. Meant to be run hourly, from the bcachefs-autosnap.timer systemd \
+unit or with --daemon.\n\n\
+The policy is read from the config file, one [/path/to/subvolume] section per \
+subvolume, with keys:\n\n\
+  hourly=N, daily=N, weekly=N   how many of the most recent hours, days and \
+weeks to keep the first snapshot of\n\
+  template=FMT                  strftime template for snapshot names \
+[default: %Y-%m-%d_%H%M%S]\n\
+  dir=PATH                      where snapshots go, relative to the \
+subvolume's parent [default: .snapshots/NAME]\n\n\
+Keys a section doesn't set, and the whole policy for subvolumes given on the \
+command line that the config file doesn't list, are read from the xattrs \
+user.bcachefs.autosnap.KEY on the subvolume root - except dir, which the \
+subvolume's owner could otherwise point anywhere, and is only read from the \
+config file. Only snapshots with the \
+retention property set (see `subvolume set`) are ever deleted.")]
+    Autosnap {
+        /// Policy file [default: /etc/bcachefs/autosnap.conf]
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+
+        /// Keep running, once an hour
+        #[arg(long)]
+        daemon: bool,
+
+        /// Show what would be created and deleted, without doing it
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Subvolumes to run on, instead of every one in the config file
+        subvols: Vec<PathBuf>,
+    },
+
+    /// Show what changed between two snapshots
+    #[command(long_about = "Lists the paths created (+), deleted (-), renamed \
+(R) and modified (M) between two snapshots of the same subvolume, with the \
+byte ranges of file data that changed. Only btree keys written since the two \
+snapshots diverged are compared, each looked up in both snapshots' views. \
+Finding them still reads the whole inodes btree, and the dirents, xattrs and \
+extents of every inode either snapshot can see, in every snapshot on the \
+filesystem: the time taken grows with the filesystem, not with how much \
+changed.")]
+    Diff {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Older snapshot
+        old: PathBuf,
+
+        /// Newer snapshot
+        new: PathBuf,
+    },
+
+    /// Serialize a read-only snapshot as a stream
+    #[command(long_about = "Writes a read-only snapshot as a stream of file \
+operations that `subvolume receive` replays on another bcachefs \
+filesystem. With --parent, only what differs from an earlier snapshot of \
+the same subvolume is sent, worked out from the btree keys written since \
+they diverged rather than by comparing the trees - as with `subvolume diff`, \
+finding those keys reads the btrees across every snapshot on the \
+filesystem. The receiving side must already have that parent. Data reflinked more than once within the stream \
+is sent once and cloned after.")]
+    Send {
+        /// Send only the changes since this snapshot
+        #[arg(short, long)]
+        parent: Option<PathBuf>,
+
+        /// Write the stream to a file instead of stdout
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// Read-only snapshot to send
+        snapshot: PathBuf,
+    },
+
+    /// Recreate a snapshot from a send stream
+    #[command(long_about = "Reads a stream from `subvolume send` and \
+recreates the snapshot it describes as a read-only snapshot in the given \
+directory, under the name it was sent with. An incremental stream is \
+applied to a snapshot of its parent, which must be in the same directory \
+under the name it was sent with, and be the snapshot the stream was sent \
+against - checked by UUID. Paths in the stream are never resolved through \
+symlinks or outside the new snapshot.")]
+    Receive {
+        /// Read the stream from a file instead of stdin
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// Directory to create the snapshot in
+        dir: PathBuf,
+    },
 }
 
 // ---- Data types ----
@@ -171,8 +336,11 @@ struct SnapshotTreeResult {
 
 // ---- Ioctl layer ----
 
-const BCH_SUBVOLUME_RO:       u32 = 1 << 0;
-const BCH_SUBVOLUME_UNLINKED: u32 = 1 << 2;
+/// The ioctls pass subvolume flags through as bch_subvolume.flags: decode
+/// them with its generated accessors.
+fn subvol_flags(flags: u32) -> bch_subvolume {
+    bch_subvolume { flags: flags.to_le(), ..Default::default() }
+}
 
 /// A header-plus-flexible-array ioctl with capacity/total retry protocol:
 /// nr in is capacity, ERANGE reports the required total.
@@ -372,9 +540,11 @@ fn subvol_sizes(fd: &OwnedFd) -> Option<HashMap<u32, u64>> {
 // ---- Formatting helpers ----
 
 fn flags_str(flags: u32) -> String {
+    let f = subvol_flags(flags);
     let mut parts = Vec::new();
-    if flags & BCH_SUBVOLUME_RO != 0       { parts.push("ro"); }
-    if flags & BCH_SUBVOLUME_UNLINKED != 0 { parts.push("unlinked"); }
+    if f.ro()                { parts.push("ro"); }
+    if f.unlinked_obsolete() { parts.push("unlinked"); }
+    if f.retention()         { parts.push("retention"); }
     if parts.is_empty() {
         String::new()
     } else {
@@ -490,7 +660,7 @@ fn print_flat(dir: &Path, recursive: bool, show_snapshots: bool,
 
     entries.retain(|(_, e)| {
         if !show_snapshots && e.snapshot_parent != 0 { return false; }
-        if readonly && (e.flags & BCH_SUBVOLUME_RO) == 0 { return false; }
+        if readonly && !subvol_flags(e.flags).ro() { return false; }
         true
     });
 
@@ -597,7 +767,7 @@ fn collect_subvol_json(dir: &Path, recursive: bool, show_snapshots: bool, readon
 
     for e in &entries {
         if !show_snapshots && e.snapshot_parent != 0 { continue; }
-        if readonly && (e.flags & BCH_SUBVOLUME_RO) == 0 { continue; }
+        if readonly && !subvol_flags(e.flags).ro() { continue; }
 
         let mut obj = serde_json::json!({
             "subvolid": e.subvolid,
@@ -741,7 +911,7 @@ fn print_snapshot_flat(dir: &Path, readonly: bool, sort: Option<SortBy>) -> Resu
 
     let mut entries: Vec<(String, &SnapshotNode, u64)> = tree.nodes.iter()
         .filter(|n| n.subvol != 0)
-        .filter(|n| !readonly || (n.flags & BCH_SUBVOLUME_RO) != 0)
+        .filter(|n| !readonly || subvol_flags(n.flags).ro())
         .map(|n| {
             let path = resolve_subvol_path(&fd, n.subvol)
                 .unwrap_or_else(|| format!("subvol {}", n.subvol));
@@ -843,6 +1013,384 @@ fn collect_snapshot_targets(dir: &Path, recursive: bool,
     Ok(targets)
 }
 
+// ---- Display: subvolume show ----
+
+/* bch_subvolume.state codewords (enum bch_subvolume_state) */
+fn subvol_state_str(v: &bch_subvolume) -> &'static str {
+    use bch_bindgen::c::bch_subvolume_state::*;
+
+    match u32::from_le(v.state) {
+        s if s == SUBVOLUME_STATE_live as u32     => "live",
+        s if s == SUBVOLUME_STATE_unlinked as u32 => "unlinked",
+        s if s == SUBVOLUME_STATE_deleted as u32  => "deleted",
+        /* Not upgraded: the obsolete flag is the truth */
+        0 if v.unlinked_obsolete() => "unlinked",
+        0 => "live",
+        _ => "invalid",
+    }
+}
+
+fn subvol_label(v: &bch_subvolume) -> String {
+    let len = v.label.iter().position(|&b| b == 0).unwrap_or(v.label.len());
+    String::from_utf8_lossy(&v.label[..len]).into_owned()
+}
+
+struct SubvolShow {
+    path:            PathBuf,
+    subvolid:        u64,
+    snapshot:        u32,
+    inode:           u64,
+    otime_sec:       i64,
+    creation_parent: u32,
+    fs_path_parent:  u32,
+    flags:           u32,
+    state:           &'static str,
+    label:           String,
+    own_sectors:     Option<u64>,
+    sectors:         Option<u64>,
+    tree_sectors:    Option<u64>,
+    /// In sectors; 0 for no limit
+    quota:           u64,
+    tree_quota:      u64,
+}
+
+fn subvol_show(path: &Path) -> Result<(SubvolShow, OwnedFd)> {
+    let (path, _, subvolid) = subvol_of(path)?;
+    let handle = BcachefsHandle::open(&path)
+        .map_err(|e| anyhow!("opening filesystem at {}: {e}", path.display()))?;
+
+    let v = subvol_info(&handle, subvolid)?;
+    let flags = u32::from_le(v.flags);
+    let snapshot = u32::from_le(v.snapshot);
+    let (otime_sec, _) = handle.time_to_unix(u64::from_le(v.otime.lo) as i64)?;
+
+    let tree_quota = match snapshot_info(&handle, snapshot)? {
+        Some(s) => snapshot_tree_info(&handle, u32::from_le(s.tree))?
+            .map_or(0, |t| u64::from_le(t.quota_sectors)),
+        None    => 0,
+    };
+
+    let fd = open_dir(&path)?;
+    let tree = query_snapshot_tree(&fd, 0).ok();
+
+    Ok((SubvolShow {
+        subvolid,
+        snapshot,
+        inode:           u64::from_le(v.inode),
+        otime_sec,
+        creation_parent: u32::from_le(v.creation_parent),
+        fs_path_parent:  u32::from_le(v.fs_path_parent),
+        flags,
+        state:           subvol_state_str(&v),
+        label:           subvol_label(&v),
+        own_sectors:     tree.as_ref().and_then(|t| t.nodes.iter()
+                                .find(|n| n.id == snapshot).map(|n| n.sectors)),
+        sectors:         tree.as_ref().and_then(|t| compute_subvol_sizes(t)
+                                .get(&(subvolid as u32)).copied()),
+        tree_sectors:    tree.as_ref().map(|t| t.nodes.iter().map(|n| n.sectors).sum()),
+        quota:           u64::from_le(v.quota_sectors),
+        tree_quota,
+        path,
+    }, fd))
+}
+
+fn show_flags_str(flags: u32) -> String {
+    let f = subvol_flags(flags);
+    let mut parts = Vec::new();
+    if f.ro()        { parts.push("ro"); }
+    if f.snap()      { parts.push("snapshot"); }
+    if f.retention() { parts.push("retention"); }
+    if parts.is_empty() { "-".to_string() } else { parts.join(",") }
+}
+
+fn subvol_ref_str(fd: &OwnedFd, id: u32) -> String {
+    match id {
+        0 => "-".to_string(),
+        _ => match resolve_subvol_path(fd, id) {
+            Some(p) => format!("{id} ({p})"),
+            None    => id.to_string(),
+        },
+    }
+}
+
+fn quota_str(sectors: u64) -> String {
+    match sectors {
+        0 => "none".to_string(),
+        _ => fmt_sectors_human(sectors),
+    }
+}
+
+fn print_subvol_show(sv: &SubvolShow, fd: &OwnedFd) {
+    let sectors = |s: Option<u64>| s.map(fmt_sectors_human).unwrap_or_else(|| "-".to_string());
+
+    println!("Path:                {}", sv.path.display());
+    println!("Subvolume ID:        {}", sv.subvolid);
+    println!("Snapshot ID:         {}", sv.snapshot);
+    println!("Root inode:          {}", sv.inode);
+    println!("Created:             {}", format_time(sv.otime_sec, 0));
+    println!("Snapshot of:         {}", subvol_ref_str(fd, sv.creation_parent));
+    println!("Path parent:         {}", subvol_ref_str(fd, sv.fs_path_parent));
+    println!("Flags:               {}", show_flags_str(sv.flags));
+    println!("State:               {}", sv.state);
+    println!("Label:               {}", if sv.label.is_empty() { "-" } else { &sv.label });
+    println!("Usage (own):         {}", sectors(sv.own_sectors));
+    println!("Usage (cumulative):  {}", sectors(sv.sectors));
+    println!("Quota:               {}", quota_str(sv.quota));
+    println!("Usage (tree):        {}", sectors(sv.tree_sectors));
+    println!("Tree quota:          {}", quota_str(sv.tree_quota));
+}
+
+fn subvol_show_json(sv: &SubvolShow, fd: &OwnedFd) -> serde_json::Value {
+    let parent = |id: u32| match id {
+        0 => serde_json::Value::Null,
+        _ => serde_json::json!({ "id": id, "path": resolve_subvol_path(fd, id) }),
+    };
+
+    serde_json::json!({
+        "path":            sv.path.display().to_string(),
+        "id":              sv.subvolid,
+        "snapshot":        sv.snapshot,
+        "inode":           sv.inode,
+        "otime":           format_time(sv.otime_sec, 0),
+        "otime_unix":      sv.otime_sec,
+        "creation_parent": parent(sv.creation_parent),
+        "fs_path_parent":  parent(sv.fs_path_parent),
+        "flags":           sv.flags,
+        "ro":              subvol_flags(sv.flags).ro(),
+        "snapshot_subvol": subvol_flags(sv.flags).snap(),
+        "retention":       subvol_flags(sv.flags).retention(),
+        "state":           sv.state,
+        "label":           sv.label,
+        "own_sectors":     sv.own_sectors,
+        "sectors":         sv.sectors,
+        "tree_sectors":    sv.tree_sectors,
+        "quota_sectors":   (sv.quota != 0).then_some(sv.quota),
+        "tree_quota_sectors": (sv.tree_quota != 0).then_some(sv.tree_quota),
+    })
+}
+
+// ---- Property parsing: subvolume set ----
+
+fn parse_bool_prop(key: &str, val: &str) -> Result<bool> {
+    match val {
+        "true" | "yes" | "on" | "1"  => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => bail!("{key}={val}: expected true or false"),
+    }
+}
+
+/// A size in bytes, rounded up to sectors; "none" for no limit
+fn parse_quota_prop(key: &str, val: &str) -> Result<u64> {
+    match val {
+        "none" | "0" => Ok(0),
+        _ => Ok(parse_human_size(val)
+            .with_context(|| format!("{key}={val}: expected a size or none"))?
+            .div_ceil(512)),
+    }
+}
+
+fn parse_subvol_props(props: &[String]) -> Result<bch_ioctl_subvolume_set> {
+    let mut arg = bch_ioctl_subvolume_set::default();
+
+    for p in props {
+        let (key, val) = p.split_once('=')
+            .ok_or_else(|| anyhow!("{p}: expected property=value"))?;
+
+        match key {
+            "ro" => {
+                arg.flags |= BCH_SUBVOL_SET_RO;
+                arg.ro = parse_bool_prop(key, val)? as u8;
+            }
+            "retention" => {
+                arg.flags |= BCH_SUBVOL_SET_RETENTION;
+                arg.retention = parse_bool_prop(key, val)? as u8;
+            }
+            "label" => {
+                if val.len() > arg.label.len() {
+                    bail!("label: {} bytes, maximum is {}", val.len(), arg.label.len());
+                }
+                arg.flags |= BCH_SUBVOL_SET_LABEL;
+                arg.label.fill(0);
+                arg.label[..val.len()].copy_from_slice(val.as_bytes());
+            }
+            "quota" => {
+                arg.flags |= BCH_SUBVOL_SET_QUOTA;
+                arg.quota_sectors = parse_quota_prop(key, val)?;
+            }
+            "tree_quota" => {
+                arg.flags |= BCH_SUBVOL_SET_TREE_QUOTA;
+                arg.tree_quota_sectors = parse_quota_prop(key, val)?;
+            }
+            _ => bail!("unknown property {key} (have: ro, retention, label, quota, tree_quota)"),
+        }
+    }
+
+    Ok(arg)
+}
+
+// ---- Display: snapshot diff ----
+
+enum Change {
+    Created,
+    Deleted,
+    Renamed(String),
+    Modified(Vec<std::ops::Range<u64>>),
+}
+
+struct DiffEntry {
+    path:   String,
+    change: Change,
+}
+
+fn diff_entry_json(e: &DiffEntry) -> serde_json::Value {
+    let mut obj = serde_json::json!({ "path": &e.path });
+    match &e.change {
+        Change::Created => obj["change"] = "created".into(),
+        Change::Deleted => obj["change"] = "deleted".into(),
+        Change::Renamed(from) => {
+            obj["change"] = "renamed".into();
+            obj["from"] = serde_json::json!(from);
+        }
+        Change::Modified(ranges) => {
+            obj["change"] = "modified".into();
+            if !ranges.is_empty() {
+                obj["ranges"] = ranges.iter().map(|r| serde_json::json!([r.start, r.end])).collect();
+            }
+        }
+    }
+    obj
+}
+
+fn print_diff_entry(e: &DiffEntry) {
+    match &e.change {
+        Change::Created => println!("+\t{}", e.path),
+        Change::Deleted => println!("-\t{}", e.path),
+        Change::Renamed(from) => println!("R\t{} -> {}", from, e.path),
+        Change::Modified(ranges) if ranges.is_empty() => println!("M\t{}", e.path),
+        Change::Modified(ranges) => println!("M\t{}\t{}", e.path,
+            ranges.iter().map(|r| format!("{}-{}", r.start, r.end)).collect::<Vec<_>>().join(",")),
+    }
+}
+
+fn path_str(p: &Path) -> String {
+    if p.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        p.to_string_lossy().into_owned()
+    }
+}
+
+/// What changed between two snapshots, by path: from the inodes, dirents,
+/// xattrs and extents that differ (see wrappers::snapshot_diff), with
+/// renames told apart from a delete and a create by the inode number
+/// staying the same.
+fn snapshot_diff_entries(old: &Path, new: &Path) -> Result<Vec<DiffEntry>> {
+    use std::collections::BTreeMap;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (old, old_dev, old_subvol) = subvol_of(old)?;
+    let (new, new_dev, new_subvol) = subvol_of(new)?;
+    if old_dev != new_dev {
+        bail!("{} and {} are on different filesystems", old.display(), new.display());
+    }
+
+    let handle = BcachefsHandle::open(&new)
+        .map_err(|e| anyhow!("opening filesystem at {}: {e}", new.display()))?;
+
+    let root = subvol_root_inode(&handle, new_subvol)?;
+    if subvol_root_inode(&handle, old_subvol)? != root {
+        bail!("{} and {} are not snapshots of the same subvolume",
+                      old.display(), new.display());
+    }
+
+    let old_snap = subvol_snapshot(&handle, old_subvol)?;
+    let new_snap = subvol_snapshot(&handle, new_subvol)?;
+    let diff = snapshot_diff(&handle, &SnapshotPair::new(&handle, Some(old_snap), new_snap)?)?;
+
+    let mut old_paths = PathResolver::new(&handle, old_snap, root);
+    let mut new_paths = PathResolver::new(&handle, new_snap, root);
+
+    /* Links each inode lost and gained: */
+    let mut links: BTreeMap<u64, (Vec<PathBuf>, Vec<PathBuf>)> = BTreeMap::new();
+    for d in &diff.dirents {
+        if let Some(de) = d.old.as_ref().filter(|de| de.d_type != DT_SUBVOL) {
+            if let Some(dir) = old_paths.dir_path(d.dir)? {
+                links.entry(de.target).or_default().0
+                    .push(dir.join(std::ffi::OsStr::from_bytes(&de.name)));
+            }
+        }
+        if let Some(de) = d.new.as_ref().filter(|de| de.d_type != DT_SUBVOL) {
+            if let Some(dir) = new_paths.dir_path(d.dir)? {
+                links.entry(de.target).or_default().1
+                    .push(dir.join(std::ffi::OsStr::from_bytes(&de.name)));
+            }
+        }
+    }
+
+    let mut inums: Vec<u64> = links.keys()
+        .chain(diff.inodes.keys())
+        .chain(diff.extents.keys())
+        .chain(diff.xattrs.iter())
+        .copied()
+        .collect();
+    inums.sort();
+    inums.dedup();
+
+    let mut ret = Vec::new();
+    for inum in inums {
+        let (removed, added) = links.remove(&inum).unwrap_or_default();
+        let change = diff.inodes.get(&inum);
+        let exists_old = change.map(|c| c.old.is_some()).unwrap_or(true);
+        let exists_new = change.map(|c| c.new.is_some()).unwrap_or(true);
+
+        match (exists_old, exists_new) {
+            (false, true) => {
+                ret.extend(added.iter().map(|p| DiffEntry { path: path_str(p), change: Change::Created }));
+                continue;
+            }
+            (true, false) => {
+                ret.extend(removed.iter().map(|p| DiffEntry { path: path_str(p), change: Change::Deleted }));
+                continue;
+            }
+            (false, false) => continue,
+            (true, true) => {}
+        }
+
+        let mut removed = removed.into_iter();
+        let mut added = added.into_iter();
+        loop {
+            match (removed.next(), added.next()) {
+                (Some(from), Some(to)) => ret.push(DiffEntry {
+                    path: path_str(&to), change: Change::Renamed(path_str(&from)),
+                }),
+                (Some(p), None) => ret.push(DiffEntry { path: path_str(&p), change: Change::Deleted }),
+                (None, Some(p)) => ret.push(DiffEntry { path: path_str(&p), change: Change::Created }),
+                (None, None) => break,
+            }
+        }
+
+        let inode = change.and_then(|c| Some((c.old.as_ref()?, c.new.as_ref()?)));
+        let attrs_changed = inode.is_some_and(|(o, n)| o.modified(n));
+        let ranges = diff.extents.get(&inum);
+        if !attrs_changed && ranges.is_none() && !diff.xattrs.contains(&inum) {
+            continue;
+        }
+
+        let Some(path) = new_paths.path(inum)? else { continue };
+        let size = inode.map(|(o, n)| o.size.max(n.size)).unwrap_or(u64::MAX);
+        let ranges = ranges.map(|r| r.iter()
+            .map(|r| r.start..r.end.min(size))
+            .filter(|r| r.start < r.end)
+            .collect())
+            .unwrap_or_default();
+
+        ret.push(DiffEntry { path: path_str(&path), change: Change::Modified(ranges) });
+    }
+
+    ret.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(ret)
+}
+
 // ---- Command handlers ----
 
 /// A listing that skipped a subtree exits nonzero: the results printed are
@@ -856,10 +1404,17 @@ fn subvolume(cli: Cli) -> Result<ExitCode> {
         /* rw is the default, so it only has to not contradict --read-only -
          * which clap enforces, so there's nothing left for it to say here: */
         Subcommands::Snapshot { read_only, source, dest, rw: _ }                => cmd_snapshot(read_only, source, dest)?,
+        Subcommands::Rollback { save, subvol, snapshot }                        => cmd_rollback(save, subvol, snapshot)?,
         Subcommands::List { json, tree, recursive, snapshots, readonly, sort, target }
                                                                                 => cmd_list(json, tree, recursive, snapshots, readonly, sort, target, &mut skipped)?,
         Subcommands::ListSnapshots { flat, json, readonly, sort, recursive, target }
                                                                                 => cmd_list_snapshots(flat, json, readonly, sort, recursive, target, &mut skipped)?,
+        Subcommands::Show { json, path }                                        => cmd_show(json, path)?,
+        Subcommands::Set { path, properties }                                   => cmd_set(path, properties)?,
+        Subcommands::Autosnap { config, daemon, dry_run, subvols }              => return cmd_autosnap(config, daemon, dry_run, subvols),
+        Subcommands::Diff { json, old, new }                                    => cmd_diff(json, old, new)?,
+        Subcommands::Send { parent, file, snapshot }                            => cmd_send(parent, file, snapshot)?,
+        Subcommands::Receive { file, dir }                                      => cmd_receive(file, dir)?,
     }
 
     Ok(if skipped.any() { ExitCode::FAILURE } else { ExitCode::SUCCESS })
@@ -919,6 +1474,66 @@ fn cmd_snapshot(read_only: bool, source: Option<PathBuf>, dest: PathBuf) -> Resu
     Ok(())
 }
 
+fn cmd_rollback(save: Option<PathBuf>, subvol: PathBuf, snapshot: PathBuf) -> Result<()> {
+    let subvol = subvol
+        .canonicalize()
+        .with_context(|| format!("{}", subvol.display()))?;
+    let parent = subvol
+        .parent()
+        .ok_or_else(|| anyhow!("can't roll back the root of the directory tree"))?;
+
+    let save = save.unwrap_or_else(|| {
+        let mut name = subvol.file_name().unwrap_or_default().to_os_string();
+        name.push(Local::now().format(".pre-rollback-%Y%m%d-%H%M%S").to_string());
+        parent.join(name)
+    });
+
+    /* Not from inside the subvolume: the handle would keep it busy */
+    fs_for_path(parent)?
+        .rollback_subvolume(&subvol, &snapshot, &save)
+        .map_err(|e| match e.0 {
+            libc::EBUSY => anyhow!("{}: subvolume is in use: close files in it, and unmount it \
+                                    if it's mounted, then retry", subvol.display()),
+            _ => anyhow!(e),
+        })
+        .context("Failed to roll back the subvolume")?;
+
+    println!("{}: rolled back to {}, previous state kept at {}",
+             subvol.display(), snapshot.display(), save.display());
+    Ok(())
+}
+
+fn cmd_show(json: bool, path: PathBuf) -> Result<()> {
+    let (sv, fd) = subvol_show(&path)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&subvol_show_json(&sv, &fd))?);
+    } else {
+        print_subvol_show(&sv, &fd);
+    }
+    Ok(())
+}
+
+fn cmd_set(path: PathBuf, properties: Vec<String>) -> Result<()> {
+    let arg = parse_subvol_props(&properties)?;
+    let (path, _, _) = subvol_of(&path)?;
+
+    fs_for_path(&path)?
+        .set_subvolume(&path, arg)
+        .context("Failed to set subvolume properties")?;
+    Ok(())
+}
+
+fn cmd_diff(json: bool, old: PathBuf, new: PathBuf) -> Result<()> {
+    let entries = snapshot_diff_entries(&old, &new)?;
+    if json {
+        let v: Vec<_> = entries.iter().map(diff_entry_json).collect();
+        println!("{}", serde_json::to_string_pretty(&v)?);
+    } else {
+        entries.iter().for_each(print_diff_entry);
+    }
+    Ok(())
+}
+
 fn cmd_list(json: bool, tree: bool, recursive: bool, snapshots: bool,
             readonly: bool, sort: Option<SortBy>, target: PathBuf,
             skipped: &mut Skipped) -> Result<()> {
@@ -0,0 +1,450 @@
+//! `bcachefs subvolume autosnap`: scheduled read-only snapshots, pruned by a
+//! retention policy.
+//!
+//! The policy for a subvolume is how many hourly, daily and weekly snapshots
+//! to keep, the strftime template they're named with and the directory they
+//! go in. It comes from a section of the config file:
+//!
+//! ```text
+//! [/home]
+//! hourly = 24
+//! daily = 7
+//! weekly = 4
+//! ```
+//!
+//! and whatever a section leaves out - or all of it, for subvolumes named on
+//! the command line that the config file doesn't have - is read from xattrs
+//! on the subvolume root, user.bcachefs.autosnap.<key>. Except for the
+//! directory: anyone who owns the subvolume can set those xattrs, and the
+//! timer runs as root, so where snapshots are created (and deleted) only
+//! comes from the config file.
+//!
+//! A run takes a snapshot unless there's already one in the current hour (or
+//! day, or week - the shortest period the policy keeps any of), then prunes:
+//! the first snapshot taken in each of the N most recent hours, days and
+//! weeks that have one is kept, for N the respective count, and the rest are
+//! deleted. Only snapshots of this subvolume in its snapshot directory with
+//! the retention flag set - which autosnap sets on the ones it takes, and
+//! `subvolume set retention=` can set or clear - are candidates; anything
+//! else in there is left alone.
+//!
+//! Deleting a subvolume only unlinks it: its snapshot node, and the space,
+//! are reclaimed in the background. So a run waits for that, reporting the
+//! kernel's snapshot deletion progress, rather than returning with the space
+//! still in use and the next run piling more deletions on top.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bch_bindgen::c::{BCH_SUBVOL_SET_RETENTION, BCH_SUBVOL_SNAPSHOT_RO, bch_ioctl_subvolume_set};
+use chrono::{DateTime, Local, TimeZone, Timelike};
+
+use super::subvolume_send::subvol_of;
+use crate::wrappers::file_extents::{snapshot_info, subvol_info};
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::sysfs::read_sysfs_fd_str;
+
+const DEFAULT_CONFIG: &str = "/etc/bcachefs/autosnap.conf";
+const XATTR_PREFIX: &str = "user.bcachefs.autosnap.";
+const DEFAULT_TEMPLATE: &str = "%Y-%m-%d_%H%M%S";
+
+// ---- Policy ----
+
+#[derive(Clone, Debug, Default)]
+struct Policy {
+    hourly:   Option<u32>,
+    daily:    Option<u32>,
+    weekly:   Option<u32>,
+    template: Option<String>,
+    dir:      Option<PathBuf>,
+}
+
+const POLICY_KEYS: [&str; 5] = ["hourly", "daily", "weekly", "template", "dir"];
+
+/// Policy keys that may be set by xattr: not dir, see above
+const XATTR_KEYS: [&str; 4] = ["hourly", "daily", "weekly", "template"];
+
+impl Policy {
+    fn set(&mut self, key: &str, val: &str) -> Result<()> {
+        let count = || val.parse::<u32>()
+            .map_err(|_| anyhow!("{key}: expected a count, got {val:?}"));
+
+        match key {
+            "hourly"   => self.hourly   = Some(count()?),
+            "daily"    => self.daily    = Some(count()?),
+            "weekly"   => self.weekly   = Some(count()?),
+            "template" => self.template = Some(val.to_string()),
+            "dir"      => self.dir      = Some(PathBuf::from(val)),
+            _ => bail!("unknown key {key} (expected one of {})", POLICY_KEYS.join(", ")),
+        }
+        Ok(())
+    }
+
+    /// Fill in what the config file didn't set from xattrs on @root
+    fn fill_from_xattrs(&mut self, root: &Path) -> Result<()> {
+        let dir_xattr = format!("{XATTR_PREFIX}dir");
+        if read_xattr(root, &dir_xattr)?.is_some() {
+            eprintln!("{}: ignoring {dir_xattr}: the snapshot directory can only be set in the config file",
+                      root.display());
+        }
+
+        let mut x = Policy::default();
+        for key in XATTR_KEYS {
+            let name = format!("{XATTR_PREFIX}{key}");
+            if let Some(val) = read_xattr(root, &name)? {
+                x.set(key, val.trim())
+                    .with_context(|| format!("{name} on {}", root.display()))?;
+            }
+        }
+
+        self.hourly   = self.hourly.or(x.hourly);
+        self.daily    = self.daily.or(x.daily);
+        self.weekly   = self.weekly.or(x.weekly);
+        self.template = self.template.take().or(x.template);
+        Ok(())
+    }
+
+    fn periods(&self) -> Vec<(Period, u32)> {
+        [(Period::Hour, self.hourly), (Period::Day, self.daily), (Period::Week, self.weekly)]
+            .into_iter()
+            .filter_map(|(p, n)| n.filter(|&n| n > 0).map(|n| (p, n)))
+            .collect()
+    }
+}
+
+fn read_xattr(path: &Path, name: &str) -> Result<Option<String>> {
+    use rustix::fs::getxattr;
+    use rustix::io::Errno;
+
+    let read = |buf: &mut [u8]| -> Result<Option<usize>> {
+        match getxattr(path, name, buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(Errno::NODATA | Errno::NOTSUP) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {name} from {}", path.display())),
+        }
+    };
+
+    // An empty buffer returns the attribute's size.
+    let Some(len) = read(&mut [])? else { return Ok(None) };
+    let mut buf = vec![0u8; len];
+    let Some(len) = read(&mut buf)? else { return Ok(None) };
+    buf.truncate(len);
+
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|_| anyhow!("{name} on {}: not valid UTF-8", path.display()))
+}
+
+/// `[/path/to/subvolume]` sections of `key = value` lines; # starts a
+/// comment.
+fn parse_config(text: &str) -> Result<Vec<(PathBuf, Policy)>> {
+    let mut ret: Vec<(PathBuf, Policy)> = Vec::new();
+
+    for (nr, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(path) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            ret.push((PathBuf::from(path.trim()), Policy::default()));
+            continue;
+        }
+
+        let (key, val) = line.split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected key = value", nr + 1))?;
+        let (_, policy) = ret.last_mut()
+            .ok_or_else(|| anyhow!("line {}: {} before any [subvolume] section",
+                                   nr + 1, key.trim()))?;
+        policy.set(key.trim(), val.trim())
+            .with_context(|| format!("line {}", nr + 1))?;
+    }
+
+    Ok(ret)
+}
+
+// ---- Retention ----
+
+#[derive(Clone, Copy, Debug)]
+enum Period {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Period {
+    /// Which hour/day/week @t is in, local time; weeks are ISO weeks.
+    fn of(self, t: &DateTime<Local>) -> String {
+        t.format(match self {
+            Period::Hour => "%Y-%m-%d %H",
+            Period::Day  => "%Y-%m-%d",
+            Period::Week => "%G-W%V",
+        }).to_string()
+    }
+}
+
+struct Snap {
+    path:     PathBuf,
+    snapshot: u32,
+    otime:    DateTime<Local>,
+}
+
+/// Snapshots of @origin in @dir that are subject to retention, oldest first.
+fn existing_snapshots(handle: &BcachefsHandle, dir: &Path, origin: u64) -> Result<Vec<Snap>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+
+    let mut ret = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("reading {}", dir.display()))?;
+        /* Not a subvolume root: not ours */
+        let Ok((path, _, subvol)) = subvol_of(&entry.path()) else { continue };
+
+        let v = subvol_info(handle, subvol)?;
+        if u32::from_le(v.creation_parent) as u64 != origin ||
+           !v.retention() {
+            continue;
+        }
+
+        let (sec, nsec) = handle.time_to_unix(u64::from_le(v.otime.lo) as i64)
+            .map_err(|e| anyhow!("reading superblock: {e}"))?;
+        let otime = Local.timestamp_opt(sec, nsec).single()
+            .ok_or_else(|| anyhow!("{}: invalid creation time", path.display()))?;
+
+        ret.push(Snap { path, snapshot: u32::from_le(v.snapshot), otime });
+    }
+
+    ret.sort_by_key(|s| s.otime);
+    Ok(ret)
+}
+
+/// Indices into @snaps (oldest first) that the policy keeps.
+fn keep_set(snaps: &[Snap], policy: &Policy) -> HashSet<usize> {
+    let mut keep = HashSet::new();
+
+    for (period, n) in policy.periods() {
+        /* The first snapshot in each period stands for that period: */
+        let mut firsts: Vec<(String, usize)> = Vec::new();
+        for (i, s) in snaps.iter().enumerate() {
+            let p = period.of(&s.otime);
+            if firsts.last().is_none_or(|(last, _)| *last != p) {
+                firsts.push((p, i));
+            }
+        }
+
+        keep.extend(firsts.iter().rev().take(n as usize).map(|(_, i)| *i));
+    }
+
+    keep
+}
+
+// ---- Running a policy ----
+
+struct Run {
+    dry_run: bool,
+    /// Snapshot nodes of the subvolumes deleted this run, to wait on
+    deleted: Vec<u32>,
+}
+
+fn autosnap_one(run: &mut Run, handle: &BcachefsHandle, root: &Path, mut policy: Policy) -> Result<()> {
+    let (root, _, subvol) = subvol_of(root)?;
+    policy.fill_from_xattrs(&root)?;
+
+    let periods = policy.periods();
+    if periods.is_empty() {
+        bail!("{}: no hourly, daily or weekly count set", root.display());
+    }
+
+    let parent = root.parent()
+        .ok_or_else(|| anyhow!("{}: no parent directory", root.display()))?;
+    let dir = match policy.dir.as_deref() {
+        Some(d) => parent.join(d),
+        None    => parent.join(".snapshots").join(root.file_name().unwrap_or_default()),
+    };
+
+    let mut snaps = existing_snapshots(handle, &dir, subvol)?;
+
+    let now = Local::now();
+    let current = periods[0].0;
+    if !snaps.last().is_some_and(|s| current.of(&s.otime) == current.of(&now)) {
+        let template = policy.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+        let name = now.format(template).to_string();
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            bail!("{}: template {template:?} gives invalid name {name:?}", root.display());
+        }
+        let dst = dir.join(&name);
+
+        if run.dry_run {
+            println!("would create {}", dst.display());
+        } else if dst.exists() {
+            bail!("{} already exists (template {template:?} too coarse?)", dst.display());
+        } else {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("creating {}", dir.display()))?;
+
+            handle.snapshot_subvolume(BCH_SUBVOL_SNAPSHOT_RO, Some(&root), &dst)
+                .with_context(|| format!("snapshotting {} to {}", root.display(), dst.display()))?;
+
+            let mut arg = bch_ioctl_subvolume_set::default();
+            arg.flags = BCH_SUBVOL_SET_RETENTION;
+            arg.retention = 1;
+            handle.set_subvolume(&dst, arg)
+                .with_context(|| format!("setting retention on {}", dst.display()))?;
+
+            println!("created {}", dst.display());
+        }
+
+        /* It counts for retention, from now on: */
+        snaps.push(Snap { path: dst, snapshot: 0, otime: now });
+    }
+
+    let keep = keep_set(&snaps, &policy);
+    for (i, s) in snaps.iter().enumerate() {
+        if keep.contains(&i) {
+            continue;
+        }
+
+        if run.dry_run {
+            println!("would delete {}", s.path.display());
+            continue;
+        }
+
+        handle.delete_subvolume(&s.path)
+            .with_context(|| format!("deleting {}", s.path.display()))?;
+        println!("deleted {}", s.path.display());
+        run.deleted.push(s.snapshot);
+    }
+
+    Ok(())
+}
+
+/// Whether a deleted subvolume's snapshot node is gone - its keys deleted,
+/// and the space with them.
+fn snapshot_reclaimed(handle: &BcachefsHandle, id: u32) -> Result<bool> {
+    let Some(v) = snapshot_info(handle, id)? else { return Ok(true) };
+
+    use bch_bindgen::c::bch_snapshot_state::*;
+
+    Ok(match u32::from_le(v.state) {
+        /* Keys from before the state field: the obsolete flags are the truth */
+        0 => v.deleted_obsolete() || v.no_keys_obsolete(),
+        s => s == SNAPSHOT_STATE_no_keys as u32 || s == SNAPSHOT_STATE_deleted as u32,
+    })
+}
+
+fn wait_for_deletion(handle: &BcachefsHandle, mut ids: Vec<u32>) -> Result<()> {
+    let mut last_status = String::new();
+
+    loop {
+        let mut pending = Vec::new();
+        for id in ids {
+            if !snapshot_reclaimed(handle, id)? {
+                pending.push(id);
+            }
+        }
+        ids = pending;
+
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        /* Progress, if the kernel is new enough to report it: */
+        if let Ok(status) = read_sysfs_fd_str(handle.sysfs_fd(), "snapshot_delete_status") {
+            let status = status.lines()
+                .find(|l| l.trim_start().starts_with("Progress"))
+                .unwrap_or_else(|| status.lines().next().unwrap_or_default())
+                .trim()
+                .to_string();
+            if status != last_status {
+                println!("waiting for {} snapshot(s) to be deleted: {status}", ids.len());
+                last_status = status;
+            }
+        }
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// The subvolumes to run on, each with its policy from the config file.
+fn policies(config: Option<PathBuf>, subvols: Vec<PathBuf>) -> Result<Vec<(PathBuf, Policy)>> {
+    /* Only the default config file is allowed to not exist: */
+    let explicit = config.is_some();
+    let config = config.unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG));
+
+    let sections = match fs::read_to_string(&config) {
+        Ok(text) => parse_config(&text)
+            .with_context(|| format!("parsing {}", config.display()))?,
+        Err(e) if e.kind() == ErrorKind::NotFound && !explicit => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", config.display())),
+    };
+
+    if subvols.is_empty() {
+        if sections.is_empty() {
+            bail!("no subvolumes in {} and none given", config.display());
+        }
+        return Ok(sections);
+    }
+
+    subvols.into_iter().map(|path| {
+        let canon = path.canonicalize()
+            .with_context(|| format!("Failed to resolve {}", path.display()))?;
+        let policy = sections.iter()
+            .find(|(p, _)| p.canonicalize().is_ok_and(|p| p == canon))
+            .map(|(_, policy)| policy.clone())
+            .unwrap_or_default();
+        Ok((canon, policy))
+    }).collect()
+}
+
+fn run_once(policies: &[(PathBuf, Policy)], dry_run: bool) -> bool {
+    let mut ok = true;
+
+    for (root, policy) in policies {
+        let handle = match BcachefsHandle::open(root) {
+            Ok(h) => h,
+            Err(e) => {
+                eprintln!("{}: opening filesystem: {e}", root.display());
+                ok = false;
+                continue;
+            }
+        };
+
+        let mut run = Run { dry_run, deleted: Vec::new() };
+        let ret = autosnap_one(&mut run, &handle, root, policy.clone());
+        let waited = wait_for_deletion(&handle, run.deleted);
+
+        for e in [ret, waited].into_iter().filter_map(Result::err) {
+            eprintln!("{}: {e:#}", root.display());
+            ok = false;
+        }
+    }
+
+    ok
+}
+
+pub(super) fn cmd_autosnap(config: Option<PathBuf>, daemon: bool, dry_run: bool,
+                           subvols: Vec<PathBuf>) -> Result<ExitCode> {
+    let policies = policies(config, subvols)?;
+
+    if !daemon {
+        return Ok(if run_once(&policies, dry_run) { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+
+    loop {
+        /* A failed run is reported; the next one may well succeed */
+        run_once(&policies, dry_run);
+
+        let now = Local::now();
+        let into_hour = now.minute() as u64 * 60 + now.second() as u64;
+        thread::sleep(Duration::from_secs(3600 - into_hour));
+    }
+}
@@ -0,0 +1,1154 @@
+//! `subvolume send` / `subvolume receive`: a read-only snapshot as a stream
+//! of file operations, to recreate it on another filesystem.
+//!
+//! What changed is worked out from the btree keys (see
+//! wrappers::snapshot_diff): against a parent snapshot, only the keys
+//! written in one snapshot's ancestry and not the other's are compared -
+//! though finding them reads the btrees across every snapshot.
+//! What's sent is expressed in paths and file contents, read through the
+//! mounted snapshot, because the receiving side has its own inode numbers
+//! and replays the stream through the VFS.
+//!
+//! Stream format, all integers little endian: the magic, a u32 version,
+//! the snapshot's name and its parent's name (empty for a full stream), the
+//! snapshot's UUID and its parent's (zero for a full stream), then records
+//! of (u16 op, u32 payload length, payload), ending with OP_END. A stream
+//! without OP_END was truncated and is rejected.
+//!
+//! The receiving side runs as root and takes paths from the stream: every
+//! one is resolved under the receive root without following symlinks, and
+//! an incremental stream is only applied to the snapshot whose UUID it
+//! names as its parent.
+//!
+//! Namespace changes are ordered so every path named exists when it's used:
+//! removed dirents deepest first - an inode that's still linked in the new
+//! snapshot is parked under an orphan name at the root rather than removed -
+//! then added dirents shallowest first, taking parked inodes back where
+//! they went and making new links from ones already in place; then file
+//! data, xattrs, and finally attributes, so directory times aren't
+//! disturbed by what's done inside them.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bch_bindgen::c::{bch_ioctl_subvolume_set, BCH_SUBVOL_SET_RO};
+use rustix::fd::{AsRawFd, OwnedFd};
+use rustix::fs::{openat, openat2, Mode, OFlags, ResolveFlags, CWD};
+
+use crate::util::{path_subvol, subvol_root};
+use crate::wrappers::file_extents::{subvol_info, subvol_is_ro, subvol_root_inode,
+    subvol_snapshot};
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::snapshot_diff::{lookup_inode, reflink_pointers, snapshot_diff,
+    Dirent, PathResolver, SnapshotDiff, SnapshotPair, DT_SUBVOL};
+
+const STREAM_MAGIC: &[u8; 8] = b"bchsend\0";
+const STREAM_VERSION: u32 = 2;
+
+/// Largest payload a reader will accept: data is sent in 1MiB writes, and
+/// nothing else comes close
+const MAX_PAYLOAD: u32 = 16 << 20;
+const DATA_CHUNK: u64 = 1 << 20;
+
+const OP_MKDIR: u16    = 1;
+const OP_MKFILE: u16   = 2;
+const OP_SYMLINK: u16  = 3;
+const OP_MKNOD: u16    = 4;
+const OP_LINK: u16     = 5;
+const OP_RENAME: u16   = 6;
+const OP_UNLINK: u16   = 7;
+const OP_RMDIR: u16    = 8;
+const OP_WRITE: u16    = 9;
+const OP_CLONE: u16    = 10;
+const OP_PUNCH: u16    = 11;
+const OP_TRUNCATE: u16 = 12;
+const OP_XATTRS: u16   = 13;
+const OP_ATTRS: u16    = 14;
+const OP_END: u16      = 15;
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Mkdir    { path: PathBuf },
+    Mkfile   { path: PathBuf },
+    Symlink  { path: PathBuf, target: PathBuf },
+    Mknod    { path: PathBuf, mode: u32, rdev: u64 },
+    Link     { path: PathBuf, src: PathBuf },
+    Rename   { from: PathBuf, to: PathBuf },
+    Unlink   { path: PathBuf },
+    Rmdir    { path: PathBuf },
+    Write    { path: PathBuf, offset: u64, data: Vec<u8> },
+    /// Reflink @len bytes from a file already received
+    Clone    { path: PathBuf, offset: u64, len: u64, src: PathBuf, src_offset: u64 },
+    Punch    { path: PathBuf, offset: u64, len: u64 },
+    Truncate { path: PathBuf, size: u64 },
+    /// The complete set: xattrs not listed are removed
+    Xattrs   { path: PathBuf, xattrs: Vec<(Vec<u8>, Vec<u8>)> },
+    Attrs    { path: PathBuf, mode: u32, uid: u32, gid: u32,
+               atime: (i64, u32), mtime: (i64, u32) },
+    End,
+}
+
+// ---- Encoding ----
+
+#[derive(Default)]
+struct Enc(Vec<u8>);
+
+impl Enc {
+    fn u32(&mut self, v: u32) { self.0.extend_from_slice(&v.to_le_bytes()); }
+    fn u64(&mut self, v: u64) { self.0.extend_from_slice(&v.to_le_bytes()); }
+    fn i64(&mut self, v: i64) { self.0.extend_from_slice(&v.to_le_bytes()); }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.0.extend_from_slice(v);
+    }
+
+    fn path(&mut self, p: &Path) { self.bytes(p.as_os_str().as_bytes()); }
+
+    fn time(&mut self, (sec, nsec): (i64, u32)) {
+        self.i64(sec);
+        self.u32(nsec);
+    }
+}
+
+struct Dec<'a>(&'a [u8]);
+
+impl Dec<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        if self.0.len() < n {
+            bail!("record truncated");
+        }
+        let (v, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(v)
+    }
+
+    fn u32(&mut self) -> Result<u32> { Ok(u32::from_le_bytes(self.take(4)?.try_into()?)) }
+    fn u64(&mut self) -> Result<u64> { Ok(u64::from_le_bytes(self.take(8)?.try_into()?)) }
+    fn i64(&mut self) -> Result<i64> { Ok(i64::from_le_bytes(self.take(8)?.try_into()?)) }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let n = self.u32()? as usize;
+        Ok(self.take(n)?.to_vec())
+    }
+
+    fn path(&mut self) -> Result<PathBuf> {
+        Ok(PathBuf::from(OsStr::from_bytes(&self.bytes()?)))
+    }
+
+    fn time(&mut self) -> Result<(i64, u32)> {
+        Ok((self.i64()?, self.u32()?))
+    }
+}
+
+impl Record {
+    /// For error messages: what was being done, and to which path
+    fn describe(&self) -> String {
+        let (op, path) = match self {
+            Record::Mkdir { path }          => ("mkdir", path),
+            Record::Mkfile { path }         => ("create", path),
+            Record::Symlink { path, .. }    => ("symlink", path),
+            Record::Mknod { path, .. }      => ("mknod", path),
+            Record::Link { path, .. }       => ("link", path),
+            Record::Rename { from, .. }     => ("rename", from),
+            Record::Unlink { path }         => ("unlink", path),
+            Record::Rmdir { path }          => ("rmdir", path),
+            Record::Write { path, .. }      => ("write", path),
+            Record::Clone { path, .. }      => ("clone", path),
+            Record::Punch { path, .. }      => ("punch hole", path),
+            Record::Truncate { path, .. }   => ("truncate", path),
+            Record::Xattrs { path, .. }     => ("set xattrs", path),
+            Record::Attrs { path, .. }      => ("set attributes", path),
+            Record::End                     => return "end".to_string(),
+        };
+        format!("{op} {}", path.display())
+    }
+
+    fn encode(&self) -> (u16, Vec<u8>) {
+        let mut e = Enc::default();
+        let op = match self {
+            Record::Mkdir { path }          => { e.path(path); OP_MKDIR }
+            Record::Mkfile { path }         => { e.path(path); OP_MKFILE }
+            Record::Symlink { path, target } => { e.path(path); e.path(target); OP_SYMLINK }
+            Record::Mknod { path, mode, rdev } => {
+                e.path(path); e.u32(*mode); e.u64(*rdev); OP_MKNOD
+            }
+            Record::Link { path, src }      => { e.path(path); e.path(src); OP_LINK }
+            Record::Rename { from, to }     => { e.path(from); e.path(to); OP_RENAME }
+            Record::Unlink { path }         => { e.path(path); OP_UNLINK }
+            Record::Rmdir { path }          => { e.path(path); OP_RMDIR }
+            Record::Write { path, offset, data } => {
+                e.path(path); e.u64(*offset); e.bytes(data); OP_WRITE
+            }
+            Record::Clone { path, offset, len, src, src_offset } => {
+                e.path(path); e.u64(*offset); e.u64(*len); e.path(src); e.u64(*src_offset);
+                OP_CLONE
+            }
+            Record::Punch { path, offset, len } => {
+                e.path(path); e.u64(*offset); e.u64(*len); OP_PUNCH
+            }
+            Record::Truncate { path, size } => { e.path(path); e.u64(*size); OP_TRUNCATE }
+            Record::Xattrs { path, xattrs } => {
+                e.path(path);
+                e.u32(xattrs.len() as u32);
+                for (name, val) in xattrs {
+                    e.bytes(name);
+                    e.bytes(val);
+                }
+                OP_XATTRS
+            }
+            Record::Attrs { path, mode, uid, gid, atime, mtime } => {
+                e.path(path); e.u32(*mode); e.u32(*uid); e.u32(*gid);
+                e.time(*atime); e.time(*mtime);
+                OP_ATTRS
+            }
+            Record::End => OP_END,
+        };
+        (op, e.0)
+    }
+
+    fn decode(op: u16, payload: &[u8]) -> Result<Record> {
+        let mut d = Dec(payload);
+        let r = match op {
+            OP_MKDIR    => Record::Mkdir { path: d.path()? },
+            OP_MKFILE   => Record::Mkfile { path: d.path()? },
+            OP_SYMLINK  => Record::Symlink { path: d.path()?, target: d.path()? },
+            OP_MKNOD    => Record::Mknod { path: d.path()?, mode: d.u32()?, rdev: d.u64()? },
+            OP_LINK     => Record::Link { path: d.path()?, src: d.path()? },
+            OP_RENAME   => Record::Rename { from: d.path()?, to: d.path()? },
+            OP_UNLINK   => Record::Unlink { path: d.path()? },
+            OP_RMDIR    => Record::Rmdir { path: d.path()? },
+            OP_WRITE    => Record::Write { path: d.path()?, offset: d.u64()?, data: d.bytes()? },
+            OP_CLONE    => Record::Clone {
+                path: d.path()?, offset: d.u64()?, len: d.u64()?,
+                src: d.path()?, src_offset: d.u64()?,
+            },
+            OP_PUNCH    => Record::Punch { path: d.path()?, offset: d.u64()?, len: d.u64()? },
+            OP_TRUNCATE => Record::Truncate { path: d.path()?, size: d.u64()? },
+            OP_XATTRS   => {
+                let path = d.path()?;
+                let nr = d.u32()?;
+                let mut xattrs = Vec::new();
+                for _ in 0..nr {
+                    xattrs.push((d.bytes()?, d.bytes()?));
+                }
+                Record::Xattrs { path, xattrs }
+            }
+            OP_ATTRS    => Record::Attrs {
+                path: d.path()?, mode: d.u32()?, uid: d.u32()?, gid: d.u32()?,
+                atime: d.time()?, mtime: d.time()?,
+            },
+            OP_END      => Record::End,
+            _ => bail!("unknown record type {op}"),
+        };
+        if !d.0.is_empty() {
+            bail!("record type {op}: {} trailing bytes", d.0.len());
+        }
+        Ok(r)
+    }
+}
+
+fn write_record(out: &mut impl Write, r: &Record) -> Result<()> {
+    let (op, payload) = r.encode();
+    out.write_all(&op.to_le_bytes())?;
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(&payload)?;
+    Ok(())
+}
+
+fn read_record(input: &mut impl Read) -> Result<Record> {
+    let mut hdr = [0u8; 6];
+    input.read_exact(&mut hdr)
+        .context("stream ended without an end record - truncated?")?;
+    let op = u16::from_le_bytes([hdr[0], hdr[1]]);
+    let len = u32::from_le_bytes([hdr[2], hdr[3], hdr[4], hdr[5]]);
+    if len > MAX_PAYLOAD {
+        bail!("record type {op}: payload of {len} bytes is too big - corrupt stream?");
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    input.read_exact(&mut payload)
+        .context("stream ended without an end record - truncated?")?;
+    Record::decode(op, &payload)
+}
+
+// ---- Snapshot identity ----
+
+/// Set by `receive` on the root of the snapshot it creates: the UUID of the
+/// snapshot it was sent as, then the new snapshot's own. A snapshot taken
+/// of a received one inherits the xattr, and the second half is how it's
+/// told apart.
+const RECEIVED_XATTR: &str = "trusted.bcachefs_received";
+
+/// A snapshot's own UUID: the filesystem's UUID with the subvolume ID and
+/// creation time folded in - subvolume IDs are reused after a delete,
+/// creation times aren't.
+fn local_uuid(handle: &BcachefsHandle, subvol: u64) -> Result<[u8; 16]> {
+    let otime = u64::from_le(subvol_info(handle, subvol)?.otime.lo);
+    let mut uuid = handle.uuid();
+    for (b, x) in uuid[..8].iter_mut().zip(subvol.to_le_bytes()) {
+        *b ^= x;
+    }
+    for (b, x) in uuid[8..].iter_mut().zip(otime.to_le_bytes()) {
+        *b ^= x;
+    }
+    Ok(uuid)
+}
+
+/// The UUID the snapshot rooted at @path goes by in streams: the one it
+/// was received as, if it was received, so that a chain of incremental
+/// sends can be continued from any copy.
+fn snapshot_uuid(handle: &BcachefsHandle, path: &Path, subvol: u64) -> Result<[u8; 16]> {
+    let own = local_uuid(handle, subvol)?;
+    let mut buf = [0u8; 32];
+    Ok(match rustix::fs::lgetxattr(path, RECEIVED_XATTR, &mut buf) {
+        Ok(32) if buf[16..] == own => buf[..16].try_into()?,
+        _ => own,
+    })
+}
+
+// ---- Send ----
+
+/// The d_type values bcachefs dirents carry (DT_* from dirent.h)
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/// Where an inode unlinked from its old place waits for its new one
+fn orphan_path(inum: u64) -> PathBuf {
+    PathBuf::from(format!(".bcachefs-send-o{inum}"))
+}
+
+fn os(name: &[u8]) -> &OsStr {
+    OsStr::from_bytes(name)
+}
+
+/// A step of the namespace pass: a record, or a new inode - which record
+/// that takes depends on what it is, read from the snapshot
+#[derive(Debug, PartialEq)]
+enum NsOp {
+    Record(Record),
+    Create(PathBuf, Dirent),
+}
+
+/// The namespace pass, from the dirents removed and added as paths: moves
+/// parked inodes back into place, notes created ones in @cur and @created,
+/// and makes each new link from one of the inode's links that's already
+/// there. @links_new gives the links to an inode in the new snapshot that
+/// the diff may not name, most likely first.
+fn namespace_ops(
+    diff:          &SnapshotDiff,
+    cur:           &mut HashMap<u64, PathBuf>,
+    created:       &mut HashSet<u64>,
+    mut removed:   Vec<(PathBuf, Dirent)>,
+    mut added:     Vec<(PathBuf, Dirent)>,
+    mut links_new: impl FnMut(u64) -> Result<Vec<PathBuf>>,
+) -> Result<Vec<NsOp>> {
+    let mut ops = Vec::new();
+    let mut orphans: HashMap<u64, (PathBuf, u8)> = HashMap::new();
+
+    removed.sort_by_key(|(p, _)| Reverse(p.components().count()));
+
+    for (path, de) in removed {
+        if diff.exists_new(de.target) && !orphans.contains_key(&de.target) {
+            let o = orphan_path(de.target);
+            ops.push(NsOp::Record(Record::Rename { from: path, to: o.clone() }));
+            orphans.insert(de.target, (o, de.d_type));
+        } else if de.d_type == DT_DIR {
+            ops.push(NsOp::Record(Record::Rmdir { path }));
+        } else {
+            ops.push(NsOp::Record(Record::Unlink { path }));
+        }
+    }
+
+    added.sort_by(|a, b| a.0.components().count().cmp(&b.0.components().count())
+                  .then_with(|| a.0.cmp(&b.0)));
+
+    let mut links = Vec::new();
+    for (path, de) in added {
+        let t = de.target;
+        if let Some((o, _)) = orphans.remove(&t) {
+            ops.push(NsOp::Record(Record::Rename { from: o, to: path.clone() }));
+            cur.insert(t, path);
+        } else if !diff.exists_old(t) && !created.contains(&t) {
+            ops.push(NsOp::Create(path.clone(), de));
+            created.insert(t);
+            cur.insert(t, path);
+        } else {
+            links.push((path, t));
+        }
+    }
+
+    /* The inode's backpointer may well be the link being made, or one still
+     * to be made: any of its links that's in place will do */
+    let mut pending: HashSet<PathBuf> = links.iter().map(|(p, _)| p.clone()).collect();
+    let mut linked: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+
+    for (path, t) in links {
+        pending.remove(&path);
+
+        let mut srcs: Vec<PathBuf> = cur.get(&t).cloned().into_iter().collect();
+        srcs.extend(links_new(t)?);
+        srcs.extend(linked.get(&t).into_iter().flatten().cloned());
+
+        let src = srcs.into_iter()
+            .find(|p| *p != path && !pending.contains(p))
+            .ok_or_else(|| anyhow!("{}: no other link to inode {t} to link it to",
+                                   path.display()))?;
+        ops.push(NsOp::Record(Record::Link { path: path.clone(), src }));
+        linked.entry(t).or_default().push(path);
+    }
+
+    /* Parked, and not linked anywhere new: a link that went away from an
+     * inode that still has others */
+    for (_, (path, d_type)) in orphans {
+        ops.push(NsOp::Record(if d_type == DT_DIR {
+            Record::Rmdir { path }
+        } else {
+            Record::Unlink { path }
+        }));
+    }
+    Ok(ops)
+}
+
+/// Links to @inum in the new snapshot: its backpointer, then the dirent its
+/// old backpointer named, if that's still there - a new link moves the
+/// backpointer to itself.
+fn new_links(diff: &SnapshotDiff, new: &mut PathResolver<'_>, inum: u64) -> Result<Vec<PathBuf>> {
+    let mut ret: Vec<PathBuf> = new.path(inum)?.into_iter().collect();
+    if let Some(i) = diff.inodes.get(&inum).and_then(|c| c.old.as_ref()) {
+        ret.extend(new.link_path(i.dir, i.dir_offset, inum)?);
+    }
+    Ok(ret)
+}
+
+struct Sender<'h, W: Write> {
+    handle:   &'h BcachefsHandle,
+    diff:     SnapshotDiff,
+    old:      Option<PathResolver<'h>>,
+    new:      PathResolver<'h>,
+    /// The snapshot as mounted: where contents and attributes are read
+    src:      PathBuf,
+    out:      W,
+    /// Where inodes moved or created by this stream are now
+    cur:      HashMap<u64, PathBuf>,
+    created:  HashSet<u64>,
+    /// Indirect extents already sent, (index, sectors) -> where they landed
+    reflinks: HashMap<(u64, u64), (PathBuf, u64)>,
+}
+
+impl<W: Write> Sender<'_, W> {
+    fn emit(&mut self, r: Record) -> Result<()> {
+        write_record(&mut self.out, &r)
+    }
+
+    fn path_new(&mut self, inum: u64) -> Result<Option<PathBuf>> {
+        if let Some(p) = self.cur.get(&inum) {
+            return Ok(Some(p.clone()));
+        }
+        self.new.path(inum)
+    }
+
+    fn dirent_paths(&mut self, old: bool) -> Result<Vec<(PathBuf, Dirent)>> {
+        let mut ret = Vec::new();
+        for i in 0..self.diff.dirents.len() {
+            let d = &self.diff.dirents[i];
+            let Some(de) = (if old { &d.old } else { &d.new }).clone() else { continue };
+            let dir = d.dir;
+
+            if de.d_type == DT_SUBVOL {
+                eprintln!("warning: skipping nested subvolume {}", os(&de.name).to_string_lossy());
+                continue;
+            }
+
+            let resolver = if old { self.old.as_mut() } else { Some(&mut self.new) };
+            if let Some(dir) = resolver.map(|r| r.dir_path(dir)).transpose()?.flatten() {
+                ret.push((dir.join(os(&de.name)), de));
+            }
+        }
+        Ok(ret)
+    }
+
+    fn send_namespace(&mut self) -> Result<()> {
+        let removed = self.dirent_paths(true)?;
+        let added = self.dirent_paths(false)?;
+
+        let (diff, new) = (&self.diff, &mut self.new);
+        let ops = namespace_ops(diff, &mut self.cur, &mut self.created, removed, added,
+                                |inum| new_links(diff, new, inum))?;
+
+        for op in ops {
+            match op {
+                NsOp::Record(r)        => self.emit(r)?,
+                NsOp::Create(path, de) => self.create(&path, &de)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn create(&mut self, path: &Path, de: &Dirent) -> Result<()> {
+        let r = match de.d_type {
+            DT_DIR => Record::Mkdir { path: path.to_path_buf() },
+            DT_REG => Record::Mkfile { path: path.to_path_buf() },
+            DT_LNK => {
+                let target = std::fs::read_link(self.src.join(path))
+                    .with_context(|| format!("reading link {}", path.display()))?;
+                Record::Symlink { path: path.to_path_buf(), target }
+            }
+            _ => {
+                let m = std::fs::symlink_metadata(self.src.join(path))
+                    .with_context(|| format!("stat {}", path.display()))?;
+                Record::Mknod { path: path.to_path_buf(), mode: m.mode(), rdev: m.rdev() }
+            }
+        };
+        self.emit(r)
+    }
+
+    /// Contents of @path over @range, as writes - and holes, unless the
+    /// file is new in this stream and has nothing to punch.
+    fn send_plain(&mut self, f: &File, path: &Path, start: u64, end: u64,
+                  punch: bool) -> Result<()> {
+        use rustix::fs::{seek, SeekFrom};
+
+        let mut offset = start;
+        while offset < end {
+            let data = match seek(f, SeekFrom::Data(offset)) {
+                Ok(data) => data.min(end),
+                Err(rustix::io::Errno::NXIO) => end,
+                Err(e) => return Err(anyhow!("{}: seek: {e}", path.display())),
+            };
+            if data > offset && punch {
+                self.emit(Record::Punch { path: path.to_path_buf(), offset, len: data - offset })?;
+            }
+            if data >= end {
+                break;
+            }
+
+            let hole = seek(f, SeekFrom::Hole(data)).unwrap_or(end).min(end);
+            let mut pos = data;
+            while pos < hole {
+                let mut buf = vec![0u8; (hole - pos).min(DATA_CHUNK) as usize];
+                f.read_exact_at(&mut buf, pos)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                let len = buf.len() as u64;
+                self.emit(Record::Write { path: path.to_path_buf(), offset: pos, data: buf })?;
+                pos += len;
+            }
+            offset = hole;
+        }
+        Ok(())
+    }
+
+    /// File data in @start..@end: reflinked ranges whose indirect extent
+    /// was already sent become clones of where it was sent to.
+    fn send_data(&mut self, inum: u64, path: &Path, start: u64, end: u64) -> Result<()> {
+        let f = File::open(self.src.join(path))
+            .with_context(|| format!("opening {}", path.display()))?;
+        let punch = !self.created.contains(&inum);
+
+        let mut offset = start;
+        for (r, idx) in reflink_pointers(self.handle, self.new.snapshot(), inum, start, end)? {
+            let r = r.start.max(start)..r.end.min(end);
+            if r.start >= r.end {
+                continue;
+            }
+            self.send_plain(&f, path, offset, r.start, punch)?;
+
+            let key = (idx, r.end - r.start);
+            if let Some((src, src_offset)) = self.reflinks.get(&key).cloned() {
+                self.emit(Record::Clone {
+                    path: path.to_path_buf(), offset: r.start, len: r.end - r.start,
+                    src, src_offset,
+                })?;
+            } else {
+                self.send_plain(&f, path, r.start, r.end, punch)?;
+                self.reflinks.insert(key, (path.to_path_buf(), r.start));
+            }
+            offset = r.end;
+        }
+        self.send_plain(&f, path, offset, end, punch)
+    }
+
+    fn send_files(&mut self) -> Result<()> {
+        let mut inums: Vec<u64> = self.diff.extents.keys().copied().collect();
+        inums.extend(self.diff.inodes.iter()
+            .filter(|(_, c)| c.new.as_ref().is_some_and(|i| i.is_reg()))
+            .map(|(&i, _)| i));
+        inums.sort();
+        inums.dedup();
+
+        for inum in inums {
+            let inode = match self.diff.inodes.get(&inum) {
+                Some(c) => c.new.clone(),
+                None => lookup_inode(self.handle, self.new.snapshot(), inum)?,
+            };
+            let Some(inode) = inode.filter(|i| i.is_reg()) else { continue };
+            let Some(path) = self.path_new(inum)? else { continue };
+
+            let ranges = self.diff.extents.get(&inum).cloned().unwrap_or_default();
+            for r in ranges {
+                let end = r.end.min(inode.size);
+                if r.start < end {
+                    self.send_data(inum, &path, r.start, end)?;
+                }
+            }
+
+            if self.diff.inodes.contains_key(&inum) {
+                self.emit(Record::Truncate { path, size: inode.size })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_xattrs(&mut self) -> Result<()> {
+        let mut inums: Vec<u64> = self.diff.xattrs.iter().copied().collect();
+        inums.extend(self.created.iter().copied());
+        inums.sort();
+        inums.dedup();
+
+        for inum in inums {
+            if !self.diff.exists_new(inum) {
+                continue;
+            }
+            let Some(path) = self.path_new(inum)? else { continue };
+            let xattrs = read_xattrs(&self.src.join(&path))?;
+
+            if !xattrs.is_empty() || !self.created.contains(&inum) {
+                self.emit(Record::Xattrs { path, xattrs })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_attrs(&mut self) -> Result<()> {
+        let inums: Vec<u64> = self.diff.inodes.iter()
+            .filter(|(_, c)| c.new.is_some())
+            .map(|(&i, _)| i)
+            .collect();
+
+        for inum in inums {
+            let Some(path) = self.path_new(inum)? else { continue };
+            let m = std::fs::symlink_metadata(self.src.join(&path))
+                .with_context(|| format!("stat {}", path.display()))?;
+
+            self.emit(Record::Attrs {
+                path,
+                mode:  m.mode(),
+                uid:   m.uid(),
+                gid:   m.gid(),
+                atime: (m.atime(), m.atime_nsec() as u32),
+                mtime: (m.mtime(), m.mtime_nsec() as u32),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Every xattr on @path, bar the bcachefs_effective namespace - those are
+/// computed from the inode's options, not stored - and RECEIVED_XATTR,
+/// which describes the snapshot on this filesystem only.
+fn read_xattrs(path: &Path) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut names = vec![0u8; 65536]; // XATTR_LIST_MAX
+    let len = rustix::fs::llistxattr(path, &mut names)
+        .map_err(|e| anyhow!("listing xattrs of {}: {e}", path.display()))?;
+
+    let mut ret = Vec::new();
+    for name in names[..len].split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        if name.starts_with(b"bcachefs_effective.") || name == RECEIVED_XATTR.as_bytes() {
+            continue;
+        }
+
+        let mut val = vec![0u8; 65536]; // XATTR_SIZE_MAX
+        let cname = CString::new(name)?;
+        let n = rustix::fs::lgetxattr(path, &cname, &mut val)
+            .map_err(|e| anyhow!("reading xattr {} of {}: {e}",
+                                 String::from_utf8_lossy(name), path.display()))?;
+        val.truncate(n);
+        ret.push((name.to_vec(), val));
+    }
+    Ok(ret)
+}
+
+/// @path canonicalized, which must be a subvolume root, with the
+/// filesystem and subvolume it's the root of.
+pub(super) fn subvol_of(path: &Path) -> Result<(PathBuf, u64, u64)> {
+    let path = path.canonicalize()
+        .with_context(|| format!("Failed to resolve {}", path.display()))?;
+    if subvol_root(&path)? != path {
+        bail!("{} is not a subvolume", path.display());
+    }
+    let (dev, subvol) = path_subvol(&path)
+        .ok_or_else(|| anyhow!("{} is not on a bcachefs filesystem", path.display()))?;
+    Ok((path, dev, subvol))
+}
+
+fn snapshot_name(path: &Path) -> Result<Vec<u8>> {
+    Ok(path.file_name()
+        .ok_or_else(|| anyhow!("{}: no name to send it under", path.display()))?
+        .as_bytes()
+        .to_vec())
+}
+
+pub(super) fn cmd_send(parent: Option<PathBuf>, file: Option<PathBuf>,
+                       snapshot: PathBuf) -> Result<()> {
+    let (snapshot, dev, subvol) = subvol_of(&snapshot)?;
+    let parent = parent.map(|p| subvol_of(&p)).transpose()?;
+
+    let handle = BcachefsHandle::open(&snapshot)
+        .map_err(|e| anyhow!("opening filesystem at {}: {e}", snapshot.display()))?;
+
+    /* The stream has to describe one point in time, on both ends: */
+    if !subvol_is_ro(&handle, subvol)? {
+        bail!("{} is not read-only - send a snapshot taken with `subvolume snapshot -r`",
+              snapshot.display());
+    }
+
+    let root = subvol_root_inode(&handle, subvol)?;
+    let new_snap = subvol_snapshot(&handle, subvol)?;
+
+    let old_snap = match &parent {
+        Some((p, p_dev, p_subvol)) => {
+            if *p_dev != dev {
+                bail!("{} is on a different filesystem from {}", p.display(), snapshot.display());
+            }
+            if !subvol_is_ro(&handle, *p_subvol)? {
+                bail!("parent {} is not read-only", p.display());
+            }
+            if subvol_root_inode(&handle, *p_subvol)? != root {
+                bail!("{} is not a snapshot of the same subvolume as {}",
+                      p.display(), snapshot.display());
+            }
+            Some(subvol_snapshot(&handle, *p_subvol)?)
+        }
+        None => None,
+    };
+
+    let pair = SnapshotPair::new(&handle, old_snap, new_snap)?;
+    let diff = snapshot_diff(&handle, &pair)?;
+
+    let out: Box<dyn Write> = match &file {
+        Some(f) => Box::new(File::create(f)
+            .with_context(|| format!("creating {}", f.display()))?),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
+
+    out.write_all(STREAM_MAGIC)?;
+    out.write_all(&STREAM_VERSION.to_le_bytes())?;
+    let mut hdr = Enc::default();
+    hdr.bytes(&snapshot_name(&snapshot)?);
+    hdr.bytes(&match &parent {
+        Some((p, ..)) => snapshot_name(p)?,
+        None => Vec::new(),
+    });
+    hdr.0.extend_from_slice(&snapshot_uuid(&handle, &snapshot, subvol)?);
+    hdr.0.extend_from_slice(&match &parent {
+        Some((p, _, p_subvol)) => snapshot_uuid(&handle, p, *p_subvol)?,
+        None => [0; 16],
+    });
+    out.write_all(&hdr.0)?;
+
+    let mut s = Sender {
+        handle:   &handle,
+        diff,
+        old:      old_snap.map(|s| PathResolver::new(&handle, s, root)),
+        new:      PathResolver::new(&handle, new_snap, root),
+        src:      snapshot,
+        out,
+        cur:      HashMap::new(),
+        created:  HashSet::new(),
+        reflinks: HashMap::new(),
+    };
+
+    s.send_namespace()?;
+    s.send_files()?;
+    s.send_xattrs()?;
+    s.send_attrs()?;
+    s.emit(Record::End)?;
+    s.out.flush()?;
+    Ok(())
+}
+
+// ---- Receive ----
+
+/// Every path in a stream is resolved under the receive root by openat2():
+/// the stream creates symlinks of its own, and none of its paths may lead
+/// through one - or out of the root any other way.
+fn resolve_flags() -> ResolveFlags {
+    ResolveFlags::BENEATH | ResolveFlags::NO_SYMLINKS
+}
+
+fn check_path(rel: &Path) -> Result<()> {
+    if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!("invalid path in stream: {}", rel.display());
+    }
+    Ok(())
+}
+
+/// An O_PATH fd as a path the calls that have no fd variant for it can take:
+/// it names the inode the fd refers to - a symlink's own, for a symlink.
+fn fd_path(fd: &OwnedFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+struct Receiver {
+    root: OwnedFd,
+    /// The file last written to, kept open across a run of writes
+    open: Option<(PathBuf, File)>,
+}
+
+impl Receiver {
+    /// @rel under the root, the empty path being the root itself; the
+    /// final component isn't followed either.
+    fn open_at(&self, rel: &Path, flags: OFlags) -> Result<OwnedFd> {
+        check_path(rel)?;
+        let rel = if rel.as_os_str().is_empty() { Path::new(".") } else { rel };
+        Ok(openat2(&self.root, rel, flags | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+                   Mode::empty(), resolve_flags())?)
+    }
+
+    /// The directory @rel is in, and its name there
+    fn parent<'p>(&self, rel: &'p Path) -> Result<(OwnedFd, &'p OsStr)> {
+        check_path(rel)?;
+        let name = rel.file_name()
+            .ok_or_else(|| anyhow!("invalid path in stream: {}", rel.display()))?;
+        let dir = self.open_at(rel.parent().unwrap_or(Path::new("")),
+                               OFlags::PATH | OFlags::DIRECTORY)?;
+        Ok((dir, name))
+    }
+
+    fn file(&mut self, path: &Path) -> Result<&File> {
+        if self.open.as_ref().map(|(p, _)| p.as_path()) != Some(path) {
+            let f = File::from(self.open_at(path, OFlags::WRONLY)
+                .with_context(|| format!("opening {}", path.display()))?);
+            self.open = Some((path.to_path_buf(), f));
+        }
+        Ok(&self.open.as_ref().unwrap().1)
+    }
+
+    fn apply(&mut self, r: Record) -> Result<()> {
+        use rustix::fs::{chmodat, chownat, linkat, mkdirat, mknodat, renameat, symlinkat,
+                         unlinkat, utimensat, AtFlags, FileType, Gid, Timespec, Timestamps,
+                         Uid, CWD};
+
+        /* Anything that moves names around invalidates the cached file's
+         * path: */
+        if !matches!(r, Record::Write { .. } | Record::Punch { .. } | Record::Truncate { .. }) {
+            self.open = None;
+        }
+
+        match r {
+            Record::Mkdir { path } => {
+                let (dir, name) = self.parent(&path)?;
+                mkdirat(&dir, name, Mode::from_raw_mode(0o700))?;
+            }
+            Record::Mkfile { path } => {
+                let (dir, name) = self.parent(&path)?;
+                openat(&dir, name,
+                       OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL |
+                       OFlags::NOFOLLOW | OFlags::CLOEXEC,
+                       Mode::from_raw_mode(0o600))?;
+            }
+            Record::Symlink { path, target } => {
+                let (dir, name) = self.parent(&path)?;
+                symlinkat(&target, &dir, name)?;
+            }
+            Record::Mknod { path, mode, rdev } => {
+                let (dir, name) = self.parent(&path)?;
+                mknodat(&dir, name, FileType::from_raw_mode(mode),
+                        Mode::from_raw_mode(mode & 0o7777), rdev)?;
+            }
+            Record::Link { path, src } => {
+                let (src_dir, src_name) = self.parent(&src)?;
+                let (dir, name) = self.parent(&path)?;
+                linkat(&src_dir, src_name, &dir, name, AtFlags::empty())?;
+            }
+            Record::Rename { from, to } => {
+                let (from_dir, from_name) = self.parent(&from)?;
+                let (dir, name) = self.parent(&to)?;
+                renameat(&from_dir, from_name, &dir, name)?;
+            }
+            Record::Unlink { path } => {
+                let (dir, name) = self.parent(&path)?;
+                unlinkat(&dir, name, AtFlags::empty())?;
+            }
+            Record::Rmdir { path } => {
+                let (dir, name) = self.parent(&path)?;
+                unlinkat(&dir, name, AtFlags::REMOVEDIR)?;
+            }
+            Record::Write { path, offset, data } => {
+                self.file(&path)?.write_all_at(&data, offset)?;
+            }
+            Record::Clone { path, offset, len, src, src_offset } => {
+                let dst = File::from(self.open_at(&path, OFlags::WRONLY)?);
+                let src = File::from(self.open_at(&src, OFlags::RDONLY)?);
+                clone_range(&src, src_offset, &dst, offset, len)?;
+            }
+            Record::Punch { path, offset, len } => {
+                use rustix::fs::{fallocate, FallocateFlags};
+                fallocate(self.file(&path)?,
+                          FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE, offset, len)?;
+            }
+            Record::Truncate { path, size } => self.file(&path)?.set_len(size)?,
+            Record::Xattrs { path, xattrs } => {
+                let fd = self.open_at(&path, OFlags::PATH)?;
+                set_xattrs(&fd_path(&fd), &xattrs)?;
+            }
+            Record::Attrs { path, mode, uid, gid, atime, mtime } => {
+                let fd = self.open_at(&path, OFlags::PATH)?;
+                chownat(&fd, "", Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)),
+                        AtFlags::EMPTY_PATH)?;
+                /* chown clears setuid, so mode goes after; symlinks have none */
+                if mode & libc::S_IFMT != libc::S_IFLNK {
+                    chmodat(CWD, fd_path(&fd), Mode::from_raw_mode(mode & 0o7777),
+                            AtFlags::empty())?;
+                }
+                let times = Timestamps {
+                    last_access:       Timespec { tv_sec: atime.0, tv_nsec: atime.1 as _ },
+                    last_modification: Timespec { tv_sec: mtime.0, tv_nsec: mtime.1 as _ },
+                };
+                utimensat(CWD, fd_path(&fd), &times, AtFlags::empty())?;
+            }
+            Record::End => {}
+        }
+        Ok(())
+    }
+}
+
+/// Reflink where the filesystem can - copy_file_range() remaps within a
+/// bcachefs filesystem when the range is block aligned - and copy where it
+/// can't.
+fn clone_range(src: &File, mut src_off: u64, dst: &File, mut dst_off: u64,
+               mut len: u64) -> Result<()> {
+    while len > 0 {
+        let mut s = src_off as libc::loff_t;
+        let mut d = dst_off as libc::loff_t;
+        let ret = unsafe {
+            libc::copy_file_range(src.as_raw_fd(), &mut s, dst.as_raw_fd(), &mut d,
+                                  len as usize, 0)
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if ret == 0 {
+            bail!("clone source ended {len} bytes short");
+        }
+        src_off += ret as u64;
+        dst_off += ret as u64;
+        len -= ret as u64;
+    }
+    Ok(())
+}
+
+/// @path is an fd_path(): what it resolves to is the inode, so the calls
+/// that follow it are the right ones, symlink or not.
+fn set_xattrs(path: &Path, xattrs: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    use rustix::fs::{listxattr, removexattr, setxattr, XattrFlags};
+
+    let mut names = vec![0u8; 65536]; // XATTR_LIST_MAX
+    let len = listxattr(path, &mut names)?;
+    for name in names[..len].split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        if !name.starts_with(b"bcachefs_effective.") &&
+            !xattrs.iter().any(|(n, _)| n == name) {
+            removexattr(path, &CString::new(name)?)?;
+        }
+    }
+    for (name, val) in xattrs {
+        setxattr(path, &CString::new(name.as_slice())?, val, XattrFlags::empty())
+            .with_context(|| format!("setting xattr {}", String::from_utf8_lossy(name)))?;
+    }
+    Ok(())
+}
+
+fn receive_into(input: &mut impl Read, tmp: &Path) -> Result<()> {
+    let root = openat2(CWD, tmp, OFlags::PATH | OFlags::DIRECTORY | OFlags::NOFOLLOW |
+                       OFlags::CLOEXEC, Mode::empty(), ResolveFlags::empty())
+        .with_context(|| format!("opening {}", tmp.display()))?;
+    let mut r = Receiver { root, open: None };
+    loop {
+        match read_record(input)? {
+            Record::End => return Ok(()),
+            rec => {
+                let desc = rec.describe();
+                r.apply(rec).with_context(|| desc)?;
+            }
+        }
+    }
+}
+
+pub(super) fn cmd_receive(file: Option<PathBuf>, dir: PathBuf) -> Result<()> {
+    let input: Box<dyn Read> = match &file {
+        Some(f) => Box::new(File::open(f).with_context(|| format!("opening {}", f.display()))?),
+        None => Box::new(std::io::stdin().lock()),
+    };
+    let mut input = BufReader::new(input);
+
+    let mut magic = [0u8; 12];
+    input.read_exact(&mut magic).context("reading stream header")?;
+    if &magic[..8] != STREAM_MAGIC {
+        bail!("not a bcachefs send stream");
+    }
+    let version = u32::from_le_bytes(magic[8..].try_into()?);
+    if version > STREAM_VERSION {
+        bail!("stream version {version} is newer than this tool supports ({STREAM_VERSION})");
+    }
+    if version < STREAM_VERSION {
+        bail!("stream version {version} doesn't identify its snapshots - resend it with a newer `subvolume send`");
+    }
+
+    let mut name = |what: &str| -> Result<PathBuf> {
+        let mut len = [0u8; 4];
+        input.read_exact(&mut len).context("reading stream header")?;
+        let len = u32::from_le_bytes(len);
+        if len > 4096 {
+            bail!("{what} name of {len} bytes - corrupt stream?");
+        }
+        let mut name = vec![0u8; len as usize];
+        input.read_exact(&mut name).context("reading stream header")?;
+        let name = PathBuf::from(OsStr::from_bytes(&name));
+        if !name.as_os_str().is_empty() &&
+            (name.components().count() != 1 ||
+             !matches!(name.components().next(), Some(Component::Normal(_)))) {
+            bail!("invalid {what} name in stream: {}", name.display());
+        }
+        Ok(name)
+    };
+    let snap_name = name("snapshot")?;
+    let parent_name = name("parent")?;
+    if snap_name.as_os_str().is_empty() {
+        bail!("stream has no snapshot name");
+    }
+
+    let mut uuids = [0u8; 32];
+    input.read_exact(&mut uuids).context("reading stream header")?;
+    let (snap_uuid, parent_uuid) = uuids.split_at(16);
+
+    let dir = dir.canonicalize()
+        .with_context(|| format!("Failed to resolve {}", dir.display()))?;
+    let dst = dir.join(&snap_name);
+    if dst.symlink_metadata().is_ok() {
+        bail!("{} already exists", dst.display());
+    }
+
+    let tmp = dir.join(format!(".{}.receiving", snap_name.display()));
+    if tmp.symlink_metadata().is_ok() {
+        bail!("{} exists - left over from an interrupted receive? delete it and retry",
+              tmp.display());
+    }
+
+    let handle = BcachefsHandle::open(&dir)
+        .map_err(|e| anyhow!("opening filesystem at {}: {e}", dir.display()))?;
+
+    if parent_name.as_os_str().is_empty() {
+        handle.create_subvolume(&tmp)
+            .with_context(|| format!("creating {}", tmp.display()))?;
+    } else {
+        /* A snapshot of the same name isn't necessarily the one the stream
+         * is a delta against, and applied to any other it'd produce garbage: */
+        let parent = dir.join(&parent_name);
+        if parent.symlink_metadata().is_err() {
+            bail!("incremental stream: parent snapshot {} not found", parent.display());
+        }
+        let (parent, _, p_subvol) = subvol_of(&parent)?;
+        if snapshot_uuid(&handle, &parent, p_subvol)? != parent_uuid {
+            bail!("incremental stream: {} is not the snapshot it was sent against",
+                  parent.display());
+        }
+        handle.snapshot_subvolume(0, Some(&parent), &tmp)
+            .with_context(|| format!("snapshotting {}", parent.display()))?;
+    }
+
+    let ret = receive_into(&mut input, &tmp)
+        .and_then(|_| finish_receive(&handle, &tmp, &dst, snap_uuid));
+
+    /* The writable copy was only ever scaffolding - received or not, it
+     * goes: */
+    if let Err(e) = handle.delete_subvolume(&tmp) {
+        eprintln!("warning: deleting {}: {e}", tmp.display());
+    }
+    ret
+}
+
+/// Snapshot the received tree to @dst, record what it was received as, and
+/// only then make it read-only: the record needs @dst's own identity.
+fn finish_receive(handle: &BcachefsHandle, tmp: &Path, dst: &Path,
+                  snap_uuid: &[u8]) -> Result<()> {
+    handle.snapshot_subvolume(0, Some(tmp), dst)
+        .with_context(|| format!("creating snapshot {}", dst.display()))?;
+
+    let ret = (|| -> Result<()> {
+        let (dst, _, subvol) = subvol_of(dst)?;
+        let mut received = snap_uuid.to_vec();
+        received.extend_from_slice(&local_uuid(handle, subvol)?);
+        rustix::fs::lsetxattr(&dst, RECEIVED_XATTR, &received,
+                              rustix::fs::XattrFlags::empty())
+            .with_context(|| format!("setting {RECEIVED_XATTR} on {}", dst.display()))?;
+
+        let mut arg = bch_ioctl_subvolume_set::default();
+        arg.flags = BCH_SUBVOL_SET_RO;
+        arg.ro = 1;
+        handle.set_subvolume(&dst, arg)
+            .with_context(|| format!("making {} read-only", dst.display()))?;
+        Ok(())
+    })();
+
+    if ret.is_err() {
+        let _ = handle.delete_subvolume(dst);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(path: &str, target: u64) -> (PathBuf, Dirent) {
+        (PathBuf::from(path), Dirent { name: path.as_bytes().to_vec(), target, d_type: DT_REG })
+    }
+
+    fn ops(removed: Vec<(PathBuf, Dirent)>, added: Vec<(PathBuf, Dirent)>,
+           links_new: &[&str]) -> Result<Vec<NsOp>> {
+        /* No inode in the diff: each exists on both sides */
+        let diff = SnapshotDiff::default();
+        namespace_ops(&diff, &mut HashMap::new(), &mut HashSet::new(), removed, added,
+                      |_| Ok(links_new.iter().map(PathBuf::from).collect()))
+    }
+
+    fn rename(from: &Path, to: &str) -> NsOp {
+        NsOp::Record(Record::Rename { from: from.into(), to: to.into() })
+    }
+
+    fn link_op(path: &str, src: &str) -> NsOp {
+        NsOp::Record(Record::Link { path: path.into(), src: src.into() })
+    }
+
+    #[test]
+    fn three_links_one_renamed() {
+        /* a, b and c link inode 10; c is renamed to d, which moves the
+         * backpointer to d */
+        let o = orphan_path(10);
+        let r = ops(vec![link("c", 10)], vec![link("d", 10)], &["d", "a"]).unwrap();
+        assert_eq!(r, [rename(Path::new("c"), o.to_str().unwrap()), rename(&o, "d")]);
+    }
+
+    #[test]
+    fn three_links_two_new() {
+        /* a links inode 10; b and c are new links to it, and the backpointer
+         * is c - which isn't there yet when b is made */
+        let r = ops(vec![], vec![link("b", 10), link("c", 10)], &["c", "a"]).unwrap();
+        assert_eq!(r, [link_op("b", "a"), link_op("c", "a")]);
+    }
+
+    #[test]
+    fn new_link_with_nothing_in_place() {
+        /* The only link known is the one being made */
+        assert!(ops(vec![], vec![link("b", 10)], &["b"]).is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! `bcachefs systemd-generator` - mount units for multi-device fstab entries.
+//!
+//! systemd-fstab-generator sees one device per fstab line: `UUID=` becomes a
+//! dependency on /dev/disk/by-uuid/<uuid>, which udev points at whichever
+//! member it probed last, and `LABEL=` the same. For a filesystem with several
+//! members the mount is ordered after one of them, at random, and started as
+//! soon as that one appears. mount.bcachefs then waits for the rest itself -
+//! but from inside a mount job, where the wait counts against the job's
+//! timeout and nothing else in the boot knows what it's waiting for.
+//!
+//! So this generator writes the mount units for bcachefs fstab entries
+//! itself, with the dependencies read from the superblocks: every member
+//! device scan_sbs() finds. Units go in the early directory, which takes
+//! precedence over the normal one systemd-fstab-generator writes to, so ours
+//! replace its units of the same name rather than fighting them.
+//!
+//! Members that haven't appeared by the time the generator runs can't be
+//! named - a member is only a device unit once it's a device - so they are
+//! left to mount.bcachefs, as before; `systemctl daemon-reload` picks them up.
+//!
+//! How hard the dependencies are follows the mount options. A filesystem that
+//! refuses to mount degraded (`degraded=no`, or its superblock default) gets
+//! Requires=, so a missing member fails the mount job promptly and by name;
+//! otherwise Wants=, and mount.bcachefs decides - or asks. The unlock policy
+//! (`key_location=`) is wired in too: `network` needs the network up, and
+//! `keyfile` needs the keyfiles mounted.
+//!
+//! A generator runs early in boot and its failure is logged and forgotten, so
+//! an entry this can't handle is skipped with a warning and left to
+//! systemd-fstab-generator, never an error.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use bcachefs_kernel::c;
+use bcachefs_kernel::c::bch_sb_handle;
+use clap::Parser;
+use log::{info, warn};
+
+use crate::commands::mount::parse_mountflag_options;
+use crate::device_scan;
+use crate::key::{UnlockPolicy, DEFAULT_KEYFILE_PATH};
+use crate::logging;
+
+/// Write systemd mount units for bcachefs fstab entries
+#[derive(Parser, Debug)]
+#[command(
+    about,
+    long_about = "Write systemd mount units for the bcachefs entries in fstab, \
+depending on every member device of each filesystem rather than the one device \
+systemd-fstab-generator would pick. Run by systemd at boot and on \
+daemon-reload as a generator (installed as bcachefs-generator in the system \
+generator directory); with no directories given, prints the units it would \
+write.\n\n\
+Entries are matched by UUID=, OLD_BLKID_UUID= or LABEL= sources. The \
+degraded= and key_location= mount options decide how hard the device \
+dependencies are and what else the mount has to wait for."
+)]
+pub struct Cli {
+    /// fstab to read; defaults to $SYSTEMD_FSTAB, or /etc/fstab
+    #[arg(long)]
+    fstab: Option<PathBuf>,
+
+    /// Verbose mode
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Generator output directory for ordinary units (unused)
+    #[arg(value_name = "NORMAL_DIR")]
+    _normal_dir: Option<PathBuf>,
+
+    /// Generator output directory for units that override ordinary ones
+    early_dir: Option<PathBuf>,
+
+    /// Generator output directory for fallback units (unused)
+    #[arg(value_name = "LATE_DIR")]
+    _late_dir: Option<PathBuf>,
+}
+
+struct FstabEntry {
+    source:  String,
+    target:  String,
+    options: String,
+}
+
+/// fstab escapes whitespace in fields as octal, `\040`.
+fn fstab_unescape(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let b = s.as_bytes();
+    let mut i = 0;
+
+    while i < b.len() {
+        let octal = b.get(i + 1..i + 4)
+            .filter(|d| d.iter().all(|d| (b'0'..=b'7').contains(d)));
+
+        if let (b'\\', Some(d)) = (b[i], octal) {
+            out.push(d.iter().fold(0u8, |v, d| v.wrapping_mul(8) + (d - b'0')));
+            i += 4;
+            continue;
+        }
+        out.push(b[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn fstab_entries(path: &Path) -> Result<Vec<FstabEntry>> {
+    let fstab = fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+
+    Ok(fstab.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| {
+            let f: Vec<&str> = l.split_whitespace().collect();
+            (f.len() >= 3 && f[2] == "bcachefs").then(|| FstabEntry {
+                source:  fstab_unescape(f[0]),
+                target:  fstab_unescape(f[1]),
+                options: f.get(3).map_or("defaults", |o| o).to_string(),
+            })
+        })
+        .collect())
+}
+
+/// `systemd-escape --path`: the name of the unit for a path.
+fn unit_name_from_path(path: &str, suffix: &str) -> String {
+    let trimmed: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    if trimmed.is_empty() {
+        return format!("-{suffix}");
+    }
+
+    let mut name = String::new();
+    for (i, b) in trimmed.join("/").bytes().enumerate() {
+        match b {
+            b'/' => name.push('-'),
+            b'.' if i == 0 => name.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b':' || b == b'_' || b == b'.' =>
+                name.push(b as char),
+            b => { let _ = write!(name, "\\x{b:02x}"); }
+        }
+    }
+    format!("{name}{suffix}")
+}
+
+fn opt_present(options: &str, name: &str) -> bool {
+    options.split(',').any(|o| o == name)
+}
+
+fn opt_values<'a>(options: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    options.split(',').filter_map(move |o| o.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// A generated mount unit, and the target that should pull it in.
+struct MountUnit {
+    name:    String,
+    text:    String,
+    target:  &'static str,
+    /// Wanted by the target rather than required by it.
+    nofail:  bool,
+    noauto:  bool,
+}
+
+fn mount_unit(entry: &FstabEntry, fstab: &Path, sbs: &[(PathBuf, bch_sb_handle)]) -> MountUnit {
+    let options = &entry.options;
+    let parsed = parse_mountflag_options(options);
+    let opts = bcachefs_kernel::opts::parse_mount_opts(None, parsed.fs_opts.as_deref(), true)
+        .unwrap_or_default();
+
+    let sb = &sbs[0].1;
+    let uuid = sb.sb().uuid();
+    let encrypted = unsafe { bch_bindgen::c::bch2_sb_is_encrypted(sb.sb) };
+
+    let nofail = opt_present(options, "nofail");
+    let noauto = opt_present(options, "noauto");
+    let netdev = opt_present(options, "_netdev");
+
+    let policy = parsed.unlock_policy.as_ref().filter(|_| encrypted);
+    let network = netdev || matches!(policy, Some(UnlockPolicy::Network));
+    if matches!(policy, Some(UnlockPolicy::Network)) && !netdev {
+        warn!("{}: key_location=network without _netdev; systemd-fstab-generator \
+               will still order it before local-fs.target", entry.target);
+    }
+
+    let target = if network { "remote-fs.target" } else { "local-fs.target" };
+
+    let dep = if crate::degraded::degraded_action(sbs, &opts)
+        == c::bch_degraded_actions::BCH_DEGRADED_no as u8 { "Requires" } else { "Wants" };
+
+    let mut devices: Vec<String> = sbs.iter()
+        .map(|(path, _)| {
+            let path = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+            unit_name_from_path(&path.to_string_lossy(), ".device")
+        })
+        .collect();
+    devices.sort();
+    devices.dedup();
+
+    let mut text = String::new();
+    let _ = writeln!(text, "# Automatically generated by bcachefs systemd-generator\n");
+    let _ = writeln!(text, "[Unit]");
+    let _ = writeln!(text, "Documentation=man:fstab(5) man:bcachefs(8)");
+    let _ = writeln!(text, "SourcePath={}", fstab.display());
+    let _ = writeln!(text, "# {} of {} members present when generated",
+                     device_scan::present_devices(sbs).len(),
+                     device_scan::expected_devices(sbs));
+    if !nofail {
+        let _ = writeln!(text, "Before={target}");
+    }
+    for d in &devices {
+        let _ = writeln!(text, "{dep}={d}");
+        let _ = writeln!(text, "After={d}");
+    }
+
+    if network {
+        let _ = writeln!(text, "Wants=network-online.target");
+        let _ = writeln!(text, "After=network-online.target");
+    }
+    if matches!(policy, Some(UnlockPolicy::Keyfile)) {
+        let keyfile = parsed.keyfile.as_deref()
+            .unwrap_or(Path::new(DEFAULT_KEYFILE_PATH));
+        let _ = writeln!(text, "RequiresMountsFor={}", keyfile.display());
+    }
+
+    // Ours replaces systemd-fstab-generator's unit, so the x-systemd options
+    // it would have handled are ours to handle:
+    for v in opt_values(options, "x-systemd.requires") {
+        let unit = if v.starts_with('/') { unit_name_from_path(v, ".device") } else { v.to_string() };
+        let _ = writeln!(text, "Requires={unit}\nAfter={unit}");
+    }
+    for v in opt_values(options, "x-systemd.after") {
+        let _ = writeln!(text, "After={v}");
+    }
+    for v in opt_values(options, "x-systemd.before") {
+        let _ = writeln!(text, "Before={v}");
+    }
+    for v in opt_values(options, "x-systemd.requires-mounts-for") {
+        let _ = writeln!(text, "RequiresMountsFor={v}");
+    }
+
+    // OLD_BLKID_UUID=, not the fstab source: mount(8) resolves UUID= and LABEL=
+    // to a single device with libblkid before the helper ever sees them, and
+    // this is the spelling it leaves alone.
+    let _ = writeln!(text, "\n[Mount]");
+    let _ = writeln!(text, "What=OLD_BLKID_UUID={uuid}");
+    let _ = writeln!(text, "Where={}", entry.target);
+    let _ = writeln!(text, "Type=bcachefs");
+    let _ = writeln!(text, "Options={options}");
+    for v in opt_values(options, "x-systemd.mount-timeout") {
+        let _ = writeln!(text, "TimeoutSec={v}");
+    }
+
+    MountUnit {
+        name: unit_name_from_path(&entry.target, ".mount"),
+        text,
+        target,
+        nofail,
+        noauto,
+    }
+}
+
+fn write_unit(dir: &Path, unit: &MountUnit) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(&unit.name), &unit.text)
+        .with_context(|| format!("writing {}", unit.name))?;
+
+    if unit.noauto {
+        return Ok(());
+    }
+
+    let link_dir = dir.join(format!("{}.{}", unit.target,
+                                    if unit.nofail { "wants" } else { "requires" }));
+    fs::create_dir_all(&link_dir)?;
+
+    let link = link_dir.join(&unit.name);
+    let _ = fs::remove_file(&link);
+    std::os::unix::fs::symlink(Path::new("..").join(&unit.name), &link)
+        .with_context(|| format!("linking {}", link.display()))
+}
+
+fn cmd_systemd_generator(cli: Cli) -> Result<()> {
+    logging::setup(cli.verbose, false);
+
+    let fstab = cli.fstab.clone()
+        .or_else(|| std::env::var_os("SYSTEMD_FSTAB").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("/etc/fstab"));
+
+    let entries = match fstab_entries(&fstab) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("{e:#}");
+            return Ok(());
+        }
+    };
+
+    for entry in entries {
+        let by_fs = device_scan::parse_uuid_equals(&entry.source).ok().flatten().is_some()
+            || entry.source.starts_with("LABEL=");
+        if !by_fs {
+            info!("{}: {} names a device, leaving it to systemd-fstab-generator",
+                  entry.target, entry.source);
+            continue;
+        }
+
+        let sbs = match device_scan::scan_sbs(&entry.source, &Default::default()) {
+            Ok(sbs) if !sbs.is_empty() => sbs,
+            Ok(_) => {
+                info!("{}: no members of {} found yet", entry.target, entry.source);
+                continue;
+            }
+            Err(e) => {
+                warn!("{}: scanning for {}: {e:#}", entry.target, entry.source);
+                continue;
+            }
+        };
+
+        let unit = mount_unit(&entry, &fstab, &sbs);
+
+        match cli.early_dir.as_deref() {
+            Some(dir) => if let Err(e) = write_unit(dir, &unit) {
+                warn!("{}: {e:#}", entry.target);
+            },
+            None => println!("# {}\n{}", unit.name, unit.text),
+        }
+    }
+
+    Ok(())
+}
+
+pub const CMD: super::CmdDef = typed_cmd!("systemd-generator",
+    "Write systemd mount units for bcachefs fstab entries", Cli, cmd_systemd_generator);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fstab_unescape_octal() {
+        assert_eq!(fstab_unescape("/mnt/data"), "/mnt/data");
+        assert_eq!(fstab_unescape(r"/mnt/my\040disk"), "/mnt/my disk");
+        assert_eq!(fstab_unescape(r"a\011b\134c"), "a\tb\\c");
+        /* Not three octal digits: left alone */
+        assert_eq!(fstab_unescape(r"a\08b"), r"a\08b");
+        assert_eq!(fstab_unescape(r"a\04"), r"a\04");
+        assert_eq!(fstab_unescape("a\\"), "a\\");
+    }
+
+    #[test]
+    fn unit_names() {
+        assert_eq!(unit_name_from_path("/", ".mount"), "-.mount");
+        assert_eq!(unit_name_from_path("/mnt/data", ".mount"), "mnt-data.mount");
+        assert_eq!(unit_name_from_path("//mnt//data/", ".mount"), "mnt-data.mount");
+        assert_eq!(unit_name_from_path("/mnt/my disk", ".mount"), "mnt-my\\x20disk.mount");
+        assert_eq!(unit_name_from_path("/srv/a-b", ".mount"), "srv-a\\x2db.mount");
+        assert_eq!(unit_name_from_path("/.snapshots", ".mount"), "\\x2esnapshots.mount");
+        assert_eq!(unit_name_from_path("/srv/.x", ".mount"), "srv-.x.mount");
+    }
+}
@@ -1,8 +1,8 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs;
 use std::io::{self, IsTerminal, Write as IoWrite};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use clap::{Parser, ValueEnum};
@@ -25,36 +25,64 @@ const SYSFS_BASE: &str = "/sys/fs/bcachefs";
 // JSON structs matching kernel output from bch2_time_stats_to_json()
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct DurationStats {
-    min:    u64,
-    max:    u64,
+pub(crate) struct DurationStats {
+    pub min:    u64,
+    pub max:    u64,
     #[serde(default)]
-    total:  u64,
-    mean:   u64,
-    stddev: u64,
+    pub total:  u64,
+    pub mean:   u64,
+    pub stddev: u64,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct EwmaStats {
-    mean:   u64,
-    stddev: u64,
+pub(crate) struct EwmaStats {
+    pub mean:   u64,
+    pub stddev: u64,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[allow(dead_code)]
-struct TimeStats {
-    count:              u64,
-    duration_ns:        DurationStats,
-    duration_ewma_ns:   EwmaStats,
+pub(crate) struct TimeStats {
+    pub count:              u64,
+    pub duration_ns:        DurationStats,
+    pub duration_ewma_ns:   EwmaStats,
     #[serde(rename = "between_ns")]
-    frequency_ns:       DurationStats,
+    pub frequency_ns:       DurationStats,
     #[serde(rename = "between_ewma_ns")]
-    frequency_ewma_ns:  EwmaStats,
+    pub frequency_ewma_ns:  EwmaStats,
+    /// 15-point streaming quantile estimate (device IO latency only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantiles_ns:       Option<Vec<u64>>,
+    /// [bucket start, count] of the nonempty latency histogram buckets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub histogram_ns:       Option<Vec<(u64, u64)>>,
 }
 
-struct StatEntry {
-    name:   String,
-    stats:  TimeStats,
+impl TimeStats {
+    pub(crate) fn without_distribution(mut self) -> TimeStats {
+        self.quantiles_ns = None;
+        self.histogram_ns = None;
+        self
+    }
+
+    /// Duration at percentile `p` (0-100): from the histogram, or failing
+    /// that the quantile estimate, which stops at p93.75.
+    pub(crate) fn percentile(&self, p: f64) -> Option<u64> {
+        let v = match self.histogram_ns.as_deref() {
+            Some(hist) if !hist.is_empty() => hist_percentile(hist, p)?,
+            _ => {
+                let q = self.quantiles_ns.as_deref()?;
+                let i = ((p / 100.0 * (q.len() + 1) as f64).ceil() as usize).checked_sub(1)?;
+                *q.get(i)?
+            }
+        };
+        Some(if self.duration_ns.max > 0 { v.min(self.duration_ns.max) } else { v })
+    }
+}
+
+pub(crate) struct StatEntry {
+    pub name:   String,
+    pub stats:  TimeStats,
 }
 
 #[derive(Deserialize, Debug)]
@@ -66,6 +94,63 @@ struct BtreeTransFnStats {
     lock_hold_times:    Option<TimeStats>,
 }
 
+// Latency histograms: log-linear, 4 buckets per power of two
+// (TIME_STATS_HIST_SUB_BITS in fs/util/time_stats.h); the kernel gives us
+// the start of each nonempty bucket.
+
+const HIST_SUB_BITS: u32 = 2;
+
+fn hist_bucket_end(start: u64) -> u64 {
+    if start < 1 << HIST_SUB_BITS { return start + 1 }
+    let shift = 63 - start.leading_zeros() - HIST_SUB_BITS;
+    start + (1 << shift)
+}
+
+/* Interpolates within the bucket the percentile falls in */
+fn hist_percentile(hist: &[(u64, u64)], p: f64) -> Option<u64> {
+    let total: u64 = hist.iter().map(|&(_, n)| n).sum();
+    if total == 0 { return None }
+
+    let rank = p / 100.0 * total as f64;
+    let mut seen = 0;
+    for &(start, n) in hist {
+        if (seen + n) as f64 >= rank {
+            let frac = (rank - seen as f64) / n as f64;
+            return Some(start + (frac * (hist_bucket_end(start) - start) as f64) as u64);
+        }
+        seen += n;
+    }
+    hist.last().map(|&(start, _)| start)
+}
+
+/* Sparklines and heatmaps fold the histogram into powers of two, over a
+ * fixed range so that rows compare: 1us (and faster) to 8s (and slower). */
+const OCTAVE_FIRST_SHIFT: u32 = 10;
+const NR_OCTAVES: usize = 24;
+
+fn octave_counts(hist: &[(u64, u64)]) -> [u64; NR_OCTAVES] {
+    let mut counts = [0; NR_OCTAVES];
+    for &(start, n) in hist {
+        let octave = 63u32.saturating_sub(start.leading_zeros());
+        let i = octave.saturating_sub(OCTAVE_FIRST_SHIFT) as usize;
+        counts[i.min(NR_OCTAVES - 1)] += n;
+    }
+    counts
+}
+
+const SPARK: &[char] = &[' ', '\u{2581}', '\u{2582}', '\u{2583}', '\u{2584}',
+                         '\u{2585}', '\u{2586}', '\u{2587}', '\u{2588}'];
+
+fn sparkline(s: &TimeStats) -> String {
+    let Some(hist) = s.histogram_ns.as_deref() else { return String::new() };
+    let counts = octave_counts(hist);
+    let max = counts.iter().copied().max().unwrap_or(0);
+
+    counts.iter()
+        .map(|&n| if n == 0 { SPARK[0] } else { SPARK[1 + (n * 7 / max) as usize] })
+        .collect()
+}
+
 const NAME_WIDTH: usize = 40;
 const COL_WIDTH: usize = 13;
 
@@ -73,7 +158,7 @@ const TIME_UNITS: &[(&str, u64)] = &[
     ("ns", 1), ("us", 1_000), ("ms", 1_000_000), ("s", 1_000_000_000),
 ];
 
-fn fmt_duration(ns: u64) -> String {
+pub(crate) fn fmt_duration(ns: u64) -> String {
     if ns == 0 { return "0".to_string() }
     let (name, scale) = TIME_UNITS.iter()
         .rev()
@@ -91,39 +176,66 @@ const COLUMNS: &[&str; NUM_COLS] = &[
     "FREQ_MEAN", "FREQ_STDDEV",
 ];
 
+/* Same number of columns as COLUMNS, so the sort column carries over */
+const PCT_COLUMNS: &[&str; NUM_COLS] = &[
+    "NAME", "COUNT",
+    "P50", "P90", "P99", "P99.9",
+    "DUR_MAX", "DUR_MEAN",
+    "1us     HISTOGRAM     8s",
+];
+
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum View {
     Mean,
     Recent,
+    Percentiles,
 }
 
 impl View {
     fn label(self) -> &'static str {
         match self {
-            View::Mean   => "mean",
-            View::Recent => "recent",
+            View::Mean        => "mean",
+            View::Recent      => "recent",
+            View::Percentiles => "percentiles",
         }
     }
     fn toggle(self) -> View {
         match self {
-            View::Mean   => View::Recent,
-            View::Recent => View::Mean,
+            View::Mean        => View::Recent,
+            View::Recent      => View::Percentiles,
+            View::Percentiles => View::Mean,
         }
     }
+    fn columns(self) -> &'static [&'static str; NUM_COLS] {
+        if self == View::Percentiles { PCT_COLUMNS } else { COLUMNS }
+    }
 }
 
-/* (dur_mean, dur_stddev, freq_mean, freq_stddev) for the active view. */
+/* (dur_mean, dur_stddev, freq_mean, freq_stddev) for the mean or recent view. */
 fn view_vals(s: &TimeStats, view: View) -> (u64, u64, u64, u64) {
     match view {
-        View::Mean   => (s.duration_ns.mean,      s.duration_ns.stddev,
-                         s.frequency_ns.mean,     s.frequency_ns.stddev),
         View::Recent => (s.duration_ewma_ns.mean, s.duration_ewma_ns.stddev,
                          s.frequency_ewma_ns.mean, s.frequency_ewma_ns.stddev),
+        _            => (s.duration_ns.mean,      s.duration_ns.stddev,
+                         s.frequency_ns.mean,     s.frequency_ns.stddev),
     }
 }
 
 fn sort_val(e: &StatEntry, col: usize, view: View) -> u64 {
     let s = &e.stats;
+
+    if view == View::Percentiles {
+        return match col {
+            1 => s.count,
+            2..=5 => s.percentile(PERCENTILES[col - 2]).unwrap_or(0),
+            6 => s.duration_ns.max,
+            7 => s.duration_ns.mean,
+            _ => 0,
+        };
+    }
+
     let (dm, ds, fm, fs) = view_vals(s, view);
     match col {
         1 => s.count,
@@ -149,15 +261,29 @@ fn sort_entries(entries: &mut [StatEntry], sort_col: usize, reverse: bool, view:
     });
 }
 
-fn format_header() -> String {
-    COLUMNS.iter().enumerate()
+fn format_header(view: View) -> String {
+    view.columns().iter().enumerate()
         .map(|(i, &name)| if i == 0 { format!("{:<NAME_WIDTH$}", name) } else { format!("{:>COL_WIDTH$}", name) })
         .collect::<Vec<_>>()
         .join(" ")
 }
 
+fn fmt_percentile(s: &TimeStats, p: f64) -> String {
+    s.percentile(p).map_or_else(|| "-".to_string(), fmt_duration)
+}
+
 fn format_row(e: &StatEntry, view: View) -> String {
     let s = &e.stats;
+
+    if view == View::Percentiles {
+        return format!("{:<NAME_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {}",
+            e.name, s.count,
+            fmt_percentile(s, PERCENTILES[0]), fmt_percentile(s, PERCENTILES[1]),
+            fmt_percentile(s, PERCENTILES[2]), fmt_percentile(s, PERCENTILES[3]),
+            fmt_duration(s.duration_ns.max), fmt_duration(s.duration_ns.mean),
+            sparkline(s));
+    }
+
     let (dm, ds, fm, fs) = view_vals(s, view);
     format!("{:<NAME_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$} {:>COL_WIDTH$}",
         e.name, s.count,
@@ -207,7 +333,7 @@ struct FsSnapshot {
 
 // Sysfs reading
 
-fn find_all_sysfs_dirs() -> Result<Vec<PathBuf>> {
+pub(crate) fn find_all_sysfs_dirs() -> Result<Vec<PathBuf>> {
     let base = Path::new(SYSFS_BASE);
     if !base.exists() {
         return Err(anyhow!("No bcachefs filesystems found ({}/ does not exist)", SYSFS_BASE));
@@ -225,7 +351,7 @@ fn find_all_sysfs_dirs() -> Result<Vec<PathBuf>> {
     Ok(results)
 }
 
-fn read_time_stats(sysfs_path: &Path) -> Result<Vec<StatEntry>> {
+pub(crate) fn read_time_stats(sysfs_path: &Path) -> Result<Vec<StatEntry>> {
     let json_dir = sysfs_path.join("time_stats_json");
     if !json_dir.exists() {
         return Err(anyhow!("time_stats_json not found in {} - kernel may need to be updated",
@@ -247,7 +373,7 @@ fn read_time_stats(sysfs_path: &Path) -> Result<Vec<StatEntry>> {
     Ok(entries)
 }
 
-fn read_device_latency_stats(
+pub(crate) fn read_device_latency_stats(
     sysfs_path: &Path,
     name_mode: DeviceNameMode,
 ) -> Result<Vec<StatEntry>> {
@@ -352,6 +478,115 @@ fn print_json(snaps: &[FsSnapshot]) -> Result<()> {
     Ok(())
 }
 
+// Comparing snapshots
+
+type FsStats = BTreeMap<String, TimeStats>;
+type Snapshot = BTreeMap<String, FsStats>;
+
+fn load_snapshot(path: &Path) -> Result<Snapshot> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("parsing {} (expected timestats --json output)", path.display()))
+}
+
+/* The figure regressions are judged on: p99 where both sides have it */
+fn regression_metric(before: &TimeStats, after: &TimeStats) -> (&'static str, u64, u64) {
+    match (before.percentile(99.0), after.percentile(99.0)) {
+        (Some(b), Some(a)) => ("p99", b, a),
+        _ => ("mean", before.duration_ns.mean, after.duration_ns.mean),
+    }
+}
+
+fn fmt_change(before: Option<u64>, after: Option<u64>) -> String {
+    match (before, after) {
+        (Some(b), Some(a)) if b > 0 =>
+            format!("{} {:+.0}%", fmt_duration(a), (a as f64 - b as f64) * 100.0 / b as f64),
+        (_, Some(a)) => fmt_duration(a),
+        _ => "-".to_string(),
+    }
+}
+
+const DIFF_COL_WIDTH: usize = 16;
+
+fn diff_stats(before_path: &Path, after_path: &Path, cli: &Cli) -> Result<()> {
+    let before = load_snapshot(before_path)?;
+    let after = load_snapshot(after_path)?;
+
+    /* Runs on different kernel builds are usually different filesystems, so
+     * with one on each side compare those regardless of UUID; otherwise
+     * match them up by name. */
+    let pairs: Vec<(&String, &FsStats, &FsStats)> =
+        if before.len() == 1 && after.len() == 1 {
+            let (label, b) = before.iter().next().unwrap();
+            vec![(label, b, after.values().next().unwrap())]
+        } else {
+            for label in before.keys().filter(|l| !after.contains_key(*l)) {
+                eprintln!("warning: {} only in {}", label, before_path.display());
+            }
+            for label in after.keys().filter(|l| !before.contains_key(*l)) {
+                eprintln!("warning: {} only in {}", label, after_path.display());
+            }
+            before.iter()
+                .filter_map(|(label, b)| after.get(label).map(|a| (label, b, a)))
+                .collect()
+        };
+
+    let multi = pairs.len() > 1;
+    let mut regressed = Vec::new();
+
+    for (label, b, a) in pairs {
+        if multi { println!("{}:", label); }
+        println!("  {:<NAME_WIDTH$} {:>COL_WIDTH$} {:>DIFF_COL_WIDTH$} {:>DIFF_COL_WIDTH$} {:>DIFF_COL_WIDTH$} {:>DIFF_COL_WIDTH$} {:>DIFF_COL_WIDTH$} {:>DIFF_COL_WIDTH$}",
+            "NAME", "COUNT", "MEAN", "P50", "P90", "P99", "P99.9", "MAX");
+
+        let names: BTreeSet<&String> = b.keys().chain(a.keys()).collect();
+        for name in names {
+            let (sb, sa) = (b.get(name), a.get(name));
+            let count = |s: Option<&TimeStats>| s.map_or(0, |s| s.count);
+            if !cli.all && count(sb) == 0 && count(sa) == 0 { continue }
+
+            let (Some(sb), Some(sa)) = (sb, sa) else {
+                println!("  {:<NAME_WIDTH$} (only in {})", name,
+                         if sb.is_some() { "before" } else { "after" });
+                continue;
+            };
+
+            let mut row = format!("  {:<NAME_WIDTH$} {:>COL_WIDTH$} {:>DIFF_COL_WIDTH$}",
+                name, sa.count,
+                fmt_change(Some(sb.duration_ns.mean), Some(sa.duration_ns.mean)));
+            for p in PERCENTILES {
+                row.push_str(&format!(" {:>DIFF_COL_WIDTH$}",
+                    fmt_change(sb.percentile(p), sa.percentile(p))));
+            }
+            row.push_str(&format!(" {:>DIFF_COL_WIDTH$}",
+                fmt_change(Some(sb.duration_ns.max), Some(sa.duration_ns.max))));
+
+            let (metric, vb, va) = regression_metric(sb, sa);
+            let over = cli.threshold.is_some_and(|t|
+                sb.count > 0 && sa.count > 0 && vb > 0 &&
+                (va as f64) > vb as f64 * (1.0 + t / 100.0));
+            if over {
+                println!("{}", row.red());
+                regressed.push(format!("{}: {} {} -> {}", name, metric,
+                                       fmt_duration(vb), fmt_duration(va)));
+            } else {
+                println!("{}", row);
+            }
+        }
+        println!();
+    }
+
+    if !regressed.is_empty() {
+        for r in &regressed {
+            eprintln!("  {}", r);
+        }
+        return Err(anyhow!("{} stats regressed by more than {}%",
+                           regressed.len(), cli.threshold.unwrap_or_default()));
+    }
+    Ok(())
+}
+
 // CLI
 
 #[derive(ValueEnum, Clone, Copy, Debug, Default)]
@@ -396,6 +631,19 @@ pub struct Cli {
     #[arg(long)]
     json: bool,
 
+    /// Show latency percentiles and histograms instead of mean and stddev
+    #[arg(short, long)]
+    percentiles: bool,
+
+    /// Compare two snapshots saved with --json, e.g. from runs on two kernel builds
+    #[arg(long, num_args = 2, value_names = ["BEFORE", "AFTER"], conflicts_with = "json")]
+    diff: Option<Vec<PathBuf>>,
+
+    /// With --diff, fail if any stat's p99 (or mean, without a histogram) got
+    /// more than this many percent worse
+    #[arg(long, value_name = "PERCENT", requires = "diff")]
+    threshold: Option<f64>,
+
     /// Skip per-device IO latency stats
     #[arg(long)]
     no_device_stats: bool,
@@ -420,7 +668,7 @@ pub struct Cli {
 fn display_stats(snaps: Vec<FsSnapshot>, cli: &Cli) -> Result<()> {
     let multi = snaps.len() > 1;
     let sort_col = cli.sort.col_index();
-    let view = View::Mean;
+    let view = if cli.percentiles { View::Percentiles } else { View::Mean };
 
     for mut snap in snaps {
         if multi { println!("{}:", snap.label); }
@@ -434,7 +682,7 @@ fn display_stats(snaps: Vec<FsSnapshot>, cli: &Cli) -> Result<()> {
             if !first { println!(); }
             first = false;
             println!("{}:", section.label);
-            println!("  {}", format_header());
+            println!("  {}", format_header(view));
             for e in &section.entries {
                 println!("  {}", format_row(e, view));
             }
@@ -457,12 +705,14 @@ struct TuiState {
     view:           View,
     cursor:         usize,
     scroll_offset:  usize,
+    heatmap:        Option<Heatmap>,    // Some => heatmap of one stat on screen
+    status:         Option<String>,     // transient message
 }
 
-fn format_tui_header(sort_col: usize, reverse: bool) -> String {
+fn format_tui_header(sort_col: usize, reverse: bool, view: View) -> String {
     let arrow = if reverse { "\u{25b2}" } else { "\u{25bc}" };
     let mut out = String::from("  ");
-    for (i, &name) in COLUMNS.iter().enumerate() {
+    for (i, &name) in view.columns().iter().enumerate() {
         if i > 0 { out.push(' '); }
         let col = if i == 0 { format!("{:<NAME_WIDTH$}", name) }
                   else       { format!("{:>COL_WIDTH$}", name) };
@@ -482,7 +732,7 @@ fn build_frame(snaps: &[FsSnapshot], state: &TuiState, multi: bool) -> (Vec<Stri
 
     let pause = if state.paused { " PAUSED" } else { "" };
     lines.push(format!(
-        "bcachefs timestats ({}s{})  q:quit  Tab:page  \u{2190}\u{2192}:sort  r:reverse  a:all  e:view  p:pause  1-9:interval",
+        "bcachefs timestats ({}s{})  q:quit  Tab:page  \u{2190}\u{2192}:sort  r:reverse  a:all  e:view  Enter:heatmap  p:pause  1-9:interval",
         state.interval.as_secs(), pause,
     ));
 
@@ -499,9 +749,12 @@ fn build_frame(snaps: &[FsSnapshot], state: &TuiState, multi: bool) -> (Vec<Stri
     }
     tabs.push_str(&format!("    view: {}", state.view.label()));
     lines.push(tabs);
+    if let Some(s) = &state.status {
+        lines.push(format!("  {}", s));
+    }
     lines.push(String::new());
 
-    let header = format_tui_header(state.sort_col, state.reverse);
+    let header = format_tui_header(state.sort_col, state.reverse, state.view);
 
     for snap in snaps {
         if multi { lines.push(format!("{}:", snap.label)); }
@@ -555,6 +808,135 @@ fn render_frame(
     stdout.flush()
 }
 
+/* The stat on the given row of a page, in build_frame's order */
+fn entry_at(snaps: &[FsSnapshot], page: Page, row: usize) -> Option<(&FsSnapshot, &StatEntry)> {
+    snaps.iter()
+        .flat_map(|snap| snap.sections.iter()
+            .filter(move |sec| sec.page == page)
+            .flat_map(move |sec| sec.entries.iter().map(move |e| (snap, e))))
+        .nth(row)
+}
+
+// Latency heatmap
+//
+// One stat's histogram over time: a column per refresh, of the events since
+// the one before, and a row per power of two.
+
+const HEATMAP_MAX_COLUMNS: usize = 500;
+
+const HEAT: &[char] = &[' ', '\u{2591}', '\u{2592}', '\u{2593}', '\u{2588}'];
+
+struct Heatmap {
+    fs:      String,
+    name:    String,
+    last:    Option<([u64; NR_OCTAVES], Instant)>,
+    columns: VecDeque<[u64; NR_OCTAVES]>,
+}
+
+impl Heatmap {
+    fn new(fs: &str, name: &str) -> Heatmap {
+        Heatmap {
+            fs:      fs.to_string(),
+            name:    name.to_string(),
+            last:    None,
+            columns: VecDeque::new(),
+        }
+    }
+
+    fn update(&mut self, snaps: &[FsSnapshot], interval: Duration) {
+        /* Keypresses refresh too; keep the columns about an interval apart */
+        if self.last.is_some_and(|(_, at)| at.elapsed() < interval / 2) { return }
+
+        let Some(hist) = snaps.iter()
+            .filter(|snap| snap.label == self.fs)
+            .flat_map(|snap| snap.sections.iter().flat_map(|sec| &sec.entries))
+            .find(|e| e.name == self.name)
+            .and_then(|e| e.stats.histogram_ns.as_deref()) else { return };
+
+        let counts = octave_counts(hist);
+        if let Some((last, _)) = self.last {
+            self.columns.push_back(std::array::from_fn(|i| counts[i].saturating_sub(last[i])));
+            if self.columns.len() > HEATMAP_MAX_COLUMNS { self.columns.pop_front(); }
+        }
+        self.last = Some((counts, Instant::now()));
+    }
+}
+
+fn heat(n: u64, max: u64) -> char {
+    if n == 0 || max == 0 { return HEAT[0] }
+    /* Log scale, so a tail a thousandth the size of the peak still shows */
+    let levels = (HEAT.len() - 2) as f64;
+    let level = ((n as f64).ln_1p() / (max as f64).ln_1p() * levels).round() as usize;
+    HEAT[1 + level.min(HEAT.len() - 2)]
+}
+
+fn render_heatmap(stdout: &mut io::Stdout, h: &Heatmap, interval: Duration) -> io::Result<()> {
+    const LABEL_WIDTH: usize = 8;
+
+    let (term_w, _) = terminal::size().unwrap_or((120, 40));
+    let width = (term_w as usize).saturating_sub(LABEL_WIDTH + 3).max(1);
+    let cols: Vec<_> = h.columns.iter().skip(h.columns.len().saturating_sub(width)).collect();
+
+    /* Only the powers of two anything landed in */
+    let used = |i: usize| cols.iter().any(|c| c[i] != 0);
+    let lo = (0..NR_OCTAVES).find(|&i| used(i));
+    let hi = (0..NR_OCTAVES).rev().find(|&i| used(i));
+    let max = cols.iter().flat_map(|c| c.iter()).copied().max().unwrap_or(0);
+
+    execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::All))?;
+    write!(stdout, "{}\r\n", format!("{} {}: latency over time, a column per {}s",
+        h.fs, h.name, interval.as_secs()).reversed())?;
+    write!(stdout, "  q:back  p:pause  1-9:interval\r\n\r\n")?;
+
+    let (Some(lo), Some(hi)) = (lo, hi) else {
+        write!(stdout, "  waiting for events...\r\n")?;
+        return stdout.flush();
+    };
+
+    /* Slowest at the top */
+    for i in (lo..=hi).rev() {
+        let mut line = format!("{:>LABEL_WIDTH$} \u{2502}",
+                               fmt_duration(1 << (OCTAVE_FIRST_SHIFT + i as u32)));
+        line.extend(cols.iter().map(|c| heat(c[i], max)));
+        write!(stdout, "{}\r\n", line)?;
+    }
+    stdout.flush()
+}
+
+/* Keys that need the stats on screen - Enter opens a heatmap of the stat under
+ * the cursor - or mean something else in the heatmap; the rest go to handle_key. */
+fn handle_tui_key(
+    state: &mut TuiState,
+    key: &event::KeyEvent,
+    snaps: &[FsSnapshot],
+    total_rows: usize,
+) -> bool {
+    state.status = None;
+
+    if state.heatmap.is_some() {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc | KeyCode::Backspace => {
+                state.heatmap = None;
+                return false;
+            }
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return true,
+            KeyCode::Char('p' | '1'..='9') => {}
+            _ => return false,
+        }
+    } else if key.code == KeyCode::Enter {
+        match entry_at(snaps, state.page, state.cursor) {
+            Some((snap, e)) if e.stats.histogram_ns.is_some() =>
+                state.heatmap = Some(Heatmap::new(&snap.label, &e.name)),
+            Some((_, e)) =>
+                state.status = Some(format!("{}: no latency histogram kept for this one", e.name)),
+            None => {}
+        }
+        return false;
+    }
+
+    handle_key(state, key.code, key.modifiers, total_rows)
+}
+
 fn handle_key(state: &mut TuiState, key: KeyCode, modifiers: KeyModifiers, total_rows: usize) -> bool {
     match key {
         KeyCode::Char('q') | KeyCode::Esc => return true,
@@ -604,9 +986,11 @@ fn run_interactive(cli: Cli, sysfs_paths: Vec<PathBuf>) -> Result<()> {
         paused:        false,
         interval:      Duration::from_secs_f64(cli.interval),
         page:          Page::Base,
-        view:          View::Mean,
+        view:          if cli.percentiles { View::Percentiles } else { View::Mean },
         cursor:        0,
         scroll_offset: 0,
+        heatmap:       None,
+        status:        None,
     };
 
     run_tui(|stdout| loop {
@@ -631,18 +1015,24 @@ fn run_interactive(cli: Cli, sysfs_paths: Vec<PathBuf>) -> Result<()> {
             state.cursor = total_rows - 1;
         }
 
-        render_frame(stdout, &snaps, &mut state, sysfs_paths.len() > 1)?;
+        let interval = state.interval;
+        if let Some(h) = state.heatmap.as_mut() {
+            h.update(&snaps, interval);
+            render_heatmap(stdout, h, interval)?;
+        } else {
+            render_frame(stdout, &snaps, &mut state, sysfs_paths.len() > 1)?;
+        }
 
         if event::poll(state.interval)? {
             if let Event::Key(key) = event::read()? {
-                if handle_key(&mut state, key.code, key.modifiers, total_rows) { return Ok(()) }
+                if handle_tui_key(&mut state, &key, &snaps, total_rows) { return Ok(()) }
             }
             while event::poll(Duration::ZERO)? { let _ = event::read()?; }
         }
 
         if state.paused {
             if let Event::Key(key) = event::read()? {
-                if handle_key(&mut state, key.code, key.modifiers, total_rows) { return Ok(()) }
+                if handle_tui_key(&mut state, &key, &snaps, total_rows) { return Ok(()) }
             }
         }
     })
@@ -651,6 +1041,9 @@ fn run_interactive(cli: Cli, sysfs_paths: Vec<PathBuf>) -> Result<()> {
 // Entry point
 
 fn timestats(cli: Cli) -> Result<()> {
+    if let Some(paths) = cli.diff.as_deref() {
+        return diff_stats(&paths[0], &paths[1], &cli);
+    }
 
     let sysfs_paths: Vec<PathBuf> = if let Some(ref fs_arg) = cli.filesystem {
         let handle = BcachefsHandle::open(fs_arg)
@@ -672,3 +1065,55 @@ fn timestats(cli: Cli) -> Result<()> {
 }
 
 pub const CMD: super::CmdDef = typed_cmd!("timestats", "Show operation latency statistics", Cli, timestats);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_ends() {
+        /* Below 1 << HIST_SUB_BITS, buckets are one wide: */
+        assert_eq!(hist_bucket_end(0), 1);
+        assert_eq!(hist_bucket_end(3), 4);
+        /* then four per power of two: */
+        assert_eq!(hist_bucket_end(4), 5);
+        assert_eq!(hist_bucket_end(7), 8);
+        assert_eq!(hist_bucket_end(8), 10);
+        assert_eq!(hist_bucket_end(14), 16);
+        assert_eq!(hist_bucket_end(16), 20);
+        assert_eq!(hist_bucket_end(28), 32);
+        assert_eq!(hist_bucket_end(1024), 1280);
+        assert_eq!(hist_bucket_end(7 << 8), 2048);
+    }
+
+    #[test]
+    fn percentile_empty() {
+        assert_eq!(hist_percentile(&[], 50.0), None);
+        assert_eq!(hist_percentile(&[(1024, 0)], 50.0), None);
+    }
+
+    #[test]
+    fn percentile_single_bucket() {
+        let h = [(1024, 4)];
+
+        assert_eq!(hist_percentile(&h, 0.0), Some(1024));
+        assert_eq!(hist_percentile(&h, 50.0), Some(1152));
+        assert_eq!(hist_percentile(&h, 100.0), Some(1280));
+    }
+
+    #[test]
+    fn percentile_bucket_boundaries() {
+        let h = [(8, 2), (16, 2)];
+
+        assert_eq!(hist_percentile(&h, 25.0), Some(9));
+        /* exactly the first bucket's count: its end, not the next one's start */
+        assert_eq!(hist_percentile(&h, 50.0), Some(10));
+        assert_eq!(hist_percentile(&h, 75.0), Some(18));
+        assert_eq!(hist_percentile(&h, 100.0), Some(20));
+
+        let h = [(0, 1), (1, 1), (2, 2)];
+        assert_eq!(hist_percentile(&h, 25.0), Some(1));
+        assert_eq!(hist_percentile(&h, 50.0), Some(2));
+        assert_eq!(hist_percentile(&h, 100.0), Some(3));
+    }
+}
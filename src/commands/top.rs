@@ -1,13 +1,14 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs::{self, OpenOptions};
-use std::io::{self, IsTerminal, Read, Write};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::process;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 use bch_bindgen::c::bch_ioctl_query_counters;
+use chrono::{Local, TimeZone};
 use bcachefs_kernel::sb::io::{COUNTERS, CounterInfo};
 use clap::Parser;
 use crossterm::{
@@ -17,9 +18,10 @@ use crossterm::{
     terminal::{self, ClearType},
 };
 use owo_colors::OwoColorize;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::commands::DeviceNameArgs;
+use crate::commands::timestats::{fmt_duration, read_time_stats, TimeStats};
 use crate::util::{fmt_bytes_human, fmt_num_human, run_tui};
 use crate::wrappers::handle::BcachefsHandle;
 use crate::wrappers::ioctl::{ioctl_ptr, IoctlBuf, BCH_IOCTL_QUERY_COUNTERS};
@@ -29,7 +31,7 @@ const BCH_IOCTL_QUERY_COUNTERS_MOUNT: u16 = 1 << 0;
 
 // ioctl query
 
-fn read_counters(fd: std::os::fd::BorrowedFd, flags: u16, nr_stable: u16) -> Result<Vec<u64>> {
+pub(crate) fn read_counters(fd: std::os::fd::BorrowedFd, flags: u16, nr_stable: u16) -> Result<Vec<u64>> {
     let mut buf = IoctlBuf::<bch_ioctl_query_counters>::new::<u64>(nr_stable as usize);
     let hdr = buf.hdr_mut();
     hdr.nr = nr_stable;
@@ -49,13 +51,14 @@ struct IoDone {
     write: HashMap<String, u64>,
 }
 
-struct DevIoEntry {
-    label:      String,     // "dev/data_type"
-    read_bytes: u64,
-    write_bytes: u64,
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct DevIoEntry {
+    pub label:      String,     // "dev/data_type"
+    pub read_bytes: u64,
+    pub write_bytes: u64,
 }
 
-fn read_device_io(sysfs_path: &Path, name_mode: DeviceNameMode) -> Vec<DevIoEntry> {
+pub(crate) fn read_device_io(sysfs_path: &Path, name_mode: DeviceNameMode) -> Vec<DevIoEntry> {
     let mut entries = Vec::new();
     let Ok(dir) = fs::read_dir(sysfs_path) else { return entries };
 
@@ -122,76 +125,67 @@ fn event_dir(instance_dir: &Path, event: &str) -> PathBuf {
     instance_dir.join("events/bcachefs").join(event)
 }
 
-struct TraceView {
-    event:        String,
-    instance_dir: PathBuf,
-    pipe:         Option<fs::File>,
-    lines:        VecDeque<String>,
-    partial:      String,
-    /// rows scrolled up from the live tail; 0 == following the tail
-    scroll_back:  usize,
-    paused:       bool,
-    backtrace:    bool,
+/// A tracefs instance of our own with some bcachefs tracepoints enabled,
+/// scoped to one mount; torn down again on drop.
+struct TraceInstance {
+    dir:     PathBuf,
+    enabled: Vec<String>,
+    pipe:    Option<fs::File>,
+    partial: String,
 }
 
-impl TraceView {
-    fn start(event: &str, fs_name: &str) -> Result<TraceView> {
+impl TraceInstance {
+    fn start(tag: &str, events: &[&str], fs_name: &str) -> Result<TraceInstance> {
         let root = tracefs_root()
             .context("tracefs not found at /sys/kernel/tracing (need root)")?;
 
-        let instance_dir = root.join("instances")
-            .join(format!("bcachefs-top-{}", process::id()));
-        fs::create_dir_all(&instance_dir)
-            .with_context(|| format!("creating trace instance {}", instance_dir.display()))?;
+        let dir = root.join("instances")
+            .join(format!("bcachefs-top-{}-{}", process::id(), tag));
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("creating trace instance {}", dir.display()))?;
 
-        let event_dir = event_dir(&instance_dir, event);
-
-        // Scope to this mount before enabling. fs_str records c->name in the
-        // `fs` field (40 bytes, so full UUIDs fit), so an exact match works.
-        fs::write(event_dir.join("filter"), format!("fs == \"{fs_name}\"\n"))
-            .with_context(|| format!("scoping bcachefs:{event} to this mount"))?;
-        fs::write(event_dir.join("enable"), b"1\n")
-            .with_context(|| format!("enabling tracepoint bcachefs:{event}"))?;
+        /* From here on drop cleans up whatever we got as far as enabling */
+        let mut inst = TraceInstance {
+            dir,
+            enabled: Vec::new(),
+            pipe: None,
+            partial: String::new(),
+        };
+
+        for &event in events {
+            let event_dir = event_dir(&inst.dir, event);
+
+            // Scope to this mount before enabling. fs_str records c->name in the
+            // `fs` field (40 bytes, so full UUIDs fit), so an exact match works.
+            fs::write(event_dir.join("filter"), format!("fs == \"{fs_name}\"\n"))
+                .with_context(|| format!("scoping bcachefs:{event} to this mount"))?;
+            fs::write(event_dir.join("enable"), b"1\n")
+                .with_context(|| format!("enabling tracepoint bcachefs:{event}"))?;
+            inst.enabled.push(event.to_string());
+        }
 
-        let pipe = OpenOptions::new()
+        inst.pipe = Some(OpenOptions::new()
             .read(true)
             .custom_flags(libc::O_NONBLOCK)
-            .open(instance_dir.join("trace_pipe"))
-            .context("opening trace_pipe")?;
+            .open(inst.dir.join("trace_pipe"))
+            .context("opening trace_pipe")?);
 
-        Ok(TraceView {
-            event: event.to_string(),
-            instance_dir,
-            pipe: Some(pipe),
-            lines: VecDeque::new(),
-            partial: String::new(),
-            scroll_back: 0,
-            paused: false,
-            backtrace: false,
-        })
+        Ok(inst)
     }
 
-    /// Pause/resume the live tail. We also stop recording into the instance
-    /// (tracing_on) so a long pause doesn't fill the ring buffer; resume
-    /// picks up new events from that point.
-    fn set_paused(&mut self, paused: bool) {
-        self.paused = paused;
-        let _ = fs::write(self.instance_dir.join("tracing_on"),
-                          if paused { b"0\n" } else { b"1\n" });
+    fn set_tracing_on(&self, on: bool) {
+        let _ = fs::write(self.dir.join("tracing_on"), if on { b"1\n" } else { b"0\n" });
     }
 
-    /// Toggle a kernel stack dump after each event via the event's trigger.
-    fn set_backtrace(&mut self, on: bool) {
-        self.backtrace = on;
-        let trigger = event_dir(&self.instance_dir, &self.event).join("trigger");
-        let _ = fs::write(&trigger, if on { "stacktrace\n" } else { "!stacktrace\n" });
+    fn event_dir(&self, event: &str) -> PathBuf {
+        event_dir(&self.dir, event)
     }
 
-    /// Non-blocking: pull queued events into the ring buffer. Bounded per
-    /// call so a hot event (trace_pipe continuously fed) can't spin here
-    /// forever and hang the UI - whatever's left stays in the kernel buffer
-    /// and we pick it up next cycle (or it ages out, fine for a live tail).
-    fn drain(&mut self) {
+    /// Non-blocking: hand each complete line queued in trace_pipe to `f`.
+    /// Bounded per call so a hot event (trace_pipe continuously fed) can't
+    /// spin here forever and hang the UI - whatever's left stays in the
+    /// kernel buffer and we pick it up next cycle.
+    fn read_lines(&mut self, mut f: impl FnMut(&str)) {
         let Some(pipe) = self.pipe.as_mut() else { return };
         let mut buf = [0u8; 16384];
         for _ in 0..16 {
@@ -204,8 +198,7 @@ impl TraceView {
                         let line = line.trim_end();
                         // trace_pipe interleaves '#'-prefixed header/comment lines
                         if line.is_empty() || line.starts_with('#') { continue; }
-                        self.lines.push_back(line.to_string());
-                        if self.lines.len() > TRACE_MAX_LINES { self.lines.pop_front(); }
+                        f(line);
                     }
                 }
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
@@ -213,19 +206,69 @@ impl TraceView {
             }
         }
     }
-
-    fn scroll_up(&mut self, n: usize)   { self.scroll_back = self.scroll_back.saturating_add(n); }
-    fn scroll_down(&mut self, n: usize) { self.scroll_back = self.scroll_back.saturating_sub(n); }
 }
 
-impl Drop for TraceView {
+impl Drop for TraceInstance {
     fn drop(&mut self) {
         self.pipe = None; // close trace_pipe before tearing down the instance
-        let _ = fs::write(event_dir(&self.instance_dir, &self.event).join("enable"), b"0\n");
-        let _ = fs::remove_dir(&self.instance_dir);
+        for event in &self.enabled {
+            let _ = fs::write(self.event_dir(event).join("enable"), b"0\n");
+        }
+        let _ = fs::remove_dir(&self.dir);
     }
 }
 
+struct TraceView {
+    event:        String,
+    inst:         TraceInstance,
+    lines:        VecDeque<String>,
+    /// rows scrolled up from the live tail; 0 == following the tail
+    scroll_back:  usize,
+    paused:       bool,
+    backtrace:    bool,
+}
+
+impl TraceView {
+    fn start(event: &str, fs_name: &str) -> Result<TraceView> {
+        Ok(TraceView {
+            event: event.to_string(),
+            inst: TraceInstance::start("trace", &[event], fs_name)?,
+            lines: VecDeque::new(),
+            scroll_back: 0,
+            paused: false,
+            backtrace: false,
+        })
+    }
+
+    /// Pause/resume the live tail. We also stop recording into the instance
+    /// (tracing_on) so a long pause doesn't fill the ring buffer; resume
+    /// picks up new events from that point.
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.inst.set_tracing_on(!paused);
+    }
+
+    /// Toggle a kernel stack dump after each event via the event's trigger.
+    fn set_backtrace(&mut self, on: bool) {
+        self.backtrace = on;
+        let trigger = self.inst.event_dir(&self.event).join("trigger");
+        let _ = fs::write(&trigger, if on { "stacktrace\n" } else { "!stacktrace\n" });
+    }
+
+    /// Pull queued events into the ring buffer (it ages out, fine for a
+    /// live tail).
+    fn drain(&mut self) {
+        let lines = &mut self.lines;
+        self.inst.read_lines(|line| {
+            lines.push_back(line.to_string());
+            if lines.len() > TRACE_MAX_LINES { lines.pop_front(); }
+        });
+    }
+
+    fn scroll_up(&mut self, n: usize)   { self.scroll_back = self.scroll_back.saturating_add(n); }
+    fn scroll_down(&mut self, n: usize) { self.scroll_back = self.scroll_back.saturating_sub(n); }
+}
+
 fn render_trace(tv: &mut TraceView, stdout: &mut io::Stdout) -> io::Result<()> {
     let (_, term_h) = terminal::size().unwrap_or((120, 40));
     let visible = (term_h as usize).saturating_sub(3).max(1);
@@ -252,6 +295,439 @@ fn render_trace(tv: &mut TraceView, stdout: &mut io::Stdout) -> io::Result<()> {
     stdout.flush()
 }
 
+// IO attribution (ftrace)
+//
+// Which processes, and which files, the IO is for: the data_read, data_write
+// and data_update_write tracepoints start with an "inum N sectors N" summary
+// line, and trace_pipe tells us which task each event fired in. Reconcile and
+// copygc moves run in their own threads, so bytes moved are charged back to
+// the processes that wrote each inode, in proportion to what each wrote while
+// we've been watching.
+//
+// Buffered writes reach data_write from writeback, so by process they show up
+// under the flusher thread; by inode they're exact.
+
+const ATTR_EVENTS: &[&str] = &[
+    "data_read",
+    "data_write",
+    "data_update_write",
+    "transaction_commit",
+    "journal_flush",
+];
+
+#[derive(Default, Clone, Copy)]
+struct IoUsage {
+    read:    u64,   // bytes
+    written: u64,
+    moved:   u64,
+    commits: u64,
+    flushes: u64,
+}
+
+impl IoUsage {
+    fn add(&mut self, o: &IoUsage) {
+        self.read    += o.read;
+        self.written += o.written;
+        self.moved   += o.moved;
+        self.commits += o.commits;
+        self.flushes += o.flushes;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AttrView {
+    Processes,
+    Commands,
+    Inodes,
+}
+
+impl AttrView {
+    const ALL: &'static [AttrView] = &[AttrView::Processes, AttrView::Commands, AttrView::Inodes];
+    fn label(self) -> &'static str {
+        match self {
+            AttrView::Processes => "processes",
+            AttrView::Commands  => "commands",
+            AttrView::Inodes    => "inodes",
+        }
+    }
+}
+
+const ATTR_SORT_COLUMNS: &[&str] = &["read", "written", "moved", "commits", "flushes"];
+
+fn attr_sort_key(u: &IoUsage, col: usize) -> u64 {
+    match col {
+        0 => u.read,
+        1 => u.written,
+        2 => u.moved,
+        3 => u.commits,
+        _ => u.flushes,
+    }
+}
+
+/// The task and event of one event's first line in trace_pipe:
+///   "  fio-1234  [003] ..... 5678.123456: data_write: <fs>: inum 4096 sectors 8"
+/// The rest of a multi-line fs_str payload comes through as unprefixed lines,
+/// which don't parse and are skipped.
+struct TraceEvent<'a> {
+    comm:    &'a str,
+    pid:     u32,
+    event:   &'a str,
+    payload: &'a str,
+}
+
+fn parse_trace_event(line: &str) -> Option<TraceEvent<'_>> {
+    let (task, rest) = line.split_once(" [")?;
+    let (comm, pid) = task.trim().rsplit_once('-')?;
+    let pid = pid.parse().ok()?;
+
+    let (_cpu_flags_ts, rest) = rest.split_once(": ")?;
+    let (event, rest) = rest.split_once(": ")?;
+    let (_fs, payload) = rest.split_once(": ").unwrap_or((rest, ""));
+
+    Some(TraceEvent { comm, pid, event, payload })
+}
+
+fn parse_inum_sectors(payload: &str) -> Option<(u64, u64)> {
+    let mut f = payload.split_whitespace();
+    match (f.next()?, f.next()?, f.next()?, f.next()?) {
+        ("inum", inum, "sectors", sectors) => Some((inum.parse().ok()?, sectors.parse().ok()?)),
+        _ => None,
+    }
+}
+
+struct Attribution {
+    inst:    TraceInstance,
+    since:   Instant,
+    events:  u64,
+    procs:   HashMap<u32, (String, IoUsage)>,
+    inodes:  HashMap<u64, IoUsage>,
+    /// bytes written to each inode, by pid: who moves are charged to
+    writers: HashMap<u64, HashMap<u32, u64>>,
+    view:    AttrView,
+    sort:    usize,                     // index into ATTR_SORT_COLUMNS
+    scroll:  usize,
+}
+
+impl Attribution {
+    fn start(fs_name: &str) -> Result<Attribution> {
+        Ok(Attribution {
+            inst: TraceInstance::start("attr", ATTR_EVENTS, fs_name)?,
+            since: Instant::now(),
+            events: 0,
+            procs: HashMap::new(),
+            inodes: HashMap::new(),
+            writers: HashMap::new(),
+            view: AttrView::Processes,
+            sort: 1,
+            scroll: 0,
+        })
+    }
+
+    fn reset(&mut self) {
+        self.since = Instant::now();
+        self.events = 0;
+        self.procs.clear();
+        self.inodes.clear();
+        self.writers.clear();
+    }
+
+    fn account(&mut self, e: TraceEvent) {
+        let proc = &mut self.procs.entry(e.pid)
+            .or_insert_with(|| (e.comm.to_string(), IoUsage::default())).1;
+
+        match e.event {
+            "transaction_commit" => proc.commits += 1,
+            "journal_flush"      => proc.flushes += 1,
+            _ => {
+                let Some((inum, sectors)) = parse_inum_sectors(e.payload) else { return };
+                let bytes = sectors << 9;
+                let inode = self.inodes.entry(inum).or_default();
+
+                match e.event {
+                    "data_read" => {
+                        proc.read  += bytes;
+                        inode.read += bytes;
+                    }
+                    "data_write" => {
+                        proc.written  += bytes;
+                        inode.written += bytes;
+                        *self.writers.entry(inum).or_default().entry(e.pid).or_default() += bytes;
+                    }
+                    "data_update_write" => inode.moved += bytes,
+                    _ => return,
+                }
+            }
+        }
+        self.events += 1;
+    }
+
+    fn drain(&mut self) {
+        let mut lines = Vec::new();
+        self.inst.read_lines(|line| lines.push(line.to_string()));
+        for line in &lines {
+            if let Some(e) = parse_trace_event(line) {
+                self.account(e);
+            }
+        }
+    }
+
+    /// Per-process usage, with each inode's moves split between its writers;
+    /// moves of inodes we saw no writes to are returned separately.
+    fn procs_charged(&self) -> (HashMap<u32, (String, IoUsage)>, u64) {
+        let mut procs = self.procs.clone();
+        let mut unattributed = 0;
+
+        for (inum, inode) in &self.inodes {
+            if inode.moved == 0 { continue }
+
+            let writers = self.writers.get(inum);
+            let total: u64 = writers.map_or(0, |w| w.values().sum());
+            if total == 0 {
+                unattributed += inode.moved;
+                continue;
+            }
+            for (pid, &w) in writers.unwrap() {
+                if let Some((_, u)) = procs.get_mut(pid) {
+                    u.moved += (inode.moved as u128 * w as u128 / total as u128) as u64;
+                }
+            }
+        }
+        (procs, unattributed)
+    }
+
+    /// Rows for the current view, sorted: (name, usage), plus bytes moved
+    /// that couldn't be charged to anyone.
+    fn rows(&self) -> (Vec<(String, IoUsage)>, u64) {
+        let (mut rows, unattributed): (Vec<(String, IoUsage)>, u64) = match self.view {
+            AttrView::Processes => {
+                let (procs, unattributed) = self.procs_charged();
+                (procs.into_iter()
+                    .map(|(pid, (comm, u))| (format!("{comm}-{pid}"), u))
+                    .collect(), unattributed)
+            }
+            AttrView::Commands => {
+                let (procs, unattributed) = self.procs_charged();
+                let mut by_comm: HashMap<String, IoUsage> = HashMap::new();
+                for (comm, u) in procs.into_values() {
+                    by_comm.entry(comm).or_default().add(&u);
+                }
+                (by_comm.into_iter().collect(), unattributed)
+            }
+            AttrView::Inodes => (self.inodes.iter()
+                .map(|(inum, u)| (format!("inode {inum}"), *u))
+                .collect(), 0),
+        };
+
+        rows.sort_by(|a, b| attr_sort_key(&b.1, self.sort).cmp(&attr_sort_key(&a.1, self.sort))
+            .then_with(|| a.0.cmp(&b.0)));
+        (rows, unattributed)
+    }
+}
+
+fn render_attribution(a: &mut Attribution, human_readable: bool, stdout: &mut io::Stdout) -> io::Result<()> {
+    let (_, term_h) = terminal::size().unwrap_or((120, 40));
+    let visible = (term_h as usize).saturating_sub(6).max(1);
+    let h = human_readable;
+
+    let (rows, unattributed) = a.rows();
+    a.scroll = a.scroll.min(rows.len().saturating_sub(visible));
+
+    execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::All))?;
+    write!(stdout, "{}\r\n",
+        format!("IO attribution: {} events over {}s", a.events, a.since.elapsed().as_secs()).reversed())?;
+    write!(stdout, "  q:back  Tab:processes/commands/inodes  s:sort  z:reset  h:human-readable  \u{2191}\u{2193}/PgUp/PgDn:scroll\r\n")?;
+
+    let mut tabs = String::from("  ");
+    for (i, &v) in AttrView::ALL.iter().enumerate() {
+        if i > 0 { tabs.push_str("  "); }
+        let label = format!("[{}]", v.label());
+        if v == a.view {
+            tabs.push_str(&format!("{}", label.reversed()));
+        } else {
+            tabs.push_str(&label);
+        }
+    }
+    if unattributed != 0 {
+        tabs.push_str(&format!("    moved, no writer seen: {}", fmt_bytes(unattributed, h)));
+    }
+    write!(stdout, "{}\r\n\r\n", tabs)?;
+
+    let mut header = format!("  {:<32}", "");
+    for (i, col) in ATTR_SORT_COLUMNS.iter().enumerate() {
+        let col = if i == a.sort { format!("{col}\u{25bc}") } else { col.to_string() };
+        header.push_str(&format!(" {:>14}", col));
+    }
+    write!(stdout, "{}\r\n", header)?;
+
+    for (name, u) in rows.iter().skip(a.scroll).take(visible) {
+        write!(stdout, "  {:<32} {:>14} {:>14} {:>14} {:>14} {:>14}\r\n",
+            name,
+            fmt_bytes(u.read, h), fmt_bytes(u.written, h), fmt_bytes(u.moved, h),
+            fmt_counter(u.commits, false, h), fmt_counter(u.flushes, false, h))?;
+    }
+    stdout.flush()
+}
+
+// Samples
+//
+// Everything the display shows comes from a Sample, whether it was just taken
+// or read back from a recording: the TUI keeps a history of them and renders
+// one, with rates against an earlier one. So replay is the same TUI driven
+// from a file, and a live session can be stepped back through too.
+//
+// Recordings are JSON lines - a RecordingHeader, then one Sample per line -
+// appended and flushed a sample at a time, so a recorder that is killed loses
+// at most the sample it was writing, and replay skips a torn last line.
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Sample {
+    /// Wall clock, milliseconds since the epoch
+    t:          u64,
+    /// Indexed by stable_id, so recordings outlive counter renumbering
+    counters:   Vec<u64>,
+    #[serde(default)]
+    dev_io:     Vec<DevIoEntry>,
+    #[serde(default)]
+    time_stats: BTreeMap<String, TimeStats>,
+}
+
+const RECORDING_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct RecordingHeader {
+    bcachefs_top: u32,
+    fs:           String,
+    /// Counter values at mount, for the "mount" column
+    mount:        Vec<u64>,
+}
+
+/* Live history kept for stepping back and windowed rates: bounded, since every
+ * sample carries the time stats too. */
+const LIVE_HISTORY: usize = 300;
+
+/* Rate views: rates over the last N samples. */
+const RATE_WINDOWS: &[usize] = &[1, 10, 60];
+
+const REPLAY_SPEEDS: &[u64] = &[1, 2, 5, 10, 30, 60, 300, 600, 3600];
+
+/* Replaying through a gap in a recording - the recorder was stopped - jumps
+ * rather than waiting it out in real time. */
+const REPLAY_MAX_GAP_MS: u64 = 5 * 60 * 1000;
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn fmt_time_ms(t: u64) -> String {
+    match Local.timestamp_millis_opt(t as i64) {
+        chrono::LocalResult::Single(dt) => dt.format("%Y-%m-%d %H:%M:%S").to_string(),
+        _ => t.to_string(),
+    }
+}
+
+fn per_sec(v: u64, elapsed_ms: u64) -> u64 {
+    if elapsed_ms == 0 { 0 } else { (v as u128 * 1000 / elapsed_ms as u128) as u64 }
+}
+
+/// Where live samples come from, and where they go if we're recording.
+struct Live {
+    handle:     BcachefsHandle,
+    nr_stable:  u16,
+    sysfs_path: PathBuf,
+    name_mode:  DeviceNameMode,
+    record:     Option<fs::File>,
+}
+
+impl Live {
+    fn open(handle: BcachefsHandle, name_mode: DeviceNameMode, record: Option<&Path>)
+        -> Result<(Live, RecordingHeader)>
+    {
+        let nr_stable  = COUNTERS.iter().map(|c| c.stable_id).max().unwrap_or(0) + 1;
+        let mount      = read_counters(handle.ioctl_fd(), BCH_IOCTL_QUERY_COUNTERS_MOUNT, nr_stable)?;
+        let sysfs_path = sysfs_path_from_fd(handle.sysfs_fd())?;
+        let fs_name    = sysfs_path.file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let header = RecordingHeader { bcachefs_top: RECORDING_VERSION, fs: fs_name, mount };
+        let record = record.map(|p| recording_open(p, &header)).transpose()?;
+
+        Ok((Live { handle, nr_stable, sysfs_path, name_mode, record }, header))
+    }
+
+    fn sample(&mut self) -> Result<Sample> {
+        let sample = Sample {
+            t:          now_ms(),
+            counters:   read_counters(self.handle.ioctl_fd(), 0, self.nr_stable)?,
+            dev_io:     read_device_io(&self.sysfs_path, self.name_mode),
+            // The time stats page doesn't use the histograms, and they'd
+            // make up most of a recording
+            time_stats: read_time_stats(&self.sysfs_path)
+                .map(|v| v.into_iter().map(|e| (e.name, e.stats.without_distribution())).collect())
+                .unwrap_or_default(),
+        };
+
+        if let Some(f) = self.record.as_mut() {
+            let mut line = serde_json::to_string(&sample)?;
+            line.push('\n');
+            f.write_all(line.as_bytes()).context("writing recording")?;
+        }
+
+        Ok(sample)
+    }
+}
+
+/* Appending to an existing recording is allowed - restarting the recorder
+ * shouldn't need a new file - but only for the same filesystem. */
+fn recording_open(path: &Path, header: &RecordingHeader) -> Result<fs::File> {
+    let mut f = OpenOptions::new().read(true).append(true).create(true).open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+
+    let mut first = String::new();
+    io::BufReader::new(&f).read_line(&mut first)?;
+
+    if first.is_empty() {
+        let mut line = serde_json::to_string(header)?;
+        line.push('\n');
+        f.write_all(line.as_bytes())?;
+    } else {
+        let existing: RecordingHeader = serde_json::from_str(&first)
+            .with_context(|| format!("{} is not a top recording", path.display()))?;
+        anyhow::ensure!(existing.fs == header.fs,
+            "{} is a recording of {}, not {}", path.display(), existing.fs, header.fs);
+    }
+
+    Ok(f)
+}
+
+fn recording_load(path: &Path) -> Result<(RecordingHeader, Vec<Sample>)> {
+    let f = fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut lines = io::BufReader::new(f).lines();
+
+    let header: RecordingHeader = serde_json::from_str(&lines.next().transpose()?.unwrap_or_default())
+        .with_context(|| format!("{} is not a top recording", path.display()))?;
+    anyhow::ensure!(header.bcachefs_top <= RECORDING_VERSION,
+        "{}: recording format {} is newer than this bcachefs", path.display(), header.bcachefs_top);
+
+    let mut samples = Vec::new();
+    let mut bad = 0;
+    for line in lines {
+        match serde_json::from_str::<Sample>(&line?) {
+            Ok(s)  => samples.push(s),
+            Err(_) => bad += 1,
+        }
+    }
+
+    if bad > 0 {
+        eprintln!("{}: skipped {} unreadable samples", path.display(), bad);
+    }
+    anyhow::ensure!(!samples.is_empty(), "{}: no samples", path.display());
+
+    Ok((header, samples))
+}
+
 // CLI
 
 #[derive(Parser, Debug)]
@@ -277,6 +753,15 @@ pub struct Cli {
     #[arg(short = 'd', long, default_value = "1")]
     delay: u32,
 
+    /// Append samples to a recording file as well. Without a terminal, records
+    /// quietly: -n samples, or until killed.
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay a recording made with --record, instead of sampling live
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
     #[command(flatten)]
     device_names: DeviceNameArgs,
 
@@ -290,14 +775,16 @@ pub struct Cli {
 enum Page {
     Base,
     Devices,
+    TimeStats,
 }
 
 impl Page {
-    const ALL: &'static [Page] = &[Page::Base, Page::Devices];
+    const ALL: &'static [Page] = &[Page::Base, Page::Devices, Page::TimeStats];
     fn label(self) -> &'static str {
         match self {
-            Page::Base    => "counters",
-            Page::Devices => "devices",
+            Page::Base      => "counters",
+            Page::Devices   => "devices",
+            Page::TimeStats => "time stats",
         }
     }
     fn next(self) -> Page {
@@ -311,56 +798,60 @@ impl Page {
 }
 
 struct TopState {
-    handle:         BcachefsHandle,
-    nr_stable:      u16,
+    live:           Option<Live>,       // None => replaying
+    replay_path:    Option<PathBuf>,
     mount_vals:     Vec<u64>,
     start_vals:     Vec<u64>,
-    prev_vals:      Vec<u64>,
-    prev_dev_io:    HashMap<String, (u64, u64)>,    // label -> (read, write)
+    samples:        VecDeque<Sample>,
+    pos:            usize,              // sample on display
+    follow:         bool,               // live: pos tracks the newest sample
+    playing:        bool,               // replay: advancing on its own
+    speed:          usize,              // index into REPLAY_SPEEDS
+    replay_clock:   u64,                // replay: recording time being shown
+    window:         usize,              // index into RATE_WINDOWS
     human_readable: bool,
-    name_mode:      DeviceNameMode,
-    sysfs_path:     PathBuf,
     interval_secs:  u32,
     page:           Page,
     cursor:         usize,
     scroll_offset:  usize,
     fs_name:        String,             // c->name (= sysfs dir), for trace scoping
     trace:          Option<TraceView>,  // Some => live-trace drill-down active
+    attr:           Option<Attribution>, // Some => IO attribution view active
     status:         Option<String>,     // transient message (e.g. trace-start error)
 }
 
 impl TopState {
     fn new(
-        handle: BcachefsHandle,
+        live: Option<Live>,
+        header: RecordingHeader,
+        samples: Vec<Sample>,
         human_readable: bool,
-        name_mode: DeviceNameMode,
-    ) -> Result<Self> {
-        let ioctl_fd = handle.ioctl_fd();
-        let nr_stable = COUNTERS.iter().map(|c| c.stable_id).max().unwrap_or(0) + 1;
-
-        let mount_vals = read_counters(ioctl_fd, BCH_IOCTL_QUERY_COUNTERS_MOUNT, nr_stable)?;
-        let start_vals = read_counters(ioctl_fd, 0, nr_stable)?;
-        let prev_vals  = read_counters(ioctl_fd, 0, nr_stable)?;
-
-        let sysfs_path = sysfs_path_from_fd(handle.sysfs_fd())?;
-        let fs_name = sysfs_path.file_name()
-            .map(|n| n.to_string_lossy().into_owned())
-            .unwrap_or_default();
-
-        Ok(TopState {
-            handle, nr_stable,
-            mount_vals, start_vals, prev_vals,
-            prev_dev_io: HashMap::new(),
+    ) -> Self {
+        let start_vals = samples.first().map(|s| s.counters.clone()).unwrap_or_default();
+        let replay_clock = samples.first().map_or(0, |s| s.t);
+
+        TopState {
+            live,
+            replay_path: None,
+            mount_vals: header.mount,
+            start_vals,
+            samples: samples.into(),
+            pos: 0,
+            follow: true,
+            playing: false,
+            speed: 0,
+            replay_clock,
+            window: 0,
             human_readable,
-            name_mode,
-            sysfs_path, interval_secs: 1,
+            interval_secs: 1,
             page: Page::Base,
             cursor: 0,
             scroll_offset: 0,
-            fs_name,
+            fs_name: header.fs,
             trace: None,
+            attr: None,
             status: None,
-        })
+        }
     }
 
     fn get_val(vals: &[u64], stable_id: u16) -> u64 {
@@ -368,11 +859,63 @@ impl TopState {
         if idx < vals.len() { vals[idx] } else { 0 }
     }
 
+    fn curr(&self) -> &Sample {
+        &self.samples[self.pos]
+    }
+
+    /// The rate baseline: the rate window's worth of samples back.
+    fn prev(&self) -> &Sample {
+        &self.samples[self.pos.saturating_sub(RATE_WINDOWS[self.window])]
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.curr().t.saturating_sub(self.prev().t)
+    }
+
+    fn sample_live(&mut self) -> Result<()> {
+        let Some(live) = self.live.as_mut() else { return Ok(()) };
+
+        self.samples.push_back(live.sample()?);
+        if self.samples.len() > LIVE_HISTORY {
+            self.samples.pop_front();
+            self.pos = self.pos.saturating_sub(1);
+        }
+        if self.follow {
+            self.pos = self.samples.len() - 1;
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, delta: isize) {
+        let last = self.samples.len() - 1;
+        self.pos = self.pos.saturating_add_signed(delta).min(last);
+        self.follow = self.pos == last;
+        self.replay_clock = self.curr().t;
+    }
+
+    /// Replay: move forward to wherever the replay clock has got to.
+    fn advance_replay(&mut self, real_elapsed: Duration) {
+        self.replay_clock += real_elapsed.as_millis() as u64 * REPLAY_SPEEDS[self.speed];
+
+        while self.pos + 1 < self.samples.len() {
+            let next = self.samples[self.pos + 1].t;
+            if next.saturating_sub(self.curr().t) > REPLAY_MAX_GAP_MS {
+                self.replay_clock = self.replay_clock.max(next);
+            }
+            if next > self.replay_clock { break }
+            self.pos += 1;
+        }
+
+        if self.pos + 1 == self.samples.len() {
+            self.playing = false;
+        }
+    }
+
     /// Name of the counter under the cursor on the counters page (same
     /// v_mount != 0 filtering as build_frame), for trace drill-down.
-    fn counter_at_cursor(&self, curr: &[u64]) -> Option<String> {
+    fn counter_at_cursor(&self) -> Option<String> {
         if self.page != Page::Base { return None; }
-        active_counters(curr, &self.mount_vals)
+        active_counters(&self.curr().counters, &self.mount_vals)
             .nth(self.cursor)
             .map(|c| c.name.to_string())
     }
@@ -390,19 +933,57 @@ fn active_counters<'a>(curr: &'a [u64], mount: &'a [u64])
         TopState::get_val(curr, c.stable_id) != TopState::get_val(mount, c.stable_id))
 }
 
+fn dev_io_map(dev_io: &[DevIoEntry]) -> HashMap<&str, (u64, u64)> {
+    dev_io.iter()
+        .map(|d| (d.label.as_str(), (d.read_bytes, d.write_bytes)))
+        .collect()
+}
+
+/* Events/s and mean duration over the rate window, then the kernel's own
+ * recent mean and all-time max. The windowed mean needs duration totals, which
+ * older kernels don't export - shown as "-" then. */
+fn time_stats_row(name: &str, curr: &TimeStats, prev: Option<&TimeStats>, elapsed_ms: u64) -> String {
+    let (count, total) = match prev {
+        Some(p) => (curr.count.wrapping_sub(p.count),
+                    curr.duration_ns.total.wrapping_sub(p.duration_ns.total)),
+        None    => (0, 0),
+    };
+
+    let mean = if count > 0 && total > 0 { fmt_duration(total / count) } else { "-".into() };
+
+    format!("{:<40} {:>12} {:>12} {:>12} {:>12}",
+        name, per_sec(count, elapsed_ms), mean,
+        fmt_duration(curr.duration_ewma_ns.mean), fmt_duration(curr.duration_ns.max))
+}
+
 /* Build the current frame as Vec<String>, return the line index of the
  * cursor row so the caller can adjust scroll_offset to keep it visible.
  * Total visible rows on this page is also returned (for cursor clamping). */
-fn build_frame(state: &TopState, curr: &[u64], dev_io: &[DevIoEntry])
-    -> (Vec<String>, Option<usize>, usize)
-{
+fn build_frame(state: &TopState) -> (Vec<String>, Option<usize>, usize) {
     let mut lines = Vec::new();
     let mut cursor_line = None;
 
-    lines.push("Every counter has a corresponding tracepoint; Enter drills into a live trace of the".into());
-    lines.push("selected event, scoped to this mount.".into());
-    lines.push(String::new());
-    lines.push("  q:quit  Enter:live-trace  h:human-readable  Tab:page  \u{2191}\u{2193}:scroll  PgUp/PgDn  1-9:interval".into());
+    let curr = state.curr();
+    let prev = state.prev();
+    let elapsed = state.elapsed_ms();
+
+    if let Some(path) = &state.replay_path {
+        lines.push(format!("{}", format!("replay {}: {}  sample {}/{}{}",
+            path.display(), fmt_time_ms(curr.t), state.pos + 1, state.samples.len(),
+            if state.playing { format!("  [playing {}x]", REPLAY_SPEEDS[state.speed]) }
+            else { "  [paused]".into() }).reversed()));
+        lines.push(String::new());
+        lines.push("  q:quit  Space:play/pause  \u{2190}\u{2192}:step  </>:step 60  g/G:start/end  1-9:speed  r:rate window  h:human-readable  Tab:page".into());
+    } else {
+        lines.push("Every counter has a corresponding tracepoint; Enter drills into a live trace of the".into());
+        lines.push("selected event, scoped to this mount.".into());
+        lines.push(String::new());
+        lines.push("  q:quit  Enter:live-trace  a:IO by process/inode  h:human-readable  Tab:page  \u{2191}\u{2193}:scroll  PgUp/PgDn  1-9:interval  \u{2190}\u{2192}:history  r:rate window".into());
+        if !state.follow {
+            lines.push(format!("  history: {}  sample {}/{} (\u{2192} to the end to follow)",
+                fmt_time_ms(curr.t), state.pos + 1, state.samples.len()));
+        }
+    }
     if let Some(s) = &state.status {
         lines.push(format!("  {}", s));
     }
@@ -418,20 +999,33 @@ fn build_frame(state: &TopState, curr: &[u64], dev_io: &[DevIoEntry])
             tabs.push_str(&label);
         }
     }
+    let window = RATE_WINDOWS[state.window];
+    if window > 1 {
+        tabs.push_str(&format!("    rates over {} samples", window));
+    }
     lines.push(tabs);
     lines.push(String::new());
 
     let h = state.human_readable;
-    let interval = state.interval_secs as u64;
 
-    match state.page {
+    let mut push_row = |lines: &mut Vec<String>, row: usize, row_str: String| {
+        if row == state.cursor {
+            cursor_line = Some(lines.len());
+            lines.push(format!("{}{}", "\u{25ba} ".bold(), row_str.bold()));
+        } else {
+            lines.push(format!("  {}", row_str));
+        }
+    };
+
+    let total_rows = match state.page {
         Page::Base => {
             lines.push(format!("{:<40} {:>14} {:>14} {:>14}",
-                "", format!("{}/s", state.interval_secs), "total", "mount"));
+                "", "/s", "total", "mount"));
 
-            for (row, c) in active_counters(curr, &state.mount_vals).enumerate() {
-                let cv = TopState::get_val(curr, c.stable_id);
-                let pv = TopState::get_val(&state.prev_vals, c.stable_id);
+            let mut rows = 0;
+            for (row, c) in active_counters(&curr.counters, &state.mount_vals).enumerate() {
+                let cv = TopState::get_val(&curr.counters, c.stable_id);
+                let pv = TopState::get_val(&prev.counters, c.stable_id);
                 let sv = TopState::get_val(&state.start_vals, c.stable_id);
                 let mv = TopState::get_val(&state.mount_vals, c.stable_id);
 
@@ -439,61 +1033,55 @@ fn build_frame(state: &TopState, curr: &[u64], dev_io: &[DevIoEntry])
                 let v_total = cv.wrapping_sub(sv);
                 let v_mount = cv.wrapping_sub(mv);
 
-                let row_str = format!("{:<40} {:>12}/s {:>14} {:>14}",
+                push_row(&mut lines, row, format!("{:<40} {:>12}/s {:>14} {:>14}",
                     c.name,
-                    fmt_counter(v_rate / interval, c.is_sectors, h),
+                    fmt_counter(per_sec(v_rate, elapsed), c.is_sectors, h),
                     fmt_counter(v_total, c.is_sectors, h),
-                    fmt_counter(v_mount, c.is_sectors, h));
-
-                if row == state.cursor {
-                    cursor_line = Some(lines.len());
-                    lines.push(format!("{}{}", "\u{25ba} ".bold(), row_str.bold()));
-                } else {
-                    lines.push(format!("  {}", row_str));
-                }
+                    fmt_counter(v_mount, c.is_sectors, h)));
+                rows += 1;
             }
+            rows
         }
         Page::Devices => {
             lines.push(format!("{:<40} {:>14} {:>14} {:>14} {:>14}",
                 "", "read/s", "read", "write/s", "write"));
 
-            for (row, dev) in dev_io.iter().enumerate() {
-                let (prev_r, prev_w) = state.prev_dev_io
-                    .get(&dev.label)
+            let prev_dev_io = dev_io_map(&prev.dev_io);
+            for (row, dev) in curr.dev_io.iter().enumerate() {
+                let (prev_r, prev_w) = prev_dev_io
+                    .get(dev.label.as_str())
                     .copied()
                     .unwrap_or((dev.read_bytes, dev.write_bytes));
-                let rate_r = dev.read_bytes.wrapping_sub(prev_r) / interval;
-                let rate_w = dev.write_bytes.wrapping_sub(prev_w) / interval;
+                let rate_r = per_sec(dev.read_bytes.wrapping_sub(prev_r), elapsed);
+                let rate_w = per_sec(dev.write_bytes.wrapping_sub(prev_w), elapsed);
 
-                let row_str = format!("{:<40} {:>14} {:>14} {:>14} {:>14}",
+                push_row(&mut lines, row, format!("{:<40} {:>14} {:>14} {:>14} {:>14}",
                     &dev.label,
                     fmt_bytes(rate_r, h), fmt_bytes(dev.read_bytes, h),
-                    fmt_bytes(rate_w, h), fmt_bytes(dev.write_bytes, h));
-
-                if row == state.cursor {
-                    cursor_line = Some(lines.len());
-                    lines.push(format!("{}{}", "\u{25ba} ".bold(), row_str.bold()));
-                } else {
-                    lines.push(format!("  {}", row_str));
-                }
+                    fmt_bytes(rate_w, h), fmt_bytes(dev.write_bytes, h)));
             }
+            curr.dev_io.len()
         }
-    }
+        Page::TimeStats => {
+            lines.push(format!("{:<40} {:>12} {:>12} {:>12} {:>12}",
+                "", "events/s", "mean", "recent", "max"));
 
-    let total_rows = match state.page {
-        Page::Base    => active_counters(curr, &state.mount_vals).count(),
-        Page::Devices => dev_io.len(),
+            for (row, (name, s)) in curr.time_stats.iter().enumerate() {
+                push_row(&mut lines, row,
+                         time_stats_row(name, s, prev.time_stats.get(name), elapsed));
+            }
+            curr.time_stats.len()
+        }
     };
+
     (lines, cursor_line, total_rows)
 }
 
-fn render(state: &mut TopState, curr: &[u64], dev_io: &[DevIoEntry], stdout: &mut io::Stdout)
-    -> io::Result<usize>
-{
+fn render(state: &mut TopState, stdout: &mut io::Stdout) -> io::Result<usize> {
     let (_, term_h) = terminal::size().unwrap_or((120, 40));
     let visible = (term_h as usize).saturating_sub(1).max(1);
 
-    let (lines, cursor_line, total_rows) = build_frame(state, curr, dev_io);
+    let (lines, cursor_line, total_rows) = build_frame(state);
 
     if let Some(cl) = cursor_line {
         if cl < state.scroll_offset {
@@ -514,21 +1102,17 @@ fn render(state: &mut TopState, curr: &[u64], dev_io: &[DevIoEntry], stdout: &mu
 }
 
 /* Print one frame: counters page (rate / total / mount) followed by devices
- * page (read/s / read / write/s / write). prev_* gives the baseline for
- * rates; on the first frame the caller passes curr as prev so rates are 0. */
-fn print_frame(
-    curr: &[u64], prev: &[u64], mount: &[u64],
-    dev_io: &[DevIoEntry], prev_dev_io: &HashMap<String, (u64, u64)>,
-    delay: u32, h: bool,
-) {
-    let d = delay as u64;
+ * page (read/s / read / write/s / write). prev gives the baseline for rates;
+ * on the first frame the caller passes curr as prev so rates are 0. */
+fn print_frame(curr: &Sample, prev: &Sample, mount: &[u64], h: bool) {
+    let elapsed = curr.t.saturating_sub(prev.t);
 
     println!("counters:");
     println!("  {:<40} {:>12}   {:>14} {:>14}",
-        "", format!("{}/s", delay), "total", "mount");
+        "", "/s", "total", "mount");
     for c in COUNTERS {
-        let cv = TopState::get_val(curr, c.stable_id);
-        let pv = TopState::get_val(prev, c.stable_id);
+        let cv = TopState::get_val(&curr.counters, c.stable_id);
+        let pv = TopState::get_val(&prev.counters, c.stable_id);
         let mv = TopState::get_val(mount, c.stable_id);
         let v_mount = cv.wrapping_sub(mv);
         if v_mount == 0 { continue }
@@ -536,23 +1120,25 @@ fn print_frame(
         let v_rate = cv.wrapping_sub(pv);
         println!("  {:<40} {:>12}/s {:>14} {:>14}",
             c.name,
-            fmt_counter(v_rate / d, c.is_sectors, h),
-            fmt_counter(cv,         c.is_sectors, h),
-            fmt_counter(v_mount,    c.is_sectors, h));
+            fmt_counter(per_sec(v_rate, elapsed), c.is_sectors, h),
+            fmt_counter(cv,                       c.is_sectors, h),
+            fmt_counter(v_mount,                  c.is_sectors, h));
     }
 
-    if !dev_io.is_empty() {
+    if !curr.dev_io.is_empty() {
+        let prev_dev_io = dev_io_map(&prev.dev_io);
+
         println!();
         println!("devices:");
         println!("  {:<40} {:>14} {:>14} {:>14} {:>14}",
             "", "read/s", "read", "write/s", "write");
-        for dev in dev_io {
+        for dev in &curr.dev_io {
             let (pr, pw) = prev_dev_io
-                .get(&dev.label)
+                .get(dev.label.as_str())
                 .copied()
                 .unwrap_or((dev.read_bytes, dev.write_bytes));
-            let rate_r = dev.read_bytes.wrapping_sub(pr) / d;
-            let rate_w = dev.write_bytes.wrapping_sub(pw) / d;
+            let rate_r = per_sec(dev.read_bytes.wrapping_sub(pr), elapsed);
+            let rate_w = per_sec(dev.write_bytes.wrapping_sub(pw), elapsed);
             println!("  {:<40} {:>14} {:>14} {:>14} {:>14}",
                 &dev.label,
                 fmt_bytes(rate_r, h), fmt_bytes(dev.read_bytes,  h),
@@ -565,59 +1151,53 @@ fn print_frame(
  * sleep `delay` seconds, take a fresh sample, and print rates against the
  * previous sample. Total samples taken = count + 1; total frames printed = count. */
 fn run_non_interactive(
-    handle: &BcachefsHandle,
+    live: &mut Live,
+    mount: &[u64],
     human_readable: bool,
     count: u32,
     delay: u32,
-    name_mode: DeviceNameMode,
 ) -> Result<()> {
-    let ioctl_fd   = handle.ioctl_fd();
-    let nr_stable  = COUNTERS.iter().map(|c| c.stable_id).max().unwrap_or(0) + 1;
-    let mount_vals = read_counters(ioctl_fd, BCH_IOCTL_QUERY_COUNTERS_MOUNT, nr_stable)?;
-    let sysfs_path = sysfs_path_from_fd(handle.sysfs_fd())?;
-
-    let mut prev_vals   = read_counters(ioctl_fd, 0, nr_stable)?;
-    let mut prev_dev_io: HashMap<String, (u64, u64)> = read_device_io(&sysfs_path, name_mode)
-        .into_iter()
-        .map(|d| (d.label, (d.read_bytes, d.write_bytes)))
-        .collect();
+    let mut prev = live.sample()?;
 
     for i in 0..count {
         std::thread::sleep(Duration::from_secs(delay as u64));
-        let curr   = read_counters(ioctl_fd, 0, nr_stable)?;
-        let dev_io = read_device_io(&sysfs_path, name_mode);
+        let curr = live.sample()?;
 
         if i > 0 { println!(); }
-        print_frame(&curr, &prev_vals, &mount_vals, &dev_io, &prev_dev_io, delay, human_readable);
+        print_frame(&curr, &prev, mount, human_readable);
 
-        prev_vals   = curr;
-        prev_dev_io = dev_io.into_iter()
-            .map(|d| (d.label, (d.read_bytes, d.write_bytes)))
-            .collect();
+        prev = curr;
     }
     Ok(())
 }
 
-fn run_interactive(
-    handle: BcachefsHandle,
-    human_readable: bool,
-    delay: u32,
-    name_mode: DeviceNameMode,
-) -> Result<()> {
-    let mut state = TopState::new(handle, human_readable, name_mode)?;
-    state.interval_secs = delay.max(1);
+/* Recording with no one watching: sample every `delay` seconds, `count` times
+ * or until killed, and print nothing. */
+fn run_record(live: &mut Live, count: u32, delay: u32) -> Result<()> {
+    let mut i = 0;
+    loop {
+        live.sample()?;
+        i += 1;
+        if count > 0 && i >= count { return Ok(()) }
+        std::thread::sleep(Duration::from_secs(delay as u64));
+    }
+}
+
+/* Replay without a terminal: every frame, against the sample before it. */
+fn print_recording(header: &RecordingHeader, samples: &[Sample], human_readable: bool) {
+    for (i, w) in samples.windows(2).enumerate() {
+        if i > 0 { println!(); }
+        println!("{}:", fmt_time_ms(w[1].t));
+        print_frame(&w[1], &w[0], &header.mount, human_readable);
+    }
+}
 
-    /* Sample once up front; prev == curr so the first frame shows zero rates.
-     * curr/dev_io persist across iterations: we only take a fresh sample (and
+fn run_interactive(mut state: TopState) -> Result<()> {
+    /* Samples persist across iterations: we only take a fresh sample (and
      * advance the rate baseline) on the interval timeout, never on a keypress -
      * otherwise scrolling would resample with ~no elapsed time and zero the
      * rate column. */
-    let mut curr = read_counters(state.handle.ioctl_fd(), 0, state.nr_stable)?;
-    state.prev_vals = curr.clone();
-    let mut dev_io = read_device_io(&state.sysfs_path, state.name_mode);
-    state.prev_dev_io = dev_io.iter()
-        .map(|d| (d.label.clone(), (d.read_bytes, d.write_bytes)))
-        .collect();
+    let mut last_tick = Instant::now();
 
     run_tui(move |stdout| loop {
         /* Live trace drill-down: tail the selected tracepoint instead of the
@@ -650,7 +1230,42 @@ fn run_interactive(
             continue;
         }
 
-        let total_rows = render(&mut state, &curr, &dev_io, stdout)?;
+        /* IO attribution: same polling as the trace view, but the events are
+         * folded into per-process and per-inode totals instead of shown. */
+        if let Some(a) = state.attr.as_mut() {
+            a.drain();
+            render_attribution(a, state.human_readable, stdout)?;
+
+            if event::poll(Duration::from_millis(200))? {
+                if let Event::Key(key) = event::read()? {
+                    let a = state.attr.as_mut().unwrap();
+                    match key.code {
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                        KeyCode::Char('q') | KeyCode::Esc | KeyCode::Backspace => state.attr = None,
+                        KeyCode::Tab | KeyCode::BackTab => {
+                            let i = AttrView::ALL.iter().position(|&v| v == a.view).unwrap_or(0);
+                            let n = AttrView::ALL.len();
+                            let back = key.code == KeyCode::BackTab || key.modifiers.contains(KeyModifiers::SHIFT);
+                            a.view = AttrView::ALL[if back { (i + n - 1) % n } else { (i + 1) % n }];
+                            a.scroll = 0;
+                        }
+                        KeyCode::Char('s') => a.sort = (a.sort + 1) % ATTR_SORT_COLUMNS.len(),
+                        KeyCode::Char('z') => a.reset(),
+                        KeyCode::Char('h') => state.human_readable = !state.human_readable,
+                        KeyCode::Up       => a.scroll = a.scroll.saturating_sub(1),
+                        KeyCode::Down     => a.scroll = a.scroll.saturating_add(1),
+                        KeyCode::PageUp   => a.scroll = a.scroll.saturating_sub(20),
+                        KeyCode::PageDown => a.scroll = a.scroll.saturating_add(20),
+                        KeyCode::Home     => a.scroll = 0,
+                        _ => {}
+                    }
+                }
+                while event::poll(Duration::ZERO)? { let _ = event::read()?; }
+            }
+            continue;
+        }
+
+        let total_rows = render(&mut state, stdout)?;
 
         /* Clamp cursor to current page's row count (e.g. counters can drop
          * out from under us when v_mount goes back to zero between ticks). */
@@ -658,7 +1273,16 @@ fn run_interactive(
             state.cursor = total_rows - 1;
         }
 
-        if event::poll(Duration::from_secs(state.interval_secs as u64))? {
+        let replaying = state.live.is_none();
+        let timeout = if !replaying {
+            Duration::from_secs(state.interval_secs as u64)
+        } else if state.playing {
+            Duration::from_millis(100)
+        } else {
+            Duration::from_secs(3600)
+        };
+
+        if event::poll(timeout)? {
             if let Event::Key(key) = event::read()? {
                 let (_, term_h) = terminal::size().unwrap_or((120, 40));
                 let page_step = (term_h as usize).saturating_sub(1).max(1);
@@ -667,14 +1291,26 @@ fn run_interactive(
 
                 match key.code {
                     KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Enter if replaying => {
+                        state.status = Some("live trace isn't available when replaying".into());
+                    }
                     KeyCode::Enter => {
-                        if let Some(name) = state.counter_at_cursor(&curr) {
+                        if let Some(name) = state.counter_at_cursor() {
                             match TraceView::start(&name, &state.fs_name) {
                                 Ok(tv) => state.trace = Some(tv),
                                 Err(e) => state.status = Some(format!("trace start failed: {e:#}")),
                             }
                         }
                     }
+                    KeyCode::Char('a') if replaying => {
+                        state.status = Some("IO attribution isn't available when replaying".into());
+                    }
+                    KeyCode::Char('a') => {
+                        match Attribution::start(&state.fs_name) {
+                            Ok(a) => state.attr = Some(a),
+                            Err(e) => state.status = Some(format!("IO attribution failed: {e:#}")),
+                        }
+                    }
                     KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
                     KeyCode::Tab => {
                         state.page = if key.modifiers.contains(KeyModifiers::SHIFT) {
@@ -700,51 +1336,90 @@ fn run_interactive(
                     },
                     KeyCode::Home => state.cursor = 0,
                     KeyCode::End  => if total_rows > 0 { state.cursor = total_rows - 1; },
+                    KeyCode::Left      => state.seek(-1),
+                    KeyCode::Right     => state.seek(1),
+                    KeyCode::Char('<') => state.seek(-60),
+                    KeyCode::Char('>') => state.seek(60),
+                    KeyCode::Char('g') => state.seek(isize::MIN),
+                    KeyCode::Char('G') => state.seek(isize::MAX),
+                    KeyCode::Char(' ') if replaying => {
+                        state.playing = !state.playing && state.pos + 1 < state.samples.len();
+                        state.replay_clock = state.curr().t;
+                        last_tick = Instant::now();
+                    }
+                    KeyCode::Char('r') => state.window = (state.window + 1) % RATE_WINDOWS.len(),
                     KeyCode::Char('h') => state.human_readable = !state.human_readable,
                     KeyCode::Char(c @ '1'..='9') => {
-                        state.interval_secs = (c as u32) - ('0' as u32);
+                        let n = (c as u32) - ('0' as u32);
+                        if replaying {
+                            state.speed = n as usize - 1;
+                        } else {
+                            state.interval_secs = n;
+                        }
                     }
                     _ => {}
                 }
             }
             while event::poll(Duration::ZERO)? { let _ = event::read()?; }
-        } else {
-            /* Interval elapsed with no input: advance the rate baseline and
-             * take a fresh sample. (Keypresses fall through here only on
-             * timeout, so scrolling never resamples.) */
-            state.prev_vals = curr;
-            state.prev_dev_io = dev_io.into_iter()
-                .map(|d| (d.label, (d.read_bytes, d.write_bytes)))
-                .collect();
-            curr = read_counters(state.handle.ioctl_fd(), 0, state.nr_stable)?;
-            dev_io = read_device_io(&state.sysfs_path, state.name_mode);
+        } else if !replaying {
+            /* Interval elapsed with no input: take a fresh sample. (Keypresses
+             * fall through here only on timeout, so scrolling never
+             * resamples.) */
+            state.sample_live()?;
+        }
+
+        if replaying && state.playing {
+            state.advance_replay(last_tick.elapsed());
         }
+        last_tick = Instant::now();
     })
 }
 
 fn top(cli: Cli) -> Result<()> {
-
-    let fs_arg = cli.filesystem.as_deref().unwrap_or(".");
-    let handle = BcachefsHandle::open(fs_arg)
-        .with_context(|| format!("opening filesystem '{}'", fs_arg))?;
-
-    let delay = cli.delay.max(1);
-
     /* --once is shorthand for -n 1; if we're not on a TTY default to one
      * frame so piped output is sane. Otherwise count > 0 means N frames
      * and exit; count == 0 means run the interactive TUI. */
+    let tty = io::stdout().is_terminal();
     let count = if cli.once { 1 }
                 else if cli.count > 0 { cli.count }
-                else if !io::stdout().is_terminal() { 1 }
+                else if !tty { 1 }
                 else { 0 };
 
+    if let Some(path) = cli.replay.as_deref() {
+        let (header, samples) = recording_load(path)?;
+
+        if count > 0 {
+            print_recording(&header, &samples, cli.human_readable);
+            return Ok(());
+        }
+
+        let mut state = TopState::new(None, header, samples, cli.human_readable);
+        state.replay_path = Some(path.to_path_buf());
+        return run_interactive(state);
+    }
+
+    let fs_arg = cli.filesystem.as_deref().unwrap_or(".");
+    let handle = BcachefsHandle::open(fs_arg)
+        .with_context(|| format!("opening filesystem '{}'", fs_arg))?;
+
+    let delay = cli.delay.max(1);
     let name_mode = cli.device_names.name_mode();
 
+    let (mut live, header) = Live::open(handle, name_mode, cli.record.as_deref())?;
+
+    if cli.record.is_some() && !tty && !cli.once {
+        return run_record(&mut live, cli.count, delay);
+    }
+
     if count > 0 {
-        run_non_interactive(&handle, cli.human_readable, count, delay, name_mode)
-    } else {
-        run_interactive(handle, cli.human_readable, delay, name_mode)
+        return run_non_interactive(&mut live, &header.mount, cli.human_readable, count, delay);
     }
+
+    /* Sample once up front; prev == curr so the first frame shows zero rates. */
+    let first = live.sample()?;
+    let mut state = TopState::new(Some(live), header, vec![first], cli.human_readable);
+    state.interval_secs = delay;
+    run_interactive(state)
 }
 
 pub const CMD: super::CmdDef = typed_cmd!("top", "Show live performance counters", Cli, top);
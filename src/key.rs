@@ -1,9 +1,9 @@
 use std::{
     ffi::{CStr, CString},
     fs,
-    io::{stdin, IsTerminal},
+    io::{stdin, IsTerminal, Read},
     mem,
-    path::Path,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     ptr, thread,
     time::Duration,
@@ -11,8 +11,9 @@ use std::{
 
 use anyhow::{anyhow, bail, ensure, Result};
 use bcachefs_kernel::c::{
-    bch_key, bch_sb_handle,
-    bch2_chacha20, bch_encrypted_key, bch_sb_field_crypt,
+    bch_key, bch_key_server, bch_key_slot, bch_key_slot_type, bch_sb, bch_sb_handle,
+    bch2_chacha20, bch_encrypted_key, bch_sb_field_crypt, bch_sb_field_key_servers,
+    bch_sb_field_key_slots, nonce,
 };
 use bch_bindgen::keyutils::{self, keyctl_search};
 use log::info;
@@ -63,10 +64,23 @@ pub enum UnlockPolicy {
     Ask,
     /// Try to read the passphrase from `stdin` without prompting
     Stdin,
+    /// Try keyfile slots with the keyfiles in /etc/bcachefs/keys (or the
+    /// --keyfile path), then prompt for a passphrase
+    Keyfile,
+    /// Unlock a network-bound key slot via its key server, retrying while the
+    /// network comes up, then prompt for a passphrase
+    Network,
 }
 
+/// How long the network unlock policy keeps trying key servers before giving
+/// up and prompting: at boot, the network may not be up yet.
+const NETWORK_UNLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where the keyfile unlock policy looks for keyfiles, if not told otherwise.
+pub const DEFAULT_KEYFILE_PATH: &str = "/etc/bcachefs/keys";
+
 impl UnlockPolicy {
-    pub fn apply(&self, sb: &bch_sb_handle) -> Result<KeyHandle> {
+    pub fn apply(&self, sb: &bch_sb_handle, keyfile: Option<&Path>) -> Result<KeyHandle> {
         let uuid = sb.sb().uuid();
 
         info!("Using filesystem unlock policy '{self}' on {uuid}");
@@ -88,6 +102,49 @@ impl UnlockPolicy {
                     .ok_or_else(|| anyhow!("incorrect passphrase"))?;
                 KeyHandle::new(&passphrase_correct, Keyring::User)
             }
+            Self::Keyfile => {
+                if let Ok(handle) = KeyHandle::new_from_search(&uuid) {
+                    return Ok(handle);
+                }
+
+                // The default location not existing just means there are no
+                // keyfiles; one that was asked for not existing is an error:
+                let path = keyfile.unwrap_or(Path::new(DEFAULT_KEYFILE_PATH));
+                if keyfile.is_some() || path.exists() {
+                    if let Some(passphrase_correct) = keyfile_unlock(sb, path)? {
+                        return KeyHandle::new(&passphrase_correct, Keyring::User);
+                    }
+                    info!("No keyfile in {} unlocks {uuid}", path.display());
+                }
+
+                let passphrase_correct = Passphrase::ask_and_check(sb)?;
+                KeyHandle::new(&passphrase_correct, Keyring::User)
+            }
+            Self::Network => {
+                if let Ok(handle) = KeyHandle::new_from_search(&uuid) {
+                    return Ok(handle);
+                }
+
+                let start = std::time::Instant::now();
+                loop {
+                    match network_unlock(sb) {
+                        Ok(Some(passphrase_correct)) =>
+                            return KeyHandle::new(&passphrase_correct, Keyring::User),
+                        Ok(None) => break,
+                        Err(e) if start.elapsed() >= NETWORK_UNLOCK_TIMEOUT => {
+                            info!("Giving up on key servers: {e:#}");
+                            break;
+                        }
+                        Err(e) => {
+                            info!("{e:#}, retrying");
+                            thread::sleep(Duration::from_secs(2));
+                        }
+                    }
+                }
+
+                let passphrase_correct = Passphrase::ask_and_check(sb)?;
+                KeyHandle::new(&passphrase_correct, Keyring::User)
+            }
         }
     }
 }
@@ -307,22 +364,35 @@ impl Passphrase {
         &self,
         sb: &bch_sb_handle,
         key: bch_key,
+    ) -> bch_encrypted_key {
+        let seq = sb.sb().crypt().expect("called on encrypted fs").key_seq();
+        self.encrypt_key_seq(sb, key, seq)
+    }
+
+    /// As encrypt_key(), but for key sequence number `seq` - key_seq - 1 is
+    /// for crypt->old_key, while a key rotation is in progress.
+    pub fn encrypt_key_seq(
+        &self,
+        sb: &bch_sb_handle,
+        key: bch_key,
+        seq: u64,
     ) -> bch_encrypted_key {
         let crypt = sb.sb().crypt().expect("called on encrypted fs");
-        let mut new_key = bch_encrypted_key::new_unencrypted(key);
-        
-        let mut passphrase_key: bch_key = self.derive(crypt);
+        wrap_key(sb, self.derive(crypt), key, seq)
+    }
 
-        unsafe {
-            bch2_chacha20(
-                ptr::addr_of_mut!(passphrase_key),
-                sb.sb().nonce(),
-                ptr::addr_of_mut!(new_key).cast(),
-                mem::size_of_val(&new_key),
-            )
+    /// Prompt for (or read from stdin) a passphrase that unlocks the
+    /// filesystem, and keep it: for commands that change the passphrase or
+    /// key slots.
+    pub fn ask_current(sb: &bch_sb_handle) -> Result<(Self, PassphraseCorrect)> {
+        let passphrase = if stdin().is_terminal() {
+            Self::ask_in_terminal()?
+        } else {
+            Self::read_from_stdin()?
         };
-
-        new_key
+        let passphrase_correct = passphrase.check(sb)
+            .ok_or_else(|| anyhow!("incorrect passphrase"))?;
+        Ok((passphrase, passphrase_correct))
     }
 
     pub fn check(&self, sb: &bch_sb_handle) -> Option<PassphraseCorrect> {
@@ -335,9 +405,63 @@ impl Passphrase {
             "sb_key should be encrypted when calling Passphrase::check",
         );
 
-        let mut passphrase_key: bch_key = self.derive(crypt);
+        // Not the passphrase the master key is wrapped with, but it may be
+        // one of the others - or a recovery key, which is typed in the same
+        // way:
+        PassphraseCorrect::from_passphrase_key(sb, self.derive(crypt))
+            .or_else(|| key_slots_unlock(sb, self.get().to_bytes(), &[
+                bch_key_slot_type::BCH_KEY_SLOT_passphrase,
+                bch_key_slot_type::BCH_KEY_SLOT_recovery,
+            ]))
+    }
+}
+
+pub struct PassphraseCorrect {
+    pub fs_uuid:          Uuid,
+    pub passphrase_key:   bch_key,
+    pub cleartext_sb_key: bch_encrypted_key,
+    /// The key slot that unlocked the filesystem, if it wasn't the passphrase
+    /// the master key is wrapped with directly
+    pub slot:             Option<usize>,
+}
+
+/// Wrap `key` for crypt->key (or, with `seq` = key_seq - 1, crypt->old_key)
+/// with `wrapping_key` - what goes in the keyring.
+pub fn wrap_key(
+    sb: &bch_sb_handle,
+    mut wrapping_key: bch_key,
+    key: bch_key,
+    seq: u64,
+) -> bch_encrypted_key {
+    let mut new_key = bch_encrypted_key::new_unencrypted(key);
+
+    unsafe {
+        bch2_chacha20(
+            ptr::addr_of_mut!(wrapping_key),
+            sb.sb().key_nonce(seq),
+            ptr::addr_of_mut!(new_key).cast(),
+            mem::size_of_val(&new_key),
+        )
+    };
+
+    new_key
+}
+
+/// A new random key, for wrapping the master key with once there are key
+/// slots.
+pub fn wrapping_key_new() -> Result<bch_key> {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes[..])?;
+
+    let d = |i: usize| u64::from_ne_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+    Ok(bch_key { key: [d(0), d(1), d(2), d(3)] })
+}
 
-        let mut cleartext_sb_key = crypt.key().clone();
+impl PassphraseCorrect {
+    /// Check a passphrase key - what the master key is wrapped with, and what
+    /// goes in the keyring - by unwrapping the master key with it.
+    fn from_passphrase_key(sb: &bch_sb_handle, mut passphrase_key: bch_key) -> Option<Self> {
+        let mut cleartext_sb_key = sb.sb().crypt()?.key().clone();
         unsafe {
             bch2_chacha20(
                 ptr::addr_of_mut!(passphrase_key),
@@ -354,14 +478,189 @@ impl Passphrase {
             fs_uuid: sb.sb().uuid(),
             passphrase_key,
             cleartext_sb_key,
+            slot: None,
         })
     }
 }
 
-pub struct PassphraseCorrect {
-    pub fs_uuid:          Uuid,
-    pub passphrase_key:   bch_key,
-    pub cleartext_sb_key: bch_encrypted_key,
+// ---- key slots ----
+//
+// Once a filesystem has key slots, the master key is wrapped with a random key
+// and each slot - a passphrase, a keyfile's contents, or a recovery key - holds
+// its own copy of that key, so that unlocking with any of them puts the same
+// key in the keyring. See struct bch_key_slot.
+
+pub fn key_slots(sb: &bch_sb) -> &[bch_key_slot] {
+    sb.field::<bch_sb_field_key_slots>()
+        .map(|f| f.slots())
+        .unwrap_or(&[])
+}
+
+/// The key a slot is wrapped with is already unique to the slot - its salt is
+/// random - so the nonce only has to be fixed: use the salt.
+fn key_slot_nonce(slot: &bch_key_slot) -> nonce {
+    let d = |i: usize| u32::from_ne_bytes(slot.salt[i * 4..i * 4 + 4].try_into().unwrap());
+    nonce { d: [d(0), d(1), d(2), d(3)] }
+}
+
+/// Wrap or unwrap (it's symmetric) a slot's contents with `secret`.
+fn key_slot_crypt(slot: &bch_key_slot, secret: &[u8], key: &mut bch_encrypted_key) {
+    let slot_ptr = (slot as *const bch_key_slot).cast_mut();
+    let mut slot_key: bch_key = unsafe {
+        bch_bindgen::c::derive_key_slot(slot_ptr, secret.as_ptr().cast(), secret.len())
+    };
+
+    unsafe {
+        bch2_chacha20(
+            ptr::addr_of_mut!(slot_key),
+            key_slot_nonce(slot),
+            ptr::addr_of_mut!(*key).cast(),
+            mem::size_of_val(key),
+        )
+    };
+}
+
+/// Try `secret` against each key slot of one of `types`.
+pub fn key_slots_unlock(
+    sb: &bch_sb_handle,
+    secret: &[u8],
+    types: &[bch_key_slot_type],
+) -> Option<PassphraseCorrect> {
+    key_slots(sb.sb())
+        .iter()
+        .enumerate()
+        .filter(|(_, slot)| types.contains(&slot.slot_type()))
+        .find_map(|(i, slot)| {
+            let mut key = slot.key.clone();
+            key_slot_crypt(slot, secret, &mut key);
+            if key.is_encrypted() {
+                return None;
+            }
+            PassphraseCorrect::from_passphrase_key(sb, key.into_key())
+                .map(|mut p| { p.slot = Some(i); p })
+        })
+}
+
+/// A new key slot, that gets whoever has `secret` the key the master key is
+/// wrapped with.
+pub fn key_slot_new(
+    slot_type: bch_key_slot_type,
+    label: &str,
+    secret: &[u8],
+    passphrase_key: &bch_key,
+) -> Result<bch_key_slot> {
+    let mut slot = bch_key_slot {
+        flags:      0,
+        kdf_flags:  0,
+        salt:       [0; 16],
+        label:      [0; 32],
+        key:        bch_encrypted_key::new_unencrypted(passphrase_key.clone()),
+    };
+
+    ensure!(label.len() <= slot.label.len(),
+            "label too long (max {} bytes)", slot.label.len());
+    slot.label[..label.len()].copy_from_slice(label.as_bytes());
+
+    unsafe { bch_bindgen::c::bch_key_slot_kdf_init(&mut slot) };
+    slot.flags = (u64::from_le(slot.flags) | slot_type.0 as u64).to_le();
+
+    let mut key = slot.key.clone();
+    key_slot_crypt(&slot, secret, &mut key);
+    slot.key = key;
+
+    Ok(slot)
+}
+
+/// Try the keyfile slots with the keyfile at `path`, or with each file in it if
+/// it's a directory.
+pub fn keyfile_unlock(sb: &bch_sb_handle, path: &Path) -> Result<Option<PassphraseCorrect>> {
+    let files: Vec<PathBuf> = if path.is_dir() {
+        let mut files = fs::read_dir(path)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    for file in files {
+        let secret = Zeroizing::new(fs::read(&file)
+            .map_err(|e| anyhow!("reading keyfile {}: {e}", file.display()))?);
+
+        if let Some(passphrase_correct) =
+            key_slots_unlock(sb, &secret, &[bch_key_slot_type::BCH_KEY_SLOT_keyfile]) {
+            info!("Unlocked with keyfile {}", file.display());
+            return Ok(Some(passphrase_correct));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn key_servers(sb: &bch_sb) -> &[bch_key_server] {
+    sb.field::<bch_sb_field_key_servers>()
+        .map(|f| f.servers())
+        .unwrap_or(&[])
+}
+
+/// The key server entry for a network-bound key slot.
+pub fn key_slot_server<'a>(sb: &'a bch_sb, slot: &bch_key_slot) -> Option<&'a bch_key_server> {
+    key_servers(sb).iter().find(|s| s.slot_salt == slot.salt)
+}
+
+/// Try the network-bound key slots, asking each one's key server for its
+/// secret. Ok(None) if there are none, or if none unlock the filesystem; an
+/// error if no key server could be reached.
+pub fn network_unlock(sb: &bch_sb_handle) -> Result<Option<PassphraseCorrect>> {
+    let mut err = None;
+    let mut tried = false;
+
+    for slot in key_slots(sb.sb()) {
+        if slot.slot_type() != bch_key_slot_type::BCH_KEY_SLOT_network {
+            continue;
+        }
+        let Some(server) = key_slot_server(sb.sb(), slot) else { continue };
+        let addr = String::from_utf8_lossy(server.addr()).into_owned();
+
+        match crate::key_server::recover(&addr, &server.server_pk, &server.client_pk) {
+            Ok(secret) => {
+                tried = true;
+                let mut key = slot.key.clone();
+                key_slot_crypt(slot, &secret[..], &mut key);
+                if !key.is_encrypted() {
+                    if let Some(passphrase_correct) =
+                        PassphraseCorrect::from_passphrase_key(sb, key.into_key()) {
+                        info!("Unlocked via key server {addr}");
+                        return Ok(Some(passphrase_correct));
+                    }
+                }
+            }
+            Err(e) => err = Some(e),
+        }
+    }
+
+    match err {
+        Some(e) if !tried => Err(e),
+        _ => Ok(None),
+    }
+}
+
+/// A new random recovery key, as hex in dash-separated groups of 8 digits.
+/// It's entered in place of a passphrase, so it's kept to what's easy to type
+/// and read back.
+pub fn recovery_key_new() -> Result<Zeroizing<String>> {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes[..])?;
+
+    let hex: Zeroizing<String> = Zeroizing::new(bytes.iter().map(|b| format!("{b:02x}")).collect());
+    Ok(Zeroizing::new(hex.as_bytes()
+        .chunks(8)
+        .map(|c| std::str::from_utf8(c).unwrap())
+        .collect::<Vec<_>>()
+        .join("-")))
 }
 
 /// A passphrase is the one question a pipe can answer, which is why this
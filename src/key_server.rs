@@ -0,0 +1,325 @@
+//! Key server protocol, for network-bound key slots.
+//!
+//! A network-bound key slot is unlocked with a secret only a key server can
+//! compute, so a machine that can reach its key server - the one on its own
+//! network - unlocks without anyone typing a passphrase, while disks that leave
+//! the network don't. The server keeps no per-client state: it has one X25519
+//! keypair, and answers anyone who asks (see c_src/crypto.c for the crypto).
+//!
+//! The protocol is HTTP/1.0, with bodies of lowercase hex fields separated by
+//! spaces - easy to poke at with curl:
+//!
+//!   GET /adv
+//!       200: <server public key>
+//!
+//!   POST /rec
+//!       body: <client public key> <ephemeral public key>
+//!       200:  <nonce> <sealed secret>
+//!
+//! Provisioning a slot fetches the server's public key from /adv - printed so
+//! it can be checked, or pinned in advance with --server-key - generates a
+//! client keypair and computes the slot secret from the client private key and
+//! the server public key. The slot stores the server's address and public key,
+//! and the client public key; the client private key is thrown away.
+//!
+//! Unlocking posts the stored client public key to /rec along with a fresh
+//! ephemeral public key; the server computes the slot secret from its private
+//! key and the client public key, and seals it to the ephemeral key. Opening
+//! the seal checks that it came from the server key the slot has pinned.
+//!
+//! The server is trusted: it computes every slot secret in the clear to seal
+//! it, so whoever controls the server, or has a copy of its private key, can
+//! unlock every network-bound slot provisioned against it - given the disk.
+//! There is no McCallum-Relyea style blinding (as in Tang), which would hide
+//! the secret from the server; run it only on a machine as trusted as the
+//! disks' own passphrases.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use log::{error, info};
+use zeroize::Zeroizing;
+
+pub const DEFAULT_PORT: u16 = 7436;
+
+const KEY_BYTES: usize      = 32;
+const SECRET_BYTES: usize   = 32;
+const NONCE_BYTES: usize    = 24;
+const SEALED_BYTES: usize   = SECRET_BYTES + 16;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type PublicKey = [u8; KEY_BYTES];
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn hex_decode<const N: usize>(s: &str) -> Result<[u8; N]> {
+    ensure!(s.len() == N * 2 && s.is_ascii(), "expected {} hex digits, got {:?}", N * 2, s);
+
+    let mut out = [0u8; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("bad hex: {s:?}"))?;
+    }
+    Ok(out)
+}
+
+/// host:port, with the default port if none was given.
+pub fn normalize_addr(addr: &str) -> String {
+    let has_port = match addr.rsplit_once(':') {
+        Some((host, port)) => !host.ends_with(':') && port.parse::<u16>().is_ok(),
+        None => false,
+    };
+
+    if has_port { addr.to_string() } else { format!("{addr}:{DEFAULT_PORT}") }
+}
+
+fn keypair() -> Result<(PublicKey, Zeroizing<[u8; KEY_BYTES]>)> {
+    let mut pk = [0u8; KEY_BYTES];
+    let mut sk = Zeroizing::new([0u8; KEY_BYTES]);
+
+    let ret = unsafe { bch_bindgen::c::key_server_keypair(pk.as_mut_ptr(), sk.as_mut_ptr()) };
+    ensure!(ret == 0, "error generating key server keypair");
+    Ok((pk, sk))
+}
+
+fn secret(sk: &[u8; KEY_BYTES], pk: &PublicKey) -> Result<Zeroizing<[u8; SECRET_BYTES]>> {
+    let mut secret = Zeroizing::new([0u8; SECRET_BYTES]);
+
+    let ret = unsafe {
+        bch_bindgen::c::key_server_secret(secret.as_mut_ptr(), sk.as_ptr(), pk.as_ptr())
+    };
+    ensure!(ret == 0, "bad public key");
+    Ok(secret)
+}
+
+// ---- client ----
+
+/// One HTTP/1.0 request; returns the response body, if the status was 200.
+fn request(addr: &str, method: &str, path: &str, body: &str) -> Result<String> {
+    let sock_addr = addr.to_socket_addrs()
+        .with_context(|| format!("resolving {addr}"))?
+        .next()
+        .ok_or_else(|| anyhow!("{addr}: no address"))?;
+
+    let mut stream = TcpStream::connect_timeout(&sock_addr, TIMEOUT)
+        .with_context(|| format!("connecting to {addr}"))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    write!(stream, "{method} {path} HTTP/1.0\r\nHost: {addr}\r\nContent-Length: {}\r\n\r\n{body}",
+           body.len())?;
+
+    let mut response = String::new();
+    stream.take(4096).read_to_string(&mut response)
+        .with_context(|| format!("reading response from {addr}"))?;
+
+    let (head, body) = response.split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("{addr}: malformed response"))?;
+    let status = head.split(' ').nth(1).unwrap_or("");
+    if status != "200" {
+        bail!("{addr}: {}", head.lines().next().unwrap_or(""));
+    }
+
+    Ok(body.trim().to_string())
+}
+
+/// The key server's public key, from its advertisement.
+pub fn fetch_server_key(addr: &str) -> Result<PublicKey> {
+    hex_decode(&request(addr, "GET", "/adv", "")?)
+}
+
+/// New slot parameters for the server with public key `server_pk`: the client
+/// public key to store in the slot, and the slot secret.
+pub fn provision(server_pk: &PublicKey) -> Result<(PublicKey, Zeroizing<[u8; SECRET_BYTES]>)> {
+    let (client_pk, client_sk) = keypair()?;
+    Ok((client_pk, secret(&client_sk, server_pk)?))
+}
+
+/// Ask the key server for a slot's secret.
+pub fn recover(addr: &str, server_pk: &PublicKey, client_pk: &PublicKey)
+    -> Result<Zeroizing<[u8; SECRET_BYTES]>>
+{
+    let (eph_pk, eph_sk) = keypair()?;
+
+    let body = request(addr, "POST", "/rec",
+                       &format!("{} {}", hex_encode(client_pk), hex_encode(&eph_pk)))?;
+    let (nonce, sealed) = body.split_once(' ')
+        .ok_or_else(|| anyhow!("{addr}: malformed response"))?;
+    let nonce: [u8; NONCE_BYTES] = hex_decode(nonce)?;
+    let sealed: [u8; SEALED_BYTES] = hex_decode(sealed)?;
+
+    let mut secret = Zeroizing::new([0u8; SECRET_BYTES]);
+    let ret = unsafe {
+        bch_bindgen::c::key_server_open(secret.as_mut_ptr(), sealed.as_ptr(), nonce.as_ptr(),
+                                        server_pk.as_ptr(), eph_sk.as_ptr())
+    };
+    ensure!(ret == 0, "{addr}: response not from the key server this slot was made with");
+    Ok(secret)
+}
+
+// ---- server ----
+
+/// The server's keypair, from `path`: 32 bytes of private key, generated if it
+/// doesn't exist yet.
+pub fn server_key_load(path: &Path) -> Result<(PublicKey, Zeroizing<[u8; KEY_BYTES]>)> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if !path.exists() {
+        let (pk, sk) = keypair()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o400)
+            .open(path)
+            .and_then(|mut f| f.write_all(&sk[..]))
+            .with_context(|| format!("creating {}", path.display()))?;
+        info!("Generated key server key {}", path.display());
+        return Ok((pk, sk));
+    }
+
+    let bytes = Zeroizing::new(std::fs::read(path)
+        .with_context(|| format!("reading {}", path.display()))?);
+    let sk: Zeroizing<[u8; KEY_BYTES]> = Zeroizing::new(bytes[..].try_into()
+        .map_err(|_| anyhow!("{}: not a key server key", path.display()))?);
+
+    let mut pk = [0u8; KEY_BYTES];
+    let ret = unsafe { bch_bindgen::c::key_server_public_key(pk.as_mut_ptr(), sk.as_ptr()) };
+    ensure!(ret == 0, "{}: not a key server key", path.display());
+    Ok((pk, sk))
+}
+
+fn handle_rec(body: &str, sk: &[u8; KEY_BYTES]) -> Result<String> {
+    let (client_pk, eph_pk) = body.trim().split_once(' ')
+        .ok_or_else(|| anyhow!("malformed request"))?;
+    let client_pk: PublicKey = hex_decode(client_pk)?;
+    let eph_pk: PublicKey = hex_decode(eph_pk)?;
+
+    let secret = secret(sk, &client_pk)?;
+
+    let mut nonce = [0u8; NONCE_BYTES];
+    let mut sealed = [0u8; SEALED_BYTES];
+    let ret = unsafe {
+        bch_bindgen::c::key_server_seal(sealed.as_mut_ptr(), nonce.as_mut_ptr(), secret.as_ptr(),
+                                        eph_pk.as_ptr(), sk.as_ptr())
+    };
+    ensure!(ret == 0, "bad ephemeral key");
+
+    Ok(format!("{} {}", hex_encode(&nonce), hex_encode(&sealed)))
+}
+
+/// Connections served at once: each is one short request, and a client that
+/// stalls holds its thread only until the deadline.
+const SERVE_THREADS: usize = 8;
+
+/// Most a request may take to arrive, start to finish - read timeouts alone
+/// would let a client that trickles a byte at a time hold a thread forever.
+const REQUEST_DEADLINE: Duration = Duration::from_secs(10);
+
+const REQUEST_MAX: usize = 8192;
+
+/// Read one HTTP/1.0 request: method, path and body.
+fn read_request(stream: &mut TcpStream) -> Result<(String, String, String)> {
+    let start = Instant::now();
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    let head_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        ensure!(buf.len() < REQUEST_MAX, "request too big");
+        ensure!(start.elapsed() < REQUEST_DEADLINE, "request timed out");
+
+        let n = stream.read(&mut chunk)?;
+        ensure!(n > 0, "connection closed mid-request");
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = std::str::from_utf8(&buf[..head_end])
+        .map_err(|_| anyhow!("malformed request"))?
+        .to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("").to_string();
+    let path = request_line.next().unwrap_or("").to_string();
+
+    let len = lines
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, v)| v.trim().parse::<usize>())
+        .transpose()
+        .map_err(|_| anyhow!("bad content-length"))?
+        .unwrap_or(0);
+    ensure!(head_end + 4 + len <= REQUEST_MAX, "request too big");
+
+    let mut body = buf.split_off(head_end + 4);
+    while body.len() < len {
+        ensure!(start.elapsed() < REQUEST_DEADLINE, "request timed out");
+
+        let n = stream.read(&mut chunk)?;
+        ensure!(n > 0, "connection closed mid-request");
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(len);
+
+    let body = String::from_utf8(body).map_err(|_| anyhow!("malformed request"))?;
+    Ok((method, path, body))
+}
+
+fn serve_conn(mut stream: TcpStream, pk: &PublicKey, sk: &[u8; KEY_BYTES]) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let (status, response) = match read_request(&mut stream) {
+        Ok((method, path, body)) => {
+            info!("{method} {path} from {:?}", stream.peer_addr().ok());
+
+            match (method.as_str(), path.as_str()) {
+                ("GET", "/adv") => ("200 OK", hex_encode(pk)),
+                ("POST", "/rec") => match handle_rec(&body, sk) {
+                    Ok(r) => ("200 OK", r),
+                    Err(e) => ("400 Bad Request", e.to_string()),
+                },
+                _ => ("404 Not Found", "not found".to_string()),
+            }
+        }
+        Err(e) => ("400 Bad Request", e.to_string()),
+    };
+
+    write!(stream,
+           "HTTP/1.0 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{response}",
+           response.len())?;
+    Ok(())
+}
+
+/// Serve /adv and /rec on `listen` until killed, from a fixed pool of threads.
+pub fn serve(listen: &str, pk: &PublicKey, sk: &[u8; KEY_BYTES]) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .with_context(|| format!("listening on {listen}"))?;
+
+    std::thread::scope(|s| {
+        for _ in 0..SERVE_THREADS {
+            s.spawn(|| loop {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        if let Err(e) = serve_conn(stream, pk, sk) {
+                            error!("{addr}: {e:#}");
+                        }
+                    }
+                    Err(e) => error!("accepting connection: {e}"),
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
@@ -70,7 +70,7 @@ const STATX_SUBVOL: libc::c_uint = 0x8000;
 /// bcachefs reports the superblock's device for every subvolume in it, so the
 /// pair changes if and only if we cross into a different filesystem or a
 /// different subvolume.
-fn path_subvol(path: &Path) -> Option<(u64, u64)> {
+pub(crate) fn path_subvol(path: &Path) -> Option<(u64, u64)> {
     use std::os::unix::ffi::OsStrExt;
 
     let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
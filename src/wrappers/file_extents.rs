@@ -0,0 +1,226 @@
+//! A file's data extents on a mounted filesystem, via
+//! BCH_IOCTL_QUERY_BTREE_KEYS: the extents btree in the snapshot the file
+//! is seen through, with reflink pointers resolved to the indirect extents
+//! behind them - that's where the replicas and the poison flag live.
+//!
+//! Holes, reservations and inline data have no replicas and are skipped.
+
+use std::mem;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use bcachefs_kernel::btree::bkey::{pos, spos, BkeyValSC};
+use bcachefs_kernel::c;
+use bcachefs_kernel::data::extents::{bkey_extent_flags_sc, bkey_ptrs_sc, bkey_reconcile_opts_sc};
+
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::online_iter::{OnlineBtreeIter, OnlineIterFlags};
+
+pub struct FileExtent {
+    /// Byte range in the file, [start, end)
+    pub start:     u64,
+    pub end:       u64,
+    /// Devices with a pointer to this extent, cached copies included
+    pub devs:      Vec<u32>,
+    /// Those of @devs with only a cached copy: possibly stale, since a
+    /// cached pointer's bucket may be reused without updating the extent
+    pub cached:    Vec<u32>,
+    pub poisoned:  bool,
+    pub reconcile: ExtentReconcile,
+}
+
+/// Background work still pending on an extent, from its
+/// bch_extent_reconcile entry - all clear if it has none.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExtentReconcile {
+    /// BIT(BCH_RECONCILE_*) for each option the data doesn't match yet
+    pub need_rb: u8,
+    /// Can't be done until the system configuration changes
+    pub pending: bool,
+    /// Evacuating or rereplicating: processed ahead of other work
+    pub hipri:   bool,
+}
+
+/// The mounted filesystem @path is on. BcachefsHandle::open() takes a
+/// regular file for an image, so go through the directory holding it.
+pub fn open_fs_of(path: &Path) -> Result<BcachefsHandle> {
+    let dir = if path.is_dir() {
+        path
+    } else {
+        path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."))
+    };
+
+    BcachefsHandle::open(dir)
+        .map_err(|e| anyhow!("opening filesystem at {}: {}", dir.display(), e))
+}
+
+/// Copy a key's value into its struct. Keys written before a field was added
+/// are shorter: those fields read as zero, as with bkey_val_copy_pad().
+fn val_copy_pad<T: Default>(bytes: &[u8]) -> T {
+    let mut v = T::default();
+    let len = bytes.len().min(mem::size_of::<T>());
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), &mut v as *mut T as *mut u8, len);
+    }
+    v
+}
+
+/// A subvolume's key, as stored.
+pub fn subvol_info(handle: &BcachefsHandle, subvol: u64) -> Result<c::bch_subvolume> {
+    let mut iter = OnlineBtreeIter::with_buf_size(handle, c::btree_id::subvolumes, 0,
+        pos(0, subvol), pos(0, subvol), OnlineIterFlags::default(), 4096);
+
+    match iter.next().map_err(|e| anyhow!("querying subvolumes btree: {e}"))? {
+        Some(k) if matches!(k.v(), BkeyValSC::subvolume(..)) => Ok(val_copy_pad(k.val_bytes())),
+        _ => bail!("subvolume {subvol} not found"),
+    }
+}
+
+/// A snapshot node's key; None if there isn't one.
+pub fn snapshot_info(handle: &BcachefsHandle, id: u32) -> Result<Option<c::bch_snapshot>> {
+    let mut iter = OnlineBtreeIter::with_buf_size(handle, c::btree_id::snapshots, 0,
+        pos(0, id as u64), pos(0, id as u64), OnlineIterFlags::default(), 4096);
+
+    match iter.next().map_err(|e| anyhow!("querying snapshots btree: {e}"))? {
+        Some(k) if matches!(k.v(), BkeyValSC::snapshot(..)) => Ok(Some(val_copy_pad(k.val_bytes()))),
+        _ => Ok(None),
+    }
+}
+
+/// A snapshot tree's key; None if there isn't one.
+pub fn snapshot_tree_info(handle: &BcachefsHandle, tree: u32) -> Result<Option<c::bch_snapshot_tree>> {
+    let mut iter = OnlineBtreeIter::with_buf_size(handle, c::btree_id::snapshot_trees, 0,
+        pos(0, tree as u64), pos(0, tree as u64), OnlineIterFlags::default(), 4096);
+
+    match iter.next().map_err(|e| anyhow!("querying snapshot_trees btree: {e}"))? {
+        Some(k) if matches!(k.v(), BkeyValSC::snapshot_tree(..)) => Ok(Some(val_copy_pad(k.val_bytes()))),
+        _ => Ok(None),
+    }
+}
+
+/// A subvolume's snapshot ID, root inode number and flags.
+fn subvol_lookup(handle: &BcachefsHandle, subvol: u64) -> Result<(u32, u64)> {
+    let v = subvol_info(handle, subvol)?;
+    Ok((u32::from_le(v.snapshot), u64::from_le(v.inode)))
+}
+
+/// The snapshot ID a subvolume's files are currently seen through.
+pub fn subvol_snapshot(handle: &BcachefsHandle, subvol: u64) -> Result<u32> {
+    Ok(subvol_lookup(handle, subvol)?.0)
+}
+
+/// The inode number of a subvolume's root directory - shared by all of its
+/// snapshots.
+pub fn subvol_root_inode(handle: &BcachefsHandle, subvol: u64) -> Result<u64> {
+    Ok(subvol_lookup(handle, subvol)?.1)
+}
+
+/// Whether a subvolume is read-only (BCH_SUBVOLUME_RO).
+pub fn subvol_is_ro(handle: &BcachefsHandle, subvol: u64) -> Result<bool> {
+    Ok(subvol_info(handle, subvol)?.ro())
+}
+
+fn extent_poisoned(val: &BkeyValSC<'_>) -> bool {
+    bkey_extent_flags_sc(val) & (1 << c::bch_extent_flags_e::BCH_EXTENT_FLAG_poisoned as u32) != 0
+}
+
+fn extent_reconcile(val: &BkeyValSC<'_>) -> ExtentReconcile {
+    bkey_reconcile_opts_sc(val)
+        .map(|r| ExtentReconcile {
+            need_rb: r.need_rb() as u8,
+            pending: r.pending() != 0,
+            hipri:   r.hipri() != 0,
+        })
+        .unwrap_or_default()
+}
+
+fn extent_devs(val: &BkeyValSC<'_>) -> Vec<u32> {
+    let mut devs: Vec<u32> = bkey_ptrs_sc(val).map(|p| p.dev() as u32).collect();
+    devs.sort();
+    devs.dedup();
+    devs
+}
+
+fn extent_cached_devs(val: &BkeyValSC<'_>) -> Vec<u32> {
+    let dirty: Vec<u32> = bkey_ptrs_sc(val)
+        .filter(|p| p.cached() == 0)
+        .map(|p| p.dev() as u32)
+        .collect();
+
+    extent_devs(val).into_iter().filter(|d| !dirty.contains(d)).collect()
+}
+
+/// Indirect extents behind a reflink pointer: [idx, idx + size) sectors of
+/// the reflink btree, mapped to file sectors starting at @file_start.
+fn reflink_extents(handle: &BcachefsHandle, idx: u64, size: u64, file_start: u64,
+                   out: &mut Vec<FileExtent>) -> Result<()> {
+    let mut iter = OnlineBtreeIter::new(handle, c::btree_id::reflink, 0,
+        pos(0, idx + 1), pos(0, idx + size), OnlineIterFlags::default());
+
+    while let Some(k) = iter.next().map_err(|e| anyhow!("querying reflink btree: {e}"))? {
+        let val = k.v();
+        if !matches!(val, BkeyValSC::reflink_v(..)) {
+            continue;
+        }
+
+        let start = (k.k.p.offset - k.k.size as u64).max(idx);
+        let end = k.k.p.offset.min(idx + size);
+        if start < end {
+            out.push(FileExtent {
+                start:     (file_start + start - idx) << 9,
+                end:       (file_start + end - idx) << 9,
+                devs:      extent_devs(&val),
+                cached:    extent_cached_devs(&val),
+                poisoned:  extent_poisoned(&val),
+                reconcile: extent_reconcile(&val),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Data extents of inode @inum in @subvol overlapping the byte range
+/// [start, end), clamped to it, in file order.
+pub fn file_extents(handle: &BcachefsHandle, subvol: u64, inum: u64,
+                    start: u64, end: u64) -> Result<Vec<FileExtent>> {
+    let snapshot = subvol_snapshot(handle, subvol)?;
+    let start_sector = start >> 9;
+    let end_sector = end.div_ceil(512);
+
+    /* Extents are indexed by their end position: */
+    let mut iter = OnlineBtreeIter::new(handle, c::btree_id::extents, 0,
+        spos(inum, start_sector + 1, snapshot), spos(inum, u64::MAX, snapshot),
+        OnlineIterFlags::default());
+
+    let mut ret = Vec::new();
+    while let Some(k) = iter.next().map_err(|e| anyhow!("querying extents btree: {e}"))? {
+        let k_end = k.k.p.offset;
+        let k_start = k_end - k.k.size as u64;
+        if k_start >= end_sector {
+            break;
+        }
+
+        let val = k.v();
+        match val {
+            BkeyValSC::extent(..) => ret.push(FileExtent {
+                start:     k_start << 9,
+                end:       k_end << 9,
+                devs:      extent_devs(&val),
+                cached:    extent_cached_devs(&val),
+                poisoned:  extent_poisoned(&val),
+                reconcile: extent_reconcile(&val),
+            }),
+            BkeyValSC::reflink_p(_, v) => {
+                reflink_extents(handle, v.idx(), k_end - k_start, k_start, &mut ret)?;
+            }
+            _ => {}
+        }
+    }
+
+    for e in &mut ret {
+        e.start = e.start.max(start);
+        e.end = e.end.min(end);
+    }
+    ret.retain(|e| e.start < e.end);
+    Ok(ret)
+}
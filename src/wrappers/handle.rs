@@ -10,8 +10,10 @@ use bch_bindgen::c::{
     bch_ioctl_disk_set_state, bch_ioctl_disk_set_state_v2,
     bch_ioctl_disk_resize, bch_ioctl_disk_resize_v2,
     bch_ioctl_disk_resize_journal, bch_ioctl_disk_resize_journal_v2,
-    bch_ioctl_subvolume, bch_ioctl_subvolume_v2,
+    bch_ioctl_subvolume, bch_ioctl_subvolume_v2, bch_ioctl_subvolume_rollback,
+    bch_ioctl_subvolume_set, bch_ioctl_key_rotate,
     bch_ioctl_query_btree_keys, bch_ioctl_query_uuid, bch_ioctl_read_super,
+    bch_ioctl_subvol_to_path,
     BCH_BY_INDEX, BCH_SUBVOL_SNAPSHOT_CREATE,
 };
 use bch_bindgen::accounting::data_type;
@@ -383,7 +385,78 @@ impl BcachefsHandle {
         )
     }
 
-    fn disk_ioctl<V2, V1>(&self, flags: u32, dev: u64) -> Result<(), Errno>
+    /// Roll the subvolume at `subvol` back to the snapshot at `target`, in
+    /// place, keeping its state from before the rollback as a read-only
+    /// snapshot at `save`
+    pub fn rollback_subvolume<P: AsRef<Path>>(
+        &self,
+        subvol: P,
+        target: P,
+        save: P,
+    ) -> Result<(), Errno> {
+        let subvol = path_to_cstr(subvol);
+        let target = path_to_cstr(target);
+        let save = path_to_cstr(save);
+
+        let mut err_buf = [0u8; 8192];
+        let mut arg = bch_ioctl_subvolume_rollback {
+            dirfd:      libc::AT_FDCWD as u32,
+            subvol_ptr: subvol.as_ptr() as u64,
+            target_ptr: target.as_ptr() as u64,
+            save_ptr:   save.as_ptr() as u64,
+            ..Default::default()
+        };
+        arg.err.msg_ptr = err_buf.as_mut_ptr() as u64;
+        arg.err.msg_len = err_buf.len() as u32;
+
+        ioctl_w::<BCH_IOCTL_SUBVOLUME_ROLLBACK>(self.ioctl_fd(), &arg)
+            .map(|_| ())
+            .map_err(|e| {
+                print_errmsg(&err_buf);
+                io_errno(e)
+            })
+    }
+
+    /// Change the properties of the subvolume rooted at `path` selected by
+    /// `arg.flags` (BCH_SUBVOL_SET_*); the path is filled in here
+    pub fn set_subvolume<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mut arg: bch_ioctl_subvolume_set,
+    ) -> Result<(), Errno> {
+        let path = path_to_cstr(path);
+
+        let mut err_buf = [0u8; 8192];
+        arg.dirfd       = libc::AT_FDCWD as u32;
+        arg.path_ptr    = path.as_ptr() as u64;
+        arg.err.msg_ptr = err_buf.as_mut_ptr() as u64;
+        arg.err.msg_len = err_buf.len() as u32;
+
+        ioctl_w::<BCH_IOCTL_SUBVOLUME_SET>(self.ioctl_fd(), &arg)
+            .map(|_| ())
+            .map_err(|e| {
+                print_errmsg(&err_buf);
+                io_errno(e)
+            })
+    }
+
+    /// Start rotating the master encryption key: the filesystem re-encrypts
+    /// everything with the new key in the background
+    pub fn key_rotate(&self) -> Result<(), Errno> {
+        let mut err_buf = [0u8; 8192];
+        let mut arg = bch_ioctl_key_rotate::default();
+        arg.err.msg_ptr = err_buf.as_mut_ptr() as u64;
+        arg.err.msg_len = err_buf.len() as u32;
+
+        ioctl_w::<BCH_IOCTL_KEY_ROTATE>(self.ioctl_fd(), &arg)
+            .map(|_| ())
+            .map_err(|e| {
+                print_errmsg(&err_buf);
+                io_errno(e)
+            })
+    }
+
+        fn disk_ioctl<V2, V1>(&self, flags: u32, dev: u64) -> Result<(), Errno>
     where
         V2: Ioctl<Arg = bch_ioctl_disk_v2>,
         V1: Ioctl<Arg = bch_ioctl_disk>,
@@ -486,6 +559,22 @@ impl BcachefsHandle {
             .map(|_| ()).map_err(io_errno)
     }
 
+    /// BCH_IOCTL_SUBVOLUME_TO_PATH: the path of a subvolume's root, relative
+    /// to the filesystem root (empty for the root subvolume).
+    pub(crate) fn subvolume_to_path(&self, subvolid: u32) -> Result<String, Errno> {
+        let mut buf = vec![0u8; 4096];
+        let mut arg = bch_ioctl_subvol_to_path {
+            subvolid,
+            buf_size: buf.len() as u32,
+            buf:      buf.as_mut_ptr() as u64,
+        };
+
+        ioctl_rw::<BCH_IOCTL_SUBVOLUME_TO_PATH>(self.ioctl_fd(), &mut arg).map_err(io_errno)?;
+
+        let path = CStr::from_bytes_until_nul(&buf).map_err(|_| Errno(libc::EPROTO))?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
     /// Read the on-disk metadata version from the filesystem superblock.
     pub(crate) fn sb_version(&self) -> Result<u16, Errno> {
         let buf = self.read_super()?;
@@ -496,6 +585,25 @@ impl BcachefsHandle {
         Ok(sb.version)
     }
 
+    /// Convert a filesystem timestamp (inode times, subvolume otime) to
+    /// seconds and nanoseconds since the epoch, as bch2_time_to_timespec()
+    /// does: they're in units of the superblock's time_precision, relative
+    /// to its time_base_lo.
+    pub(crate) fn time_to_unix(&self, time: i64) -> Result<(i64, u32), Errno> {
+        let buf = self.read_super()?;
+        if buf.len() < mem::size_of::<bch_sb>() {
+            return Err(Errno(libc::EIO));
+        }
+        let sb = unsafe { &*(buf.as_ptr() as *const bch_sb) };
+
+        let nsec_per_unit = (u32::from_le(sb.time_precision) as i64).max(1);
+        let units_per_sec = 1_000_000_000 / nsec_per_unit;
+        let t = time + u64::from_le(sb.time_base_lo) as i64 / nsec_per_unit;
+
+        Ok((t.div_euclid(units_per_sec),
+            (t.rem_euclid(units_per_sec) * nsec_per_unit) as u32))
+    }
+
     /// Query device usage (v2 with flex array, v1 fallback).
     pub(crate) fn dev_usage(&self, dev_idx: u32) -> Result<DevUsage, Errno> {
         let nr_data_types = data_type::nr.0 as usize;
@@ -1,9 +1,11 @@
 pub mod accounting;
 pub mod bdev;
+pub mod file_extents;
 pub mod handle;
 pub mod ioctl;
 pub mod online_iter;
 pub mod sb_display;
+pub mod snapshot_diff;
 pub mod super_io;
 pub mod sysfs;
 
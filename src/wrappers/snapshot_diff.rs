@@ -0,0 +1,478 @@
+//! What differs between two snapshots of a subvolume, from the btree keys
+//! alone, on a mounted filesystem.
+//!
+//! A key is visible in the snapshot it was written in and every descendant,
+//! until overwritten. So a key written in a snapshot both sides descend from
+//! reads the same from both - unless one side overwrote it, and then the
+//! overwrite is a key written in a snapshot only that side descends from.
+//! The positions that can differ are exactly the ones with a key written in
+//! one side's ancestry and not the other's: those are looked up in both
+//! views, and the rest of the btree is passed over without being compared.
+//!
+//! Keys aren't indexed by snapshot, so finding those positions still reads
+//! every snapshot's keys: the whole inodes btree, then the dirents, xattrs
+//! and extents of every inode either side sees. That takes time in
+//! proportion to the filesystem, not to how much changed.
+//!
+//! "old" may be absent - a full diff against nothing, where everything the
+//! new side sees counts as changed.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use bcachefs_kernel::btree::bkey::{pos, spos, BkeySC, BkeyValSC, POS_MIN, SPOS_MAX};
+use bcachefs_kernel::c;
+use bcachefs_kernel::{dirent, inode};
+
+use crate::wrappers::handle::BcachefsHandle;
+use crate::wrappers::online_iter::{OnlineBtreeIter, OnlineIterFlags};
+
+/// DT_SUBVOL: a dirent pointing at another subvolume's root
+pub const DT_SUBVOL: u8 = 16;
+
+/// The fields of an inode needed to tell what changed and where it is,
+/// unpacked from an inode key. Anything to be reproduced elsewhere is
+/// better read through the VFS, in the units userspace expects.
+#[derive(Clone, Debug)]
+pub struct Inode {
+    pub inum:       u64,
+    pub mode:       u32,
+    pub size:       u64,
+    pub uid:        u32,
+    pub gid:        u32,
+    /// In filesystem time units: only good for comparing
+    pub mtime:      u64,
+    /// Backpointer: the dirent (dir inode, dirent offset) that links it
+    pub dir:        u64,
+    pub dir_offset: u64,
+    /// Nonzero for subvolume roots
+    pub subvol:     u32,
+    bytes:          Vec<u8>,
+}
+
+impl PartialEq for Inode {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Inode {
+    pub fn is_dir(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    pub fn is_reg(&self) -> bool {
+        self.mode & libc::S_IFMT == libc::S_IFREG
+    }
+
+    /// Whether anything a user would call a modification differs - not just
+    /// ctime, or the backpointer a rename rewrites.
+    pub fn modified(&self, other: &Inode) -> bool {
+        self.mode != other.mode ||
+            self.size != other.size ||
+            self.uid != other.uid ||
+            self.gid != other.gid ||
+            self.mtime != other.mtime
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dirent {
+    pub name:   Vec<u8>,
+    /// Target inode number - or for DT_SUBVOL, the child subvolume ID
+    pub target: u64,
+    pub d_type: u8,
+}
+
+pub struct InodeChange {
+    pub old: Option<Inode>,
+    pub new: Option<Inode>,
+}
+
+pub struct DirentChange {
+    pub dir: u64,
+    pub old: Option<Dirent>,
+    pub new: Option<Dirent>,
+}
+
+#[derive(Default)]
+pub struct SnapshotDiff {
+    /// Inodes whose key differs, by inode number
+    pub inodes:  BTreeMap<u64, InodeChange>,
+    /// Dirents that differ, in (dir, hash) order
+    pub dirents: Vec<DirentChange>,
+    /// Inodes with an xattr that differs
+    pub xattrs:  BTreeSet<u64>,
+    /// Byte ranges of file data that may differ, by inode, merged
+    pub extents: BTreeMap<u64, Vec<Range<u64>>>,
+}
+
+impl SnapshotDiff {
+    /// Whether @inum exists on the given side. An inode not in the diff
+    /// reads the same from both: it exists on both or neither, and it's
+    /// only ever asked about because a dirent names it.
+    pub fn exists_old(&self, inum: u64) -> bool {
+        self.inodes.get(&inum).map(|c| c.old.is_some()).unwrap_or(true)
+    }
+
+    pub fn exists_new(&self, inum: u64) -> bool {
+        self.inodes.get(&inum).map(|c| c.new.is_some()).unwrap_or(true)
+    }
+}
+
+fn snapshot_parent(handle: &BcachefsHandle, id: u32) -> Result<u32> {
+    let mut iter = OnlineBtreeIter::with_buf_size(handle, c::btree_id::snapshots, 0,
+        pos(0, id as u64), pos(0, id as u64), OnlineIterFlags::default(), 4096);
+
+    match iter.next().map_err(|e| anyhow!("querying snapshots btree: {e}"))? {
+        Some(k) => match k.v() {
+            BkeyValSC::snapshot(_, v) => Ok(u32::from_le(v.parent)),
+            _ => bail!("snapshot {id} not found"),
+        },
+        None => bail!("snapshot {id} not found"),
+    }
+}
+
+/// @id and every snapshot it descends from, nearest first.
+pub fn snapshot_ancestors(handle: &BcachefsHandle, id: u32) -> Result<Vec<u32>> {
+    let mut ret = vec![id];
+    let mut cur = id;
+    loop {
+        cur = snapshot_parent(handle, cur)?;
+        if cur == 0 {
+            return Ok(ret);
+        }
+        if ret.contains(&cur) {
+            bail!("snapshot {id}: loop in parent pointers at {cur}");
+        }
+        ret.push(cur);
+    }
+}
+
+pub struct SnapshotPair {
+    pub old:  Option<u32>,
+    pub new:  u32,
+    only_old: HashSet<u32>,
+    only_new: HashSet<u32>,
+    /// Every snapshot either side descends from
+    either:   HashSet<u32>,
+}
+
+impl SnapshotPair {
+    pub fn new(handle: &BcachefsHandle, old: Option<u32>, new: u32) -> Result<Self> {
+        let old_anc: HashSet<u32> = match old {
+            Some(old) => snapshot_ancestors(handle, old)?.into_iter().collect(),
+            None => HashSet::new(),
+        };
+        let new_anc: HashSet<u32> = snapshot_ancestors(handle, new)?.into_iter().collect();
+
+        Ok(SnapshotPair {
+            old,
+            new,
+            only_old: old_anc.difference(&new_anc).copied().collect(),
+            only_new: new_anc.difference(&old_anc).copied().collect(),
+            either:   old_anc.union(&new_anc).copied().collect(),
+        })
+    }
+
+    /// Whether a key written in @snapshot is visible from either side.
+    pub fn sees(&self, snapshot: u32) -> bool {
+        self.either.contains(&snapshot)
+    }
+
+    /// Whether a key written in @snapshot can read differently from the two
+    /// sides.
+    pub fn differs(&self, snapshot: u32) -> bool {
+        self.only_old.contains(&snapshot) || self.only_new.contains(&snapshot)
+    }
+}
+
+/// Every key in @btree within @inums written in a snapshot only one side sees
+/// - including whiteouts, which are how a deletion on one side shows up.
+fn for_each_changed_key<F>(handle: &BcachefsHandle, btree: c::btree_id,
+                           pair: &SnapshotPair, inums: &[Range<u64>], mut f: F) -> Result<()>
+where
+    F: FnMut(&BkeySC<'_>),
+{
+    for r in inums {
+        let mut iter = OnlineBtreeIter::with_buf_size(handle, btree, 0,
+            pos(r.start, 0), spos(r.end - 1, u64::MAX, u32::MAX),
+            OnlineIterFlags::ALL_SNAPSHOTS, 1 << 16);
+
+        while let Some(k) = iter.next().map_err(|e| anyhow!("querying {btree:?} btree: {e}"))? {
+            if pair.differs(k.k.p.snapshot) {
+                f(&k);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs of consecutive inode numbers: the key ranges, in a btree keyed by
+/// inode number, that hold those inodes' keys.
+fn inum_ranges(inums: &BTreeSet<u64>) -> Vec<Range<u64>> {
+    let mut ret: Vec<Range<u64>> = Vec::new();
+    for &i in inums {
+        match ret.last_mut() {
+            Some(last) if last.end == i => last.end = i + 1,
+            _ => ret.push(i..i + 1),
+        }
+    }
+    ret
+}
+
+/// The key visible at @inode:@offset in @snapshot, passed through @f;
+/// None if there is none, or it's been deleted there.
+fn lookup<T, F>(handle: &BcachefsHandle, btree: c::btree_id,
+                inode: u64, offset: u64, snapshot: u32, f: F) -> Result<Option<T>>
+where
+    F: FnOnce(&BkeySC<'_>) -> Result<Option<T>>,
+{
+    let mut iter = OnlineBtreeIter::with_buf_size(handle, btree, 0,
+        spos(inode, offset, snapshot), SPOS_MAX, OnlineIterFlags::SLOTS, 4096);
+
+    match iter.next().map_err(|e| anyhow!("querying {btree:?} btree: {e}"))? {
+        Some(k) if k.k.p.inode == inode && k.k.p.offset == offset => f(&k),
+        _ => Ok(None),
+    }
+}
+
+fn inode_unpack(k: &BkeySC<'_>) -> Result<Option<Inode>> {
+    if !matches!(k.v(), BkeyValSC::inode(..) | BkeyValSC::inode_v2(..) | BkeyValSC::inode_v3(..)) {
+        return Ok(None);
+    }
+
+    let u = inode::unpack(k);
+
+    Ok(Some(Inode {
+        inum:       u.bi_inum,
+        mode:       u.bi_mode as u32,
+        size:       u.bi_size,
+        uid:        u.bi_uid,
+        gid:        u.bi_gid,
+        mtime:      u.bi_mtime,
+        dir:        u.bi_dir,
+        dir_offset: u.bi_dir_offset,
+        subvol:     u.bi_subvol,
+        bytes:      k.val_bytes().to_vec(),
+    }))
+}
+
+fn dirent_unpack(k: &BkeySC<'_>) -> Option<Dirent> {
+    let BkeyValSC::dirent(bk, d) = k.v() else {
+        return None;
+    };
+
+    let d_type = d.d_type();
+    let target = unsafe {
+        if d_type == DT_SUBVOL {
+            u32::from_le(d.__bindgen_anon_1.__bindgen_anon_1.d_child_subvol) as u64
+        } else {
+            u64::from_le(d.__bindgen_anon_1.d_inum)
+        }
+    };
+
+    Some(Dirent { name: dirent::get_name(bk, d).to_vec(), target, d_type })
+}
+
+/// Inode @inum as seen from @snapshot.
+pub fn lookup_inode(handle: &BcachefsHandle, snapshot: u32, inum: u64) -> Result<Option<Inode>> {
+    lookup(handle, c::btree_id::inodes, 0, inum, snapshot, inode_unpack)
+}
+
+/// The dirent at @dir:@offset as seen from @snapshot.
+pub fn lookup_dirent(handle: &BcachefsHandle, snapshot: u32,
+                     dir: u64, offset: u64) -> Result<Option<Dirent>> {
+    lookup(handle, c::btree_id::dirents, dir, offset, snapshot, |k| Ok(dirent_unpack(k)))
+}
+
+fn merge_ranges(r: &mut Vec<Range<u64>>) {
+    r.sort_by_key(|r| r.start);
+    let mut out: Vec<Range<u64>> = Vec::with_capacity(r.len());
+    for i in r.drain(..) {
+        match out.last_mut() {
+            Some(last) if i.start <= last.end => last.end = last.end.max(i.end),
+            _ => out.push(i),
+        }
+    }
+    *r = out;
+}
+
+/// Everything that differs between the two sides of @pair: one pass over the
+/// inodes btree, for the inodes either side can see and the keys that can
+/// differ, then over just those inodes in the dirents, xattrs and extents
+/// btrees; then a lookup in each side for the positions that can differ.
+pub fn snapshot_diff(handle: &BcachefsHandle, pair: &SnapshotPair) -> Result<SnapshotDiff> {
+    let mut ret = SnapshotDiff::default();
+
+    /* Every key either side sees in the other btrees belongs to an inode
+     * with a key in either side's ancestry: */
+    let mut visible = BTreeSet::new();
+    let mut inums = BTreeSet::new();
+    let mut iter = OnlineBtreeIter::new(handle, c::btree_id::inodes, 0, POS_MIN, SPOS_MAX,
+        OnlineIterFlags::ALL_SNAPSHOTS);
+    while let Some(k) = iter.next().map_err(|e| anyhow!("querying inodes btree: {e}"))? {
+        if pair.sees(k.k.p.snapshot) {
+            visible.insert(k.k.p.offset);
+        }
+        if pair.differs(k.k.p.snapshot) {
+            inums.insert(k.k.p.offset);
+        }
+    }
+    let visible = inum_ranges(&visible);
+
+    for inum in inums {
+        let old = match pair.old {
+            Some(s) => lookup_inode(handle, s, inum)?,
+            None => None,
+        };
+        let new = lookup_inode(handle, pair.new, inum)?;
+        if old != new {
+            ret.inodes.insert(inum, InodeChange { old, new });
+        }
+    }
+
+    let mut dirents = BTreeSet::new();
+    for_each_changed_key(handle, c::btree_id::dirents, pair, &visible, |k| {
+        dirents.insert((k.k.p.inode, k.k.p.offset));
+    })?;
+
+    for (dir, offset) in dirents {
+        let old = match pair.old {
+            Some(s) => lookup_dirent(handle, s, dir, offset)?,
+            None => None,
+        };
+        let new = lookup_dirent(handle, pair.new, dir, offset)?;
+        if old != new {
+            ret.dirents.push(DirentChange { dir, old, new });
+        }
+    }
+
+    /* An xattr key is a (name, value) pair hashed to its slot - an inode
+     * with any of them changed has its whole set resent, so which one
+     * doesn't matter: */
+    for_each_changed_key(handle, c::btree_id::xattrs, pair, &visible, |k| {
+        ret.xattrs.insert(k.k.p.inode);
+    })?;
+
+    /* Extents are indexed by end position; whiteouts carry a size too: */
+    for_each_changed_key(handle, c::btree_id::extents, pair, &visible, |k| {
+        let end = k.k.p.offset;
+        let start = end - k.k.size as u64;
+        if start < end {
+            ret.extents.entry(k.k.p.inode).or_default().push((start << 9)..(end << 9));
+        }
+    })?;
+
+    for r in ret.extents.values_mut() {
+        merge_ranges(r);
+    }
+
+    Ok(ret)
+}
+
+/// Reflink pointers visible in @snapshot in inode @inum over [start, end)
+/// bytes: (file byte range, indirect extent index in sectors), clamped.
+pub fn reflink_pointers(handle: &BcachefsHandle, snapshot: u32, inum: u64,
+                        start: u64, end: u64) -> Result<Vec<(Range<u64>, u64)>> {
+    let start_sector = start >> 9;
+    let end_sector = end.div_ceil(512);
+
+    let mut iter = OnlineBtreeIter::new(handle, c::btree_id::extents, 0,
+        spos(inum, start_sector + 1, snapshot), spos(inum, u64::MAX, snapshot),
+        OnlineIterFlags::default());
+
+    let mut ret = Vec::new();
+    while let Some(k) = iter.next().map_err(|e| anyhow!("querying extents btree: {e}"))? {
+        let k_end = k.k.p.offset;
+        let k_start = k_end - k.k.size as u64;
+        if k_start >= end_sector {
+            break;
+        }
+
+        if let BkeyValSC::reflink_p(_, v) = k.v() {
+            let mut idx = v.idx();
+            let s = k_start.max(start_sector);
+            let e = k_end.min(end_sector);
+            idx += s - k_start;
+            ret.push(((s << 9)..(e << 9), idx));
+        }
+    }
+    Ok(ret)
+}
+
+/// Paths of inodes within a subvolume as seen from one snapshot, walked up
+/// through inode backpointers to the subvolume root. Directories are
+/// cached, so resolving a batch of paths costs a lookup per distinct
+/// directory rather than per path component.
+pub struct PathResolver<'h> {
+    handle:   &'h BcachefsHandle,
+    snapshot: u32,
+    root:     u64,
+    dirs:     HashMap<u64, Option<PathBuf>>,
+}
+
+impl<'h> PathResolver<'h> {
+    pub fn new(handle: &'h BcachefsHandle, snapshot: u32, root: u64) -> Self {
+        let mut dirs = HashMap::new();
+        dirs.insert(root, Some(PathBuf::new()));
+        PathResolver { handle, snapshot, root, dirs }
+    }
+
+    pub fn snapshot(&self) -> u32 {
+        self.snapshot
+    }
+
+    /// The directory @dir, relative to the subvolume root - None if it
+    /// isn't reachable from the root in this snapshot.
+    pub fn dir_path(&mut self, dir: u64) -> Result<Option<PathBuf>> {
+        if let Some(p) = self.dirs.get(&dir) {
+            return Ok(p.clone());
+        }
+        /* Unreachable until proven otherwise - a loop in the backpointers
+         * then ends the walk instead of recursing forever: */
+        self.dirs.insert(dir, None);
+        let p = self.walk(dir)?;
+        self.dirs.insert(dir, p.clone());
+        Ok(p)
+    }
+
+    /// The path of inode @inum through its backpointer - for a file with
+    /// several links, the one it was created with or last renamed to.
+    pub fn path(&mut self, inum: u64) -> Result<Option<PathBuf>> {
+        if inum == self.root {
+            return Ok(Some(PathBuf::new()));
+        }
+        if let Some(p) = self.dirs.get(&inum) {
+            return Ok(p.clone());
+        }
+        self.walk(inum)
+    }
+
+    fn walk(&mut self, inum: u64) -> Result<Option<PathBuf>> {
+        let Some(inode) = lookup_inode(self.handle, self.snapshot, inum)? else {
+            return Ok(None);
+        };
+        if inode.dir == 0 || inode.subvol != 0 {
+            return Ok(None);
+        }
+        self.link_path(inode.dir, inode.dir_offset, inum)
+    }
+
+    /// The path of the dirent at @dir:@offset, if in this snapshot it's
+    /// there and links to @inum.
+    pub fn link_path(&mut self, dir: u64, offset: u64, inum: u64) -> Result<Option<PathBuf>> {
+        let Some(d) = lookup_dirent(self.handle, self.snapshot, dir, offset)? else {
+            return Ok(None);
+        };
+        if d.target != inum || d.d_type == DT_SUBVOL {
+            return Ok(None);
+        }
+
+        use std::os::unix::ffi::OsStrExt;
+        Ok(self.dir_path(dir)?
+            .map(|p| p.join(std::ffi::OsStr::from_bytes(&d.name))))
+    }
+}